@@ -0,0 +1,187 @@
+"use strict";
+
+// Historic tiers, from the most detailed to the most reduced
+const TIERS = ["minute", "hour", "days"];
+const CHARTS = [
+  { canvas: "pressure", series: [{ channel: "pressure", color: "#1f77b4" }] },
+  {
+    canvas: "temperatures",
+    series: [
+      { channel: "bmp280Temp", color: "#d62728" },
+      { channel: "htu21Temp", color: "#ff7f0e" },
+    ],
+  },
+  { canvas: "humidity", series: [{ channel: "humidity", color: "#2ca02c" }] },
+];
+const REFRESH_MS = 5000;
+
+const tiers = { minute: [], hour: [], days: [] };
+// Displayed duration in seconds, up to now
+let windowSeconds = 1800;
+
+// The token of the listener, if any, is given to the page in its query
+function url(path) {
+  return path + window.location.search;
+}
+
+async function fetchTier(tier) {
+  const response = await fetch(url("tier/" + tier));
+  if (!response.ok) {
+    throw new Error(tier + " : " + response.status);
+  }
+  const samples = await response.json();
+  if (!Array.isArray(samples)) {
+    throw new Error(tier + " : " + (samples.error || "unexpected answer"));
+  }
+  tiers[tier] = samples;
+}
+
+// Samples of the most detailed tier going back to the start of the window
+function samplesOfWindow(start) {
+  let selected = null;
+  for (const tier of TIERS) {
+    const samples = tiers[tier];
+    if (samples.length > 0 && samples[0].timestamp <= start) {
+      selected = samples;
+      break;
+    }
+  }
+  if (selected === null) {
+    for (const tier of TIERS) {
+      const samples = tiers[tier];
+      if (samples.length > 0 && (selected === null || samples[0].timestamp < selected[0].timestamp)) {
+        selected = samples;
+      }
+    }
+  }
+  return (selected || []).filter((sample) => sample.timestamp >= start);
+}
+
+function formatTime(timestamp, span) {
+  const date = new Date(timestamp);
+  const pad = (value) => String(value).padStart(2, "0");
+  const time = pad(date.getHours()) + ":" + pad(date.getMinutes());
+  if (span <= 86400000) {
+    return span <= 600000 ? time + ":" + pad(date.getSeconds()) : time;
+  }
+  return pad(date.getDate()) + "/" + pad(date.getMonth() + 1) + (span <= 604800000 ? " " + time : "");
+}
+
+function drawChart(chart, samples, start, end) {
+  const canvas = document.getElementById(chart.canvas);
+  const context = canvas.getContext("2d");
+  const width = canvas.width;
+  const height = canvas.height;
+  const margin = { left: 60, right: 10, top: 10, bottom: 24 };
+  context.clearRect(0, 0, width, height);
+  context.font = "12px sans-serif";
+  context.fillStyle = "#555";
+
+  let min = Infinity;
+  let max = -Infinity;
+  for (const sample of samples) {
+    for (const series of chart.series) {
+      min = Math.min(min, sample[series.channel]);
+      max = Math.max(max, sample[series.channel]);
+    }
+  }
+  if (samples.length === 0) {
+    context.fillText("no sample", width / 2 - 30, height / 2);
+    return;
+  }
+  if (max - min < 0.1) {
+    min -= 0.05;
+    max += 0.05;
+  }
+  const x = (timestamp) =>
+    margin.left + ((timestamp - start) / (end - start)) * (width - margin.left - margin.right);
+  const y = (value) =>
+    height - margin.bottom - ((value - min) / (max - min)) * (height - margin.top - margin.bottom);
+
+  // grid and labels
+  context.strokeStyle = "#e0e0e0";
+  context.lineWidth = 1;
+  for (let index = 0; index <= 4; index++) {
+    const value = min + ((max - min) * index) / 4;
+    context.beginPath();
+    context.moveTo(margin.left, y(value));
+    context.lineTo(width - margin.right, y(value));
+    context.stroke();
+    context.fillText(value.toFixed(1), 4, y(value) + 4);
+  }
+  for (let index = 0; index <= 6; index++) {
+    const timestamp = start + ((end - start) * index) / 6;
+    const label = formatTime(timestamp, end - start);
+    const labelX = Math.min(x(timestamp), width - margin.right - context.measureText(label).width);
+    context.fillText(label, Math.max(margin.left, labelX - 20), height - 6);
+  }
+
+  for (const series of chart.series) {
+    context.strokeStyle = series.color;
+    context.lineWidth = 1.5;
+    context.beginPath();
+    samples.forEach((sample, index) => {
+      const pointX = x(sample.timestamp);
+      const pointY = y(sample[series.channel]);
+      if (index === 0) {
+        context.moveTo(pointX, pointY);
+      } else {
+        context.lineTo(pointX, pointY);
+      }
+    });
+    context.stroke();
+  }
+  chart.series.forEach((series, index) => {
+    context.fillStyle = series.color;
+    context.fillText(series.channel, width - margin.right - 90, margin.top + 12 + 14 * index);
+  });
+}
+
+function draw() {
+  const end = Date.now();
+  const start = end - windowSeconds * 1000;
+  const samples = samplesOfWindow(start);
+  for (const chart of CHARTS) {
+    drawChart(chart, samples, start, end);
+  }
+  for (const button of document.querySelectorAll("#windows button")) {
+    button.classList.toggle("selected", Number(button.dataset.window) === windowSeconds);
+  }
+}
+
+async function refresh(allTiers) {
+  const status = document.getElementById("status");
+  try {
+    await Promise.all((allTiers ? TIERS : ["minute"]).map(fetchTier));
+    const latest = tiers.minute[tiers.minute.length - 1];
+    status.textContent = latest ? "Last sample " + new Date(latest.timestamp).toLocaleString() : "";
+  } catch (error) {
+    status.textContent = "Error : " + error.message;
+  }
+  draw();
+}
+
+function zoom(event) {
+  event.preventDefault();
+  const factor = event.deltaY > 0 ? 1.5 : 1 / 1.5;
+  windowSeconds = Math.round(Math.min(Math.max(windowSeconds * factor, 60), 5 * 366 * 86400));
+  draw();
+}
+
+for (const button of document.querySelectorAll("#windows button")) {
+  button.addEventListener("click", () => {
+    windowSeconds = Number(button.dataset.window);
+    draw();
+  });
+}
+for (const chart of CHARTS) {
+  document.getElementById(chart.canvas).addEventListener("wheel", zoom, { passive: false });
+}
+
+let nbRefreshes = 0;
+refresh(true);
+setInterval(() => {
+  nbRefreshes += 1;
+  // the reduced tiers change once a minute at most
+  refresh(nbRefreshes % 12 === 0);
+}, REFRESH_MS);
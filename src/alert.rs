@@ -0,0 +1,508 @@
+use crate::{
+    config::{Config, Section},
+    json_display::{self, JsonDisplay},
+    sensor_data::{SensorData, CHANNELS},
+};
+use std::{collections::VecDeque, io, time::Duration};
+
+/// What makes an alert active
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    Above(f64),
+    Below(f64),
+    /// The channel decreased by more than the amount during the window
+    Falls {
+        amount: f64,
+        window: Duration,
+    },
+    /// The channel increased by more than the amount during the window
+    Rises {
+        amount: f64,
+        window: Duration,
+    },
+}
+
+/// Alert rule read from an `[alert <name>]` section of the configuration:
+/// ```text
+/// [alert humidity_high]
+/// channel = humidity
+/// above = 80          # or below = ..., or falls = ... / rises = ... with within = 3h
+/// hysteresis = 2      # the alert is resolved when humidity < 78
+/// for = 10m           # firing only if the condition lasts 10 minutes
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlertRule {
+    name: String,
+    channel: String,
+    condition: Condition,
+    hysteresis: f64,
+    duration: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AlertState {
+    Inactive,
+    /// The condition is met, but not for long enough yet
+    Pending {
+        since: Duration,
+    },
+    Firing {
+        since: Duration,
+    },
+    Resolved {
+        at: Duration,
+    },
+}
+
+/// Change of state of an alert
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlertEvent {
+    pub name: String,
+    pub channel: String,
+    pub state: AlertState,
+    pub value: f64,
+    pub timestamp: Duration,
+}
+
+struct Alert {
+    rule: AlertRule,
+    state: AlertState,
+    value: Option<f64>,
+    // (timestamp, value) of the samples in the window of a rate of change rule
+    recent_values: VecDeque<(Duration, f64)>,
+}
+
+pub struct AlertEngine {
+    alerts: Vec<Alert>,
+}
+
+impl AlertRule {
+    pub fn new(name: &str, channel: &str, condition: Condition) -> AlertRule {
+        AlertRule {
+            name: name.to_string(),
+            channel: channel.to_string(),
+            condition,
+            hysteresis: 0.0,
+            duration: Duration::from_secs(0),
+        }
+    }
+
+    pub fn with_hysteresis(mut self, hysteresis: f64) -> AlertRule {
+        self.hysteresis = hysteresis;
+        self
+    }
+
+    pub fn with_duration(mut self, duration: Duration) -> AlertRule {
+        self.duration = duration;
+        self
+    }
+
+    pub fn from_section(section: &Section) -> Result<AlertRule, String> {
+        section.check_keys(&[
+            "channel",
+            "above",
+            "below",
+            "falls",
+            "rises",
+            "within",
+            "hysteresis",
+            "for",
+        ])?;
+        let name = section.name()?;
+        let channel: String = section.require("channel")?;
+        if !CHANNELS.contains(&channel.as_str()) {
+            return Err(section.error(&format!(
+                "unknown channel {}, expected one of {}",
+                channel,
+                CHANNELS.join(", ")
+            )));
+        }
+        let window = section.get_duration("within")?;
+        let mut conditions = Vec::new();
+        if let Some(threshold) = section.get("above")? {
+            conditions.push(Condition::Above(threshold));
+        }
+        if let Some(threshold) = section.get("below")? {
+            conditions.push(Condition::Below(threshold));
+        }
+        for (key, falling) in &[("falls", true), ("rises", false)] {
+            if let Some(amount) = section.get(key)? {
+                let window = match window {
+                    Some(window) => window,
+                    None => return Err(section.error(&format!("{} needs a within key", key))),
+                };
+                conditions.push(match falling {
+                    true => Condition::Falls { amount, window },
+                    false => Condition::Rises { amount, window },
+                });
+            }
+        }
+        let condition = match conditions.len() {
+            1 => conditions.remove(0),
+            _ => return Err(section.error("expected exactly one of above, below, falls or rises")),
+        };
+        if window.is_some() {
+            if let Condition::Above(_) | Condition::Below(_) = condition {
+                return Err(section.error("within is only used by falls and rises"));
+            }
+        }
+        let hysteresis: f64 = section.get_or("hysteresis", 0.0)?;
+        if hysteresis < 0.0 {
+            return Err(section.error("hysteresis can't be negative"));
+        }
+        Ok(AlertRule::new(name, &channel, condition)
+            .with_hysteresis(hysteresis)
+            .with_duration(section.get_duration("for")?.unwrap_or_default()))
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    /// Is the condition met by `measure`, `active` tells if it was met by the previous samples
+    fn is_met(&self, measure: f64, active: bool) -> bool {
+        let hysteresis = if active { self.hysteresis } else { 0.0 };
+        match self.condition {
+            Condition::Above(threshold) => measure > threshold - hysteresis,
+            Condition::Below(threshold) => measure < threshold + hysteresis,
+            Condition::Falls { amount, .. } | Condition::Rises { amount, .. } => {
+                measure > amount - hysteresis
+            }
+        }
+    }
+}
+
+impl AlertState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AlertState::Inactive => "inactive",
+            AlertState::Pending { .. } => "pending",
+            AlertState::Firing { .. } => "firing",
+            AlertState::Resolved { .. } => "resolved",
+        }
+    }
+
+    /// Time of the last change of state
+    pub fn since(&self) -> Option<Duration> {
+        match *self {
+            AlertState::Inactive => None,
+            AlertState::Pending { since } | AlertState::Firing { since } => Some(since),
+            AlertState::Resolved { at } => Some(at),
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        match self {
+            AlertState::Pending { .. } | AlertState::Firing { .. } => true,
+            AlertState::Inactive | AlertState::Resolved { .. } => false,
+        }
+    }
+}
+
+impl Alert {
+    /// Value compared to the thresholds of the rule
+    fn measure(&mut self, timestamp: Duration, value: f64) -> f64 {
+        let window = match self.rule.condition {
+            Condition::Above(_) | Condition::Below(_) => return value,
+            Condition::Falls { window, .. } | Condition::Rises { window, .. } => window,
+        };
+        self.recent_values.push_back((timestamp, value));
+        while let Some(&(oldest_timestamp, _)) = self.recent_values.front() {
+            if oldest_timestamp + window < timestamp {
+                self.recent_values.pop_front();
+            } else {
+                break;
+            }
+        }
+        let oldest_value = self
+            .recent_values
+            .front()
+            .map_or(value, |&(_, value)| value);
+        match self.rule.condition {
+            Condition::Falls { .. } => oldest_value - value,
+            _ => value - oldest_value,
+        }
+    }
+
+    fn update(&mut self, timestamp: Duration, value: f64) -> Option<AlertState> {
+        let measure = self.measure(timestamp, value);
+        self.value = Some(value);
+        let met = self.rule.is_met(measure, self.state.is_active());
+        let new_state = match (self.state, met) {
+            (AlertState::Inactive, true) | (AlertState::Resolved { .. }, true) => {
+                AlertState::Pending { since: timestamp }
+            }
+            (AlertState::Pending { since }, true) => AlertState::Pending { since },
+            (AlertState::Pending { .. }, false) => AlertState::Inactive,
+            (AlertState::Firing { since }, true) => AlertState::Firing { since },
+            (AlertState::Firing { .. }, false) => AlertState::Resolved { at: timestamp },
+            (state, false) => state,
+        };
+        let new_state = match new_state {
+            AlertState::Pending { since } if timestamp >= since + self.rule.duration => {
+                AlertState::Firing { since: timestamp }
+            }
+            state => state,
+        };
+        if new_state != self.state {
+            self.state = new_state;
+            Some(new_state)
+        } else {
+            None
+        }
+    }
+}
+
+impl JsonDisplay for AlertEvent {
+    type Context = ();
+
+    fn json_item(&self, _: &(), w: &mut dyn io::Write) -> io::Result<()> {
+        w.write_fmt(format_args!(
+            "{{\"name\": \"{}\", \"channel\": \"{}\", \"state\": \"{}\", \"value\": {:.3}, \"timestamp\": {}}}\n",
+            json_display::escape(&self.name),
+            self.channel,
+            self.state.name(),
+            self.value,
+            self.timestamp.as_millis()
+        ))
+    }
+}
+
+impl AlertEngine {
+    pub fn new(rules: Vec<AlertRule>) -> AlertEngine {
+        AlertEngine {
+            alerts: rules
+                .into_iter()
+                .map(|rule| Alert {
+                    rule,
+                    state: AlertState::Inactive,
+                    value: None,
+                    recent_values: VecDeque::new(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Result<AlertEngine, String> {
+        let mut rules: Vec<AlertRule> = Vec::new();
+        for section in config.sections("alert") {
+            let rule = AlertRule::from_section(section)?;
+            if rules.iter().any(|other| other.name == rule.name) {
+                return Err(section.error("an alert with this name is already defined"));
+            }
+            rules.push(rule);
+        }
+        Ok(AlertEngine::new(rules))
+    }
+
+    /// Evaluate the rules on a new sample and return the alerts which changed of state
+    pub fn update(&mut self, data: &SensorData) -> Vec<AlertEvent> {
+        let timestamp = data.get_timestamp();
+        let mut events = Vec::new();
+        for alert in &mut self.alerts {
+            let value = match data.get_channel(&alert.rule.channel) {
+                Some(value) => value,
+                None => continue,
+            };
+            if let Some(state) = alert.update(timestamp, value) {
+                events.push(AlertEvent {
+                    name: alert.rule.name.clone(),
+                    channel: alert.rule.channel.clone(),
+                    state,
+                    value,
+                    timestamp,
+                });
+            }
+        }
+        events
+    }
+
+    pub fn get_state(&self, name: &str) -> Option<AlertState> {
+        self.alerts
+            .iter()
+            .find(|alert| alert.rule.name == name)
+            .map(|alert| alert.state)
+    }
+
+    pub fn write_json(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        w.write_all(b"[")?;
+        for (index, alert) in self.alerts.iter().enumerate() {
+            if index > 0 {
+                w.write_all(b",\n")?;
+            }
+            w.write_fmt(format_args!(
+                "{{\"name\": \"{}\", \"channel\": \"{}\", \"state\": \"{}\"",
+                json_display::escape(&alert.rule.name),
+                alert.rule.channel,
+                alert.state.name()
+            ))?;
+            if let Some(since) = alert.state.since() {
+                w.write_fmt(format_args!(", \"since\": {}", since.as_millis()))?;
+            }
+            if let Some(value) = alert.value {
+                w.write_fmt(format_args!(", \"value\": {:.3}", value))?;
+            }
+            w.write_all(b"}")?;
+        }
+        w.write_all(b"]\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AlertEngine, AlertRule, AlertState, Condition};
+    use crate::{config::Config, sensor_data::SensorData};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Sample at `seconds` after the epoch, pressure in hPa and humidity in %
+    fn sample(seconds: u64, pressure: f32, humidity: f32) -> SensorData {
+        SensorData::new(
+            UNIX_EPOCH + Duration::from_secs(seconds),
+            pressure / 10.0,
+            20_000,
+            20_000,
+            (humidity * 1000.0) as i32,
+        )
+    }
+
+    fn states(engine: &mut AlertEngine, samples: &[SensorData]) -> Vec<&'static str> {
+        samples
+            .iter()
+            .map(|data| {
+                engine.update(data);
+                engine.get_state("alert").unwrap().name()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn threshold_with_minimum_duration() {
+        let rule = AlertRule::new("alert", "humidity", Condition::Above(80.0))
+            .with_duration(Duration::from_secs(600));
+        let mut engine = AlertEngine::new(vec![rule]);
+        let humidities = [70.0, 81.0, 85.0, 79.0, 82.0, 83.0, 84.0, 60.0];
+        let samples: Vec<_> = humidities
+            .iter()
+            .enumerate()
+            .map(|(index, &humidity)| sample(index as u64 * 300, 1013.0, humidity))
+            .collect();
+        assert_eq!(
+            states(&mut engine, &samples),
+            [
+                "inactive", "pending", "pending", "inactive", "pending", "pending", "firing",
+                "resolved"
+            ]
+        );
+    }
+
+    #[test]
+    fn hysteresis_avoids_flapping() {
+        let rule = AlertRule::new("alert", "humidity", Condition::Above(80.0)).with_hysteresis(2.0);
+        let mut engine = AlertEngine::new(vec![rule]);
+        let samples: Vec<_> = [81.0, 79.0, 80.5, 78.5, 77.9, 79.0, 80.1]
+            .iter()
+            .enumerate()
+            .map(|(index, &humidity)| sample(index as u64 * 5, 1013.0, humidity))
+            .collect();
+        assert_eq!(
+            states(&mut engine, &samples),
+            ["firing", "firing", "firing", "firing", "resolved", "resolved", "firing"]
+        );
+    }
+
+    #[test]
+    fn rate_of_change() {
+        let rule = AlertRule::new(
+            "alert",
+            "pressure",
+            Condition::Falls {
+                amount: 3.0,
+                window: Duration::from_secs(3 * 3600),
+            },
+        );
+        let mut engine = AlertEngine::new(vec![rule]);
+        // -0.7 hPa every 30 minutes during 4 hours, then steady
+        let mut pressures: Vec<f32> = (0..=8).map(|step| 1015.0 - 0.7 * step as f32).collect();
+        pressures.extend(&[1009.4; 6]);
+        let samples: Vec<_> = pressures
+            .iter()
+            .enumerate()
+            .map(|(index, &pressure)| sample(index as u64 * 1800, pressure, 50.0))
+            .collect();
+        let states = states(&mut engine, &samples);
+        // more than 3 hPa are lost after 2h30
+        assert_eq!(states[..5], ["inactive"; 5]);
+        assert_eq!(states[5..10], ["firing"; 5]);
+        // the fall over the last 3 hours is back under 3 hPa after 5h
+        assert_eq!(states[10..], ["resolved"; 5]);
+    }
+
+    #[test]
+    fn events_report_changes() {
+        let rule = AlertRule::new("alert", "pressure", Condition::Below(990.0));
+        let mut engine = AlertEngine::new(vec![rule]);
+        assert!(engine.update(&sample(0, 1000.0, 50.0)).is_empty());
+        let events = engine.update(&sample(5, 985.0, 50.0));
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].state,
+            AlertState::Firing {
+                since: Duration::from_secs(5)
+            }
+        );
+        assert!((events[0].value - 985.0).abs() < 1e-3);
+        assert!(engine.update(&sample(10, 984.0, 50.0)).is_empty());
+
+        let mut json = Vec::new();
+        engine.write_json(&mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "[{\"name\": \"alert\", \"channel\": \"pressure\", \"state\": \"firing\", \"since\": 5000, \"value\": 984.000}]\n"
+        );
+    }
+
+    #[test]
+    fn rules_from_config() {
+        let config = Config::parse(
+            "[alert humid]\nchannel = humidity\nabove = 80\nhysteresis = 2\nfor = 10m\n\
+             [alert storm]\nchannel = pressure\nfalls = 3\nwithin = 3h\n",
+        )
+        .unwrap();
+        let engine = AlertEngine::from_config(&config).unwrap();
+        assert_eq!(
+            engine.alerts[0].rule,
+            AlertRule::new("humid", "humidity", Condition::Above(80.0))
+                .with_hysteresis(2.0)
+                .with_duration(Duration::from_secs(600))
+        );
+        assert_eq!(
+            engine.alerts[1].rule.condition,
+            Condition::Falls {
+                amount: 3.0,
+                window: Duration::from_secs(3 * 3600)
+            }
+        );
+
+        let error = |content: &str| {
+            AlertEngine::from_config(&Config::parse(content).unwrap())
+                .err()
+                .unwrap()
+        };
+        assert_eq!(
+            error("[alert a]\nchannel = humidty\nabove = 1"),
+            "line 1: section [alert a]: unknown channel humidty, expected one of pressure, bmp280Temp, htu21Temp, humidity"
+        );
+        assert_eq!(
+            error("[alert a]\nchannel = pressure\nfalls = 3"),
+            "line 1: section [alert a]: falls needs a within key"
+        );
+        assert_eq!(
+            error("[alert a]\nchannel = pressure\nabove = 1\nbelow = 0"),
+            "line 1: section [alert a]: expected exactly one of above, below, falls or rises"
+        );
+        assert_eq!(
+            error("[alert a]\nchannel = pressure\nabove = 1\n[alert a]\nchannel = pressure\nbelow = 1"),
+            "line 4: section [alert a]: an alert with this name is already defined"
+        );
+    }
+}
@@ -0,0 +1,477 @@
+use crate::{
+    config::Config,
+    metrics,
+    sensor_data::{SensorData, CHANNELS},
+};
+use std::{
+    collections::VecDeque,
+    io::{self, Write},
+    time::Duration,
+};
+
+// Days of the same hour needed for a z-score
+const MIN_REFERENCE_DAYS: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AnomalyKind {
+    /// Far from the samples of the same hour of the previous days
+    ZScore,
+    /// The variance of the recent samples changed suddenly
+    Variance,
+    /// The same value for too long, the sensor is probably dead
+    Stuck,
+}
+
+const KINDS: [AnomalyKind; 3] = [
+    AnomalyKind::ZScore,
+    AnomalyKind::Variance,
+    AnomalyKind::Stuck,
+];
+
+/// Start or end of an anomaly of a channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnomalyEvent {
+    pub kind: AnomalyKind,
+    pub channel: &'static str,
+    pub active: bool,
+    pub value: f64,
+    /// z-score, ratio of the recent variance to the previous one, or seconds spent at the
+    /// same value
+    pub score: f64,
+    pub timestamp: Duration,
+}
+
+/// Detection of the channels which behave unusually, read from the `[anomaly]` section of the
+/// configuration:
+/// ```text
+/// [anomaly]
+/// zscore = 3              # largest distance to the same hour of the previous days, in
+///                         # standard deviations
+/// days = 7                # previous days of the DAYS tier compared, 3 of them at least
+/// variance_window = 12    # recent samples whose variance is compared
+/// variance_baseline = 120 # samples before them, giving the usual variance
+/// variance_ratio = 10     # change of the variance, up or down, which is an anomaly
+/// stuck = 2h              # time at the same value of a stuck channel
+/// ```
+/// Every sample is checked, an event is produced when an anomaly starts and when it ends.
+pub struct AnomalyDetector {
+    zscore: f64,
+    days: usize,
+    variance_window: usize,
+    variance_baseline: usize,
+    variance_ratio: f64,
+    stuck: Duration,
+    /// Mean and standard deviation of each channel for each hour of the day (UTC)
+    reference: [[Option<(f64, f64)>; 4]; 24],
+    /// Last values of each channel, the baseline then the window
+    values: [VecDeque<f64>; 4],
+    /// Value of each channel and since when it hasn't changed
+    constant: [Option<(f64, Duration)>; 4],
+    /// Active anomalies of each channel, in the order of `KINDS`
+    active: [[bool; 3]; 4],
+    started: [[u64; 3]; 4],
+}
+
+impl AnomalyKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            AnomalyKind::ZScore => "zscore",
+            AnomalyKind::Variance => "variance",
+            AnomalyKind::Stuck => "stuck",
+        }
+    }
+}
+
+impl AnomalyEvent {
+    pub fn write_json(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_fmt(format_args!(
+            "{{\"kind\": \"{}\", \"channel\": \"{}\", \"state\": \"{}\", \"value\": {:.3}, \"score\": {:.2}, \"timestamp\": {}}}",
+            self.kind.name(),
+            self.channel,
+            if self.active { "started" } else { "ended" },
+            self.value,
+            self.score,
+            self.timestamp.as_millis()
+        ))
+    }
+}
+
+/// Mean and population variance
+fn mean_variance(values: impl Iterator<Item = f64> + Clone) -> (f64, f64) {
+    let count = values.clone().count() as f64;
+    let mean = values.clone().sum::<f64>() / count;
+    let variance = values.map(|value| (value - mean).powi(2)).sum::<f64>() / count;
+    (mean, variance)
+}
+
+impl AnomalyDetector {
+    pub fn new() -> AnomalyDetector {
+        AnomalyDetector {
+            zscore: 3.0,
+            days: 7,
+            variance_window: 12,
+            variance_baseline: 120,
+            variance_ratio: 10.0,
+            stuck: Duration::from_secs(2 * 3600),
+            reference: [[None; 4]; 24],
+            values: Default::default(),
+            constant: [None; 4],
+            active: [[false; 3]; 4],
+            started: [[0; 3]; 4],
+        }
+    }
+
+    /// The detector of the `[anomaly]` section, none without it
+    pub fn from_config(config: &Config) -> Result<Option<AnomalyDetector>, String> {
+        let section = match config.section("anomaly")? {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        section.check_keys(&[
+            "zscore",
+            "days",
+            "variance_window",
+            "variance_baseline",
+            "variance_ratio",
+            "stuck",
+        ])?;
+        let mut detector = AnomalyDetector::new();
+        detector.zscore = section.get_or("zscore", detector.zscore)?;
+        detector.days = section.get_or("days", detector.days)?;
+        if detector.days < MIN_REFERENCE_DAYS {
+            return Err(section.error(&format!(
+                "the z-score needs at least {} days",
+                MIN_REFERENCE_DAYS
+            )));
+        }
+        detector.variance_window = section.get_or("variance_window", detector.variance_window)?;
+        detector.variance_baseline =
+            section.get_or("variance_baseline", detector.variance_baseline)?;
+        if detector.variance_window < 2 || detector.variance_baseline < 2 {
+            return Err(section.error("the variances need at least 2 samples"));
+        }
+        detector.variance_ratio = section.get_or("variance_ratio", detector.variance_ratio)?;
+        if detector.variance_ratio <= 1.0 {
+            return Err(section.error("the variance ratio must be above 1"));
+        }
+        if let Some(stuck) = section.get_duration("stuck")? {
+            detector.stuck = stuck;
+        }
+        Ok(Some(detector))
+    }
+
+    /// Compute the reference of each hour from the samples of the DAYS tier, to be called
+    /// when it changes
+    pub fn update_reference(&mut self, days: &[SensorData]) {
+        self.reference = [[None; 4]; 24];
+        let last = match days.last() {
+            Some(last) => last.get_timestamp(),
+            None => return,
+        };
+        let start = last.saturating_sub(Duration::from_secs(self.days as u64 * 86400));
+        let recent: Vec<&SensorData> = days
+            .iter()
+            .filter(|sample| sample.get_timestamp() > start)
+            .collect();
+        for (hour, reference) in self.reference.iter_mut().enumerate() {
+            let samples: Vec<&SensorData> = recent
+                .iter()
+                .copied()
+                .filter(|sample| hour_of_day(sample.get_timestamp()) == hour)
+                .collect();
+            if samples.len() < MIN_REFERENCE_DAYS {
+                continue;
+            }
+            for (channel, reference) in CHANNELS.iter().zip(reference.iter_mut()) {
+                let values = samples
+                    .iter()
+                    .filter_map(|sample| sample.get_channel(channel));
+                let (mean, variance) = mean_variance(values);
+                // a constant channel has no meaningful z-score
+                if variance > 0.0 {
+                    *reference = Some((mean, variance.sqrt()));
+                }
+            }
+        }
+    }
+
+    /// Check a new sample, give the anomalies which start or end
+    pub fn update(&mut self, sample: &SensorData) -> Vec<AnomalyEvent> {
+        let timestamp = sample.get_timestamp();
+        let hour = hour_of_day(timestamp);
+        let mut events = Vec::new();
+        for (index, channel) in CHANNELS.iter().enumerate() {
+            let value = match sample.get_channel(channel) {
+                Some(value) if !value.is_nan() => value,
+                _ => continue,
+            };
+            let zscore = self.reference[hour][index]
+                .map(|(mean, deviation)| (value - mean) / deviation)
+                .filter(|zscore| zscore.abs() > self.zscore);
+
+            let values = &mut self.values[index];
+            if values.len() == self.variance_baseline + self.variance_window {
+                values.pop_front();
+            }
+            values.push_back(value);
+            let variance = if values.len() == self.variance_baseline + self.variance_window {
+                let (_, baseline) =
+                    mean_variance(values.iter().copied().take(self.variance_baseline));
+                let (_, recent) =
+                    mean_variance(values.iter().copied().skip(self.variance_baseline));
+                Some(recent / baseline).filter(|ratio| {
+                    baseline > 0.0
+                        && (*ratio > self.variance_ratio || *ratio < 1.0 / self.variance_ratio)
+                })
+            } else {
+                None
+            };
+
+            let since = match self.constant[index] {
+                Some((constant, since)) if constant == value => since,
+                _ => timestamp,
+            };
+            self.constant[index] = Some((value, since));
+            let constant = timestamp.saturating_sub(since);
+            let stuck = Some(constant.as_secs_f64()).filter(|_| constant >= self.stuck);
+
+            for (kind, ((score, active), started)) in KINDS.iter().zip(
+                [zscore, variance, stuck]
+                    .iter()
+                    .zip(self.active[index].iter_mut())
+                    .zip(self.started[index].iter_mut()),
+            ) {
+                if score.is_some() != *active {
+                    *active = score.is_some();
+                    if *active {
+                        *started += 1;
+                    }
+                    events.push(AnomalyEvent {
+                        kind: *kind,
+                        channel,
+                        active: *active,
+                        value,
+                        score: score.unwrap_or_default(),
+                        timestamp,
+                    });
+                }
+            }
+        }
+        events
+    }
+
+    /// Active anomalies and the number of anomalies of each kind since the start
+    pub fn write_json(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(b"[")?;
+        for (index, channel) in CHANNELS.iter().enumerate() {
+            if index > 0 {
+                w.write_all(b",\n")?;
+            }
+            w.write_fmt(format_args!("{{\"channel\": \"{}\"", channel))?;
+            for (kind, (active, started)) in KINDS
+                .iter()
+                .zip(self.active[index].iter().zip(self.started[index].iter()))
+            {
+                w.write_fmt(format_args!(
+                    ", \"{}\": {{\"active\": {}, \"count\": {}}}",
+                    kind.name(),
+                    active,
+                    started
+                ))?;
+            }
+            w.write_all(b"}")?;
+        }
+        w.write_all(b"]\n")
+    }
+
+    pub fn write_metrics(&self, w: &mut dyn Write) -> io::Result<()> {
+        let mut labels = Vec::new();
+        for channel in CHANNELS.iter() {
+            for kind in KINDS.iter() {
+                labels.push(format!("channel=\"{}\",kind=\"{}\"", channel, kind.name()));
+            }
+        }
+        let values = |values: &[[f64; 3]; 4]| -> Vec<(&str, f64)> {
+            labels
+                .iter()
+                .map(String::as_str)
+                .zip(values.iter().flatten().copied())
+                .collect()
+        };
+        metrics::write_metric(
+            w,
+            "anomaly",
+            "gauge",
+            "Anomaly of a channel, 1 while it lasts",
+            &values(
+                &self
+                    .active
+                    .map(|kinds| kinds.map(|active| active as u8 as f64)),
+            ),
+        )?;
+        metrics::write_metric(
+            w,
+            "anomalies_total",
+            "counter",
+            "Anomalies started",
+            &values(
+                &self
+                    .started
+                    .map(|kinds| kinds.map(|started| started as f64)),
+            ),
+        )
+    }
+}
+
+impl Default for AnomalyDetector {
+    fn default() -> AnomalyDetector {
+        AnomalyDetector::new()
+    }
+}
+
+/// Hour of the day (UTC) of a timestamp
+fn hour_of_day(timestamp: Duration) -> usize {
+    (timestamp.as_secs() % 86400 / 3600) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AnomalyDetector, AnomalyEvent, AnomalyKind};
+    use crate::{config::Config, sensor_data::SensorData};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // 2023-11-14 00:00:00 UTC
+    const MIDNIGHT: u64 = 1_699_920_000;
+
+    fn sample(seconds: u64, temperature: f64, humidity: f64) -> SensorData {
+        SensorData::new(
+            UNIX_EPOCH + Duration::from_secs(seconds),
+            101.3 + (seconds % 7) as f32 * 0.01,
+            (temperature * 1000.0) as i32,
+            (temperature * 1000.0) as i32,
+            (humidity * 1000.0) as i32,
+        )
+    }
+
+    fn detector(config: &str) -> AnomalyDetector {
+        AnomalyDetector::from_config(&Config::parse(config).unwrap())
+            .unwrap()
+            .unwrap()
+    }
+
+    fn humidity_events(events: Vec<AnomalyEvent>) -> Vec<(AnomalyKind, bool)> {
+        events
+            .iter()
+            .filter(|event| event.channel == "humidity")
+            .map(|event| (event.kind, event.active))
+            .collect()
+    }
+
+    #[test]
+    fn zscore_against_the_previous_days() {
+        let mut detector = detector("[anomaly]\ndays = 5\nstuck = 100d\n");
+        // one sample per hour during 7 days, the humidity of 14:00 is 56, 57 or 58 %
+        let days: Vec<SensorData> = (0..7 * 24)
+            .map(|hour| {
+                let humidity = 70.0 - (hour % 24) as f64 + (hour / 24 % 3) as f64;
+                sample(MIDNIGHT + hour * 3600, 15.0 + (hour % 5) as f64, humidity)
+            })
+            .collect();
+        detector.update_reference(&days);
+        let (mean, deviation) = detector.reference[14][3].unwrap();
+        // only the 5 last days are taken
+        assert!((mean - 57.0).abs() < 1e-9, "{}", mean);
+        assert!((deviation - 0.8f64.sqrt()).abs() < 1e-9, "{}", deviation);
+
+        let at_14 = MIDNIGHT + 7 * 86400 + 14 * 3600;
+        assert_eq!(
+            humidity_events(detector.update(&sample(at_14, 15.0, 57.5))),
+            []
+        );
+        let events = detector.update(&sample(at_14 + 5, 15.0, 66.0));
+        assert_eq!(
+            humidity_events(events.clone()),
+            [(AnomalyKind::ZScore, true)]
+        );
+        let event = events
+            .iter()
+            .find(|event| event.channel == "humidity")
+            .unwrap();
+        assert!((event.score - 10.06).abs() < 0.01, "{}", event.score);
+        assert_eq!(
+            humidity_events(detector.update(&sample(at_14 + 10, 15.0, 57.0))),
+            [(AnomalyKind::ZScore, false)]
+        );
+        // no reference without 3 days of history
+        detector.update_reference(&days[..2 * 24]);
+        assert_eq!(detector.reference[14][3], None);
+    }
+
+    #[test]
+    fn variance_changes() {
+        let mut detector = detector(
+            "[anomaly]\nvariance_window = 4\nvariance_baseline = 10\nvariance_ratio = 10\n",
+        );
+        let mut events = Vec::new();
+        for (index, noise) in [0.1; 14].iter().chain([2.0; 4].iter()).enumerate() {
+            let sign = if index % 2 == 0 { 1.0 } else { -1.0 };
+            let humidity = 50.0 + sign * noise;
+            events.extend(humidity_events(detector.update(&sample(
+                MIDNIGHT + index as u64 * 5,
+                15.0,
+                humidity,
+            ))));
+        }
+        assert_eq!(events, [(AnomalyKind::Variance, true)]);
+        let mut json = Vec::new();
+        detector.write_json(&mut json).unwrap();
+        assert!(String::from_utf8(json).unwrap().ends_with(
+            "{\"channel\": \"humidity\", \"zscore\": {\"active\": false, \"count\": 0}, \"variance\": {\"active\": true, \"count\": 1}, \"stuck\": {\"active\": false, \"count\": 0}}]\n"
+        ));
+    }
+
+    #[test]
+    fn stuck_channels() {
+        let mut detector = detector("[anomaly]\nstuck = 1m\n");
+        let mut events = Vec::new();
+        for index in 0..15 {
+            let humidity = if index < 14 { 48.2 } else { 48.3 };
+            events.extend(humidity_events(detector.update(&sample(
+                MIDNIGHT + index * 5,
+                15.0 + index as f64 * 0.1,
+                humidity,
+            ))));
+            if index == 12 {
+                // 60 seconds at the same value
+                assert_eq!(events, [(AnomalyKind::Stuck, true)]);
+            }
+        }
+        assert_eq!(
+            events,
+            [(AnomalyKind::Stuck, true), (AnomalyKind::Stuck, false)]
+        );
+        let mut metrics = Vec::new();
+        detector.write_metrics(&mut metrics).unwrap();
+        let metrics = String::from_utf8(metrics).unwrap();
+        assert!(metrics.contains("weather_anomaly{channel=\"humidity\",kind=\"stuck\"} 0\n"));
+        assert!(
+            metrics.contains("weather_anomalies_total{channel=\"humidity\",kind=\"stuck\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn invalid_detectors() {
+        let error = |text: &str| AnomalyDetector::from_config(&Config::parse(text).unwrap()).err();
+        assert_eq!(
+            error("[anomaly]\ndays = 2\n"),
+            Some("line 1: section [anomaly]: the z-score needs at least 3 days".to_string())
+        );
+        assert_eq!(
+            error("[anomaly]\nvariance_ratio = 0.5\n"),
+            Some("line 1: section [anomaly]: the variance ratio must be above 1".to_string())
+        );
+        assert!(AnomalyDetector::from_config(&Config::default())
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,294 @@
+use crate::{
+    config::{Config, Section},
+    sensor_data::{SensorData, CHANNELS},
+};
+
+/// Correction of the values of a sensor, in the unit of the JSON output (hPa, °C, %)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Correction {
+    /// `value * gain + offset`
+    Linear { offset: f64, gain: f64 },
+    /// `c0 + c1 * value + c2 * value² + ...`
+    Polynomial(Vec<f64>),
+    /// Linear interpolation between `(raw, corrected)` points sorted by raw value, the first
+    /// and last segments are extended beyond the table
+    Table(Vec<(f64, f64)>),
+}
+
+/// Corrections of the sensors, read from the `[calibration <channel>]` sections of the
+/// configuration:
+/// ```text
+/// [calibration bmp280Temp]
+/// offset = -0.8              # the board heats the BMP280
+///
+/// [calibration humidity]
+/// gain = 1.02
+/// offset = -0.5
+///
+/// [calibration pressure]
+/// polynomial = 0.3, 1.0, -0.000002
+///
+/// [calibration htu21Temp]
+/// table = -10:-10.4, 0:-0.2, 25:24.9, 40:40.3
+/// ```
+/// They are applied to the values read from the sensors before they are stored, and the
+/// samples they give are flagged as calibrated, as a whole: a sample is flagged even when only
+/// some of its channels have a correction.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Calibration {
+    /// Index of the channel in `CHANNELS` and its correction
+    corrections: Vec<(usize, Correction)>,
+}
+
+impl Correction {
+    fn from_section(section: &Section) -> Result<Correction, String> {
+        section.check_keys(&["offset", "gain", "polynomial", "table"])?;
+        let offset: Option<f64> = section.get("offset")?;
+        let gain: Option<f64> = section.get("gain")?;
+        let linear = offset.is_some() || gain.is_some();
+        match (section.get_str("polynomial"), section.get_str("table")) {
+            (Some(_), Some(_)) => Err(section.error("polynomial and table are exclusive")),
+            (Some(_), None) | (None, Some(_)) if linear => {
+                Err(section
+                    .error("offset and gain cannot be combined with a polynomial or a table"))
+            }
+            (Some(coefficients), None) => {
+                let coefficients = coefficients
+                    .split(',')
+                    .map(|coefficient| {
+                        coefficient.trim().parse::<f64>().map_err(|_| {
+                            section.error(&format!("invalid coefficient {}", coefficient.trim()))
+                        })
+                    })
+                    .collect::<Result<Vec<f64>, String>>()?;
+                Ok(Correction::Polynomial(coefficients))
+            }
+            (None, Some(points)) => {
+                let points = points
+                    .split(',')
+                    .map(|point| {
+                        point
+                            .split_once(':')
+                            .and_then(|(raw, corrected)| {
+                                Some((raw.trim().parse().ok()?, corrected.trim().parse().ok()?))
+                            })
+                            .ok_or_else(|| {
+                                section.error(&format!(
+                                    "invalid point {}, expected raw:corrected",
+                                    point.trim()
+                                ))
+                            })
+                    })
+                    .collect::<Result<Vec<(f64, f64)>, String>>()?;
+                if points.len() < 2 {
+                    return Err(section.error("a table needs at least 2 points"));
+                }
+                if points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                    return Err(section.error("the raw values of the table must increase"));
+                }
+                Ok(Correction::Table(points))
+            }
+            (None, None) if linear => Ok(Correction::Linear {
+                offset: offset.unwrap_or(0.0),
+                gain: gain.unwrap_or(1.0),
+            }),
+            (None, None) => Err(section.error("offset, gain, polynomial or table expected")),
+        }
+    }
+
+    pub fn apply(&self, value: f64) -> f64 {
+        match self {
+            Correction::Linear { offset, gain } => value * gain + offset,
+            // Horner's method, from the highest degree
+            Correction::Polynomial(coefficients) => coefficients
+                .iter()
+                .rev()
+                .fold(0.0, |result, coefficient| result * value + coefficient),
+            Correction::Table(points) => {
+                let segment = points
+                    .windows(2)
+                    .position(|pair| value < pair[1].0)
+                    .unwrap_or(points.len() - 2);
+                let ((x0, y0), (x1, y1)) = (points[segment], points[segment + 1]);
+                y0 + (value - x0) * (y1 - y0) / (x1 - x0)
+            }
+        }
+    }
+}
+
+impl Calibration {
+    pub fn from_config(config: &Config) -> Result<Calibration, String> {
+        let mut corrections: Vec<(usize, Correction)> = Vec::new();
+        for section in config.sections("calibration") {
+            let name = section.name()?;
+            let channel = CHANNELS
+                .iter()
+                .position(|channel| *channel == name)
+                .ok_or_else(|| {
+                    section.error(&format!(
+                        "unknown channel {}, expected one of {}",
+                        name,
+                        CHANNELS.join(", ")
+                    ))
+                })?;
+            if corrections.iter().any(|(index, _)| *index == channel) {
+                return Err(section.error(&format!("channel {} is already calibrated", name)));
+            }
+            corrections.push((channel, Correction::from_section(section)?));
+        }
+        Ok(Calibration { corrections })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.corrections.is_empty()
+    }
+
+    /// Calibrated sample, the raw sample is returned unchanged when there is no correction
+    pub fn apply(&self, raw: SensorData) -> SensorData {
+        if self.is_empty() {
+            return raw;
+        }
+        let mut data = raw;
+        for (channel, correction) in &self.corrections {
+            let name = CHANNELS[*channel];
+            if let Some(value) = raw.get_channel(name) {
+                data.set_channel(name, correction.apply(value));
+            }
+        }
+        data.set_calibrated(true);
+        data
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Calibration, Correction};
+    use crate::{
+        average::Average, config::Config, mapped_circular_buffer::FixedSizeRecord,
+        sensor_data::SensorData,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn assert_close(value: f64, expected: f64) {
+        assert!(
+            (value - expected).abs() < 1e-9,
+            "{} instead of {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn corrections() {
+        let linear = Correction::Linear {
+            offset: -0.5,
+            gain: 1.02,
+        };
+        assert_close(linear.apply(50.0), 50.5);
+        let polynomial = Correction::Polynomial(vec![1.0, 2.0, 3.0]);
+        assert_close(polynomial.apply(2.0), 17.0);
+        let table = Correction::Table(vec![(0.0, 1.0), (10.0, 11.0), (20.0, 31.0)]);
+        assert_close(table.apply(5.0), 6.0);
+        assert_close(table.apply(10.0), 11.0);
+        assert_close(table.apply(15.0), 21.0);
+        // the end segments are extended
+        assert_close(table.apply(-5.0), -4.0);
+        assert_close(table.apply(25.0), 41.0);
+    }
+
+    #[test]
+    fn calibrated_samples() {
+        let config = Config::parse(
+            "[calibration bmp280Temp]\noffset = -0.8\n\
+             [calibration humidity]\ngain = 1.02\noffset = -0.5\n\
+             [calibration pressure]\ntable = 900:901, 1100:1099\n",
+        )
+        .unwrap();
+        let calibration = Calibration::from_config(&config).unwrap();
+        let raw = SensorData::new(UNIX_EPOCH, 100.0, 21_800, 20_000, 50_000);
+        let data = calibration.apply(raw);
+        assert!(data.is_calibrated());
+        assert!(!raw.is_calibrated());
+        assert_close(data.get_channel("pressure").unwrap(), 1000.0);
+        assert_close(data.get_channel("bmp280Temp").unwrap(), 21.0);
+        assert_close(data.get_channel("htu21Temp").unwrap(), 20.0);
+        assert_close(data.get_channel("humidity").unwrap(), 50.5);
+
+        let empty = Calibration::from_config(&Config::default()).unwrap();
+        assert!(!empty.apply(raw).is_calibrated());
+    }
+
+    #[test]
+    fn invalid_calibrations() {
+        let error = |text: &str| Calibration::from_config(&Config::parse(text).unwrap()).err();
+        assert_eq!(
+            error("[calibration temperature]\noffset = 1\n"),
+            Some(
+                "line 1: section [calibration temperature]: unknown channel temperature, \
+                 expected one of pressure, bmp280Temp, htu21Temp, humidity"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error("[calibration humidity]\noffset = 1\ntable = 0:0, 1:1\n"),
+            Some(
+                "line 1: section [calibration humidity]: offset and gain cannot be combined \
+                 with a polynomial or a table"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error("[calibration humidity]\ntable = 10:10, 5:4\n"),
+            Some(
+                "line 1: section [calibration humidity]: the raw values of the table must increase"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error("[calibration humidity]\ntable = 10-10, 20:20\n"),
+            Some(
+                "line 1: section [calibration humidity]: invalid point 10-10, \
+                 expected raw:corrected"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error("[calibration humidity]\n"),
+            Some(
+                "line 1: section [calibration humidity]: offset, gain, polynomial or table expected"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error("[calibration humidity]\noffset = 1\n[calibration humidity]\ngain = 2\n"),
+            Some(
+                "line 3: section [calibration humidity]: channel humidity is already calibrated"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn calibrated_flag_is_kept() {
+        let mut data = SensorData::new(
+            UNIX_EPOCH + Duration::new(1_700_000_000, 999_999_999),
+            100.0,
+            21_800,
+            20_000,
+            50_000,
+        );
+        data.set_calibrated(true);
+        let mut record = [0; SensorData::RECORD_SIZE];
+        data.write_record(&mut record);
+        let read = SensorData::read_record(&record);
+        assert!(read.is_calibrated());
+        assert_eq!(read.get_timestamp(), data.get_timestamp());
+
+        let mut cumulated = SensorData::empty_cumulator();
+        data.cumulate(&mut cumulated);
+        assert!(SensorData::divide(&cumulated, 1).is_calibrated());
+        data.set_calibrated(false);
+        data.cumulate(&mut cumulated);
+        assert!(!SensorData::divide(&cumulated, 2).is_calibrated());
+    }
+}
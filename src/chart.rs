@@ -0,0 +1,335 @@
+use crate::{
+    config::format_utc,
+    sensor_data::{SensorData, CHANNELS, UNITS},
+};
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    time::Duration,
+};
+
+const WIDTH: f64 = 800.0;
+const PANEL_HEIGHT: f64 = 200.0;
+const MARGIN_LEFT: f64 = 70.0;
+const MARGIN_RIGHT: f64 = 20.0;
+const MARGIN_TOP: f64 = 28.0;
+const MARGIN_BOTTOM: f64 = 24.0;
+// Longer series are reduced to that many points, with the min/max band of each of them
+const MAX_POINTS: usize = 355;
+const COLORS: [&str; 4] = ["#1f77b4", "#d62728", "#ff7f0e", "#2ca02c"];
+
+/// Value of a channel, over `min..=max` when it is the average of several samples
+struct Point {
+    timestamp: f64,
+    value: f64,
+    band: Option<(f64, f64)>,
+}
+
+/// Write a SVG chart of the channels (indexes in `CHANNELS`) of the samples, one panel per
+/// channel. The time axis covers `range`, or the samples when it is not given.
+///
+/// The same samples always give the same document.
+pub fn write_svg(
+    samples: &[SensorData],
+    channels: &[usize],
+    range: Option<(Duration, Duration)>,
+    w: &mut dyn Write,
+) -> io::Result<()> {
+    let samples: Vec<&SensorData> = samples
+        .iter()
+        .filter(|sample| match range {
+            Some((from, to)) => (from..=to).contains(&sample.get_timestamp()),
+            None => true,
+        })
+        .collect();
+    let (start, end) = match (range, samples.first(), samples.last()) {
+        (Some((from, to)), _, _) => (from.as_secs_f64(), to.as_secs_f64()),
+        (None, Some(first), Some(last)) => (
+            first.get_timestamp().as_secs_f64(),
+            last.get_timestamp().as_secs_f64(),
+        ),
+        _ => (0.0, 0.0),
+    };
+    let height = PANEL_HEIGHT * channels.len() as f64;
+    let mut svg = String::new();
+    let _ = writeln!(
+        svg,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}\" height=\"{h}\" viewBox=\"0 0 {w} {h}\" font-family=\"sans-serif\" font-size=\"12\">",
+        w = WIDTH,
+        h = height
+    );
+    let _ = writeln!(
+        svg,
+        "<rect width=\"{}\" height=\"{}\" fill=\"white\"/>",
+        WIDTH, height
+    );
+    for (panel, channel) in channels.iter().enumerate() {
+        let points = reduce(
+            samples
+                .iter()
+                .filter_map(|sample| {
+                    sample
+                        .get_channel(CHANNELS[*channel])
+                        .map(|value| (sample.get_timestamp().as_secs_f64(), value))
+                })
+                .collect(),
+        );
+        let _ = writeln!(
+            svg,
+            "<g transform=\"translate(0,{})\">",
+            PANEL_HEIGHT * panel as f64
+        );
+        write_panel(
+            &mut svg,
+            &format!("{} ({})", CHANNELS[*channel], UNITS[*channel]),
+            COLORS[*channel % COLORS.len()],
+            &points,
+            start,
+            end,
+        );
+        svg.push_str("</g>\n");
+    }
+    svg.push_str("</svg>\n");
+    w.write_all(svg.as_bytes())
+}
+
+/// Average consecutive values when there are more than `MAX_POINTS` of them
+fn reduce(values: Vec<(f64, f64)>) -> Vec<Point> {
+    if values.len() <= MAX_POINTS {
+        return values
+            .into_iter()
+            .map(|(timestamp, value)| Point {
+                timestamp,
+                value,
+                band: None,
+            })
+            .collect();
+    }
+    let bucket_size = values.len().div_ceil(MAX_POINTS);
+    values
+        .chunks(bucket_size)
+        .map(|bucket| {
+            let count = bucket.len() as f64;
+            Point {
+                timestamp: bucket.iter().map(|(timestamp, _)| timestamp).sum::<f64>() / count,
+                value: bucket.iter().map(|(_, value)| value).sum::<f64>() / count,
+                band: Some(bucket.iter().fold(
+                    (f64::INFINITY, f64::NEG_INFINITY),
+                    |(min, max), (_, value)| (min.min(*value), max.max(*value)),
+                )),
+            }
+        })
+        .collect()
+}
+
+/// Round step of about a fifth of `span`
+fn tick_step(span: f64) -> f64 {
+    let raw = span / 5.0;
+    let magnitude = 10f64.powf(raw.log10().floor());
+    match raw / magnitude {
+        r if r < 1.5 => magnitude,
+        r if r < 3.5 => 2.0 * magnitude,
+        r if r < 7.5 => 5.0 * magnitude,
+        _ => 10.0 * magnitude,
+    }
+}
+
+fn decimals(step: f64) -> usize {
+    if step >= 1.0 {
+        0
+    } else {
+        (-step.log10().floor()) as usize
+    }
+}
+
+fn write_panel(svg: &mut String, title: &str, color: &str, points: &[Point], start: f64, end: f64) {
+    let bottom = PANEL_HEIGHT - MARGIN_BOTTOM;
+    let right = WIDTH - MARGIN_RIGHT;
+    let _ = writeln!(
+        svg,
+        "<text x=\"{}\" y=\"18\" font-weight=\"bold\">{}</text>",
+        MARGIN_LEFT, title
+    );
+    let _ = writeln!(
+        svg,
+        "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"none\" stroke=\"#999\"/>",
+        MARGIN_LEFT,
+        MARGIN_TOP,
+        right - MARGIN_LEFT,
+        bottom - MARGIN_TOP
+    );
+    if points.is_empty() {
+        let _ = writeln!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" text-anchor=\"middle\">no sample</text>",
+            (MARGIN_LEFT + right) / 2.0,
+            (MARGIN_TOP + bottom) / 2.0
+        );
+        return;
+    }
+    let (mut min, mut max) =
+        points
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), point| {
+                let (low, high) = point.band.unwrap_or((point.value, point.value));
+                (min.min(low), max.max(high))
+            });
+    if max - min < 0.1 {
+        min -= 0.05;
+        max += 0.05;
+    }
+    let (start, end) = if end > start {
+        (start, end)
+    } else {
+        (start - 1.0, start + 1.0)
+    };
+    let x =
+        |timestamp: f64| MARGIN_LEFT + (timestamp - start) / (end - start) * (right - MARGIN_LEFT);
+    let y = |value: f64| bottom - (value - min) / (max - min) * (bottom - MARGIN_TOP);
+
+    let step = tick_step(max - min);
+    let mut tick = (min / step).ceil() * step;
+    while tick <= max {
+        let _ = writeln!(
+            svg,
+            "<line x1=\"{l}\" y1=\"{y:.1}\" x2=\"{r}\" y2=\"{y:.1}\" stroke=\"#e0e0e0\"/><text x=\"{t}\" y=\"{ty:.1}\" text-anchor=\"end\">{v:.d$}</text>",
+            l = MARGIN_LEFT,
+            r = right,
+            y = y(tick),
+            t = MARGIN_LEFT - 6.0,
+            ty = y(tick) + 4.0,
+            v = tick,
+            d = decimals(step)
+        );
+        tick += step;
+    }
+    for index in 0..=4 {
+        let timestamp = start + (end - start) * index as f64 / 4.0;
+        let label = format_utc(Duration::from_secs_f64(timestamp.max(0.0)));
+        // the date is useless when the chart covers less than a day
+        let label = if end - start < 86400.0 {
+            &label[11..]
+        } else {
+            &label[..16]
+        };
+        let anchor = match index {
+            0 => "start",
+            4 => "end",
+            _ => "middle",
+        };
+        let _ = writeln!(
+            svg,
+            "<text x=\"{:.1}\" y=\"{}\" text-anchor=\"{}\">{}</text>",
+            x(timestamp),
+            PANEL_HEIGHT - 6.0,
+            anchor,
+            label
+        );
+    }
+
+    if points.iter().any(|point| point.band.is_some()) {
+        let mut path = String::new();
+        for (index, point) in points.iter().enumerate() {
+            let high = point.band.map_or(point.value, |(_, high)| high);
+            let _ = write!(
+                path,
+                "{}{:.1},{:.1} ",
+                if index == 0 { "M" } else { "L" },
+                x(point.timestamp),
+                y(high)
+            );
+        }
+        for point in points.iter().rev() {
+            let low = point.band.map_or(point.value, |(low, _)| low);
+            let _ = write!(path, "L{:.1},{:.1} ", x(point.timestamp), y(low));
+        }
+        let _ = writeln!(
+            svg,
+            "<path d=\"{}Z\" fill=\"{}\" fill-opacity=\"0.2\" stroke=\"none\"/>",
+            path, color
+        );
+    }
+    let polyline: Vec<String> = points
+        .iter()
+        .map(|point| format!("{:.1},{:.1}", x(point.timestamp), y(point.value)))
+        .collect();
+    let _ = writeln!(
+        svg,
+        "<polyline points=\"{}\" fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\"/>",
+        polyline.join(" "),
+        color
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tick_step, write_svg};
+    use crate::sensor_data::SensorData;
+    use std::{
+        env, fs,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    const SNAPSHOTS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/charts/");
+
+    /// Compare with the snapshot, which is written instead when UPDATE_SNAPSHOTS is set
+    fn check_snapshot(name: &str, svg: &[u8]) {
+        let path = format!("{}{}", SNAPSHOTS, name);
+        if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+            fs::write(&path, svg).unwrap();
+        }
+        assert_eq!(
+            String::from_utf8_lossy(svg),
+            fs::read_to_string(&path).unwrap(),
+            "{} changed",
+            name
+        );
+    }
+
+    /// One sample per `step` seconds during a day, with a daily temperature cycle
+    fn samples(nb_samples: u64, step: u64) -> Vec<SensorData> {
+        (0..nb_samples)
+            .map(|index| {
+                let phase = index as f64 / nb_samples as f64 * std::f64::consts::TAU;
+                SensorData::new(
+                    UNIX_EPOCH + Duration::from_secs(1_700_000_000 + index * step),
+                    (101.3 + 0.2 * phase.cos()) as f32,
+                    (20_000.0 + 4_000.0 * phase.sin()) as i32,
+                    21_000,
+                    (50_000.0 - 10_000.0 * phase.sin()) as i32,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn hour_chart() {
+        let mut svg = Vec::new();
+        write_svg(&samples(24, 3600), &[1, 3], None, &mut svg).unwrap();
+        check_snapshot("hour.svg", &svg);
+    }
+
+    #[test]
+    fn reduced_chart_with_bands() {
+        let mut svg = Vec::new();
+        write_svg(&samples(2000, 300), &[0], None, &mut svg).unwrap();
+        assert!(String::from_utf8_lossy(&svg).contains("fill-opacity=\"0.2\""));
+        check_snapshot("bands.svg", &svg);
+    }
+
+    #[test]
+    fn empty_range() {
+        let mut svg = Vec::new();
+        let range = (Duration::from_secs(0), Duration::from_secs(60));
+        write_svg(&samples(24, 3600), &[2], Some(range), &mut svg).unwrap();
+        check_snapshot("empty.svg", &svg);
+    }
+
+    #[test]
+    fn round_ticks() {
+        assert_eq!(tick_step(1.0), 0.2);
+        assert_eq!(tick_step(8.0), 2.0);
+        assert_eq!(tick_step(40.0), 10.0);
+        assert_eq!(tick_step(0.1), 0.02);
+    }
+}
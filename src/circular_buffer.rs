@@ -1,22 +1,32 @@
-use crate::json_display::JsonDisplay;
-use std::{fmt::Display, io};
+use crate::json_display::{self, JsonDisplay};
+use std::{
+    io,
+    iter::FromIterator,
+    marker::PhantomData,
+    ops::{Bound, Index, IndexMut, RangeBounds},
+    slice, vec,
+};
 
+/// Bounded FIFO queue.
+///
+/// All the slots are allocated at creation, a slot holds `None` as long as it is free,
+/// so an item is moved out of the buffer as soon as it is popped.
 #[derive(Debug)]
 pub struct CircularBuffer<T> {
     first: usize,
     last: usize,
     valid_items: usize,
-    data: Vec<T>,
+    data: Box<[Option<T>]>,
     max_items: usize,
 }
 
-impl<T: Display + JsonDisplay> CircularBuffer<T> {
+impl<T> CircularBuffer<T> {
     pub fn new(size: usize) -> CircularBuffer<T> {
         CircularBuffer {
             first: 0,
             last: 0,
             valid_items: 0,
-            data: Vec::<T>::with_capacity(size),
+            data: (0..size).map(|_| None).collect(),
             max_items: size,
         }
     }
@@ -25,101 +35,250 @@ impl<T: Display + JsonDisplay> CircularBuffer<T> {
         self.valid_items == 0
     }
 
+    pub fn is_full(&self) -> bool {
+        self.valid_items >= self.max_items
+    }
+
+    pub fn len(&self) -> usize {
+        self.valid_items
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.max_items
+    }
+
     pub fn get_nb_items(&self) -> usize {
         self.valid_items
     }
 
-    pub fn put_item(&mut self, item_value: T) -> bool {
-        if self.valid_items >= self.max_items {
-            println!("The queue is full\n");
-            println!("You cannot add items\n");
-            false
-        } else {
-            self.valid_items += 1;
-            if self.last + 1 < self.data.len() {
-                self.data[self.last as usize] = item_value;
-            } else {
-                self.data.push(item_value);
-            }
-            self.last = (self.last + 1) % self.max_items;
-            true
+    /// Append an item at the back of the buffer.
+    ///
+    /// When the buffer is full the item is refused and handed back to the caller.
+    pub fn push_back(&mut self, item_value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item_value);
         }
+        self.data[self.last] = Some(item_value);
+        self.last = (self.last + 1) % self.max_items;
+        self.valid_items += 1;
+        Ok(())
     }
 
-    pub fn get_item(&mut self) -> Option<&T> {
+    /// Remove the oldest item of the buffer and return it
+    pub fn pop_front(&mut self) -> Option<T> {
         if self.is_empty() {
-            println!("isempty\n");
-            None
-        } else {
-            let index = self.first;
-            self.first = (self.first + 1) % self.max_items;
-            self.valid_items -= 1;
-            Some(&self.data[index])
+            return None;
         }
+        let item = self.data[self.first].take();
+        self.first = (self.first + 1) % self.max_items;
+        self.valid_items -= 1;
+        item
+    }
+
+    /// Same as `push_back`, but only tells if the item has been added
+    pub fn put_item(&mut self, item_value: T) -> bool {
+        self.push_back(item_value).is_ok()
     }
 
     pub fn peek_item(&self, index: usize) -> Option<&T> {
+        self.get(index)
+    }
+
+    /// Access to the item at the logical position `index` (0 is the oldest item)
+    pub fn get(&self, index: usize) -> Option<&T> {
         if index < self.valid_items {
-            let internal_index = (self.first + index) % self.max_items;
-            Some(&self.data[internal_index])
+            self.data[self.internal_index(index)].as_ref()
         } else {
             None
         }
     }
 
-    pub fn write_json_chunk(&self, w: &mut io::Write) -> io::Result<()> {
-        let mut first = true;
-        let mut result: io::Result<()> = Ok(());
-        for data in self {
-            match result {
-                Ok(_) => {
-                    if !first {
-                        let _ = w.write(b",");
-                    } else {
-                        first = false;
-                    }
-                    result = data.json_item(w);
-                }
-                Err(_) => {
-                    println!("error writing json");
-                }
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index < self.valid_items {
+            let internal_index = self.internal_index(index);
+            self.data[internal_index].as_mut()
+        } else {
+            None
+        }
+    }
+
+    /// Remove all the items, the allocated storage is kept
+    pub fn clear(&mut self) {
+        while self.pop_front().is_some() {}
+        self.first = 0;
+        self.last = 0;
+    }
+
+    pub fn iter(&self) -> CircularBufferIterator<'_, T> {
+        self.into_iter()
+    }
+
+    pub fn iter_mut(&mut self) -> CircularBufferIterMut<'_, T> {
+        self.into_iter()
+    }
+
+    /// Remove the items of the given logical range and iterate over them (oldest first).
+    ///
+    /// The items are removed from the buffer even if the returned iterator is not consumed.
+    pub fn drain<R: RangeBounds<usize>>(&mut self, range: R) -> Drain<'_, T> {
+        let start = match range.start_bound() {
+            Bound::Included(&start) => start,
+            Bound::Excluded(&start) => start + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&end) => end + 1,
+            Bound::Excluded(&end) => end,
+            Bound::Unbounded => self.valid_items,
+        };
+        assert!(start <= end, "drain start {} is after end {}", start, end);
+        assert!(
+            end <= self.valid_items,
+            "drain end {} is out of bounds (len {})",
+            end,
+            self.valid_items
+        );
+
+        let drained_items: Vec<T> = (start..end)
+            .map(|index| {
+                let internal_index = self.internal_index(index);
+                self.data[internal_index].take().unwrap()
+            })
+            .collect();
+        // Move the items located after the drained range to fill the hole
+        let nb_drained = end - start;
+        for index in end..self.valid_items {
+            let from = self.internal_index(index);
+            let to = self.internal_index(index - nb_drained);
+            self.data[to] = self.data[from].take();
+        }
+        self.valid_items -= nb_drained;
+        if self.max_items > 0 {
+            self.last = self.internal_index(self.valid_items);
+        }
+
+        Drain {
+            drained_items: drained_items.into_iter(),
+            _circular_buffer: PhantomData,
+        }
+    }
+
+    fn internal_index(&self, index: usize) -> usize {
+        (self.first + index) % self.max_items
+    }
+
+    /// The valid slots, split in the part before and the part after the wrap-around
+    fn as_mut_slices(&mut self) -> (&mut [Option<T>], &mut [Option<T>]) {
+        let first_part_len = self.valid_items.min(self.max_items - self.first);
+        let second_part_len = self.valid_items - first_part_len;
+        let (wrapped, unwrapped) = self.data.split_at_mut(self.first);
+        (
+            &mut unwrapped[..first_part_len],
+            &mut wrapped[..second_part_len],
+        )
+    }
+}
+
+impl<T: JsonDisplay> CircularBuffer<T> {
+    pub fn write_json_chunk(&self, context: &T::Context, w: &mut dyn io::Write) -> io::Result<()> {
+        json_display::write_json_chunk(self, context, w)
+    }
+}
+
+impl<T> Index<usize> for CircularBuffer<T> {
+    type Output = T;
+
+    fn index(&self, index: usize) -> &T {
+        match self.get(index) {
+            Some(item) => item,
+            None => panic!(
+                "index {} is out of bounds (len {})",
+                index, self.valid_items
+            ),
+        }
+    }
+}
+
+impl<T> IndexMut<usize> for CircularBuffer<T> {
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        let valid_items = self.valid_items;
+        match self.get_mut(index) {
+            Some(item) => item,
+            None => panic!("index {} is out of bounds (len {})", index, valid_items),
+        }
+    }
+}
+
+/// Items that don't fit anymore in the buffer are dropped, like with `put_item`
+impl<T> Extend<T> for CircularBuffer<T> {
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I) {
+        for item in iter {
+            if self.push_back(item).is_err() {
+                break;
             }
         }
-        result
+    }
+}
+
+/// The capacity of the collected buffer is the number of collected items
+impl<T> FromIterator<T> for CircularBuffer<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let data: Box<[Option<T>]> = iter.into_iter().map(Some).collect();
+        let nb_items = data.len();
+        CircularBuffer {
+            first: 0,
+            last: 0,
+            valid_items: nb_items,
+            data,
+            max_items: nb_items,
+        }
     }
 }
 
 // IntToIterator is fully functionnal
-impl<'a, T: 'a + Display + JsonDisplay> IntoIterator for &'a CircularBuffer<T> {
+impl<'a, T: 'a> IntoIterator for &'a CircularBuffer<T> {
     type Item = &'a T;
     type IntoIter = CircularBufferIterator<'a, T>;
 
     fn into_iter(self) -> Self::IntoIter {
         CircularBufferIterator {
             circular_buffer: self,
-            current_index: self.first,
+            current_index: 0,
             remaining_items: self.get_nb_items(),
         }
     }
 }
 
-pub struct CircularBufferIterator<'a, T: 'a> {
-    circular_buffer: &'a CircularBuffer<T>,
-    current_index: usize,
-    remaining_items: usize,
+impl<'a, T: 'a> IntoIterator for &'a mut CircularBuffer<T> {
+    type Item = &'a mut T;
+    type IntoIter = CircularBufferIterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        let remaining_items = self.valid_items;
+        let (first_part, second_part) = self.as_mut_slices();
+        CircularBufferIterMut {
+            slots: first_part.iter_mut().chain(second_part.iter_mut()),
+            remaining_items,
+        }
+    }
 }
 
-/* // This constructor is no more need, since inToIterator compile
-impl<'a, T: 'a + Display> CircularBufferIterator<'a, T> {
-    fn new(circular_buffer : &'a CircularBuffer<T>) -> CircularBufferIterator<T> {
-         CircularBufferIterator {
-            circular_buffer : circular_buffer,
-            current_index : circular_buffer.first,
-            remaining_items : circular_buffer.get_nb_items()
+impl<T> IntoIterator for CircularBuffer<T> {
+    type Item = T;
+    type IntoIter = CircularBufferIntoIter<T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        CircularBufferIntoIter {
+            circular_buffer: self,
         }
     }
 }
-*/
+
+pub struct CircularBufferIterator<'a, T: 'a> {
+    circular_buffer: &'a CircularBuffer<T>,
+    current_index: usize,
+    remaining_items: usize,
+}
 
 impl<'a, T> Iterator for CircularBufferIterator<'a, T> {
     type Item = &'a T;
@@ -127,11 +286,270 @@ impl<'a, T> Iterator for CircularBufferIterator<'a, T> {
     fn next(&mut self) -> Option<&'a T> {
         if self.remaining_items > 0 {
             let current_index_before_update = self.current_index;
-            self.current_index = (self.current_index + 1) % self.circular_buffer.max_items;
+            self.current_index += 1;
             self.remaining_items -= 1;
-            Some(&self.circular_buffer.data[current_index_before_update])
+            self.circular_buffer.get(current_index_before_update)
         } else {
             None
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining_items, Some(self.remaining_items))
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for CircularBufferIterator<'a, T> {
+    fn next_back(&mut self) -> Option<&'a T> {
+        if self.remaining_items > 0 {
+            self.remaining_items -= 1;
+            self.circular_buffer
+                .get(self.current_index + self.remaining_items)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a, T> ExactSizeIterator for CircularBufferIterator<'a, T> {}
+
+pub struct CircularBufferIterMut<'a, T: 'a> {
+    slots: std::iter::Chain<slice::IterMut<'a, Option<T>>, slice::IterMut<'a, Option<T>>>,
+    remaining_items: usize,
+}
+
+impl<'a, T> Iterator for CircularBufferIterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<&'a mut T> {
+        let slot = self.slots.next()?;
+        self.remaining_items -= 1;
+        slot.as_mut()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining_items, Some(self.remaining_items))
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for CircularBufferIterMut<'a, T> {
+    fn next_back(&mut self) -> Option<&'a mut T> {
+        let slot = self.slots.next_back()?;
+        self.remaining_items -= 1;
+        slot.as_mut()
+    }
+}
+
+impl<'a, T> ExactSizeIterator for CircularBufferIterMut<'a, T> {}
+
+pub struct CircularBufferIntoIter<T> {
+    circular_buffer: CircularBuffer<T>,
+}
+
+impl<T> Iterator for CircularBufferIntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.circular_buffer.pop_front()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.circular_buffer.len();
+        (len, Some(len))
+    }
+}
+
+impl<T> DoubleEndedIterator for CircularBufferIntoIter<T> {
+    fn next_back(&mut self) -> Option<T> {
+        let len = self.circular_buffer.len();
+        if len == 0 {
+            return None;
+        }
+        self.circular_buffer.drain(len - 1..).next()
+    }
+}
+
+impl<T> ExactSizeIterator for CircularBufferIntoIter<T> {}
+
+pub struct Drain<'a, T: 'a> {
+    drained_items: vec::IntoIter<T>,
+    _circular_buffer: PhantomData<&'a mut CircularBuffer<T>>,
+}
+
+impl<'a, T> Iterator for Drain<'a, T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.drained_items.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.drained_items.size_hint()
+    }
+}
+
+impl<'a, T> DoubleEndedIterator for Drain<'a, T> {
+    fn next_back(&mut self) -> Option<T> {
+        self.drained_items.next_back()
+    }
+}
+
+impl<'a, T> ExactSizeIterator for Drain<'a, T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::CircularBuffer;
+    use std::collections::VecDeque;
+
+    /// Small xorshift generator, enough to produce reproducible random operation sequences
+    struct XorShift(u64);
+
+    impl XorShift {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, bound: usize) -> usize {
+            (self.next() % bound as u64) as usize
+        }
+    }
+
+    fn assert_same_content(circ_buf: &CircularBuffer<u64>, model: &VecDeque<u64>, context: &str) {
+        assert_eq!(circ_buf.len(), model.len(), "{}", context);
+        assert_eq!(circ_buf.is_empty(), model.is_empty(), "{}", context);
+        assert_eq!(
+            circ_buf.is_full(),
+            model.len() == circ_buf.capacity(),
+            "{}",
+            context
+        );
+        assert!(circ_buf.iter().eq(model.iter()), "{}", context);
+        assert!(circ_buf.iter().rev().eq(model.iter().rev()), "{}", context);
+        for index in 0..=model.len() {
+            assert_eq!(circ_buf.get(index), model.get(index), "{}", context);
+        }
+    }
+
+    /// Apply the same random operations to a `CircularBuffer` and to a `VecDeque` bounded by hand
+    fn run_against_vec_deque(seed: u64, capacity: usize, nb_operations: usize) {
+        let mut rng = XorShift(seed);
+        let mut circ_buf = CircularBuffer::<u64>::new(capacity);
+        let mut model = VecDeque::<u64>::new();
+
+        for step in 0..nb_operations {
+            let context = format!("seed {} capacity {} step {}", seed, capacity, step);
+            match rng.below(10) {
+                // pushes are the most frequent operations, in order to reach the full state
+                0..=3 => {
+                    let value = rng.next();
+                    let pushed = circ_buf.push_back(value);
+                    if model.len() < capacity {
+                        model.push_back(value);
+                        assert_eq!(pushed, Ok(()), "{}", context);
+                    } else {
+                        assert_eq!(pushed, Err(value), "{}", context);
+                    }
+                }
+                4..=6 => assert_eq!(circ_buf.pop_front(), model.pop_front(), "{}", context),
+                7 => {
+                    let start = rng.below(model.len() + 1);
+                    let end = start + rng.below(model.len() - start + 1);
+                    assert!(
+                        circ_buf.drain(start..end).eq(model.drain(start..end)),
+                        "{}",
+                        context
+                    );
+                }
+                8 => {
+                    let increment = rng.next() % 100;
+                    for item in circ_buf.iter_mut() {
+                        *item = item.wrapping_add(increment);
+                    }
+                    for item in model.iter_mut() {
+                        *item = item.wrapping_add(increment);
+                    }
+                    assert!(
+                        circ_buf
+                            .iter_mut()
+                            .rev()
+                            .map(|item| *item)
+                            .eq(model.iter().rev().copied()),
+                        "{}",
+                        context
+                    );
+                }
+                _ => {
+                    if rng.below(8) == 0 {
+                        circ_buf.clear();
+                        model.clear();
+                    } else {
+                        let nb_items = rng.below(capacity + 2);
+                        let items: Vec<u64> = (0..nb_items).map(|_| rng.next()).collect();
+                        circ_buf.extend(items.iter().copied());
+                        let room = capacity - model.len();
+                        model.extend(items.into_iter().take(room));
+                    }
+                }
+            }
+            assert_same_content(&circ_buf, &model, &context);
+        }
+        assert!(circ_buf.into_iter().eq(model.into_iter()));
+    }
+
+    #[test]
+    fn behaves_like_vec_deque() {
+        for seed in 1..200u64 {
+            for capacity in 0..9 {
+                run_against_vec_deque(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15), capacity, 300);
+            }
+        }
+    }
+
+    #[test]
+    fn wrap_around_reuses_slots() {
+        let mut circ_buf = CircularBuffer::<String>::new(3);
+        for round in 0..10 {
+            while circ_buf.push_back(round.to_string()).is_ok() {}
+            assert!(circ_buf.is_full());
+            assert_eq!(circ_buf.pop_front(), Some(round.to_string()));
+            assert_eq!(circ_buf.pop_front(), Some(round.to_string()));
+            assert_eq!(circ_buf.len(), 1);
+            // The last slot of the storage must be reused after the wrap-around
+            assert_eq!(circ_buf.push_back(format!("{}bis", round)), Ok(()));
+            assert_eq!(circ_buf.iter().next_back(), Some(&format!("{}bis", round)));
+            circ_buf.clear();
+        }
+    }
+
+    #[test]
+    fn popped_items_are_moved_out() {
+        use std::rc::Rc;
+
+        let item = Rc::new(42);
+        let mut circ_buf = CircularBuffer::new(2);
+        circ_buf.push_back(Rc::clone(&item)).unwrap();
+        assert_eq!(Rc::strong_count(&item), 2);
+        drop(circ_buf.pop_front());
+        // The freed slot must not keep the item alive
+        assert_eq!(Rc::strong_count(&item), 1);
+    }
+
+    #[test]
+    fn full_and_empty_transitions() {
+        let mut circ_buf = CircularBuffer::<u8>::new(1);
+        for value in 0..5 {
+            assert!(circ_buf.is_empty());
+            assert_eq!(circ_buf.push_back(value), Ok(()));
+            assert!(circ_buf.is_full());
+            assert_eq!(circ_buf.push_back(value), Err(value));
+            assert_eq!(circ_buf.pop_front(), Some(value));
+        }
+        let mut empty_buf = CircularBuffer::<u8>::new(0);
+        assert!(empty_buf.is_empty() && empty_buf.is_full());
+        assert_eq!(empty_buf.push_back(1), Err(1));
+        assert_eq!(empty_buf.drain(..).count(), 0);
+    }
 }
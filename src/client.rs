@@ -0,0 +1,550 @@
+use crate::{
+    config::{format_utc, parse_duration},
+    derived::DerivedChannel,
+    historic::TIER_NAMES,
+    json_display,
+    json_value::JsonValue,
+    request::Request,
+    sensor_data::{SensorData, CHANNELS, UNITS},
+    summary::{self, Period, Summary},
+};
+use std::{
+    io::{self, Read, Write},
+    os::unix::net::UnixStream,
+    thread::sleep,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const USAGE: &str = "usage : query [--socket <path>] [--format table|json|csv|sparkline] [--watch [<interval>]] latest | tier <tier> | range <from> [<to>] | channels | summary day|month";
+const DEFAULT_WATCH_INTERVAL_S: u64 = 5;
+// Number of characters of a sparkline, longer series are averaged
+const SPARKLINE_WIDTH: usize = 60;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Table,
+    Json,
+    Csv,
+    Sparkline,
+}
+
+/// Bound of a range : a timestamp in milliseconds, or a duration before now (`2h`, `30m`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeSpec {
+    Timestamp(u64),
+    Ago(Duration),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Query {
+    Latest,
+    Tier(usize),
+    Range(TimeSpec, Option<TimeSpec>),
+    Channels,
+    Summary(Period),
+}
+
+/// Command line of the `query` subcommand
+#[derive(Debug, PartialEq)]
+pub struct ClientOptions {
+    pub socket: String,
+    pub format: OutputFormat,
+    pub watch: Option<Duration>,
+    pub query: Query,
+}
+
+/// Sample read back from the JSON of the server, with the derived channels it contains
+pub struct Sample {
+    pub data: SensorData,
+    pub derived: Vec<(String, f64)>,
+}
+
+/// Answer of the server, read back from its JSON
+pub enum Answer {
+    Samples(Vec<Sample>),
+    /// Name and unit of each channel
+    Channels(Vec<(String, String)>),
+    Summaries(Vec<Summary>),
+}
+
+impl TimeSpec {
+    fn parse(text: &str) -> Result<TimeSpec, String> {
+        let invalid = || {
+            format!(
+                "invalid time {}, a timestamp in ms or a duration like 2h",
+                text
+            )
+        };
+        if text.ends_with(|c: char| c.is_ascii_digit()) {
+            text.parse().map(TimeSpec::Timestamp).map_err(|_| invalid())
+        } else {
+            parse_duration(text).map(TimeSpec::Ago).ok_or_else(invalid)
+        }
+    }
+
+    fn timestamp(&self, now: Duration) -> u64 {
+        match self {
+            TimeSpec::Timestamp(timestamp) => *timestamp,
+            TimeSpec::Ago(duration) => now.saturating_sub(*duration).as_millis() as u64,
+        }
+    }
+}
+
+impl Query {
+    /// Request sent to the server, the durations are relative to `now`
+    pub fn request(&self, now: Duration) -> Request {
+        match self {
+            Query::Latest => Request::Latest,
+            Query::Tier(tier) => Request::Tier(*tier),
+            Query::Range(from, to) => Request::Range {
+                from: from.timestamp(now),
+                to: to.map_or(now.as_millis() as u64, |to| to.timestamp(now)),
+            },
+            Query::Channels => Request::Channels,
+            Query::Summary(period) => Request::Summary(*period),
+        }
+    }
+}
+
+impl ClientOptions {
+    /// Arguments following `query`, the socket is `default_socket` unless `--socket` is given
+    pub fn parse(args: &[String], default_socket: &str) -> Result<ClientOptions, String> {
+        let mut socket = default_socket.to_string();
+        let mut format = OutputFormat::Table;
+        let mut watch = None;
+        let mut words = Vec::new();
+        let mut args = args.iter().peekable();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--socket" => socket = args.next().ok_or("missing socket path")?.clone(),
+                "--format" => {
+                    format = match args.next().map(String::as_str) {
+                        Some("table") => OutputFormat::Table,
+                        Some("json") => OutputFormat::Json,
+                        Some("csv") => OutputFormat::Csv,
+                        Some("sparkline") => OutputFormat::Sparkline,
+                        Some(format) => return Err(format!("unknown format {}", format)),
+                        None => return Err("missing format".to_string()),
+                    }
+                }
+                "--watch" => {
+                    // the interval is optional
+                    let interval = args.peek().and_then(|interval| parse_duration(interval));
+                    if interval.is_some() {
+                        args.next();
+                    }
+                    watch = Some(
+                        interval.unwrap_or_else(|| Duration::from_secs(DEFAULT_WATCH_INTERVAL_S)),
+                    );
+                }
+                option if option.starts_with("--") => {
+                    return Err(format!("unknown option {}", option))
+                }
+                word => words.push(word),
+            }
+        }
+        let query = match words.as_slice() {
+            ["latest"] => Query::Latest,
+            ["channels"] => Query::Channels,
+            ["summary", period] => Query::Summary(Period::parse(Some(period))?),
+            ["tier", tier] => match TIER_NAMES.iter().position(|name| name == tier) {
+                Some(tier) => Query::Tier(tier),
+                None => return Err(format!("unknown tier {}", tier)),
+            },
+            ["range", from] => Query::Range(TimeSpec::parse(from)?, None),
+            ["range", from, to] => Query::Range(TimeSpec::parse(from)?, Some(TimeSpec::parse(to)?)),
+            _ => return Err(USAGE.to_string()),
+        };
+        Ok(ClientOptions {
+            socket,
+            format,
+            watch,
+            query,
+        })
+    }
+}
+
+/// Send the request and read the whole answer, an error sent by the server is returned as
+/// an error
+pub fn send_request(socket: &str, request: &Request) -> Result<JsonValue, String> {
+    let mut stream = UnixStream::connect(socket)
+        .map_err(|err| format!("couldn't connect to {} : {}", socket, err))?;
+    let mut answer = String::new();
+    stream
+        .write_all(format!("{}\n", request).as_bytes())
+        .and_then(|_| stream.read_to_string(&mut answer))
+        .map_err(|err| format!("couldn't query {} : {}", socket, err))?;
+    let answer = JsonValue::parse(&answer)
+        .map_err(|why| format!("invalid answer of the server : {}", why))?;
+    match answer.get("error").and_then(JsonValue::as_str) {
+        Some(error) => Err(error.to_string()),
+        None => Ok(answer),
+    }
+}
+
+impl Sample {
+    fn from_json(json: &JsonValue) -> Result<Sample, String> {
+        Ok(Sample {
+            data: SensorData::from_json(json)?,
+            derived: match json {
+                JsonValue::Object(members) => members
+                    .iter()
+                    .filter(|(name, _)| name != "timestamp" && !CHANNELS.contains(&name.as_str()))
+                    .filter_map(|(name, value)| value.as_f64().map(|value| (name.clone(), value)))
+                    .collect(),
+                _ => Vec::new(),
+            },
+        })
+    }
+
+    fn value(&self, channel: &str) -> f64 {
+        match self.derived.iter().find(|(name, _)| *name == channel) {
+            Some((_, value)) => *value,
+            None => self.data.get_channel(channel).unwrap_or(f64::NAN),
+        }
+    }
+}
+
+/// Name and unit of the measured channels, then of the derived channels of the first sample,
+/// whose unit is only known for the built-in ones
+fn columns(samples: &[Sample]) -> Vec<(&str, &str)> {
+    let mut columns: Vec<_> = CHANNELS
+        .iter()
+        .copied()
+        .zip(UNITS.iter().copied())
+        .collect();
+    if let Some(sample) = samples.first() {
+        for (name, _) in &sample.derived {
+            let unit = DerivedChannel::from_name(name).map_or("", |channel| channel.unit());
+            columns.push((name, unit));
+        }
+    }
+    columns
+}
+
+impl Answer {
+    pub fn from_json(request: &Request, json: &JsonValue) -> Result<Answer, String> {
+        let items = json.as_array().ok_or("an array is expected")?;
+        match request {
+            Request::Channels => items
+                .iter()
+                .map(|item| {
+                    match (
+                        item.get("name").and_then(JsonValue::as_str),
+                        item.get("unit").and_then(JsonValue::as_str),
+                    ) {
+                        (Some(name), Some(unit)) => Ok((name.to_string(), unit.to_string())),
+                        _ => Err("invalid channel".to_string()),
+                    }
+                })
+                .collect::<Result<_, _>>()
+                .map(Answer::Channels),
+            Request::Summary(_) => items
+                .iter()
+                .map(Summary::from_json)
+                .collect::<Result<_, _>>()
+                .map(Answer::Summaries),
+            _ => items
+                .iter()
+                .map(Sample::from_json)
+                .collect::<Result<_, _>>()
+                .map(Answer::Samples),
+        }
+    }
+
+    pub fn write(&self, format: OutputFormat, w: &mut dyn Write) -> io::Result<()> {
+        match self {
+            Answer::Channels(channels) => match format {
+                OutputFormat::Json => {
+                    let channels: Vec<String> = channels
+                        .iter()
+                        .map(|(name, unit)| {
+                            format!(
+                                "{{\"name\": \"{}\", \"unit\": \"{}\"}}",
+                                json_display::escape(name),
+                                json_display::escape(unit)
+                            )
+                        })
+                        .collect();
+                    writeln!(w, "[{}]", channels.join(",\n"))
+                }
+                OutputFormat::Csv => {
+                    writeln!(w, "channel,unit")?;
+                    for (name, unit) in channels {
+                        writeln!(w, "{},{}", name, unit)?;
+                    }
+                    Ok(())
+                }
+                OutputFormat::Table | OutputFormat::Sparkline => {
+                    for (name, unit) in channels {
+                        writeln!(w, "{:<12}{}", name, unit)?;
+                    }
+                    Ok(())
+                }
+            },
+            Answer::Summaries(summaries) => match format {
+                OutputFormat::Json => summary::write_json(summaries, w),
+                OutputFormat::Csv => summary::write_csv(summaries, w),
+                OutputFormat::Table | OutputFormat::Sparkline => summary::write_table(summaries, w),
+            },
+            Answer::Samples(samples) => match format {
+                OutputFormat::Json => {
+                    w.write_all(b"[")?;
+                    for (index, sample) in samples.iter().enumerate() {
+                        if index > 0 {
+                            w.write_all(b",")?;
+                        }
+                        let derived: Vec<(&str, f64)> = sample
+                            .derived
+                            .iter()
+                            .map(|(name, value)| (name.as_str(), *value))
+                            .collect();
+                        sample.data.write_json_with(&derived, w)?;
+                    }
+                    w.write_all(b"]\n")
+                }
+                OutputFormat::Csv => {
+                    let columns = columns(samples);
+                    let names: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+                    writeln!(w, "timestamp,{}", names.join(","))?;
+                    for sample in samples {
+                        write!(w, "{}", sample.data.get_timestamp().as_millis())?;
+                        for name in &names {
+                            write!(w, ",{:.3}", sample.value(name))?;
+                        }
+                        writeln!(w)?;
+                    }
+                    Ok(())
+                }
+                OutputFormat::Table => write_table(samples, w),
+                OutputFormat::Sparkline => write_sparklines(samples, w),
+            },
+        }
+    }
+}
+
+fn write_table(samples: &[Sample], w: &mut dyn Write) -> io::Result<()> {
+    let columns = columns(samples);
+    let headers: Vec<String> = columns
+        .iter()
+        .map(|(channel, unit)| {
+            if unit.is_empty() {
+                channel.to_string()
+            } else {
+                format!("{} ({})", channel, unit)
+            }
+        })
+        .collect();
+    write!(w, "{:<19}", "time (UTC)")?;
+    for header in &headers {
+        write!(w, "  {}", header)?;
+    }
+    writeln!(w)?;
+    for sample in samples {
+        write!(w, "{}", format_utc(sample.data.get_timestamp()))?;
+        for ((channel, _), header) in columns.iter().zip(headers.iter()) {
+            let value = sample.value(channel);
+            write!(w, "  {:>width$.2}", value, width = header.chars().count())?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+fn write_sparklines(samples: &[Sample], w: &mut dyn Write) -> io::Result<()> {
+    if samples.is_empty() {
+        return writeln!(w, "no sample");
+    }
+    for (channel, unit) in columns(samples) {
+        let values: Vec<f64> = samples.iter().map(|sample| sample.value(channel)).collect();
+        let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        writeln!(
+            w,
+            "{:<12}{}  {:.2} .. {:.2} {}",
+            channel,
+            sparkline(&values, min, max),
+            min,
+            max,
+            unit
+        )?;
+    }
+    Ok(())
+}
+
+/// One character per value, at most `SPARKLINE_WIDTH` of them
+fn sparkline(values: &[f64], min: f64, max: f64) -> String {
+    let bucket_size = values.len().div_ceil(SPARKLINE_WIDTH);
+    values
+        .chunks(bucket_size.max(1))
+        .map(|bucket| {
+            let value = bucket.iter().sum::<f64>() / bucket.len() as f64;
+            let level = if max > min {
+                ((value - min) / (max - min) * (SPARKS.len() - 1) as f64).round() as usize
+            } else {
+                0
+            };
+            SPARKS[level.min(SPARKS.len() - 1)]
+        })
+        .collect()
+}
+
+fn query_once(options: &ClientOptions, w: &mut dyn Write) -> Result<(), String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let request = options.query.request(now);
+    let json = send_request(&options.socket, &request)?;
+    Answer::from_json(&request, &json)?
+        .write(options.format, w)
+        .map_err(|err| err.to_string())
+}
+
+/// Run the `query` subcommand, forever in watch mode
+pub fn run(options: &ClientOptions) -> Result<(), String> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    match options.watch {
+        None => query_once(options, &mut stdout),
+        Some(interval) => loop {
+            if let OutputFormat::Table | OutputFormat::Sparkline = options.format {
+                // clear the terminal
+                let _ = stdout.write_all(b"\x1b[2J\x1b[H");
+            }
+            if let Err(why) = query_once(options, &mut stdout) {
+                let _ = writeln!(stdout, "{}", why);
+            }
+            let _ = stdout.flush();
+            sleep(interval);
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Answer, ClientOptions, OutputFormat, Query, Sample, TimeSpec};
+    use crate::{
+        json_value::JsonValue, request::Request, sensor_data::SensorData, summary::Period,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    fn samples() -> Vec<Sample> {
+        (0..3)
+            .map(|index| Sample {
+                data: SensorData::new(
+                    UNIX_EPOCH + Duration::from_secs(1_700_000_000 + index * 5),
+                    101.3 + index as f32 * 0.01,
+                    20_000 + index as i32 * 500,
+                    21_000,
+                    50_000 - index as i32 * 1000,
+                ),
+                derived: vec![
+                    ("dewPoint".to_string(), 10.2 - index as f64 * 0.3),
+                    ("delta_t".to_string(), -1.0 + index as f64 * 0.5),
+                ],
+            })
+            .collect()
+    }
+
+    #[test]
+    fn command_line() {
+        let options = ClientOptions::parse(&args("tier hour"), "rustSocket").unwrap();
+        assert_eq!(
+            options,
+            ClientOptions {
+                socket: "rustSocket".to_string(),
+                format: OutputFormat::Table,
+                watch: None,
+                query: Query::Tier(1),
+            }
+        );
+        let options = ClientOptions::parse(
+            &args("--socket /run/socket --watch --format csv range 2h 1700000000000"),
+            "rustSocket",
+        )
+        .unwrap();
+        assert_eq!(options.socket, "/run/socket");
+        assert_eq!(options.format, OutputFormat::Csv);
+        assert_eq!(options.watch, Some(Duration::from_secs(5)));
+        assert_eq!(
+            options.query,
+            Query::Range(
+                TimeSpec::Ago(Duration::from_secs(7200)),
+                Some(TimeSpec::Timestamp(1_700_000_000_000))
+            )
+        );
+        let options = ClientOptions::parse(&args("--format csv summary month"), "s").unwrap();
+        assert_eq!(options.query, Query::Summary(Period::Month));
+        let options = ClientOptions::parse(&args("latest --watch 2s"), "s").unwrap();
+        assert_eq!(options.watch, Some(Duration::from_secs(2)));
+        assert_eq!(
+            ClientOptions::parse(&args("tier week"), "s"),
+            Err("unknown tier week".to_string())
+        );
+        assert!(ClientOptions::parse(&args("everything"), "s")
+            .unwrap_err()
+            .starts_with("usage"));
+    }
+
+    #[test]
+    fn relative_ranges() {
+        let now = Duration::from_secs(10_000);
+        assert_eq!(
+            Query::Range(TimeSpec::Ago(Duration::from_secs(3600)), None).request(now),
+            Request::Range {
+                from: 6_400_000,
+                to: 10_000_000
+            }
+        );
+    }
+
+    #[test]
+    fn samples_are_read_back() {
+        let mut json = Vec::new();
+        Answer::Samples(samples())
+            .write(OutputFormat::Json, &mut json)
+            .unwrap();
+        let json = JsonValue::parse(&String::from_utf8(json).unwrap()).unwrap();
+        let answer = Answer::from_json(&Request::Latest, &json).unwrap();
+        let mut csv = Vec::new();
+        answer.write(OutputFormat::Csv, &mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "timestamp,pressure,bmp280Temp,htu21Temp,humidity,dewPoint,delta_t\n\
+             1700000000000,1013.000,20.000,21.000,50.000,10.200,-1.000\n\
+             1700000005000,1013.100,20.500,21.000,49.000,9.900,-0.500\n\
+             1700000010000,1013.200,21.000,21.000,48.000,9.600,0.000\n"
+        );
+    }
+
+    #[test]
+    fn table_and_sparklines() {
+        let mut table = Vec::new();
+        Answer::Samples(samples())
+            .write(OutputFormat::Table, &mut table)
+            .unwrap();
+        let table = String::from_utf8(table).unwrap();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines[0],
+            "time (UTC)           pressure (hPa)  bmp280Temp (°C)  htu21Temp (°C)  humidity (%)  dewPoint (°C)  delta_t"
+        );
+        assert_eq!(
+            lines[1],
+            "2023-11-14 22:13:20         1013.00            20.00           21.00         50.00          10.20    -1.00"
+        );
+        let mut sparklines = Vec::new();
+        Answer::Samples(samples())
+            .write(OutputFormat::Sparkline, &mut sparklines)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(sparklines).unwrap().lines().nth(1),
+            Some("bmp280Temp  ▁▅█  20.00 .. 21.00 °C")
+        );
+    }
+}
@@ -0,0 +1,711 @@
+use crate::{
+    circular_buffer::CircularBuffer,
+    config::{hostname, Config, Section},
+    connection_limit::ConnectionLimit,
+    derived::DerivedChannels,
+    historic::{Historic, TIER_SIZES},
+    mapped_circular_buffer::FixedSizeRecord,
+    sensor_data::SensorData,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Error, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{channel, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread::{sleep, spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const PROTOCOL: &str = "RDM1";
+const NONCE_SIZE: usize = 16;
+const MAX_LINE_LENGTH: u64 = 512;
+const MAX_NODE_NAME_LENGTH: usize = 64;
+const IO_TIMEOUT_S: u64 = 30;
+const MAX_RECONNECT_DELAY_S: u64 = 300;
+const DEFAULT_MAX_CONNECTIONS: usize = 32;
+
+// Protocol, one line per message:
+//   collector : HELLO RDM1 <nonce>
+//   node      : AUTH <node> <mac(nonce, node)>
+//   collector : OK                                   or ERROR <reason>, then close
+//   node      : SAMPLE <seq> <record> <mac(nonce, node, seq, record)>
+//   collector : ACK <seq>                            or ERROR <reason>, then close
+// The nonce is random, the sequence numbers start at 1 on each connection and the records are
+// the ones of the DAYS historic file, so a message can't be forged or replayed without the
+// secret. The content is not encrypted.
+
+/// Historic tiers of each node pushing its samples, shared between the connections and the
+/// thread answering the requests
+#[derive(Clone, Default)]
+pub struct Nodes {
+    historics: Arc<Mutex<BTreeMap<String, Vec<Historic<SensorData>>>>>,
+}
+
+/// Central instance, read from the `[collector]` section of the configuration:
+/// ```text
+/// [collector]
+/// listen = 0.0.0.0:7878
+/// secret = shared by the collector and the nodes
+/// max_connections = 32    # connections served at the same time, authenticated or not
+/// ```
+pub struct Collector {
+    listener: TcpListener,
+    secret: Vec<u8>,
+    nodes: Nodes,
+    connections: ConnectionLimit,
+}
+
+/// Node pushing its samples, read from the `[push]` section of the configuration:
+/// ```text
+/// [push]
+/// server = collector.home:7878
+/// node = kitchen          # hostname by default
+/// secret = shared by the collector and the nodes
+/// buffer = 1000           # samples kept while the collector is unreachable
+/// ```
+pub struct Pusher {
+    server: String,
+    node: String,
+    secret: Vec<u8>,
+    connection: Option<PushConnection>,
+    pending: CircularBuffer<SensorData>,
+    reconnect_delay: Duration,
+    next_connection_attempt: Instant,
+}
+
+struct PushConnection {
+    reader: BufReader<TcpStream>,
+    nonce: String,
+    sequence: u64,
+}
+
+impl Nodes {
+    pub fn add(&self, node: &str, data: SensorData) {
+        let mut historics = self.historics.lock().unwrap();
+        let tiers = historics.entry(node.to_string()).or_insert_with(|| {
+            TIER_SIZES
+                .iter()
+                .map(|(size, limit)| Historic::new(*size, *limit))
+                .collect()
+        });
+        tiers[0].add(data);
+        Historic::reduce(tiers);
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.historics.lock().unwrap().keys().cloned().collect()
+    }
+
+    /// Historics of all the nodes, keyed by node name, in the format of the historics request
+    pub fn write_json(&self, derived: &DerivedChannels, w: &mut dyn Write) -> io::Result<()> {
+        let mut json = Vec::new();
+        json.push(b'{');
+        for (index, (node, tiers)) in self.historics.lock().unwrap().iter().enumerate() {
+            if index > 0 {
+                json.extend_from_slice(b",\n");
+            }
+            json.extend_from_slice(format!("\"{}\": ", node).as_bytes());
+            Historic::write_json_historics(tiers, derived, &mut json)?;
+            // write_json_historics ends with a new line
+            json.pop();
+        }
+        json.extend_from_slice(b"}\n");
+        w.write_all(&json)
+    }
+}
+
+impl Collector {
+    pub fn bind(address: &str, secret: &[u8]) -> io::Result<Collector> {
+        Ok(Collector {
+            listener: TcpListener::bind(address)?,
+            secret: secret.to_vec(),
+            nodes: Nodes::default(),
+            connections: ConnectionLimit::new(DEFAULT_MAX_CONNECTIONS),
+        })
+    }
+
+    pub fn with_max_connections(mut self, max: usize) -> Collector {
+        self.connections = ConnectionLimit::new(max);
+        self
+    }
+
+    pub fn from_config(config: &Config) -> Result<Option<Collector>, String> {
+        let section = match config.section("collector")? {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        section.check_keys(&["listen", "secret", "max_connections"])?;
+        let address: String = section.require("listen")?;
+        let max_connections = section.get_or("max_connections", DEFAULT_MAX_CONNECTIONS)?;
+        Collector::bind(&address, &require_secret(section)?)
+            .map(|collector| Some(collector.with_max_connections(max_connections)))
+            .map_err(|err| section.error(&format!("can't listen on {} : {}", address, err)))
+    }
+
+    pub fn get_port(&self) -> u16 {
+        self.listener.local_addr().map(|a| a.port()).unwrap_or(0)
+    }
+
+    pub fn get_nodes(&self) -> Nodes {
+        self.nodes.clone()
+    }
+
+    /// Each node is served by its own thread, the connections beyond the limit are refused
+    /// before the authentication
+    pub fn create_collector_thread(self) -> JoinHandle<()> {
+        spawn(move || {
+            println!("Collector started, waiting for nodes");
+            for stream in self.listener.incoming() {
+                match stream {
+                    Ok(mut stream) => {
+                        let slot = match self.connections.acquire() {
+                            Some(slot) => slot,
+                            None => {
+                                println!(
+                                    "Node {:?} refused, {} connections already served",
+                                    stream.peer_addr(),
+                                    self.connections.max()
+                                );
+                                let _ = stream.write_all(b"ERROR too many connections\n");
+                                continue;
+                            }
+                        };
+                        let secret = self.secret.clone();
+                        let nodes = self.nodes.clone();
+                        spawn(move || {
+                            let peer = stream.peer_addr();
+                            if let Err(why) = serve_node(stream, &secret, &nodes) {
+                                println!("Node {:?} disconnected : {}", peer, why);
+                            }
+                            drop(slot);
+                        });
+                    }
+                    Err(err) => {
+                        println!("Node connection failed : {}", err);
+                        sleep(Duration::from_secs(5));
+                    }
+                }
+            }
+        })
+    }
+}
+
+fn serve_node(stream: TcpStream, secret: &[u8], nodes: &Nodes) -> Result<(), String> {
+    stream
+        .set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT_S)))
+        .and_then(|_| stream.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT_S))))
+        .and_then(|_| stream.set_nodelay(true))
+        .map_err(|err| err.to_string())?;
+    let mut writer = stream.try_clone().map_err(|err| err.to_string())?;
+    let mut reader = BufReader::new(stream);
+    let nonce = random_nonce().map_err(|err| err.to_string())?;
+    writeln!(writer, "HELLO {} {}", PROTOCOL, nonce).map_err(|err| err.to_string())?;
+
+    let result = authenticate(&mut reader, secret, &nonce).and_then(|node| {
+        writeln!(writer, "OK").map_err(|err| err.to_string())?;
+        println!("Node {} connected", node);
+        receive_samples(&mut reader, &mut writer, secret, &nonce, &node, nodes)
+    });
+    if let Err(why) = &result {
+        let _ = writeln!(writer, "ERROR {}", why);
+    }
+    result
+}
+
+fn authenticate(
+    reader: &mut BufReader<TcpStream>,
+    secret: &[u8],
+    nonce: &str,
+) -> Result<String, String> {
+    let line = read_line(reader).map_err(|err| err.to_string())?;
+    let words: Vec<&str> = line.split(' ').collect();
+    match words.as_slice() {
+        ["AUTH", node, mac] if is_valid_node_name(node) => {
+            if verify_mac(secret, &[nonce.as_bytes(), node.as_bytes()], mac) {
+                Ok(node.to_string())
+            } else {
+                Err("authentication failed".to_string())
+            }
+        }
+        _ => Err("AUTH <node> <mac> expected".to_string()),
+    }
+}
+
+fn receive_samples(
+    reader: &mut BufReader<TcpStream>,
+    writer: &mut TcpStream,
+    secret: &[u8],
+    nonce: &str,
+    node: &str,
+    nodes: &Nodes,
+) -> Result<(), String> {
+    let mut expected_sequence = 1u64;
+    loop {
+        let line = match read_line(reader) {
+            Ok(line) if line.is_empty() => return Ok(()),
+            Ok(line) => line,
+            Err(err) => return Err(err.to_string()),
+        };
+        let words: Vec<&str> = line.split(' ').collect();
+        let (sequence, record, mac) = match words.as_slice() {
+            ["SAMPLE", sequence, record, mac] => (*sequence, *record, *mac),
+            _ => return Err("SAMPLE <seq> <record> <mac> expected".to_string()),
+        };
+        if sequence != expected_sequence.to_string() {
+            return Err(format!("sequence {} expected", expected_sequence));
+        }
+        let parts = [
+            nonce.as_bytes(),
+            node.as_bytes(),
+            sequence.as_bytes(),
+            record.as_bytes(),
+        ];
+        if !verify_mac(secret, &parts, mac) {
+            return Err("invalid mac".to_string());
+        }
+        let data = match decode_hex(record) {
+            Some(bytes) if bytes.len() == SensorData::RECORD_SIZE => {
+                SensorData::read_record(&bytes)
+            }
+            _ => return Err("invalid record".to_string()),
+        };
+        nodes.add(node, data);
+        writeln!(writer, "ACK {}", sequence).map_err(|err| err.to_string())?;
+        expected_sequence += 1;
+    }
+}
+
+impl Pusher {
+    pub fn new(server: &str, node: &str, secret: &[u8], buffer_size: usize) -> Pusher {
+        Pusher {
+            server: server.to_string(),
+            node: node.to_string(),
+            secret: secret.to_vec(),
+            connection: None,
+            pending: CircularBuffer::new(buffer_size),
+            reconnect_delay: Duration::from_secs(1),
+            next_connection_attempt: Instant::now(),
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Result<Option<Pusher>, String> {
+        let section = match config.section("push")? {
+            Some(section) => section,
+            None => return Ok(None),
+        };
+        section.check_keys(&["server", "node", "secret", "buffer"])?;
+        let node = section.get_or("node", hostname())?;
+        if !is_valid_node_name(&node) {
+            return Err(section.error(&format!(
+                "invalid node name {}, only letters, digits, '.', '-' and '_' are allowed",
+                node
+            )));
+        }
+        let buffer = section.get_or("buffer", 1000)?;
+        if buffer == 0 {
+            return Err(section.error("the buffer needs at least 1 sample"));
+        }
+        Ok(Some(Pusher::new(
+            &section.require::<String>("server")?,
+            &node,
+            &require_secret(section)?,
+            buffer,
+        )))
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    pub fn nb_pending_samples(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Queue the sample and push all the pending samples if the collector is reachable,
+    /// the oldest samples are dropped when the buffer is full
+    pub fn push(&mut self, data: SensorData) {
+        if self.pending.is_full() {
+            self.pending.pop_front();
+        }
+        let _ = self.pending.push_back(data);
+        self.flush();
+    }
+
+    /// Push the pending samples, (re)connecting if needed
+    pub fn flush(&mut self) {
+        if !self.ensure_connected() {
+            return;
+        }
+        while let Some(data) = self.pending.get(0) {
+            let result = match &mut self.connection {
+                Some(connection) => connection.send(&self.secret, &self.node, data),
+                None => return,
+            };
+            match result {
+                Ok(()) => {
+                    self.pending.pop_front();
+                }
+                Err(err) => {
+                    println!("Push to {} failed : {}", self.server, err);
+                    self.connection = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn ensure_connected(&mut self) -> bool {
+        if self.connection.is_some() {
+            return true;
+        }
+        if Instant::now() < self.next_connection_attempt {
+            return false;
+        }
+        match PushConnection::connect(&self.server, &self.node, &self.secret) {
+            Ok(connection) => {
+                println!("Pushing the samples to {}", self.server);
+                self.connection = Some(connection);
+                self.reconnect_delay = Duration::from_secs(1);
+                true
+            }
+            Err(err) => {
+                println!(
+                    "Connection to {} failed, next attempt in {:?} : {}",
+                    self.server, self.reconnect_delay, err
+                );
+                self.next_connection_attempt = Instant::now() + self.reconnect_delay;
+                self.reconnect_delay =
+                    (self.reconnect_delay * 2).min(Duration::from_secs(MAX_RECONNECT_DELAY_S));
+                false
+            }
+        }
+    }
+}
+
+impl PushConnection {
+    fn connect(server: &str, node: &str, secret: &[u8]) -> io::Result<PushConnection> {
+        let stream = TcpStream::connect(server)?;
+        stream.set_read_timeout(Some(Duration::from_secs(IO_TIMEOUT_S)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(IO_TIMEOUT_S)))?;
+        // each sample waits for its acknowledgment
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream);
+        let hello = read_line(&mut reader)?;
+        let nonce = match hello.split(' ').collect::<Vec<_>>().as_slice() {
+            ["HELLO", PROTOCOL, nonce] => nonce.to_string(),
+            _ => return Err(protocol_error(&hello)),
+        };
+        let mac = compute_mac(secret, &[nonce.as_bytes(), node.as_bytes()]);
+        writeln!(reader.get_mut(), "AUTH {} {}", node, mac)?;
+        let answer = read_line(&mut reader)?;
+        if answer != "OK" {
+            return Err(protocol_error(&answer));
+        }
+        Ok(PushConnection {
+            reader,
+            nonce,
+            sequence: 0,
+        })
+    }
+
+    fn send(&mut self, secret: &[u8], node: &str, data: &SensorData) -> io::Result<()> {
+        let mut record = vec![0u8; SensorData::RECORD_SIZE];
+        data.write_record(&mut record);
+        let record = encode_hex(&record);
+        let sequence = (self.sequence + 1).to_string();
+        let mac = compute_mac(
+            secret,
+            &[
+                self.nonce.as_bytes(),
+                node.as_bytes(),
+                sequence.as_bytes(),
+                record.as_bytes(),
+            ],
+        );
+        writeln!(
+            self.reader.get_mut(),
+            "SAMPLE {} {} {}",
+            sequence,
+            record,
+            mac
+        )?;
+        let answer = read_line(&mut self.reader)?;
+        if answer != format!("ACK {}", sequence) {
+            return Err(protocol_error(&answer));
+        }
+        self.sequence += 1;
+        Ok(())
+    }
+}
+
+/// Thread which pushes the received samples, they wait in the buffer of the pusher while the
+/// collector is unreachable
+pub fn create_push_thread(mut pusher: Pusher) -> (JoinHandle<()>, Sender<SensorData>) {
+    let (tx, rx) = channel::<SensorData>();
+    (
+        spawn(move || loop {
+            match rx.recv_timeout(Duration::from_secs(IO_TIMEOUT_S)) {
+                Ok(data) => pusher.push(data),
+                Err(RecvTimeoutError::Timeout) => pusher.flush(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }),
+        tx,
+    )
+}
+
+fn require_secret(section: &Section) -> Result<Vec<u8>, String> {
+    let secret: String = section.require("secret")?;
+    if secret.len() < 16 {
+        return Err(section.error("the secret needs at least 16 characters"));
+    }
+    Ok(secret.into_bytes())
+}
+
+fn is_valid_node_name(node: &str) -> bool {
+    !node.is_empty()
+        && node.len() <= MAX_NODE_NAME_LENGTH
+        && node
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+/// The parts are separated, so that their boundaries are part of the authenticated content
+fn new_mac(secret: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret).expect("HMAC accepts any key size");
+    for part in parts {
+        mac.update(&(part.len() as u32).to_be_bytes());
+        mac.update(part);
+    }
+    mac
+}
+
+fn compute_mac(secret: &[u8], parts: &[&[u8]]) -> String {
+    encode_hex(&new_mac(secret, parts).finalize().into_bytes())
+}
+
+/// Constant time comparison
+fn verify_mac(secret: &[u8], parts: &[&[u8]], mac: &str) -> bool {
+    match decode_hex(mac) {
+        Some(mac) => new_mac(secret, parts).verify_slice(&mac).is_ok(),
+        None => false,
+    }
+}
+
+fn random_nonce() -> io::Result<String> {
+    let mut nonce = [0u8; NONCE_SIZE];
+    File::open("/dev/urandom")?.read_exact(&mut nonce)?;
+    Ok(encode_hex(&nonce))
+}
+
+/// Line without its new line, empty at the end of the stream
+fn read_line(reader: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = String::new();
+    reader.by_ref().take(MAX_LINE_LENGTH).read_line(&mut line)?;
+    if !line.is_empty() && !line.ends_with('\n') {
+        return Err(Error::new(ErrorKind::InvalidData, "line too long"));
+    }
+    Ok(line.trim_end().to_string())
+}
+
+fn protocol_error(line: &str) -> Error {
+    match line.strip_prefix("ERROR ") {
+        Some(reason) => Error::new(ErrorKind::PermissionDenied, reason.to_string()),
+        None if line.is_empty() => Error::new(ErrorKind::UnexpectedEof, "connection closed"),
+        None => Error::new(
+            ErrorKind::InvalidData,
+            format!("unexpected answer {}", line),
+        ),
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&text[index..index + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compute_mac, Collector, Pusher};
+    use crate::{config::Config, derived::DerivedChannels, test_utils::sample};
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{TcpListener, TcpStream},
+        time::Duration,
+    };
+
+    const SECRET: &[u8] = b"0123456789abcdef";
+
+    fn start_collector() -> (u16, super::Nodes) {
+        let collector = Collector::bind("127.0.0.1:0", SECRET).unwrap();
+        let port = collector.get_port();
+        let nodes = collector.get_nodes();
+        collector.create_collector_thread();
+        (port, nodes)
+    }
+
+    #[test]
+    fn nodes_push_their_samples() {
+        let (port, nodes) = start_collector();
+        let server = format!("127.0.0.1:{}", port);
+        let mut kitchen = Pusher::new(&server, "kitchen", SECRET, 10);
+        let mut garden = Pusher::new(&server, "garden", SECRET, 10);
+        for seconds in 0..30 {
+            kitchen.push(sample(seconds));
+        }
+        garden.push(sample(100));
+        assert!(kitchen.is_connected() && garden.is_connected());
+        assert_eq!(kitchen.nb_pending_samples(), 0);
+        assert_eq!(nodes.names(), ["garden", "kitchen"]);
+
+        let mut json = Vec::new();
+        nodes
+            .write_json(&DerivedChannels::default(), &mut json)
+            .unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\"garden\": [{\"timestamp\": 100000,"));
+        assert!(json.contains(",\n\"kitchen\": [{\"timestamp\": 12000,"));
+        assert!(json.ends_with("]}\n"));
+        // the 12 oldest samples of kitchen were reduced to a single one in the hour tier
+        assert_eq!(json.matches("\"timestamp\"").count(), 1 + 18 + 1);
+    }
+
+    #[test]
+    fn wrong_secret_is_refused() {
+        let (port, nodes) = start_collector();
+        let mut pusher = Pusher::new(
+            &format!("127.0.0.1:{}", port),
+            "intruder",
+            b"fedcba9876543210",
+            10,
+        );
+        pusher.push(sample(1));
+        assert!(!pusher.is_connected());
+        assert_eq!(pusher.nb_pending_samples(), 1);
+        assert!(nodes.names().is_empty());
+    }
+
+    #[test]
+    fn forged_sample_is_refused() {
+        let (port, nodes) = start_collector();
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut hello = String::new();
+        reader.read_line(&mut hello).unwrap();
+        let nonce = hello.trim().rsplit(' ').next().unwrap().to_string();
+        let mac = compute_mac(SECRET, &[nonce.as_bytes(), b"kitchen"]);
+        writeln!(reader.get_mut(), "AUTH kitchen {}", mac).unwrap();
+        let mut answer = String::new();
+        reader.read_line(&mut answer).unwrap();
+        assert_eq!(answer, "OK\n");
+        // the mac of the authentication is replayed for a sample
+        writeln!(reader.get_mut(), "SAMPLE 1 {} {}", "00".repeat(28), mac).unwrap();
+        answer.clear();
+        reader.read_line(&mut answer).unwrap();
+        assert_eq!(answer, "ERROR invalid mac\n");
+        assert!(nodes.names().is_empty());
+    }
+
+    #[test]
+    fn connections_are_limited() {
+        let collector = Collector::bind("127.0.0.1:0", SECRET)
+            .unwrap()
+            .with_max_connections(1);
+        let port = collector.get_port();
+        collector.create_collector_thread();
+        let mut first = BufReader::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+        let mut hello = String::new();
+        first.read_line(&mut hello).unwrap();
+        assert!(hello.starts_with("HELLO RDM1 "));
+
+        let mut second = BufReader::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+        let mut answer = String::new();
+        second.read_line(&mut answer).unwrap();
+        assert_eq!(answer, "ERROR too many connections\n");
+
+        // the place is released when the first node leaves
+        drop(first);
+        let mut answer = String::new();
+        for _ in 0..50 {
+            let mut third = BufReader::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+            answer.clear();
+            third.read_line(&mut answer).unwrap();
+            if answer.starts_with("HELLO") {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert!(answer.starts_with("HELLO RDM1 "), "{}", answer);
+    }
+
+    #[test]
+    fn samples_are_buffered_while_the_collector_is_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        let mut pusher = Pusher::new(&address.to_string(), "kitchen", SECRET, 2);
+        for seconds in 1..=3 {
+            pusher.push(sample(seconds));
+        }
+        assert!(!pusher.is_connected());
+        assert_eq!(pusher.nb_pending_samples(), 2);
+
+        let collector = Collector::bind(&address.to_string(), SECRET).unwrap();
+        let nodes = collector.get_nodes();
+        collector.create_collector_thread();
+        pusher.next_connection_attempt = std::time::Instant::now();
+        pusher.flush();
+        assert_eq!(pusher.nb_pending_samples(), 0);
+        let mut json = Vec::new();
+        nodes
+            .write_json(&DerivedChannels::default(), &mut json)
+            .unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.starts_with("{\"kitchen\": [{\"timestamp\": 2000,"));
+        assert_eq!(json.matches("\"timestamp\"").count(), 2);
+    }
+
+    #[test]
+    fn configuration() {
+        let config =
+            Config::parse("[push]\nserver = collector:7878\nnode = kitchen\nsecret = short\n")
+                .unwrap();
+        assert_eq!(
+            Pusher::from_config(&config).err(),
+            Some("line 1: section [push]: the secret needs at least 16 characters".to_string())
+        );
+        let config = Config::parse(
+            "[push]\nserver = collector:7878\nnode = kitchen/1\nsecret = 0123456789abcdef\n",
+        )
+        .unwrap();
+        assert!(Pusher::from_config(&config).is_err());
+        let config = Config::parse(
+            "[push]\nserver = collector:7878\nsecret = 0123456789abcdef\nbuffer = 0\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Pusher::from_config(&config).err(),
+            Some("line 1: section [push]: the buffer needs at least 1 sample".to_string())
+        );
+        assert!(Collector::from_config(&Config::default())
+            .unwrap()
+            .is_none());
+    }
+}
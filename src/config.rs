@@ -0,0 +1,348 @@
+use std::{fs, str::FromStr, time::Duration};
+
+/// Content of the configuration file.
+///
+/// The file is made of sections holding `key = value` entries:
+/// ```text
+/// # comment
+/// [alert humidity_high]
+/// channel = humidity
+/// above = 80
+/// ```
+/// The first word of a section header is its kind, the optional second word is its name.
+/// Each module reads the sections of the kind it handles.
+#[derive(Debug, Default)]
+pub struct Config {
+    sections: Vec<Section>,
+}
+
+#[derive(Debug)]
+pub struct Section {
+    kind: String,
+    name: Option<String>,
+    line: usize,
+    entries: Vec<Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    key: String,
+    value: String,
+    line: usize,
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config, String> {
+        match fs::read_to_string(path) {
+            Err(why) => Err(format!("couldn't read {}: {}", path, why)),
+            Ok(content) => Config::parse(&content).map_err(|why| format!("{}: {}", path, why)),
+        }
+    }
+
+    pub fn parse(content: &str) -> Result<Config, String> {
+        let mut sections: Vec<Section> = Vec::new();
+        for (index, raw_line) in content.lines().enumerate() {
+            let line = index + 1;
+            let text = match raw_line.find('#') {
+                Some(comment_start) => &raw_line[..comment_start],
+                None => raw_line,
+            }
+            .trim();
+            if text.is_empty() {
+                continue;
+            }
+            if text.starts_with('[') {
+                if !text.ends_with(']') {
+                    return Err(format!("line {}: unterminated section header", line));
+                }
+                let mut words = text[1..text.len() - 1].split_whitespace();
+                let kind = match words.next() {
+                    Some(kind) => kind.to_string(),
+                    None => return Err(format!("line {}: empty section header", line)),
+                };
+                let name = words.next().map(str::to_string);
+                if words.next().is_some() {
+                    return Err(format!(
+                        "line {}: a section header is made of a kind and a name",
+                        line
+                    ));
+                }
+                sections.push(Section {
+                    kind,
+                    name,
+                    line,
+                    entries: Vec::new(),
+                });
+            } else {
+                let (key, value) = match text.find('=') {
+                    Some(equal) => (text[..equal].trim(), text[equal + 1..].trim()),
+                    None => return Err(format!("line {}: expected key = value", line)),
+                };
+                match sections.last_mut() {
+                    Some(section) => section.entries.push(Entry {
+                        key: key.to_string(),
+                        value: value.to_string(),
+                        line,
+                    }),
+                    None => return Err(format!("line {}: entry outside of a section", line)),
+                }
+            }
+        }
+        Ok(Config { sections })
+    }
+
+    /// Sections of the given kind, in the order of the file
+    pub fn sections<'a>(&'a self, kind: &'a str) -> impl Iterator<Item = &'a Section> + 'a {
+        self.sections
+            .iter()
+            .filter(move |section| section.kind == kind)
+    }
+
+    /// The unique section of the given kind, if any
+    pub fn section<'a>(&'a self, kind: &'a str) -> Result<Option<&'a Section>, String> {
+        let mut sections = self.sections(kind);
+        let section = sections.next();
+        match sections.next() {
+            Some(duplicate) => Err(format!(
+                "line {}: section [{}] is already defined",
+                duplicate.line, kind
+            )),
+            None => Ok(section),
+        }
+    }
+}
+
+impl Section {
+    pub fn kind(&self) -> &str {
+        &self.kind
+    }
+
+    /// Name of the section, mandatory for the kinds which can have several sections
+    pub fn name(&self) -> Result<&str, String> {
+        match &self.name {
+            Some(name) => Ok(name),
+            None => Err(format!(
+                "line {}: section [{}] needs a name",
+                self.line, self.kind
+            )),
+        }
+    }
+
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.entry(key).map(|entry| entry.value.as_str())
+    }
+
+    /// Value of the key converted with `FromStr`
+    pub fn get<T>(&self, key: &str) -> Result<Option<T>, String>
+    where
+        T: FromStr,
+        <T>::Err: std::fmt::Display,
+    {
+        match self.entry(key) {
+            None => Ok(None),
+            Some(entry) => match entry.value.parse::<T>() {
+                Ok(value) => Ok(Some(value)),
+                Err(why) => Err(format!(
+                    "line {}: invalid value for {}: {}",
+                    entry.line, key, why
+                )),
+            },
+        }
+    }
+
+    pub fn get_or<T>(&self, key: &str, default: T) -> Result<T, String>
+    where
+        T: FromStr,
+        <T>::Err: std::fmt::Display,
+    {
+        Ok(self.get(key)?.unwrap_or(default))
+    }
+
+    pub fn require<T>(&self, key: &str) -> Result<T, String>
+    where
+        T: FromStr,
+        <T>::Err: std::fmt::Display,
+    {
+        match self.get(key)? {
+            Some(value) => Ok(value),
+            None => Err(self.error(&format!("missing key {}", key))),
+        }
+    }
+
+    /// Duration written as a number followed by an optional unit : s (default), m, h or d
+    pub fn get_duration(&self, key: &str) -> Result<Option<Duration>, String> {
+        match self.entry(key) {
+            None => Ok(None),
+            Some(entry) => match parse_duration(&entry.value) {
+                Some(duration) => Ok(Some(duration)),
+                None => Err(format!(
+                    "line {}: invalid duration for {}: {}",
+                    entry.line, key, entry.value
+                )),
+            },
+        }
+    }
+
+    /// Fail if the section holds a key which is not in `known_keys`, in order to catch typos
+    pub fn check_keys(&self, known_keys: &[&str]) -> Result<(), String> {
+        match self
+            .entries
+            .iter()
+            .find(|entry| !known_keys.contains(&entry.key.as_str()))
+        {
+            Some(entry) => Err(format!(
+                "line {}: unknown key {} in section [{}]",
+                entry.line, entry.key, self.kind
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Error message pointing at the section
+    pub fn error(&self, message: &str) -> String {
+        match &self.name {
+            Some(name) => format!(
+                "line {}: section [{} {}]: {}",
+                self.line, self.kind, name, message
+            ),
+            None => format!("line {}: section [{}]: {}", self.line, self.kind, message),
+        }
+    }
+
+    fn entry(&self, key: &str) -> Option<&Entry> {
+        self.entries.iter().rev().find(|entry| entry.key == key)
+    }
+}
+
+/// Default name of the board in the exported data
+pub fn hostname() -> String {
+    match fs::read_to_string("/etc/hostname") {
+        Ok(hostname) if !hostname.trim().is_empty() => hostname.trim().to_string(),
+        _ => "localhost".to_string(),
+    }
+}
+
+pub fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim();
+    let (number, unit_seconds) = match text.chars().last()? {
+        's' => (&text[..text.len() - 1], 1.0),
+        'm' => (&text[..text.len() - 1], 60.0),
+        'h' => (&text[..text.len() - 1], 3600.0),
+        'd' => (&text[..text.len() - 1], 86400.0),
+        _ => (text, 1.0),
+    };
+    match number.trim().parse::<f64>() {
+        Ok(value) if value >= 0.0 && value.is_finite() => {
+            Some(Duration::from_secs_f64(value * unit_seconds))
+        }
+        _ => None,
+    }
+}
+
+/// Year, month and day of a number of days since the epoch, from Howard Hinnant's algorithm
+pub fn civil_date(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// `YYYY-MM-DD HH:MM:SS` of a duration since the epoch
+pub fn format_utc(timestamp: Duration) -> String {
+    let seconds = timestamp.as_secs();
+    let (days, time) = (seconds / 86400, seconds % 86400);
+    let (year, month, day) = civil_date(days as i64);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_utc, parse_duration, Config};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_sections_and_values() {
+        let config = Config::parse(
+            "# alerts\n[alert humid]\nchannel = humidity # relative\nabove=80\nfor = 10m\n\n[mqtt]\nhost = localhost\n",
+        )
+        .unwrap();
+        let alerts: Vec<_> = config.sections("alert").collect();
+        assert_eq!(alerts.len(), 1);
+        assert_eq!(alerts[0].name(), Ok("humid"));
+        assert_eq!(alerts[0].get_str("channel"), Some("humidity"));
+        assert_eq!(alerts[0].require::<f64>("above"), Ok(80.0));
+        assert_eq!(
+            alerts[0].get_duration("for"),
+            Ok(Some(Duration::from_secs(600)))
+        );
+        assert!(alerts[0].check_keys(&["channel", "above"]).is_err());
+        assert_eq!(
+            config.section("mqtt").unwrap().unwrap().get_str("host"),
+            Some("localhost")
+        );
+        assert!(config.section("influxdb").unwrap().is_none());
+    }
+
+    #[test]
+    fn errors_point_at_the_line() {
+        assert_eq!(
+            Config::parse("key = value").unwrap_err(),
+            "line 1: entry outside of a section"
+        );
+        assert_eq!(
+            Config::parse("[alert a]\nabove 80").unwrap_err(),
+            "line 2: expected key = value"
+        );
+        let config = Config::parse("[alert a]\nabove = high").unwrap();
+        let section = config.sections("alert").next().unwrap();
+        assert!(section
+            .require::<f64>("above")
+            .unwrap_err()
+            .starts_with("line 2: invalid value for above"));
+        assert_eq!(
+            section.require::<f64>("below").unwrap_err(),
+            "line 1: section [alert a]: missing key below"
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("1.5h"), Some(Duration::from_secs(5400)));
+        assert_eq!(parse_duration("2d"), Some(Duration::from_secs(172_800)));
+        assert_eq!(parse_duration("-1m"), None);
+        assert_eq!(parse_duration("soon"), None);
+    }
+
+    #[test]
+    fn utc_dates() {
+        assert_eq!(format_utc(Duration::from_secs(0)), "1970-01-01 00:00:00");
+        assert_eq!(
+            format_utc(Duration::from_secs(951_827_696)),
+            "2000-02-29 12:34:56"
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Number of connections served at the same time, each one by its own thread
+#[derive(Clone, Debug)]
+pub struct ConnectionLimit {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+/// Place of a connection, released when dropped
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    active: Arc<AtomicUsize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// None when `max` connections are already served
+    pub fn acquire(&self) -> Option<ConnectionSlot> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| {
+                Some(active + 1).filter(|active| *active <= self.max)
+            })
+            .ok()
+            .map(|_| ConnectionSlot {
+                active: self.active.clone(),
+            })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.active.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectionLimit;
+
+    #[test]
+    fn slots_are_released() {
+        let limit = ConnectionLimit::new(2);
+        let first = limit.acquire().unwrap();
+        let second = limit.clone().acquire().unwrap();
+        assert!(limit.acquire().is_none());
+        drop(first);
+        let third = limit.acquire().unwrap();
+        assert!(limit.acquire().is_none());
+        drop((second, third));
+        assert!(limit.acquire().is_some());
+        assert!(ConnectionLimit::new(0).acquire().is_none());
+    }
+}
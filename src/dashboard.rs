@@ -0,0 +1,50 @@
+/// Files of the web dashboard, embedded in the binary so that it works without any access to
+/// the Internet. The page reads the tiers with `GET /tier/<tier>`.
+const ASSETS: [(&str, &str, &str); 3] = [
+    (
+        "/",
+        "text/html; charset=utf-8",
+        include_str!("../assets/dashboard.html"),
+    ),
+    (
+        "/dashboard.js",
+        "application/javascript; charset=utf-8",
+        include_str!("../assets/dashboard.js"),
+    ),
+    (
+        "/dashboard.css",
+        "text/css; charset=utf-8",
+        include_str!("../assets/dashboard.css"),
+    ),
+];
+
+/// Content type and content of the file served at `path`
+pub fn asset(path: &str) -> Option<(&'static str, &'static str)> {
+    let path = if path == "/index.html" { "/" } else { path };
+    ASSETS
+        .iter()
+        .find(|(asset_path, _, _)| *asset_path == path)
+        .map(|(_, content_type, content)| (*content_type, *content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{asset, ASSETS};
+
+    #[test]
+    fn assets_are_self_contained() {
+        let (content_type, page) = asset("/index.html").unwrap();
+        assert_eq!(content_type, "text/html; charset=utf-8");
+        assert!(page.contains("src=\"dashboard.js\""));
+        assert!(page.contains("href=\"dashboard.css\""));
+        assert!(asset("/dashboard.js").is_some());
+        assert!(asset("/missing.js").is_none());
+        for (path, _, content) in ASSETS.iter() {
+            assert!(
+                !content.contains("http://") && !content.contains("https://"),
+                "{} loads something from another site",
+                path
+            );
+        }
+    }
+}
@@ -0,0 +1,414 @@
+use crate::{
+    config::Config,
+    expression::{Expression, Type},
+    sensor_data::{SensorData, CHANNELS},
+};
+
+/// Channel computed from the measured channels of a sample.
+///
+/// The derived channels are not stored, they are computed when a sample is written, so the
+/// reduced samples of the HOUR and DAYS tiers give the derived values of their averages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DerivedChannel {
+    /// °C, from the HTU21 temperature and humidity (Magnus formula)
+    DewPoint,
+    /// g/m³, from the HTU21 temperature and humidity
+    AbsoluteHumidity,
+    /// °C, from the HTU21 temperature and humidity (NWS Rothfusz regression)
+    HeatIndex,
+    /// hPa, from the BMP280 pressure and temperature and the altitude of the station
+    SeaLevelPressure,
+}
+
+pub const DERIVED_CHANNELS: [DerivedChannel; 4] = [
+    DerivedChannel::DewPoint,
+    DerivedChannel::AbsoluteHumidity,
+    DerivedChannel::HeatIndex,
+    DerivedChannel::SeaLevelPressure,
+];
+
+// Keys of the JSON objects of the samples which are not channels
+const RESERVED_NAMES: [&str; 2] = ["timestamp", "calibrated"];
+
+/// Derived channels written with the samples, read from the `[derived]` section of the
+/// configuration:
+/// ```text
+/// [derived]
+/// channels = dewPoint, absoluteHumidity, heatIndex, seaLevelPressure
+/// altitude = 152           # in meters, needed by seaLevelPressure
+/// ```
+/// Without `channels`, all of them are written, seaLevelPressure only when the altitude is
+/// given.
+///
+/// They are followed by the channels defined with an expression in the `[channel <name>]`
+/// sections, see `Expression`. Their names are made of ASCII letters, digits and '_', and don't
+/// start with a digit, since they are written as is in the JSON output.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DerivedChannels {
+    pub channels: Vec<DerivedChannel>,
+    pub altitude: f64,
+    pub expressions: Vec<ExpressionChannel>,
+}
+
+/// Channel defined by an expression over the measured channels, the derived channels and the
+/// channels defined before it
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpressionChannel {
+    pub name: String,
+    pub unit: String,
+    pub expression: Expression,
+}
+
+impl DerivedChannel {
+    pub fn from_name(name: &str) -> Option<DerivedChannel> {
+        DERIVED_CHANNELS
+            .iter()
+            .copied()
+            .find(|channel| channel.name() == name)
+    }
+
+    /// Name in the JSON output
+    pub fn name(&self) -> &'static str {
+        match self {
+            DerivedChannel::DewPoint => "dewPoint",
+            DerivedChannel::AbsoluteHumidity => "absoluteHumidity",
+            DerivedChannel::HeatIndex => "heatIndex",
+            DerivedChannel::SeaLevelPressure => "seaLevelPressure",
+        }
+    }
+
+    pub fn unit(&self) -> &'static str {
+        match self {
+            DerivedChannel::DewPoint | DerivedChannel::HeatIndex => "°C",
+            DerivedChannel::AbsoluteHumidity => "g/m³",
+            DerivedChannel::SeaLevelPressure => "hPa",
+        }
+    }
+
+    /// Value for a sample, `altitude` in meters
+    pub fn compute(&self, data: &SensorData, altitude: f64) -> f64 {
+        let temperature = f64::from(data.get_htu21_temperature()) / 1000.0;
+        let humidity = f64::from(data.get_htu21_humidity()) / 1000.0;
+        match self {
+            DerivedChannel::DewPoint => dew_point(temperature, humidity),
+            DerivedChannel::AbsoluteHumidity => absolute_humidity(temperature, humidity),
+            DerivedChannel::HeatIndex => heat_index(temperature, humidity),
+            DerivedChannel::SeaLevelPressure => sea_level_pressure(
+                f64::from(data.get_bmp280_pressure()) * 10.0,
+                f64::from(data.get_bmp280_temperature()) / 1000.0,
+                altitude,
+            ),
+        }
+    }
+}
+
+impl DerivedChannels {
+    fn builtin_from_config(config: &Config) -> Result<DerivedChannels, String> {
+        let section = match config.section("derived")? {
+            Some(section) => section,
+            None => return Ok(DerivedChannels::default()),
+        };
+        section.check_keys(&["channels", "altitude"])?;
+        let altitude: Option<f64> = section.get("altitude")?;
+        let channels = match section.get_str("channels") {
+            Some(names) => names
+                .split(',')
+                .map(|name| {
+                    DerivedChannel::from_name(name.trim()).ok_or_else(|| {
+                        section.error(&format!("unknown derived channel {}", name.trim()))
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?,
+            None => DERIVED_CHANNELS
+                .iter()
+                .copied()
+                .filter(|channel| {
+                    *channel != DerivedChannel::SeaLevelPressure || altitude.is_some()
+                })
+                .collect(),
+        };
+        if channels.contains(&DerivedChannel::SeaLevelPressure) && altitude.is_none() {
+            return Err(section.error("seaLevelPressure needs the altitude"));
+        }
+        Ok(DerivedChannels {
+            channels,
+            altitude: altitude.unwrap_or(0.0),
+            expressions: Vec::new(),
+        })
+    }
+
+    /// Read the `[derived]` section and the `[channel <name>]` sections
+    pub fn from_config(config: &Config) -> Result<DerivedChannels, String> {
+        let mut derived = DerivedChannels::builtin_from_config(config)?;
+        for section in config.sections("channel") {
+            section.check_keys(&["expression", "unit"])?;
+            let name = section.name()?;
+            if !is_valid_channel_name(name) {
+                return Err(section.error(&format!(
+                    "invalid channel name {}, only letters, digits and '_' are allowed, \
+                     and not a digit first",
+                    name
+                )));
+            }
+            if CHANNELS.contains(&name) || RESERVED_NAMES.contains(&name) {
+                return Err(section.error(&format!("{} is a field of the samples", name)));
+            }
+            if derived.contains(name) {
+                return Err(section.error(&format!("channel {} is already defined", name)));
+            }
+            let text: String = section.require("expression")?;
+            let expression = Expression::parse(&text)
+                .and_then(|expression| {
+                    match expression.check(&|channel| derived.contains(channel))? {
+                        Type::Number => Ok(expression),
+                        Type::Boolean => Err("the value is a boolean, not a number".to_string()),
+                    }
+                })
+                .map_err(|why| section.error(&format!("expression `{}`: {}", text, why)))?;
+            derived.expressions.push(ExpressionChannel {
+                name: name.to_string(),
+                unit: section.get_str("unit").unwrap_or("").to_string(),
+                expression,
+            });
+        }
+        Ok(derived)
+    }
+
+    /// Whether the channel is measured or derived
+    pub fn contains(&self, name: &str) -> bool {
+        CHANNELS.contains(&name)
+            || self.channels.iter().any(|channel| channel.name() == name)
+            || self
+                .expressions
+                .iter()
+                .any(|expression| expression.name == name)
+    }
+
+    /// Name and unit of the derived channels
+    pub fn names_and_units(&self) -> Vec<(&str, &str)> {
+        self.channels
+            .iter()
+            .map(|channel| (channel.name(), channel.unit()))
+            .chain(
+                self.expressions
+                    .iter()
+                    .map(|expression| (expression.name.as_str(), expression.unit.as_str())),
+            )
+            .collect()
+    }
+
+    /// Name and value of each derived channel for a sample
+    pub fn values(&self, data: &SensorData) -> Vec<(&str, f64)> {
+        let mut values: Vec<(&str, f64)> = self
+            .channels
+            .iter()
+            .map(|channel| (channel.name(), channel.compute(data, self.altitude)))
+            .collect();
+        for channel in &self.expressions {
+            let value = channel.expression.evaluate(&|name| match values
+                .iter()
+                .find(|(derived, _)| *derived == name)
+            {
+                Some((_, value)) => Some(*value),
+                None => data.get_channel(name),
+            });
+            values.push((&channel.name, value));
+        }
+        values
+    }
+}
+
+fn is_valid_channel_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    matches!(chars.next(), Some(first) if first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Dew point in °C, Magnus formula with the coefficients of Sonntag (1990)
+pub fn dew_point(temperature: f64, relative_humidity: f64) -> f64 {
+    const A: f64 = 17.62;
+    const B: f64 = 243.12;
+    let gamma = (relative_humidity.max(0.01) / 100.0).ln() + A * temperature / (B + temperature);
+    B * gamma / (A - gamma)
+}
+
+/// Mass of water vapour in g/m³
+pub fn absolute_humidity(temperature: f64, relative_humidity: f64) -> f64 {
+    let saturation_pressure = 6.112 * (17.67 * temperature / (temperature + 243.5)).exp();
+    saturation_pressure * relative_humidity * 2.1674 / (273.15 + temperature)
+}
+
+/// Apparent temperature in °C, regression of Rothfusz as used by the US National Weather
+/// Service, with its adjustments for the dry and humid air
+pub fn heat_index(temperature: f64, relative_humidity: f64) -> f64 {
+    let t = temperature * 9.0 / 5.0 + 32.0;
+    let rh = relative_humidity;
+    let simple = 0.5 * (t + 61.0 + (t - 68.0) * 1.2 + rh * 0.094);
+    let fahrenheit = if (simple + t) / 2.0 < 80.0 {
+        simple
+    } else {
+        let mut index = -42.379 + 2.049_015_23 * t + 10.143_331_27 * rh
+            - 0.224_755_41 * t * rh
+            - 0.006_837_83 * t * t
+            - 0.054_817_17 * rh * rh
+            + 0.001_228_74 * t * t * rh
+            + 0.000_852_82 * t * rh * rh
+            - 0.000_001_99 * t * t * rh * rh;
+        if rh < 13.0 && (80.0..=112.0).contains(&t) {
+            index -= (13.0 - rh) / 4.0 * ((17.0 - (t - 95.0).abs()) / 17.0).sqrt();
+        } else if rh > 85.0 && (80.0..=87.0).contains(&t) {
+            index += (rh - 85.0) / 10.0 * ((87.0 - t) / 5.0);
+        }
+        index
+    };
+    (fahrenheit - 32.0) * 5.0 / 9.0
+}
+
+/// Pressure reduced to the sea level in hPa, with the temperature gradient of the standard
+/// atmosphere
+pub fn sea_level_pressure(pressure: f64, temperature: f64, altitude: f64) -> f64 {
+    let gradient = 0.0065 * altitude;
+    pressure * (1.0 - gradient / (temperature + gradient + 273.15)).powf(-5.257)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        absolute_humidity, dew_point, heat_index, sea_level_pressure, DerivedChannel,
+        DerivedChannels,
+    };
+    use crate::{config::Config, sensor_data::SensorData};
+    use std::time::UNIX_EPOCH;
+
+    fn assert_close(value: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (value - expected).abs() <= tolerance,
+            "{} instead of {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn reference_values() {
+        // usual tables of the dew point and of the absolute humidity
+        assert_close(dew_point(20.0, 50.0), 9.3, 0.05);
+        assert_close(dew_point(30.0, 80.0), 26.2, 0.05);
+        assert_close(dew_point(0.0, 100.0), 0.0, 0.01);
+        assert_close(absolute_humidity(20.0, 50.0), 8.65, 0.02);
+        assert_close(absolute_humidity(30.0, 100.0), 30.4, 0.1);
+        // NWS heat index chart : 90°F and 70% give 106°F, 100°F and 40% give 109°F
+        assert_close(heat_index(32.222, 70.0), (106.0 - 32.0) * 5.0 / 9.0, 0.3);
+        assert_close(heat_index(37.778, 40.0), (109.0 - 32.0) * 5.0 / 9.0, 0.3);
+        // under 80°F, the simple formula is close to the temperature
+        assert_close(heat_index(20.0, 50.0), 19.4, 0.05);
+        // 1000 hPa at 500 m and 15°C
+        assert_close(sea_level_pressure(1000.0, 15.0, 500.0), 1060.7, 0.1);
+        assert_close(sea_level_pressure(1013.25, 15.0, 0.0), 1013.25, 1e-9);
+    }
+
+    #[test]
+    fn derived_channels_configuration() {
+        let config = Config::parse("[derived]\naltitude = 152\n").unwrap();
+        let derived = DerivedChannels::from_config(&config).unwrap();
+        assert_eq!(derived.channels.len(), 4);
+        assert_eq!(derived.altitude, 152.0);
+
+        let config = Config::parse("[derived]\nchannels = dewPoint, heatIndex\n").unwrap();
+        assert_eq!(
+            DerivedChannels::from_config(&config).unwrap().channels,
+            [DerivedChannel::DewPoint, DerivedChannel::HeatIndex]
+        );
+        let config = Config::parse("[derived]\nchannels = seaLevelPressure\n").unwrap();
+        assert_eq!(
+            DerivedChannels::from_config(&config).err(),
+            Some("line 1: section [derived]: seaLevelPressure needs the altitude".to_string())
+        );
+        let config = Config::parse("[derived]\nchannels = windChill\n").unwrap();
+        assert_eq!(
+            DerivedChannels::from_config(&config).err(),
+            Some("line 1: section [derived]: unknown derived channel windChill".to_string())
+        );
+        assert!(DerivedChannels::from_config(&Config::default())
+            .unwrap()
+            .channels
+            .is_empty());
+    }
+
+    #[test]
+    fn expression_channels() {
+        let config = Config::parse(
+            "[derived]\nchannels = dewPoint\n\
+             [channel delta_t]\nexpression = bmp280Temp - htu21Temp\nunit = °C\n\
+             [channel spread]\nexpression = htu21Temp - dewPoint + delta_t\n",
+        )
+        .unwrap();
+        let derived = DerivedChannels::from_config(&config).unwrap();
+        assert_eq!(
+            derived.names_and_units(),
+            [("dewPoint", "°C"), ("delta_t", "°C"), ("spread", "")]
+        );
+        let data = SensorData::new(UNIX_EPOCH, 101.3, 21_500, 20_000, 50_000);
+        let values = derived.values(&data);
+        assert_eq!(values[1], ("delta_t", 1.5));
+        assert_close(values[2].1, 20.0 - values[0].1 + 1.5, 1e-9);
+
+        let error = |text: &str| DerivedChannels::from_config(&Config::parse(text).unwrap()).err();
+        assert_eq!(
+            error("[channel hum_cal]\nexpression = humidity * 1.02 - offset\n"),
+            Some(
+                "line 1: section [channel hum_cal]: expression `humidity * 1.02 - offset`: \
+                 column 19: unknown channel offset"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error("[channel wet]\nexpression = humidity > 80\n"),
+            Some(
+                "line 1: section [channel wet]: expression `humidity > 80`: \
+                 the value is a boolean, not a number"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error("[channel humidity]\nexpression = humidity * 2\n"),
+            Some(
+                "line 1: section [channel humidity]: humidity is a field of the samples"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error("[channel timestamp]\nexpression = 1\n"),
+            Some(
+                "line 1: section [channel timestamp]: timestamp is a field of the samples"
+                    .to_string()
+            )
+        );
+        assert!(error("[channel calibrated]\nexpression = 1\n").is_some());
+        assert_eq!(
+            error("[derived]\nchannels = dewPoint\n[channel dewPoint]\nexpression = 1\n"),
+            Some(
+                "line 3: section [channel dewPoint]: channel dewPoint is already defined"
+                    .to_string()
+            )
+        );
+        for name in ["dew\"point", "back\\slash", "2nd", "a-b", "été"] {
+            assert!(
+                error(&format!("[channel {}]\nexpression = 1\n", name))
+                    .unwrap()
+                    .contains("invalid channel name"),
+                "{}",
+                name
+            );
+        }
+        assert!(error("[channel _t2]\nexpression = 1\n").is_none());
+        // a channel can only use the channels defined before it
+        assert_eq!(
+            error("[channel a]\nexpression = b\n[channel b]\nexpression = 1\n"),
+            Some(
+                "line 1: section [channel a]: expression `b`: column 1: unknown channel b"
+                    .to_string()
+            )
+        );
+    }
+}
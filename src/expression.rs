@@ -0,0 +1,609 @@
+use std::fmt;
+
+/// Expression of a user-defined channel, computed from the other channels of a sample:
+/// ```text
+/// [channel delta_t]
+/// expression = bmp280Temp - htu21Temp
+/// unit = °C
+///
+/// [channel hum_cal]
+/// expression = min(humidity * 1.02 - 0.5, 100)
+/// ```
+/// The expressions are made of numbers, channel names, `+ - * / ^`, the comparisons
+/// `< <= > >= == !=`, `&& || !` and the functions `abs`, `sqrt`, `ln`, `exp`, `min`, `max`
+/// and `if(condition, then, else)`. They are type checked when the configuration is read, so
+/// that their evaluation cannot fail: a missing channel only gives NaN.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expression {
+    /// Column of the node in the text of the expression, starting at 1
+    column: usize,
+    node: Node,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Node {
+    Number(f64),
+    Channel(String),
+    Negate(Box<Expression>),
+    Not(Box<Expression>),
+    Binary(Operator, Box<Expression>, Box<Expression>),
+    Call(Function, Vec<Expression>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Power,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+    And,
+    Or,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Function {
+    Abs,
+    Sqrt,
+    Ln,
+    Exp,
+    Min,
+    Max,
+    If,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Type {
+    Number,
+    Boolean,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(f64),
+    Identifier(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 18] = [
+    "<=", ">=", "==", "!=", "&&", "||", "+", "-", "*", "/", "^", "<", ">", "!", "(", ")", ",", "=",
+];
+
+impl Operator {
+    fn symbol(&self) -> &'static str {
+        match self {
+            Operator::Add => "+",
+            Operator::Subtract => "-",
+            Operator::Multiply => "*",
+            Operator::Divide => "/",
+            Operator::Power => "^",
+            Operator::Less => "<",
+            Operator::LessOrEqual => "<=",
+            Operator::Greater => ">",
+            Operator::GreaterOrEqual => ">=",
+            Operator::Equal => "==",
+            Operator::NotEqual => "!=",
+            Operator::And => "&&",
+            Operator::Or => "||",
+        }
+    }
+
+    /// Types of the operands and of the result
+    fn signature(&self) -> (Type, Type) {
+        match self {
+            Operator::Add
+            | Operator::Subtract
+            | Operator::Multiply
+            | Operator::Divide
+            | Operator::Power => (Type::Number, Type::Number),
+            Operator::And | Operator::Or => (Type::Boolean, Type::Boolean),
+            _ => (Type::Number, Type::Boolean),
+        }
+    }
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Function> {
+        match name {
+            "abs" => Some(Function::Abs),
+            "sqrt" => Some(Function::Sqrt),
+            "ln" => Some(Function::Ln),
+            "exp" => Some(Function::Exp),
+            "min" => Some(Function::Min),
+            "max" => Some(Function::Max),
+            "if" => Some(Function::If),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Function::Abs => "abs",
+            Function::Sqrt => "sqrt",
+            Function::Ln => "ln",
+            Function::Exp => "exp",
+            Function::Min => "min",
+            Function::Max => "max",
+            Function::If => "if",
+        }
+    }
+
+    /// Types of the arguments and of the result
+    fn signature(&self) -> (&'static [Type], Type) {
+        match self {
+            Function::Abs | Function::Sqrt | Function::Ln | Function::Exp => {
+                (&[Type::Number], Type::Number)
+            }
+            Function::Min | Function::Max => (&[Type::Number, Type::Number], Type::Number),
+            Function::If => (&[Type::Boolean, Type::Number, Type::Number], Type::Number),
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Type::Number => write!(f, "number"),
+            Type::Boolean => write!(f, "boolean"),
+        }
+    }
+}
+
+impl Expression {
+    /// Parse an expression, the errors give the column of the faulty part
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser {
+            tokens: &tokens,
+            position: 0,
+            end: text.chars().count() + 1,
+        };
+        let expression = parser.parse_or()?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(expression),
+            Some((token, column)) => Err(format!("column {}: unexpected {}", column, token)),
+        }
+    }
+
+    /// Type of the expression, when its channels are all known
+    pub fn check(&self, is_channel: &dyn Fn(&str) -> bool) -> Result<Type, String> {
+        let error = |message: String| Err(format!("column {}: {}", self.column, message));
+        match &self.node {
+            Node::Number(_) => Ok(Type::Number),
+            Node::Channel(name) if is_channel(name) => Ok(Type::Number),
+            Node::Channel(name) => error(format!("unknown channel {}", name)),
+            Node::Negate(operand) => match operand.check(is_channel)? {
+                Type::Number => Ok(Type::Number),
+                found => error(format!("- needs a number, not a {}", found)),
+            },
+            Node::Not(operand) => match operand.check(is_channel)? {
+                Type::Boolean => Ok(Type::Boolean),
+                found => error(format!("! needs a boolean, not a {}", found)),
+            },
+            Node::Binary(operator, left, right) => {
+                let (operands, result) = operator.signature();
+                for operand in [left, right] {
+                    let found = operand.check(is_channel)?;
+                    if found != operands {
+                        return error(format!(
+                            "{} needs {}s, not a {}",
+                            operator.symbol(),
+                            operands,
+                            found
+                        ));
+                    }
+                }
+                Ok(result)
+            }
+            Node::Call(function, arguments) => {
+                let (parameters, result) = function.signature();
+                if arguments.len() != parameters.len() {
+                    return error(format!(
+                        "{} takes {} argument{}, not {}",
+                        function.name(),
+                        parameters.len(),
+                        if parameters.len() > 1 { "s" } else { "" },
+                        arguments.len()
+                    ));
+                }
+                for (index, (argument, parameter)) in
+                    arguments.iter().zip(parameters.iter()).enumerate()
+                {
+                    let found = argument.check(is_channel)?;
+                    if found != *parameter {
+                        return error(format!(
+                            "argument {} of {} must be a {}, not a {}",
+                            index + 1,
+                            function.name(),
+                            parameter,
+                            found
+                        ));
+                    }
+                }
+                Ok(result)
+            }
+        }
+    }
+
+    /// Value of a checked expression, the booleans are 1 or 0
+    pub fn evaluate(&self, channel: &dyn Fn(&str) -> Option<f64>) -> f64 {
+        let boolean = |value: bool| if value { 1.0 } else { 0.0 };
+        match &self.node {
+            Node::Number(value) => *value,
+            Node::Channel(name) => channel(name).unwrap_or(f64::NAN),
+            Node::Negate(operand) => -operand.evaluate(channel),
+            Node::Not(operand) => boolean(operand.evaluate(channel) == 0.0),
+            Node::Binary(operator, left, right) => {
+                let left = left.evaluate(channel);
+                let right = right.evaluate(channel);
+                match operator {
+                    Operator::Add => left + right,
+                    Operator::Subtract => left - right,
+                    Operator::Multiply => left * right,
+                    Operator::Divide => left / right,
+                    Operator::Power => left.powf(right),
+                    Operator::Less => boolean(left < right),
+                    Operator::LessOrEqual => boolean(left <= right),
+                    Operator::Greater => boolean(left > right),
+                    Operator::GreaterOrEqual => boolean(left >= right),
+                    Operator::Equal => boolean(left == right),
+                    Operator::NotEqual => boolean(left != right),
+                    Operator::And => boolean(left != 0.0 && right != 0.0),
+                    Operator::Or => boolean(left != 0.0 || right != 0.0),
+                }
+            }
+            Node::Call(function, arguments) => {
+                let values: Vec<f64> = arguments
+                    .iter()
+                    .map(|argument| argument.evaluate(channel))
+                    .collect();
+                match function {
+                    Function::Abs => values[0].abs(),
+                    Function::Sqrt => values[0].sqrt(),
+                    Function::Ln => values[0].ln(),
+                    Function::Exp => values[0].exp(),
+                    Function::Min => values[0].min(values[1]),
+                    Function::Max => values[0].max(values[1]),
+                    Function::If if values[0] != 0.0 => values[1],
+                    Function::If => values[2],
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Number(value) => write!(f, "number {}", value),
+            Token::Identifier(name) => write!(f, "{}", name),
+            Token::Symbol(symbol) => write!(f, "'{}'", symbol),
+        }
+    }
+}
+
+/// Tokens of the text with their column
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let column = index + 1;
+        let c = chars[index];
+        if c.is_whitespace() {
+            index += 1;
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = index;
+            while index < chars.len()
+                && (chars[index].is_ascii_digit()
+                    || chars[index] == '.'
+                    || chars[index] == 'e'
+                    || chars[index] == 'E'
+                    || ((chars[index] == '-' || chars[index] == '+')
+                        && (chars[index - 1] == 'e' || chars[index - 1] == 'E')))
+            {
+                index += 1;
+            }
+            let number: String = chars[start..index].iter().collect();
+            match number.parse() {
+                Ok(value) => tokens.push((Token::Number(value), column)),
+                Err(_) => return Err(format!("column {}: invalid number {}", column, number)),
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let start = index;
+            while index < chars.len() && (chars[index].is_alphanumeric() || chars[index] == '_') {
+                index += 1;
+            }
+            tokens.push((
+                Token::Identifier(chars[start..index].iter().collect()),
+                column,
+            ));
+        } else {
+            let rest: String = chars[index..chars.len().min(index + 2)].iter().collect();
+            match SYMBOLS.iter().find(|symbol| rest.starts_with(*symbol)) {
+                // a single '=' is a common typo of '=='
+                Some(&"=") => return Err(format!("column {}: '=' instead of '=='", column)),
+                Some(symbol) => {
+                    tokens.push((Token::Symbol(symbol), column));
+                    index += symbol.len();
+                }
+                None => return Err(format!("column {}: unexpected character {}", column, c)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// Recursive descent parser, one method per level of precedence
+struct Parser<'a> {
+    tokens: &'a [(Token, usize)],
+    position: usize,
+    /// Column after the end of the text, for the errors at the end
+    end: usize,
+}
+
+impl Parser<'_> {
+    fn column(&self) -> usize {
+        self.tokens
+            .get(self.position)
+            .map_or(self.end, |(_, column)| *column)
+    }
+
+    /// Consume the next token when it is one of the symbols
+    fn symbol(&mut self, symbols: &[&'static str]) -> Option<&'static str> {
+        match self.tokens.get(self.position) {
+            Some((Token::Symbol(symbol), _)) if symbols.contains(symbol) => {
+                self.position += 1;
+                Some(symbol)
+            }
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), String> {
+        match self.symbol(&[symbol]) {
+            Some(_) => Ok(()),
+            None => Err(format!("column {}: '{}' expected", self.column(), symbol)),
+        }
+    }
+
+    /// Left associative binary operators of the same precedence
+    fn parse_binary(
+        &mut self,
+        operators: &[(&'static str, Operator)],
+        operand: fn(&mut Self) -> Result<Expression, String>,
+    ) -> Result<Expression, String> {
+        let mut left = operand(self)?;
+        let symbols: Vec<&'static str> = operators.iter().map(|(symbol, _)| *symbol).collect();
+        loop {
+            let column = self.column();
+            let symbol = match self.symbol(&symbols) {
+                Some(symbol) => symbol,
+                None => return Ok(left),
+            };
+            let operator = operators.iter().find(|(s, _)| *s == symbol).unwrap().1;
+            let right = operand(self)?;
+            left = Expression {
+                column,
+                node: Node::Binary(operator, Box::new(left), Box::new(right)),
+            };
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Expression, String> {
+        self.parse_binary(&[("||", Operator::Or)], Parser::parse_and)
+    }
+
+    fn parse_and(&mut self) -> Result<Expression, String> {
+        self.parse_binary(&[("&&", Operator::And)], Parser::parse_comparison)
+    }
+
+    fn parse_comparison(&mut self) -> Result<Expression, String> {
+        self.parse_binary(
+            &[
+                ("<", Operator::Less),
+                ("<=", Operator::LessOrEqual),
+                (">", Operator::Greater),
+                (">=", Operator::GreaterOrEqual),
+                ("==", Operator::Equal),
+                ("!=", Operator::NotEqual),
+            ],
+            Parser::parse_sum,
+        )
+    }
+
+    fn parse_sum(&mut self) -> Result<Expression, String> {
+        self.parse_binary(
+            &[("+", Operator::Add), ("-", Operator::Subtract)],
+            Parser::parse_product,
+        )
+    }
+
+    fn parse_product(&mut self) -> Result<Expression, String> {
+        self.parse_binary(
+            &[("*", Operator::Multiply), ("/", Operator::Divide)],
+            Parser::parse_unary,
+        )
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, String> {
+        let column = self.column();
+        match self.symbol(&["-", "!"]) {
+            Some("-") => Ok(Expression {
+                column,
+                node: Node::Negate(Box::new(self.parse_unary()?)),
+            }),
+            Some(_) => Ok(Expression {
+                column,
+                node: Node::Not(Box::new(self.parse_unary()?)),
+            }),
+            None => self.parse_power(),
+        }
+    }
+
+    /// `^` is right associative and binds tighter than the unary minus on its left
+    fn parse_power(&mut self) -> Result<Expression, String> {
+        let base = self.parse_primary()?;
+        let column = self.column();
+        match self.symbol(&["^"]) {
+            Some(_) => Ok(Expression {
+                column,
+                node: Node::Binary(
+                    Operator::Power,
+                    Box::new(base),
+                    Box::new(self.parse_unary()?),
+                ),
+            }),
+            None => Ok(base),
+        }
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, String> {
+        let column = self.column();
+        let token = match self.tokens.get(self.position) {
+            Some((token, _)) => token,
+            None => {
+                return Err(format!(
+                    "column {}: unexpected end of the expression",
+                    column
+                ))
+            }
+        };
+        self.position += 1;
+        match token {
+            Token::Number(value) => Ok(Expression {
+                column,
+                node: Node::Number(*value),
+            }),
+            Token::Identifier(name) => {
+                if self.symbol(&["("]).is_none() {
+                    return Ok(Expression {
+                        column,
+                        node: Node::Channel(name.clone()),
+                    });
+                }
+                let function = Function::from_name(name)
+                    .ok_or_else(|| format!("column {}: unknown function {}", column, name))?;
+                let mut arguments = Vec::new();
+                if self.symbol(&[")"]).is_none() {
+                    loop {
+                        arguments.push(self.parse_or()?);
+                        if self.symbol(&[","]).is_none() {
+                            self.expect(")")?;
+                            break;
+                        }
+                    }
+                }
+                Ok(Expression {
+                    column,
+                    node: Node::Call(function, arguments),
+                })
+            }
+            Token::Symbol("(") => {
+                let expression = self.parse_or()?;
+                self.expect(")")?;
+                Ok(expression)
+            }
+            Token::Symbol(_) => Err(format!("column {}: unexpected {}", column, token)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Expression, Type};
+
+    fn channel(name: &str) -> Option<f64> {
+        match name {
+            "bmp280Temp" => Some(21.5),
+            "htu21Temp" => Some(20.0),
+            "humidity" => Some(50.0),
+            _ => None,
+        }
+    }
+
+    fn is_channel(name: &str) -> bool {
+        channel(name).is_some()
+    }
+
+    fn evaluate(text: &str) -> f64 {
+        let expression = Expression::parse(text).unwrap();
+        assert_eq!(expression.check(&is_channel), Ok(Type::Number), "{}", text);
+        expression.evaluate(&channel)
+    }
+
+    fn error(text: &str) -> String {
+        Expression::parse(text)
+            .and_then(|expression| expression.check(&is_channel))
+            .unwrap_err()
+    }
+
+    #[test]
+    fn evaluation() {
+        assert_eq!(evaluate("bmp280Temp - htu21Temp"), 1.5);
+        assert_eq!(evaluate("humidity * 1.02 - 0.5"), 50.5);
+        assert_eq!(evaluate("1 + 2 * 3 - 4 / 2"), 5.0);
+        assert_eq!(evaluate("(1 + 2) * 3"), 9.0);
+        assert_eq!(evaluate("-2 ^ 2"), -4.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(evaluate("10 - 4 - 3"), 3.0);
+        assert_eq!(evaluate("min(humidity, 40) + max(1, abs(-2))"), 42.0);
+        assert_eq!(evaluate("sqrt(16) + ln(exp(2))"), 6.0);
+        assert_eq!(evaluate("1.5e2"), 150.0);
+        assert_eq!(
+            evaluate("if(humidity > 40 && !(htu21Temp >= 25), 1, 2)"),
+            1.0
+        );
+        assert_eq!(evaluate("if(humidity == 50 || 1 != 1, 3, 4)"), 3.0);
+        assert_eq!(
+            Expression::parse("humidity <= 10")
+                .unwrap()
+                .check(&is_channel),
+            Ok(Type::Boolean)
+        );
+        assert!(Expression::parse("pressure + 1")
+            .unwrap()
+            .evaluate(&channel)
+            .is_nan());
+    }
+
+    #[test]
+    fn errors_point_at_the_expression() {
+        assert_eq!(
+            error("bmp280Temp - temperature"),
+            "column 14: unknown channel temperature"
+        );
+        assert_eq!(
+            error("humidity * "),
+            "column 12: unexpected end of the expression"
+        );
+        assert_eq!(error("(humidity + 1"), "column 14: ')' expected");
+        assert_eq!(error("humidity 2"), "column 10: unexpected number 2");
+        assert_eq!(error("humidity = 2"), "column 10: '=' instead of '=='");
+        assert_eq!(error("humidity $ 2"), "column 10: unexpected character $");
+        assert_eq!(error("round(humidity)"), "column 1: unknown function round");
+        assert_eq!(
+            error("min(humidity)"),
+            "column 1: min takes 2 arguments, not 1"
+        );
+        assert_eq!(
+            error("humidity + (humidity > 2)"),
+            "column 10: + needs numbers, not a boolean"
+        );
+        assert_eq!(
+            error("if(humidity, 1, 2)"),
+            "column 1: argument 1 of if must be a boolean, not a number"
+        );
+        assert_eq!(
+            error("!humidity"),
+            "column 1: ! needs a boolean, not a number"
+        );
+        assert_eq!(error("1..2"), "column 1: invalid number 1..2");
+    }
+}
@@ -0,0 +1,411 @@
+use crate::{
+    config::Config,
+    metrics,
+    oversampling::{median, Combination, Reads},
+    sensor_data::{SensorData, CHANNELS},
+};
+use std::{collections::VecDeque, io};
+
+// Scale of the median absolute deviation which estimates the standard deviation of a normal
+// distribution
+const MAD_SCALE: f64 = 1.4826;
+
+/// Filter of the raw reads of the sensors, read from the `[filter]` section of the
+/// configuration:
+/// ```text
+/// [filter]
+/// pressure = 800..1100         # plausible values of a channel
+/// humidity = 0.1..100
+/// hampel = 15                  # window of the spike detection, in samples
+/// hampel_threshold = 3         # largest distance to the median of the window, in MADs
+/// ```
+/// The implausible reads are ignored before the reads of a channel are combined, and a sample
+/// is rejected when none of its reads is plausible for a channel. A sample is also rejected
+/// as a spike when a channel is farther from the median of the previous values than
+/// `hampel_threshold` times their scaled median absolute deviation. The rejected samples
+/// never reach the historics.
+///
+/// The former `median = 3` key is deprecated, it is the same as `reads = 3` and
+/// `combine = median` in the `[oversampling]` section.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SampleFilter {
+    /// Plausible range of each channel, in the order of `CHANNELS`
+    ranges: [Option<(f64, f64)>; 4],
+    hampel: Option<Hampel>,
+    accepted: u64,
+    rejected: u64,
+    // rejections of each channel, a sample can be rejected for several channels
+    missing: [u64; 4],
+    implausible: [u64; 4],
+    spikes: [u64; 4],
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Hampel {
+    window: usize,
+    threshold: f64,
+    /// Last values of each channel, the spikes included so that a lasting step is accepted
+    /// once it fills half of the window
+    values: [VecDeque<f64>; 4],
+}
+
+impl SampleFilter {
+    pub fn from_config(config: &Config) -> Result<SampleFilter, String> {
+        let section = match config.section("filter")? {
+            Some(section) => section,
+            None => return Ok(SampleFilter::default()),
+        };
+        let mut keys = CHANNELS.to_vec();
+        // median is the deprecated oversampling of the reads, see `Oversampler`
+        keys.extend(["median", "hampel", "hampel_threshold"]);
+        section.check_keys(&keys)?;
+        let mut filter = SampleFilter::default();
+        for (channel, range) in CHANNELS.iter().zip(filter.ranges.iter_mut()) {
+            if let Some(text) = section.get_str(channel) {
+                let invalid =
+                    || section.error(&format!("invalid range {}, expected min..max", text));
+                let (min, max) = text.split_once("..").ok_or_else(invalid)?;
+                let min: f64 = min.trim().parse().map_err(|_| invalid())?;
+                let max: f64 = max.trim().parse().map_err(|_| invalid())?;
+                if min >= max {
+                    return Err(invalid());
+                }
+                *range = Some((min, max));
+            }
+        }
+        let threshold = section.get("hampel_threshold")?;
+        filter.hampel = match section.get::<usize>("hampel")? {
+            Some(window) if window < 3 => {
+                return Err(section.error("the hampel window needs at least 3 samples"))
+            }
+            Some(window) => Some(Hampel {
+                window,
+                threshold: threshold.unwrap_or(3.0),
+                values: Default::default(),
+            }),
+            None if threshold.is_some() => {
+                return Err(section.error("hampel_threshold needs a hampel window"))
+            }
+            None => None,
+        };
+        Ok(filter)
+    }
+
+    /// Sample made of the reads of the sensors, or the reason of its rejection
+    pub fn filter(
+        &mut self,
+        reads: &Reads,
+        combination: Combination,
+    ) -> Result<SensorData, String> {
+        let filtered = self.check(reads, combination);
+        match filtered {
+            Ok(_) => self.accepted += 1,
+            Err(_) => self.rejected += 1,
+        }
+        filtered
+    }
+
+    fn check(&mut self, reads: &Reads, combination: Combination) -> Result<SensorData, String> {
+        if let Some(index) = reads.iter().position(Vec::is_empty) {
+            self.missing[index] += 1;
+            return Err(format!("no read of {}", CHANNELS[index]));
+        }
+        // the sample is timestamped by the first read
+        let mut sample = reads[0][0];
+        let mut values = [0.0; 4];
+        for (index, channel) in CHANNELS.iter().enumerate() {
+            let plausible: Vec<SensorData> = reads[index]
+                .iter()
+                .filter(
+                    |read| match (self.ranges[index], read.get_channel(channel)) {
+                        (Some((min, max)), Some(value)) => (min..=max).contains(&value),
+                        _ => true,
+                    },
+                )
+                .copied()
+                .collect();
+            if plausible.is_empty() {
+                self.implausible[index] += 1;
+                let last = reads[index]
+                    .last()
+                    .and_then(|read| read.get_channel(channel));
+                return Err(format!(
+                    "{} = {:.3} is implausible",
+                    channel,
+                    last.unwrap_or(f64::NAN)
+                ));
+            }
+            values[index] = combination.combine(&plausible, index);
+        }
+        if let Some(hampel) = &mut self.hampel {
+            let mut spikes = Vec::new();
+            for (index, value) in values.iter().enumerate() {
+                let window = &mut hampel.values[index];
+                if window.len() >= 3 {
+                    let mut previous: Vec<f64> = window.iter().copied().collect();
+                    let center = median(&mut previous);
+                    let mut deviations: Vec<f64> = previous
+                        .iter()
+                        .map(|value| (value - center).abs())
+                        .collect();
+                    let deviation = MAD_SCALE * median(&mut deviations);
+                    // without any deviation in the window, every change would be a spike
+                    if deviation > 0.0 && (value - center).abs() > hampel.threshold * deviation {
+                        self.spikes[index] += 1;
+                        spikes.push(format!(
+                            "{} = {:.3} is a spike, the median is {:.3}",
+                            CHANNELS[index], value, center
+                        ));
+                    }
+                }
+                if window.len() == hampel.window {
+                    window.pop_front();
+                }
+                window.push_back(*value);
+            }
+            if !spikes.is_empty() {
+                return Err(spikes.join(", "));
+            }
+        }
+        for (channel, value) in CHANNELS.iter().zip(values.iter()) {
+            sample.set_channel(channel, *value);
+        }
+        Ok(sample)
+    }
+
+    /// Counters of the accepted and rejected samples, and of the rejections of each channel
+    pub fn write_json(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        w.write_fmt(format_args!(
+            "{{\"accepted\": {}, \"rejected\": {}, \"channels\": [",
+            self.accepted, self.rejected
+        ))?;
+        for (index, channel) in CHANNELS.iter().enumerate() {
+            if index > 0 {
+                w.write_all(b",\n")?;
+            }
+            w.write_fmt(format_args!(
+                "{{\"channel\": \"{}\", \"missing\": {}, \"implausible\": {}, \"spikes\": {}}}",
+                channel, self.missing[index], self.implausible[index], self.spikes[index]
+            ))?;
+        }
+        w.write_all(b"]}\n")
+    }
+
+    pub fn write_metrics(&self, w: &mut dyn io::Write) -> io::Result<()> {
+        let labels: Vec<(String, f64)> = CHANNELS
+            .iter()
+            .enumerate()
+            .flat_map(|(index, channel)| {
+                [
+                    (
+                        format!("channel=\"{}\",reason=\"missing\"", channel),
+                        self.missing[index] as f64,
+                    ),
+                    (
+                        format!("channel=\"{}\",reason=\"implausible\"", channel),
+                        self.implausible[index] as f64,
+                    ),
+                    (
+                        format!("channel=\"{}\",reason=\"spike\"", channel),
+                        self.spikes[index] as f64,
+                    ),
+                ]
+            })
+            .collect();
+        let rejections: Vec<(&str, f64)> = labels
+            .iter()
+            .map(|(labels, value)| (labels.as_str(), *value))
+            .collect();
+        metrics::write_metric(
+            w,
+            "samples_accepted_total",
+            "counter",
+            "Samples accepted by the filter",
+            &[("", self.accepted as f64)],
+        )?;
+        metrics::write_metric(
+            w,
+            "samples_rejected_total",
+            "counter",
+            "Samples rejected by the filter",
+            &[("", self.rejected as f64)],
+        )?;
+        metrics::write_metric(
+            w,
+            "channel_rejections_total",
+            "counter",
+            "Rejections of the channels by the filter, a sample can be rejected for several channels",
+            &rejections,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SampleFilter;
+    use crate::{
+        config::Config,
+        oversampling::{Combination, Reads},
+        sensor_data::SensorData,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Read at `seconds` after the epoch, pressure in hPa and humidity in %
+    fn read(seconds: u64, pressure: f32, humidity: f32) -> SensorData {
+        SensorData::new(
+            UNIX_EPOCH + Duration::from_secs(seconds),
+            pressure / 10.0,
+            20_000,
+            20_000,
+            (humidity * 1000.0) as i32,
+        )
+    }
+
+    /// The same reads for all the channels
+    fn reads(reads: &[SensorData]) -> Reads {
+        [
+            reads.to_vec(),
+            reads.to_vec(),
+            reads.to_vec(),
+            reads.to_vec(),
+        ]
+    }
+
+    fn filter(config: &str) -> SampleFilter {
+        SampleFilter::from_config(&Config::parse(config).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn implausible_reads() {
+        let mut filter = filter("[filter]\npressure = 800..1100\nhumidity = 0.1..100\n");
+        // the glitch is ignored, the other reads are combined
+        let glitch = reads(&[
+            read(1, 1000.0, 50.0),
+            read(1, 0.0, 0.0),
+            read(1, 1002.0, 52.0),
+            read(1, 1003.0, 56.0),
+        ]);
+        let sample = filter.filter(&glitch, Combination::Median).unwrap();
+        assert!((sample.get_channel("pressure").unwrap() - 1002.0).abs() < 0.01);
+        assert_eq!(sample.get_channel("humidity"), Some(52.0));
+        let sample = filter.filter(&glitch, Combination::Mean).unwrap();
+        assert!((sample.get_channel("pressure").unwrap() - 1001.667).abs() < 0.01);
+        assert_eq!(sample.get_channel("humidity"), Some(52.666));
+        assert_eq!(
+            filter
+                .filter(&reads(&[read(2, 1000.0, 0.0)]), Combination::Mean)
+                .err(),
+            Some("humidity = 0.000 is implausible".to_string())
+        );
+        let mut failed_read = reads(&[read(2, 1000.0, 50.0)]);
+        failed_read[3].clear();
+        assert_eq!(
+            filter.filter(&failed_read, Combination::Mean).err(),
+            Some("no read of humidity".to_string())
+        );
+        let mut json = Vec::new();
+        filter.write_json(&mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"accepted\": 2, \"rejected\": 2, \"channels\": [\
+             {\"channel\": \"pressure\", \"missing\": 0, \"implausible\": 0, \"spikes\": 0},\n\
+             {\"channel\": \"bmp280Temp\", \"missing\": 0, \"implausible\": 0, \"spikes\": 0},\n\
+             {\"channel\": \"htu21Temp\", \"missing\": 0, \"implausible\": 0, \"spikes\": 0},\n\
+             {\"channel\": \"humidity\", \"missing\": 1, \"implausible\": 1, \"spikes\": 0}]}\n"
+        );
+    }
+
+    #[test]
+    fn spikes() {
+        let mut filter = filter("[filter]\nhampel = 5\n");
+        for (seconds, humidity) in [50.0, 50.2, 49.9, 50.1, 50.0].iter().enumerate() {
+            let sample = reads(&[read(seconds as u64, 1000.0, *humidity)]);
+            assert!(filter.filter(&sample, Combination::Mean).is_ok());
+        }
+        assert_eq!(
+            filter
+                .filter(&reads(&[read(5, 1000.0, 20.0)]), Combination::Mean)
+                .err(),
+            Some("humidity = 20.000 is a spike, the median is 50.000".to_string())
+        );
+        assert!(filter
+            .filter(&reads(&[read(6, 1000.0, 50.3)]), Combination::Mean)
+            .is_ok());
+        // a lasting step is accepted once it fills half of the window
+        let accepted: Vec<bool> = (7..11)
+            .map(|seconds| {
+                let sample = reads(&[read(seconds, 1000.0, 70.0)]);
+                filter.filter(&sample, Combination::Mean).is_ok()
+            })
+            .collect();
+        assert_eq!(accepted, [false, false, true, true]);
+        assert_eq!(filter.spikes, [0, 0, 0, 3]);
+    }
+
+    #[test]
+    fn every_spiking_channel_is_reported() {
+        let mut filter = filter(
+            "[filter]
+hampel = 5
+",
+        );
+        for (seconds, value) in [0.0, 0.2, -0.1, 0.1, 0.0].iter().enumerate() {
+            let sample = reads(&[read(seconds as u64, 1000.0 + value, 50.0 + value)]);
+            assert!(filter.filter(&sample, Combination::Mean).is_ok());
+        }
+        assert_eq!(
+            filter
+                .filter(&reads(&[read(5, 900.0, 20.0)]), Combination::Mean)
+                .err(),
+            Some(
+                "pressure = 900.000 is a spike, the median is 1000.000, \
+                 humidity = 20.000 is a spike, the median is 50.000"
+                    .to_string()
+            )
+        );
+        assert_eq!(filter.spikes, [1, 0, 0, 1]);
+        // one sample, rejected for two channels
+        assert_eq!((filter.accepted, filter.rejected), (5, 1));
+        let mut metrics = Vec::new();
+        filter.write_metrics(&mut metrics).unwrap();
+        let metrics = String::from_utf8(metrics).unwrap();
+        assert!(
+            metrics.contains("samples_rejected_total 1\n"),
+            "{}",
+            metrics
+        );
+        assert!(
+            metrics.contains("channel_rejections_total{channel=\"pressure\",reason=\"spike\"} 1\n")
+        );
+    }
+
+    #[test]
+    fn invalid_filters() {
+        let error = |text: &str| SampleFilter::from_config(&Config::parse(text).unwrap()).err();
+        assert_eq!(
+            error("[filter]\npressure = 1100..800\n"),
+            Some(
+                "line 1: section [filter]: invalid range 1100..800, expected min..max".to_string()
+            )
+        );
+        assert_eq!(
+            error("[filter]\nhampel = 2\n"),
+            Some(
+                "line 1: section [filter]: the hampel window needs at least 3 samples".to_string()
+            )
+        );
+        assert_eq!(
+            error("[filter]\nhampel_threshold = 2\n"),
+            Some("line 1: section [filter]: hampel_threshold needs a hampel window".to_string())
+        );
+        assert_eq!(
+            error("[filter]\nmedian = 3\n"),
+            None,
+            "the deprecated median is read by the oversampler"
+        );
+        assert_eq!(
+            SampleFilter::from_config(&Config::default()),
+            Ok(SampleFilter::default())
+        );
+    }
+}
@@ -1,83 +1,369 @@
-use crate::{average::Average, circular_buffer::CircularBuffer, json_display::JsonDisplay};
-use std::{fmt::Display, io};
+use crate::{
+    average::Average,
+    circular_buffer::CircularBuffer,
+    json_display::{self, JsonDisplay},
+    mapped_circular_buffer::{FixedSizeRecord, MappedCircularBuffer},
+    shared_circular_buffer::{SharedCircularBuffer, SharedCircularBufferReader},
+};
+use std::{io, path::Path, time::Duration};
+
+/// Names of the historic tiers, from the most detailed to the most reduced
+pub const TIER_NAMES: [&str; 3] = ["minute", "hour", "days"];
+/// Size and reduction limit of the historic tiers kept in memory
+pub const TIER_SIZES: [(usize, usize); 3] = [(32, 24), (128, 120), (9192, 9192)];
 
 pub struct Historic<T> {
-    circular_buffer: CircularBuffer<T>,
+    storage: HistoricStorage<T>,
     limit: usize,
 }
 
-impl<T: JsonDisplay + Display> Historic<T> {
+/// A historic is either only accessible by its owner, or also readable from other threads,
+/// or stored in a file
+enum HistoricStorage<T> {
+    Local(CircularBuffer<T>),
+    Shared(SharedCircularBuffer<T>),
+    Mapped(MappedCircularBuffer<T>),
+}
+
+impl<T> Historic<T> {
     pub fn new(size: usize, limit: usize) -> Historic<T> {
         Historic::<T> {
-            circular_buffer: CircularBuffer::<T>::new(size),
+            storage: HistoricStorage::Local(CircularBuffer::<T>::new(size)),
             limit,
         }
     }
 
+    /// Add an item, the oldest one is dropped when the historic is full, which only happens to
+    /// the historics which are never reduced
     pub fn add(&mut self, element: T) {
-        self.circular_buffer.put_item(element);
+        if self.is_full() {
+            self.drain_front(1);
+        }
+        let pushed = match &mut self.storage {
+            HistoricStorage::Local(circular_buffer) => circular_buffer.push_back(element),
+            HistoricStorage::Shared(circular_buffer) => circular_buffer.push_back(element),
+            HistoricStorage::Mapped(circular_buffer) => circular_buffer.push_back(element),
+        };
+        if pushed.is_err() {
+            println!("The historic can't hold any item, the item is lost");
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        match &self.storage {
+            HistoricStorage::Local(circular_buffer) => circular_buffer.is_full(),
+            HistoricStorage::Shared(circular_buffer) => circular_buffer.is_full(),
+            HistoricStorage::Mapped(circular_buffer) => circular_buffer.is_full(),
+        }
     }
 
     pub fn get_nb_items(&self) -> usize {
-        self.circular_buffer.get_nb_items()
+        match &self.storage {
+            HistoricStorage::Local(circular_buffer) => circular_buffer.len(),
+            HistoricStorage::Shared(circular_buffer) => circular_buffer.len(),
+            HistoricStorage::Mapped(circular_buffer) => circular_buffer.len(),
+        }
     }
 
     pub fn is_empty(&self) -> bool {
-        self.circular_buffer.is_empty()
+        self.get_nb_items() == 0
+    }
+
+    /// Remove the `nb_items` oldest items of the historic
+    fn drain_front(&mut self, nb_items: usize) -> Vec<T> {
+        match &mut self.storage {
+            HistoricStorage::Local(circular_buffer) => {
+                let nb_items = nb_items.min(circular_buffer.len());
+                circular_buffer.drain(..nb_items).collect()
+            }
+            HistoricStorage::Shared(circular_buffer) => circular_buffer.drain_front(nb_items),
+            HistoricStorage::Mapped(circular_buffer) => circular_buffer.drain_front(nb_items),
+        }
     }
 }
 
-impl<T: JsonDisplay + Display + Average<T>> Historic<T> {
-    pub fn reduce(historics: &mut [Historic<T>]) {
-        //let mut i = 0;
-        let mut average_data = None;
+impl<T: Clone> Historic<T> {
+    /// Copy of the content, from the oldest item
+    pub fn to_vec(&self) -> Vec<T> {
+        match &self.storage {
+            HistoricStorage::Local(circular_buffer) => circular_buffer.iter().cloned().collect(),
+            HistoricStorage::Shared(circular_buffer) => circular_buffer.iter().cloned().collect(),
+            HistoricStorage::Mapped(circular_buffer) => circular_buffer.iter().collect(),
+        }
+    }
+
+    /// Items between `from` and `to`, taken from the most detailed historic which goes back to
+    /// `from`, or else from the one which goes back the furthest
+    pub fn select_range<F>(
+        historics: &[Historic<T>],
+        from: Duration,
+        to: Duration,
+        timestamp: F,
+    ) -> Vec<T>
+    where
+        F: Fn(&T) -> Duration,
+    {
+        let contents: Vec<Vec<T>> = historics.iter().map(Historic::to_vec).collect();
+        let oldest = |content: &Vec<T>| content.first().map(&timestamp);
+        let selected = contents
+            .iter()
+            .find(|content| matches!(oldest(content), Some(oldest) if oldest <= from))
+            .or_else(|| {
+                contents
+                    .iter()
+                    .filter(|content| !content.is_empty())
+                    .min_by_key(|content| oldest(content))
+            });
+        match selected {
+            Some(content) => content
+                .iter()
+                .filter(|item| (from..=to).contains(&timestamp(item)))
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl<T: Clone> Historic<T> {
+    /// Items between `from` and `to` of all the historics, each one only completing the more
+    /// detailed ones with its items older than theirs, from the oldest item
+    pub fn merge_range<F>(
+        historics: &[Historic<T>],
+        from: Duration,
+        to: Duration,
+        timestamp: F,
+    ) -> Vec<T>
+    where
+        F: Fn(&T) -> Duration,
+    {
+        let mut merged: Vec<T> = Vec::new();
         for historic in historics {
+            let before = merged.first().map_or(to, &timestamp);
+            let mut older: Vec<T> = historic
+                .to_vec()
+                .into_iter()
+                .filter(|item| {
+                    let time = timestamp(item);
+                    from <= time && time <= to && (merged.is_empty() || time < before)
+                })
+                .collect();
+            older.append(&mut merged);
+            merged = older;
+        }
+        merged
+    }
+}
+
+impl<T: Copy> Historic<T> {
+    /// Historic whose content can be read from other threads without blocking its owner
+    pub fn new_shared(size: usize, limit: usize) -> (Historic<T>, SharedCircularBufferReader<T>) {
+        let circular_buffer = SharedCircularBuffer::<T>::new(size);
+        let reader = circular_buffer.reader();
+        (
+            Historic::<T> {
+                storage: HistoricStorage::Shared(circular_buffer),
+                limit,
+            },
+            reader,
+        )
+    }
+}
+
+impl<T: FixedSizeRecord> Historic<T> {
+    /// Historic stored in the file `path`, its content is kept across restarts
+    pub fn new_mapped<P: AsRef<Path>>(
+        path: P,
+        size: usize,
+        limit: usize,
+    ) -> io::Result<Historic<T>> {
+        Ok(Historic::<T> {
+            storage: HistoricStorage::Mapped(MappedCircularBuffer::<T>::open(path, size)?),
+            limit,
+        })
+    }
+}
+
+impl<T: Average<T> + Clone> Historic<T> {
+    /// Return the average data added to the historics, with the index of the historic
+    pub fn reduce(historics: &mut [Historic<T>]) -> Vec<(usize, T)> {
+        let mut reduced = Vec::new();
+        let mut average_data = None;
+        for (index, historic) in historics.iter_mut().enumerate() {
             // look if the previous historic produce an average data to add to the next historic
-            match average_data {
-                Some(data) => {
-                    historic.circular_buffer.put_item(data);
-                }
-                None => {}
+            if let Some(data) = average_data.take() {
+                reduced.push((index, T::clone(&data)));
+                historic.add(data);
             }
-            if historic.circular_buffer.get_nb_items() > historic.limit {
+            if historic.get_nb_items() > historic.limit {
                 let nb_elements_to_sum = historic.limit / 2;
                 //println!("Reduction queue {} nbElementsToSum = {}\n", i, nb_elements_to_sum);
                 // Accumulate on first nb_elements_to_sum element of the historic
+                // and remove them from the historic
                 let mut accumulator_data = T::empty_cumulator();
-                for j in 0..nb_elements_to_sum {
-                    match historic.circular_buffer.peek_item(j) {
-                        Some(data) => {
-                            data.cumulate(&mut accumulator_data);
-                        }
-                        None => panic!("Problem of implementation in historic reduce method"),
-                    }
-                }
-                //remove elements used for accumulation from the historic
-                for _ in 0..nb_elements_to_sum {
-                    historic.circular_buffer.get_item();
+                for data in historic.drain_front(nb_elements_to_sum) {
+                    data.cumulate(&mut accumulator_data);
                 }
                 // The average_data will be add to the next historic (if historic exists, otherwise it will be lost)
                 average_data = Some(T::divide(&accumulator_data, nb_elements_to_sum));
-            //i += 1;
             } else {
                 break;
             }
         }
+        reduced
+    }
+}
+
+impl<T: JsonDisplay> Historic<T> {
+    pub fn write_json_chunk(&self, context: &T::Context, w: &mut dyn io::Write) -> io::Result<()> {
+        match &self.storage {
+            HistoricStorage::Local(circular_buffer) => circular_buffer.write_json_chunk(context, w),
+            HistoricStorage::Shared(circular_buffer) => {
+                json_display::write_json_chunk(circular_buffer.iter(), context, w)
+            }
+            HistoricStorage::Mapped(circular_buffer) => {
+                let items: Vec<T> = circular_buffer.iter().collect();
+                json_display::write_json_chunk(&items, context, w)
+            }
+        }
     }
 
-    pub fn write_json_historics(historics: &[Historic<T>], w: &mut io::Write) {
-        w.write(b"[").unwrap();
+    pub fn write_json_historics(
+        historics: &[Historic<T>],
+        context: &T::Context,
+        w: &mut dyn io::Write,
+    ) -> io::Result<()> {
+        w.write_all(b"[")?;
         let mut first = true;
         for historic in historics {
             if !historic.is_empty() {
                 if !first {
-                    w.write(b",\n").unwrap();
+                    w.write_all(b",\n")?;
                 } else {
                     first = false;
                 }
-                historic.circular_buffer.write_json_chunk(w).unwrap();
+                historic.write_json_chunk(context, w)?;
             }
         }
-        w.write(b"]\n").unwrap();
+        w.write_all(b"]\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Historic;
+    use crate::derived::DerivedChannels;
+    use crate::sensor_data::SensorData;
+    use crate::test_utils::TempPath;
+    use std::{
+        io::{self, Write},
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    /// Stream of a client which reset the connection
+    struct Reset;
+
+    impl Write for Reset {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::ConnectionReset.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn failed_writes_are_returned() {
+        let mut historic = Historic::new(4, 4);
+        historic.add(SensorData::new(UNIX_EPOCH, 1000.0, 0, 0, 0));
+        let historics = [historic];
+        let derived = DerivedChannels::default();
+        let written = Historic::write_json_historics(&historics, &derived, &mut Reset);
+        assert_eq!(
+            written.map_err(|err| err.kind()),
+            Err(io::ErrorKind::ConnectionReset)
+        );
+        let mut json = Vec::new();
+        Historic::write_json_historics(&historics, &derived, &mut json).unwrap();
+        assert!(json.starts_with(b"[{\"timestamp\": 0,"));
+        assert!(json.ends_with(b"}\n]\n"));
+    }
+
+    #[test]
+    fn full_historics_drop_their_oldest_item() {
+        let mut historic = Historic::new(3, 3);
+        for item in 1..=5 {
+            historic.add(item);
+        }
+        assert_eq!(historic.to_vec(), [3, 4, 5]);
+
+        let path = TempPath::new("historic_full");
+        let mut historic = Historic::<SensorData>::new_mapped(path.path(), 2, 2).unwrap();
+        for seconds in 1..=3 {
+            historic.add(SensorData::new(
+                UNIX_EPOCH + Duration::from_secs(seconds),
+                1000.0,
+                0,
+                0,
+                0,
+            ));
+        }
+        let timestamps: Vec<Duration> = historic
+            .to_vec()
+            .iter()
+            .map(SensorData::get_timestamp)
+            .collect();
+        assert_eq!(timestamps, [Duration::from_secs(2), Duration::from_secs(3)]);
+    }
+
+    #[test]
+    fn range_from_the_most_detailed_tier() {
+        let seconds = |seconds: u64| Duration::from_secs(seconds);
+        let mut minute = Historic::new(4, 4);
+        let mut hour = Historic::new(8, 8);
+        for timestamp in [100, 105, 110, 115] {
+            minute.add(seconds(timestamp));
+        }
+        for timestamp in [10, 30, 50, 70, 90] {
+            hour.add(seconds(timestamp));
+        }
+        let historics = [minute, hour, Historic::new(8, 8)];
+        let select =
+            |from, to| Historic::select_range(&historics, seconds(from), seconds(to), |item| *item);
+        assert_eq!(select(105, 200), [seconds(105), seconds(110), seconds(115)]);
+        assert_eq!(select(30, 70), [seconds(30), seconds(50), seconds(70)]);
+        // nothing goes back that far, the tier going back the furthest is used
+        assert_eq!(select(0, 20), [seconds(10)]);
+        assert!(
+            Historic::<Duration>::select_range(&[], seconds(0), seconds(1), |item| *item)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn merged_range_completes_the_detailed_tiers() {
+        let seconds = |seconds: u64| Duration::from_secs(seconds);
+        let mut hour = Historic::new(4, 4);
+        let mut days = Historic::new(8, 8);
+        for timestamp in [100, 105, 110, 115] {
+            hour.add(seconds(timestamp));
+        }
+        for timestamp in [10, 30, 50, 70, 90, 110] {
+            days.add(seconds(timestamp));
+        }
+        let historics = [hour, days];
+        let merge =
+            |from, to| Historic::merge_range(&historics, seconds(from), seconds(to), |item| *item);
+        assert_eq!(
+            merge(40, 200),
+            [50, 70, 90, 100, 105, 110, 115].map(seconds)
+        );
+        assert_eq!(merge(105, 200), [105, 110, 115].map(seconds));
+        // a more detailed tier without items in the range leaves it to the next ones
+        assert_eq!(merge(20, 60), [30, 50].map(seconds));
+        assert!(
+            Historic::<Duration>::merge_range(&[], seconds(0), seconds(1), |item| *item).is_empty()
+        );
     }
 }
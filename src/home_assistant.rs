@@ -0,0 +1,143 @@
+use crate::{
+    json_display::escape,
+    mqtt::{Message, MqttSettings},
+    sensors::DeviceIdentity,
+};
+
+/// How Home Assistant shows a channel of `SensorData`
+struct ChannelDescription {
+    channel: &'static str,
+    name: &'static str,
+    device_class: &'static str,
+    unit: &'static str,
+}
+
+const CHANNEL_DESCRIPTIONS: [ChannelDescription; 4] = [
+    ChannelDescription {
+        channel: "pressure",
+        name: "Pressure",
+        device_class: "atmospheric_pressure",
+        unit: "hPa",
+    },
+    ChannelDescription {
+        channel: "bmp280Temp",
+        name: "Temperature",
+        device_class: "temperature",
+        unit: "°C",
+    },
+    ChannelDescription {
+        channel: "htu21Temp",
+        name: "Temperature",
+        device_class: "temperature",
+        unit: "°C",
+    },
+    ChannelDescription {
+        channel: "humidity",
+        name: "Humidity",
+        device_class: "humidity",
+        unit: "%",
+    },
+];
+
+/// Retained discovery configurations, one per channel, so that the sensors appear in Home
+/// Assistant without any manual configuration.
+///
+/// `devices` gives the device providing each channel. The channel values are read from the
+/// latest sample topic, and the entities are unavailable when the status topic is offline.
+pub fn discovery_messages(
+    settings: &MqttSettings,
+    devices: &[(&str, DeviceIdentity)],
+) -> Vec<Message> {
+    let discovery_prefix = match &settings.discovery_prefix {
+        Some(discovery_prefix) => discovery_prefix,
+        None => return Vec::new(),
+    };
+    let mut messages = Vec::new();
+    for (channel, identity) in devices {
+        let description = match CHANNEL_DESCRIPTIONS
+            .iter()
+            .find(|description| description.channel == *channel)
+        {
+            Some(description) => description,
+            None => continue,
+        };
+        let device_id = identity.id();
+        let object_id = channel.to_ascii_lowercase();
+        let device_name = match (&identity.iio_name, &identity.i2c_address) {
+            (Some(name), Some(address)) => format!("{} {}", name, address),
+            _ => device_id.clone(),
+        };
+        let payload = format!(
+            "{{\"name\": \"{}\", \"unique_id\": \"{}_{}\", \"device_class\": \"{}\", \"state_class\": \"measurement\", \"unit_of_measurement\": \"{}\", \"state_topic\": \"{}\", \"value_template\": \"{{{{ value_json.{} }}}}\", \"availability_topic\": \"{}\", \"device\": {{\"identifiers\": [\"{}\"], \"name\": \"{}\", \"model\": \"{}\"}}}}",
+            description.name,
+            device_id,
+            object_id,
+            description.device_class,
+            description.unit,
+            escape(&settings.latest_topic),
+            channel,
+            escape(&settings.status_topic),
+            device_id,
+            escape(&device_name),
+            escape(identity.iio_name.as_deref().unwrap_or("unknown")),
+        );
+        messages.push(Message {
+            topic: format!(
+                "{}/sensor/{}/{}/config",
+                discovery_prefix, device_id, object_id
+            ),
+            payload: payload.into_bytes(),
+            retain: true,
+        });
+    }
+    messages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::discovery_messages;
+    use crate::{mqtt::MqttSettings, sensors::DeviceIdentity};
+
+    fn identity(name: &str, address: &str) -> DeviceIdentity {
+        DeviceIdentity {
+            i2c_address: Some(address.to_string()),
+            iio_name: Some(name.to_string()),
+        }
+    }
+
+    #[test]
+    fn one_config_per_channel() {
+        let mut settings = MqttSettings::new("broker", 1883, "home/weather");
+        let devices = [
+            ("pressure", identity("bmp280", "1-0076")),
+            ("bmp280Temp", identity("bmp280", "1-0076")),
+            ("htu21Temp", identity("htu21", "1-0040")),
+            ("humidity", identity("htu21", "1-0040")),
+        ];
+        assert!(discovery_messages(&settings, &devices).is_empty());
+
+        settings.discovery_prefix = Some("homeassistant".to_string());
+        let messages = discovery_messages(&settings, &devices);
+        let topics: Vec<_> = messages
+            .iter()
+            .map(|message| message.topic.as_str())
+            .collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/sensor/bmp280_1_0076/pressure/config",
+                "homeassistant/sensor/bmp280_1_0076/bmp280temp/config",
+                "homeassistant/sensor/htu21_1_0040/htu21temp/config",
+                "homeassistant/sensor/htu21_1_0040/humidity/config",
+            ]
+        );
+        assert!(messages.iter().all(|message| message.retain));
+        assert_eq!(
+            String::from_utf8(messages[3].payload.clone()).unwrap(),
+            "{\"name\": \"Humidity\", \"unique_id\": \"htu21_1_0040_humidity\", \"device_class\": \"humidity\", \"state_class\": \"measurement\", \"unit_of_measurement\": \"%\", \"state_topic\": \"home/weather/latest\", \"value_template\": \"{{ value_json.humidity }}\", \"availability_topic\": \"home/weather/status\", \"device\": {\"identifiers\": [\"htu21_1_0040\"], \"name\": \"htu21 1-0040\", \"model\": \"htu21\"}}"
+        );
+        assert!(String::from_utf8(messages[0].payload.clone())
+            .unwrap()
+            .contains("\"device_class\": \"atmospheric_pressure\", \"state_class\": \"measurement\", \"unit_of_measurement\": \"hPa\""));
+    }
+}
@@ -0,0 +1,223 @@
+use std::{
+    io::{self, BufRead, BufReader, Error, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+// The answers of the webhooks and of InfluxDB are short, a longer body is an error
+const MAX_BODY_LENGTH: usize = 64 * 1024;
+
+/// `http://host[:port][/path]` URL, https is not supported
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+/// Status and body of an HTTP response
+#[derive(Debug)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl HttpUrl {
+    pub fn parse(url: &str) -> Result<HttpUrl, String> {
+        let rest = match url.strip_prefix("http://") {
+            Some(rest) => rest,
+            None => return Err(format!("only http:// URLs are supported : {}", url)),
+        };
+        let (authority, path) = match rest.find('/') {
+            Some(slash) => (&rest[..slash], &rest[slash..]),
+            None => (rest, "/"),
+        };
+        // [::1]:8080 or host:8080
+        let (host, port) = match authority.rfind(':') {
+            Some(colon) if !authority[colon..].contains(']') => {
+                match authority[colon + 1..].parse::<u16>() {
+                    Ok(port) => (&authority[..colon], port),
+                    Err(_) => return Err(format!("invalid port in URL {}", url)),
+                }
+            }
+            _ => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in URL {}", url));
+        }
+        Ok(HttpUrl {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+}
+
+/// Send a POST request and wait for the response, the connection is not kept alive
+pub fn post(
+    url: &HttpUrl,
+    content_type: &str,
+    body: &[u8],
+    timeout: Duration,
+) -> io::Result<HttpResponse> {
+    post_with_headers(url, content_type, &[], body, timeout)
+}
+
+/// Same as `post`, with additional headers like `Authorization`
+pub fn post_with_headers(
+    url: &HttpUrl,
+    content_type: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+    timeout: Duration,
+) -> io::Result<HttpResponse> {
+    let host = url.host.trim_start_matches('[').trim_end_matches(']');
+    let address = match (host, url.port).to_socket_addrs()?.next() {
+        Some(address) => address,
+        None => return Err(Error::new(ErrorKind::NotFound, "unknown host")),
+    };
+    let mut stream = TcpStream::connect_timeout(&address, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.host,
+        content_type,
+        body.len()
+    );
+    for (name, value) in headers {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    read_response(stream)
+}
+
+fn read_response(stream: TcpStream) -> io::Result<HttpResponse> {
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    // HTTP/1.1 204 No Content
+    let status = match status_line.split_whitespace().nth(1).map(str::parse::<u16>) {
+        Some(Ok(status)) => status,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid status line {}", status_line.trim()),
+            ))
+        }
+    };
+    let mut content_length = None;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some(colon) = header.find(':') {
+            if header[..colon].eq_ignore_ascii_case("content-length") {
+                content_length = header[colon + 1..].trim().parse::<usize>().ok();
+            }
+        }
+    }
+    let too_long = || {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "the body of the response is longer than {} bytes",
+                MAX_BODY_LENGTH
+            ),
+        )
+    };
+    let mut body = Vec::new();
+    match content_length {
+        Some(length) if length > MAX_BODY_LENGTH => return Err(too_long()),
+        Some(length) => {
+            body.resize(length, 0);
+            reader.read_exact(&mut body)?;
+        }
+        None => {
+            reader
+                .take(MAX_BODY_LENGTH as u64 + 1)
+                .read_to_end(&mut body)?;
+            if body.len() > MAX_BODY_LENGTH {
+                return Err(too_long());
+            }
+        }
+    }
+    Ok(HttpResponse { status, body })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{post, HttpUrl, MAX_BODY_LENGTH};
+    use crate::test_utils::serve_http;
+    use std::{net::TcpListener, time::Duration};
+
+    #[test]
+    fn parse_urls() {
+        assert_eq!(
+            HttpUrl::parse("http://localhost:8086/write?db=home"),
+            Ok(HttpUrl {
+                host: "localhost".to_string(),
+                port: 8086,
+                path: "/write?db=home".to_string()
+            })
+        );
+        let url = HttpUrl::parse("http://[::1]").unwrap();
+        assert_eq!(
+            (url.host.as_str(), url.port, url.get_path()),
+            ("[::1]", 80, "/")
+        );
+        assert!(HttpUrl::parse("https://example.com").is_err());
+        assert!(HttpUrl::parse("http://example.com:http/").is_err());
+    }
+
+    #[test]
+    fn post_to_local_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve_http(
+            listener,
+            vec!["HTTP/1.1 201 Created\r\nContent-Length: 2\r\n\r\nok"],
+        );
+        let url = HttpUrl::parse(&format!("http://127.0.0.1:{}/hook", port)).unwrap();
+        let response = post(&url, "text/plain", b"hello", Duration::from_secs(5)).unwrap();
+        assert_eq!(response.status, 201);
+        assert_eq!(response.body, b"ok");
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].request_line, "POST /hook HTTP/1.1\r\n");
+        assert_eq!(requests[0].body, b"hello");
+    }
+
+    #[test]
+    fn long_bodies_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let without_length: &'static str = Box::leak(
+            format!("HTTP/1.1 200 OK\r\n\r\n{}", "a".repeat(MAX_BODY_LENGTH + 1)).into_boxed_str(),
+        );
+        let server = serve_http(
+            listener,
+            vec![
+                "HTTP/1.1 200 OK\r\nContent-Length: 4000000000\r\n\r\nok",
+                without_length,
+            ],
+        );
+        let url = HttpUrl::parse(&format!("http://127.0.0.1:{}/hook", port)).unwrap();
+        for _ in 0..2 {
+            let error = post(&url, "text/plain", b"hello", Duration::from_secs(5)).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "the body of the response is longer than 65536 bytes"
+            );
+        }
+        server.join().unwrap();
+    }
+}
@@ -0,0 +1,558 @@
+use crate::{
+    config::{hostname, Config, Section},
+    http::{self, HttpUrl},
+    sensor_data::{SensorData, CHANNELS},
+    sensors::DeviceIdentity,
+};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    path::PathBuf,
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const DEFAULT_MEASUREMENT: &str = "weather";
+const DEFAULT_TIMEOUT_S: u64 = 10;
+// Datagrams are kept below the usual MTU
+const MAX_DATAGRAM_SIZE: usize = 1400;
+
+/// Conversion of the samples to InfluxDB line protocol.
+///
+/// There is one line per device, tagged with the board and the device identity, holding a
+/// field per channel of the device:
+/// ```text
+/// weather,board=raspberrypi,sensor=bmp280_1_0076 pressure=1013.250,bmp280Temp=21.370 1700000000000000000
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct LineFormat {
+    measurement: String,
+    board: String,
+    // device id and its channels, all the channels are on a single untagged line when empty
+    devices: Vec<(String, Vec<&'static str>)>,
+}
+
+/// Where the batches are sent
+#[derive(Debug, PartialEq)]
+pub enum Transport {
+    /// `/write` (1.x) or `/api/v2/write` (2.x) endpoint, with an optional token
+    Http {
+        url: HttpUrl,
+        token: Option<String>,
+        timeout: Duration,
+    },
+    /// UDP listener of InfluxDB 1.x or Telegraf
+    Udp { address: SocketAddr },
+}
+
+/// Exporter read from the `[influxdb]` section of the configuration:
+/// ```text
+/// [influxdb]
+/// url = http://192.168.1.2:8086/write?db=home   # or udp = 192.168.1.2:8089
+/// token = secret                                # optional, sent as Authorization: Token
+/// measurement = weather
+/// board = raspberrypi                           # hostname by default
+/// batch = 12                                    # points sent together
+/// flush_interval = 60s                          # a partial batch is sent after this delay
+/// spool = /var/lib/rustdatamonitoring/influxdb.spool
+/// spool_max_size = 10000000                     # bytes
+/// ```
+/// When the endpoint is unreachable, the lines are appended to the spool file and sent again,
+/// before the new ones, after the next successful batch.
+pub struct InfluxExporter {
+    format: LineFormat,
+    transport: Transport,
+    batch_size: usize,
+    flush_interval: Duration,
+    spool: Option<PathBuf>,
+    spool_max_size: u64,
+    batch: Vec<String>,
+    last_flush: Instant,
+}
+
+impl LineFormat {
+    pub fn new(measurement: &str, board: &str) -> LineFormat {
+        LineFormat {
+            measurement: measurement.to_string(),
+            board: board.to_string(),
+            devices: Vec::new(),
+        }
+    }
+
+    /// Tag the lines with the devices providing the channels
+    pub fn with_devices(
+        mut self,
+        channel_devices: &[(&'static str, DeviceIdentity)],
+    ) -> LineFormat {
+        self.devices.clear();
+        for (channel, identity) in channel_devices {
+            let id = identity.id();
+            match self.devices.iter_mut().find(|(device, _)| *device == id) {
+                Some((_, channels)) => channels.push(channel),
+                None => self.devices.push((id, vec![channel])),
+            }
+        }
+        self
+    }
+
+    pub fn from_config(config: &Config) -> Result<LineFormat, String> {
+        match config.section("influxdb")? {
+            Some(section) => LineFormat::from_section(section),
+            None => Ok(LineFormat::new(DEFAULT_MEASUREMENT, &hostname())),
+        }
+    }
+
+    fn from_section(section: &Section) -> Result<LineFormat, String> {
+        Ok(LineFormat::new(
+            &section.get_or("measurement", DEFAULT_MEASUREMENT.to_string())?,
+            &section.get_or("board", hostname())?,
+        ))
+    }
+
+    /// Lines of a sample, each one ended by a new line
+    pub fn lines(&self, data: &SensorData) -> String {
+        let mut lines = String::new();
+        let timestamp = data.get_timestamp().as_nanos();
+        if self.devices.is_empty() {
+            self.push_line(&mut lines, None, &CHANNELS, data, timestamp);
+        }
+        for (device, channels) in &self.devices {
+            self.push_line(&mut lines, Some(device), channels, data, timestamp);
+        }
+        lines
+    }
+
+    fn push_line(
+        &self,
+        lines: &mut String,
+        device: Option<&str>,
+        channels: &[&str],
+        data: &SensorData,
+        timestamp: u128,
+    ) {
+        lines.push_str(&escape(&self.measurement, ", "));
+        lines.push_str(",board=");
+        lines.push_str(&escape(&self.board, ",= "));
+        if let Some(device) = device {
+            lines.push_str(",sensor=");
+            lines.push_str(&escape(device, ",= "));
+        }
+        if data.is_calibrated() {
+            lines.push_str(",calibrated=true");
+        }
+        let mut separator = ' ';
+        for channel in channels {
+            if let Some(value) = data.get_channel(channel) {
+                lines.push_str(&format!("{}{}={:.3}", separator, channel, value));
+                separator = ',';
+            }
+        }
+        lines.push_str(&format!(" {}\n", timestamp));
+    }
+
+    /// Lines of all the samples, to backfill a database
+    pub fn write_lines(&self, samples: &[SensorData], w: &mut dyn Write) -> io::Result<()> {
+        for data in samples {
+            w.write_all(self.lines(data).as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+impl Transport {
+    fn send(&self, lines: &str) -> Result<(), String> {
+        match self {
+            Transport::Http {
+                url,
+                token,
+                timeout,
+            } => {
+                let authorization = token.as_ref().map(|token| format!("Token {}", token));
+                let headers: Vec<(&str, &str)> = authorization
+                    .iter()
+                    .map(|authorization| ("Authorization", authorization.as_str()))
+                    .collect();
+                match http::post_with_headers(
+                    url,
+                    "text/plain; charset=utf-8",
+                    &headers,
+                    lines.as_bytes(),
+                    *timeout,
+                ) {
+                    Ok(response) if (200..300).contains(&response.status) => Ok(()),
+                    Ok(response) => Err(format!(
+                        "status {} : {}",
+                        response.status,
+                        String::from_utf8_lossy(&response.body).trim()
+                    )),
+                    Err(err) => Err(err.to_string()),
+                }
+            }
+            Transport::Udp { address } => {
+                let local_address = if address.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let socket = UdpSocket::bind(local_address).map_err(|err| err.to_string())?;
+                let mut datagram = String::new();
+                for line in lines.split_inclusive('\n') {
+                    if !datagram.is_empty() && datagram.len() + line.len() > MAX_DATAGRAM_SIZE {
+                        socket
+                            .send_to(datagram.as_bytes(), address)
+                            .map_err(|err| err.to_string())?;
+                        datagram.clear();
+                    }
+                    datagram.push_str(line);
+                }
+                if !datagram.is_empty() {
+                    socket
+                        .send_to(datagram.as_bytes(), address)
+                        .map_err(|err| err.to_string())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl InfluxExporter {
+    pub fn new(format: LineFormat, transport: Transport) -> InfluxExporter {
+        InfluxExporter {
+            format,
+            transport,
+            batch_size: 12,
+            flush_interval: Duration::from_secs(60),
+            spool: None,
+            spool_max_size: 10_000_000,
+            batch: Vec::new(),
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn from_config(
+        config: &Config,
+        format: LineFormat,
+    ) -> Result<Option<InfluxExporter>, String> {
+        match config.section("influxdb")? {
+            Some(section) => InfluxExporter::from_section(section, format).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn from_section(section: &Section, format: LineFormat) -> Result<InfluxExporter, String> {
+        section.check_keys(&[
+            "url",
+            "udp",
+            "token",
+            "measurement",
+            "board",
+            "batch",
+            "flush_interval",
+            "spool",
+            "spool_max_size",
+            "timeout",
+        ])?;
+        let timeout = section
+            .get_duration("timeout")?
+            .unwrap_or(Duration::from_secs(DEFAULT_TIMEOUT_S));
+        let transport = match (section.get_str("url"), section.get_str("udp")) {
+            (Some(url), None) => Transport::Http {
+                url: HttpUrl::parse(url).map_err(|why| section.error(&why))?,
+                token: section.get("token")?,
+                timeout,
+            },
+            (None, Some(address)) => match address.to_socket_addrs().map(|mut a| a.next()) {
+                Ok(Some(address)) => Transport::Udp { address },
+                _ => return Err(section.error(&format!("invalid udp address {}", address))),
+            },
+            _ => return Err(section.error("either url or udp is needed")),
+        };
+        let mut exporter = InfluxExporter::new(format, transport);
+        exporter.batch_size = section.get_or("batch", exporter.batch_size)?.max(1);
+        exporter.flush_interval = section
+            .get_duration("flush_interval")?
+            .unwrap_or(exporter.flush_interval);
+        exporter.spool = section.get::<String>("spool")?.map(PathBuf::from);
+        exporter.spool_max_size = section.get_or("spool_max_size", exporter.spool_max_size)?;
+        Ok(exporter)
+    }
+
+    /// Add the sample to the batch, which is sent when it is full or too old
+    pub fn add(&mut self, data: &SensorData) {
+        self.batch.extend(
+            self.format
+                .lines(data)
+                .split_inclusive('\n')
+                .map(str::to_string),
+        );
+        if self.batch.len() >= self.lines_per_batch()
+            || self.last_flush.elapsed() >= self.flush_interval
+        {
+            self.flush();
+        }
+    }
+
+    fn lines_per_batch(&self) -> usize {
+        self.batch_size * self.format.devices.len().max(1)
+    }
+
+    /// Send the spooled lines and the current batch
+    pub fn flush(&mut self) {
+        self.last_flush = Instant::now();
+        let mut lines = self.read_spool();
+        let nb_spooled_lines = lines.len();
+        lines.append(&mut self.batch);
+        if lines.is_empty() {
+            return;
+        }
+        // the batches keep their size when the spooled lines are sent again
+        let lines_per_batch = self.lines_per_batch();
+        for (index, batch) in lines.chunks(lines_per_batch).enumerate() {
+            if let Err(why) = self.transport.send(&batch.concat()) {
+                println!("InfluxDB export failed : {}", why);
+                self.write_spool(&lines[index * lines_per_batch..], nb_spooled_lines > 0);
+                return;
+            }
+        }
+        if nb_spooled_lines > 0 {
+            if let Some(spool) = &self.spool {
+                let _ = fs::remove_file(spool);
+            }
+        }
+    }
+
+    /// Lines of the spool, one per point
+    fn read_spool(&self) -> Vec<String> {
+        match &self.spool {
+            Some(spool) => match fs::read_to_string(spool) {
+                Ok(content) => content.split_inclusive('\n').map(str::to_string).collect(),
+                Err(_) => Vec::new(),
+            },
+            None => Vec::new(),
+        }
+    }
+
+    /// Keep the lines for a later attempt, `replace` when they include the current spool
+    fn write_spool(&self, lines: &[String], replace: bool) {
+        let spool = match &self.spool {
+            Some(spool) => spool,
+            None => {
+                println!("{} InfluxDB lines dropped", lines.len());
+                return;
+            }
+        };
+        let current_size = match fs::metadata(spool) {
+            Ok(metadata) if !replace => metadata.len(),
+            _ => 0,
+        };
+        let mut content = String::new();
+        let mut nb_dropped_lines = 0;
+        for line in lines {
+            if current_size + (content.len() + line.len()) as u64 <= self.spool_max_size {
+                content.push_str(line);
+            } else {
+                nb_dropped_lines += 1;
+            }
+        }
+        if nb_dropped_lines > 0 {
+            println!("InfluxDB spool full, {} lines dropped", nb_dropped_lines);
+        }
+        let result = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(!replace)
+            .truncate(replace)
+            .open(spool)
+            .and_then(|mut file| file.write_all(content.as_bytes()));
+        if let Err(err) = result {
+            println!(
+                "Can't write the InfluxDB spool {} : {}",
+                spool.display(),
+                err
+            );
+        }
+    }
+}
+
+/// Thread which batches the received samples and sends them every `flush_interval`, starting
+/// with the ones spooled before a restart
+pub fn create_influxdb_thread(
+    mut exporter: InfluxExporter,
+) -> (JoinHandle<()>, Sender<SensorData>) {
+    let (tx, rx) = channel::<SensorData>();
+    (
+        spawn(move || {
+            // send what was spooled before a restart
+            exporter.flush();
+            loop {
+                match rx.recv_timeout(exporter.flush_interval) {
+                    Ok(data) => exporter.add(&data),
+                    Err(RecvTimeoutError::Timeout) => exporter.flush(),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            exporter.flush();
+        }),
+        tx,
+    )
+}
+
+/// Backslash before the characters which have a meaning in the line protocol
+fn escape(text: &str, special_characters: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        if character == '\\' || special_characters.contains(character) {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InfluxExporter, LineFormat, Transport};
+    use crate::{
+        config::Config,
+        http::HttpUrl,
+        sensors::DeviceIdentity,
+        test_utils::{sample, serve_http, TempPath},
+    };
+    use std::{
+        fs,
+        net::{TcpListener, UdpSocket},
+        time::Duration,
+    };
+
+    fn identity(name: &str, address: &str) -> DeviceIdentity {
+        DeviceIdentity {
+            i2c_address: Some(address.to_string()),
+            iio_name: Some(name.to_string()),
+        }
+    }
+
+    #[test]
+    fn line_protocol() {
+        let format = LineFormat::new("weather station", "pi,1");
+        assert_eq!(
+            format.lines(&sample(1)),
+            "weather\\ station,board=pi\\,1 pressure=1013.000,bmp280Temp=20.000,htu21Temp=21.000,humidity=50.000 1000000000\n"
+        );
+        let format = LineFormat::new("weather", "pi").with_devices(&[
+            ("pressure", identity("bmp280", "1-0076")),
+            ("bmp280Temp", identity("bmp280", "1-0076")),
+            ("humidity", identity("htu21", "1-0040")),
+        ]);
+        let mut lines = Vec::new();
+        format
+            .write_lines(&[sample(1), sample(2)], &mut lines)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(lines).unwrap(),
+            "weather,board=pi,sensor=bmp280_1_0076 pressure=1013.000,bmp280Temp=20.000 1000000000\n\
+             weather,board=pi,sensor=htu21_1_0040 humidity=50.000 1000000000\n\
+             weather,board=pi,sensor=bmp280_1_0076 pressure=1013.000,bmp280Temp=20.000 2000000000\n\
+             weather,board=pi,sensor=htu21_1_0040 humidity=50.000 2000000000\n"
+        );
+    }
+
+    #[test]
+    fn batches_are_spooled_while_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let spool = TempPath::new("influxdb_spool");
+        let config = Config::parse(&format!(
+            "[influxdb]\nurl = http://127.0.0.1:{}/api/v2/write?bucket=home\ntoken = secret\nboard = pi\nbatch = 2\nspool = {}\n",
+            port,
+            spool.path().display()
+        ))
+        .unwrap();
+        let format = LineFormat::from_config(&config).unwrap();
+        let mut exporter = InfluxExporter::from_config(&config, format)
+            .unwrap()
+            .unwrap();
+        for seconds in 1..=5 {
+            exporter.add(&sample(seconds));
+        }
+        // 2 failed batches, the fifth sample waits for the next batch
+        assert_eq!(fs::read_to_string(spool.path()).unwrap().lines().count(), 4);
+
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        let server = serve_http(listener, vec!["HTTP/1.1 204 No Content\r\n\r\n"; 3]);
+        exporter.add(&sample(6));
+        let requests = server.join().unwrap();
+        let timestamps: Vec<Vec<&str>> = requests
+            .iter()
+            .map(|request| {
+                assert_eq!(request.header("Authorization"), Some("Token secret"));
+                let body = std::str::from_utf8(&request.body).unwrap();
+                assert!(body.starts_with("weather,board=pi "));
+                body.lines()
+                    .map(|line| line.rsplit(' ').next().unwrap())
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            timestamps,
+            [
+                ["1000000000", "2000000000"],
+                ["3000000000", "4000000000"],
+                ["5000000000", "6000000000"]
+            ]
+        );
+        assert!(!spool.path().exists());
+    }
+
+    #[test]
+    fn udp_datagrams() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut exporter = InfluxExporter::new(
+            LineFormat::new("weather", "pi"),
+            Transport::Udp {
+                address: receiver.local_addr().unwrap(),
+            },
+        );
+        exporter.batch_size = 20;
+        // about 100 bytes per line, 2 datagrams are needed
+        for seconds in 1..=20 {
+            exporter.add(&sample(seconds));
+        }
+        let mut buffer = [0u8; 2048];
+        let mut nb_lines = 0;
+        while nb_lines < 20 {
+            let size = receiver.recv(&mut buffer).unwrap();
+            assert!(size <= super::MAX_DATAGRAM_SIZE);
+            nb_lines += buffer[..size].iter().filter(|byte| **byte == b'\n').count();
+        }
+        assert_eq!(nb_lines, 20);
+    }
+
+    #[test]
+    fn configuration_errors() {
+        let config = Config::parse("[influxdb]\nmeasurement = weather\n").unwrap();
+        assert_eq!(
+            InfluxExporter::from_config(&config, LineFormat::new("weather", "pi")).err(),
+            Some("line 1: section [influxdb]: either url or udp is needed".to_string())
+        );
+        let config =
+            Config::parse("[influxdb]\nurl = http://localhost:8086/write?db=home\n").unwrap();
+        let exporter = InfluxExporter::from_config(&config, LineFormat::new("weather", "pi"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            exporter.transport,
+            Transport::Http {
+                url: HttpUrl::parse("http://localhost:8086/write?db=home").unwrap(),
+                token: None,
+                timeout: Duration::from_secs(10)
+            }
+        );
+    }
+}
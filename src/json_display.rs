@@ -1,5 +1,43 @@
 use std::io::{Result, Write};
 
 pub trait JsonDisplay {
-    fn json_item(&self, f: &mut Write) -> Result<()>;
+    /// Settings the items are written with, like the derived channels of the samples
+    type Context;
+
+    fn json_item(&self, context: &Self::Context, f: &mut dyn Write) -> Result<()>;
+}
+
+/// Write the items separated by commas, without the enclosing brackets
+pub fn write_json_chunk<'a, T, I>(items: I, context: &T::Context, w: &mut dyn Write) -> Result<()>
+where
+    T: 'a + JsonDisplay,
+    I: IntoIterator<Item = &'a T>,
+{
+    let mut first = true;
+    for data in items {
+        if !first {
+            w.write_all(b",")?;
+        } else {
+            first = false;
+        }
+        data.json_item(context, w)?;
+    }
+    Ok(())
+}
+
+/// Escape a text in order to write it inside a JSON string
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
 }
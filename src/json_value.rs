@@ -0,0 +1,231 @@
+use std::{iter::Peekable, str::Chars};
+
+/// JSON document read back from the answers of the server, see `JsonDisplay` for the writing
+#[derive(Clone, Debug, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<JsonValue>),
+    /// Members in the order of the document
+    Object(Vec<(String, JsonValue)>),
+}
+
+impl JsonValue {
+    pub fn parse(text: &str) -> Result<JsonValue, String> {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Ok(value),
+            Some(c) => Err(format!("unexpected {} after the JSON value", c)),
+        }
+    }
+
+    /// Value of a member of an object
+    pub fn get(&self, name: &str) -> Option<&JsonValue> {
+        match self {
+            JsonValue::Object(members) => members
+                .iter()
+                .find(|(member, _)| member == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            JsonValue::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            JsonValue::String(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[JsonValue]> {
+        match self {
+            JsonValue::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while let Some(' ' | '\t' | '\n' | '\r') = chars.peek() {
+        chars.next();
+    }
+}
+
+fn expect_word(chars: &mut Peekable<Chars>, word: &str) -> Result<(), String> {
+    for expected in word.chars() {
+        if chars.next() != Some(expected) {
+            return Err(format!("invalid literal, {} expected", word));
+        }
+    }
+    Ok(())
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<JsonValue, String> {
+    skip_whitespace(chars);
+    match chars.peek() {
+        Some('{') => {
+            chars.next();
+            let mut members = Vec::new();
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Ok(JsonValue::Object(members));
+            }
+            loop {
+                skip_whitespace(chars);
+                if chars.next() != Some('"') {
+                    return Err("member name expected".to_string());
+                }
+                let name = parse_string(chars)?;
+                skip_whitespace(chars);
+                if chars.next() != Some(':') {
+                    return Err(format!("':' expected after {}", name));
+                }
+                members.push((name, parse_value(chars)?));
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => (),
+                    Some('}') => return Ok(JsonValue::Object(members)),
+                    _ => return Err("',' or '}' expected".to_string()),
+                }
+            }
+        }
+        Some('[') => {
+            chars.next();
+            let mut items = Vec::new();
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Ok(JsonValue::Array(items));
+            }
+            loop {
+                items.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next() {
+                    Some(',') => (),
+                    Some(']') => return Ok(JsonValue::Array(items)),
+                    _ => return Err("',' or ']' expected".to_string()),
+                }
+            }
+        }
+        Some('"') => {
+            chars.next();
+            parse_string(chars).map(JsonValue::String)
+        }
+        Some('t') => expect_word(chars, "true").map(|_| JsonValue::Bool(true)),
+        Some('f') => expect_word(chars, "false").map(|_| JsonValue::Bool(false)),
+        Some('n') => expect_word(chars, "null").map(|_| JsonValue::Null),
+        Some(c) if *c == '-' || c.is_ascii_digit() => {
+            let mut number = String::new();
+            while let Some(c) = chars.peek() {
+                if c.is_ascii_digit() || "+-.eE".contains(*c) {
+                    number.push(*c);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+            number
+                .parse()
+                .map(JsonValue::Number)
+                .map_err(|_| format!("invalid number {}", number))
+        }
+        Some(c) => Err(format!("unexpected {}", c)),
+        None => Err("unexpected end of the document".to_string()),
+    }
+}
+
+/// String after its opening quote
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let mut text = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(text),
+            Some('\\') => match chars.next() {
+                Some('n') => text.push('\n'),
+                Some('r') => text.push('\r'),
+                Some('t') => text.push('\t'),
+                Some('b') => text.push('\u{8}'),
+                Some('f') => text.push('\u{c}'),
+                Some('u') => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    let character = u32::from_str_radix(&code, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| format!("invalid escape \\u{}", code))?;
+                    text.push(character);
+                }
+                Some(c) => text.push(c),
+                None => return Err("unterminated string".to_string()),
+            },
+            Some(c) => text.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JsonValue;
+    use crate::json_display::escape;
+
+    #[test]
+    fn parse_documents() {
+        let value = JsonValue::parse(
+            "[{\"timestamp\": 1000,\n\"pressure\"  : 1013.25, \"ok\": true, \"none\": null},\n{}, -1.5e3, \"a\\\"b\"]\n",
+        )
+        .unwrap();
+        let items = value.as_array().unwrap();
+        assert_eq!(items.len(), 4);
+        assert_eq!(
+            items[0].get("timestamp").and_then(JsonValue::as_f64),
+            Some(1000.0)
+        );
+        assert_eq!(
+            items[0].get("pressure").and_then(JsonValue::as_f64),
+            Some(1013.25)
+        );
+        assert_eq!(items[0].get("ok"), Some(&JsonValue::Bool(true)));
+        assert_eq!(items[0].get("none"), Some(&JsonValue::Null));
+        assert_eq!(items[1], JsonValue::Object(Vec::new()));
+        assert_eq!(items[2].as_f64(), Some(-1500.0));
+        assert_eq!(items[3].as_str(), Some("a\"b"));
+
+        let text = "line\nwith \"quotes\" \\ and \u{1}";
+        assert_eq!(
+            JsonValue::parse(&format!("\"{}\"", escape(text))).unwrap(),
+            JsonValue::String(text.to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_documents() {
+        assert_eq!(
+            JsonValue::parse("[1, 2"),
+            Err("',' or ']' expected".to_string())
+        );
+        assert_eq!(
+            JsonValue::parse("{\"a\" 1}"),
+            Err("':' expected after a".to_string())
+        );
+        assert_eq!(
+            JsonValue::parse("[] []"),
+            Err("unexpected [ after the JSON value".to_string())
+        );
+        assert_eq!(
+            JsonValue::parse("tru"),
+            Err("invalid literal, true expected".to_string())
+        );
+    }
+}
@@ -1,30 +1,78 @@
 use std::{
-    env,
+    env, process,
+    sync::mpsc::{channel, Sender},
     time::{Duration, Instant},
 };
 
 #[cfg(test)]
 use std::{fmt::Display, io, time::SystemTime};
 
+pub mod alert;
+pub mod anomaly;
 pub mod average;
+pub mod calibration;
+pub mod chart;
 pub mod circular_buffer;
+pub mod client;
+pub mod collector;
+pub mod config;
+pub mod connection_limit;
+pub mod dashboard;
+pub mod derived;
+pub mod expression;
+pub mod filter;
 pub mod historic;
+pub mod home_assistant;
+pub mod http;
+pub mod influxdb;
 pub mod json_display;
+pub mod json_value;
+pub mod mapped_circular_buffer;
+pub mod metrics;
+pub mod mqtt;
+pub mod notifier;
+pub mod oversampling;
+pub mod request;
 pub mod sensor_data;
 pub mod sensors;
 pub mod server;
+pub mod shared_circular_buffer;
+pub mod smoothing;
+pub mod subscription;
+pub mod summary;
+pub mod systemd;
+#[cfg(test)]
+mod test_utils;
+pub mod tls;
+pub mod trend;
 
+use crate::alert::AlertEngine;
+use crate::anomaly::AnomalyDetector;
+use crate::calibration::Calibration;
+use crate::client::ClientOptions;
+use crate::collector::{Collector, Pusher};
+use crate::config::Config;
+use crate::derived::DerivedChannels;
+use crate::filter::SampleFilter;
 use crate::historic::Historic;
+use crate::influxdb::{InfluxExporter, LineFormat};
+use crate::mqtt::{MqttSettings, Publication};
+use crate::oversampling::Oversampler;
+use crate::request::Request;
 use crate::sensor_data::SensorData;
-use crate::sensors::Sensor;
-use crate::server::Server;
+use crate::sensors::{DeviceIdentity, Sensor};
+use crate::server::{ClientRequest, Server, SocketSettings};
+use crate::subscription::{Event, Subscribers};
+use crate::summary::Summaries;
+use crate::systemd::ActivatedSocket;
+use crate::trend::TrendSettings;
 
 #[cfg(test)]
 use circular_buffer::CircularBuffer;
 #[cfg(test)]
 use json_display::JsonDisplay;
 
-#[allow(dead_code)]
+#[allow(dead_code, clippy::upper_case_acronyms)]
 enum QueuesIndex {
     MINUTE = 0,
     HOUR,
@@ -32,62 +80,458 @@ enum QueuesIndex {
 }
 
 const DEFAULT_SOCKET_NAME: &str = "rustSocket";
+// Number of DAYS items (one per hour) kept when they are stored in a file : 5 years
+const DAYS_FILE_NB_ITEMS: usize = 24 * 366 * 5;
 // Sampling time in milliceconds
 static SAMPLING_TIME_MS: u64 = 5000;
 
 fn main() {
-    let socket_name = match env::args().nth(1) {
-        Some(arg) => arg,
-        None => DEFAULT_SOCKET_NAME.to_string(),
+    // usage : [--config <file>] [socket name] [DAYS historic file]
+    //         query [--socket <path>] [--format <format>] [--watch [<interval>]] <query>
+    let client_args: Vec<String> = env::args().skip(1).collect();
+    if client_args.first().map(String::as_str) == Some("query") {
+        let result = ClientOptions::parse(&client_args[1..], DEFAULT_SOCKET_NAME)
+            .and_then(|options| client::run(&options));
+        if let Err(why) = result {
+            eprintln!("{}", why);
+            process::exit(1);
+        }
+        return;
+    }
+    let mut config_file_name = None;
+    let mut positional_args = Vec::new();
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_file_name = args.next(),
+            _ => positional_args.push(arg),
+        }
+    }
+    let mut positional_args = positional_args.into_iter();
+    let socket_arg = positional_args.next();
+    // The DAYS historic is kept in memory, unless a file is given to store it
+    let days_file_name = positional_args.next();
+    let config = match config_file_name {
+        Some(config_file_name) => or_exit(Config::load(&config_file_name)),
+        None => Config::default(),
+    };
+    let socket_settings = or_exit(SocketSettings::from_config(&config));
+    // the socket given on the command line wins over the configured one
+    let socket_name = socket_arg
+        .or_else(|| socket_settings.path.clone())
+        .unwrap_or_else(|| DEFAULT_SOCKET_NAME.to_string());
+    // the sockets passed by systemd are taken before any thread is started
+    let mut activated_sockets = or_exit(systemd::listen_fds());
+    let derived = or_exit(DerivedChannels::from_config(&config));
+    let mut oversampler = or_exit(Oversampler::from_config(
+        &config,
+        Duration::from_millis(SAMPLING_TIME_MS),
+    ));
+    let mut sample_filter = or_exit(SampleFilter::from_config(&config));
+    let calibration = or_exit(Calibration::from_config(&config));
+    let trend_settings = or_exit(TrendSettings::from_config(&config));
+    // the forecast of the [trend] section uses the altitude of the [derived] one
+    let configured = |kind| matches!(config.section(kind), Ok(Some(_)));
+    if derived.altitude == 0.0 && configured("derived") && configured("trend") {
+        println!("The altitude of the [derived] section is 0 m, the forecast takes the pressure of the station as the sea level pressure");
+    }
+    let mut summaries = or_exit(Summaries::from_config(&config));
+    let mut anomaly_detector = or_exit(AnomalyDetector::from_config(&config));
+    let mut alert_engine = or_exit(AlertEngine::from_config(&config));
+    let notifiers = or_exit(notifier::notifiers_from_config(&config));
+    let mut notification_tx = if notifiers.is_empty() {
+        None
+    } else {
+        Some(notifier::create_notification_thread(notifiers).1)
+    };
+
+    let collector_nodes = or_exit(Collector::from_config(&config)).map(|collector| {
+        let nodes = collector.get_nodes();
+        collector.create_collector_thread();
+        nodes
+    });
+    let mut push_tx =
+        or_exit(Pusher::from_config(&config)).map(|pusher| collector::create_push_thread(pusher).1);
+
+    // a collector can run without sensors, it only keeps the samples of the nodes
+    let sensors = match probe_sensors() {
+        Ok(sensors) => Some(sensors),
+        Err(why) if collector_nodes.is_some() => {
+            println!("{}, only the samples of the nodes are collected", why);
+            None
+        }
+        Err(why) => {
+            eprintln!("{}", why);
+            process::exit(1);
+        }
     };
-    println!("Socket name : {}", socket_name);
-
-    let sensor_bmp280_pressure =
-        Sensor::probe("/sys/bus/i2c/devices/i2c-1/1-0076/iio:device{}/in_pressure_input").unwrap();
-    let sensor_bmp280_temperature =
-        Sensor::probe("/sys/bus/i2c/devices/i2c-1/1-0076/iio:device{}/in_temp_input").unwrap();
-    let sensor_htu21_temperature =
-        Sensor::probe("/sys/bus/i2c/devices/i2c-1/1-0040/iio:device{}/in_temp_input").unwrap();
-    let sensor_htu21_humidity =
-        Sensor::probe("/sys/bus/i2c/devices/i2c-1/1-0040/iio:device{}/in_humidityrelative_input")
-            .unwrap();
+    let devices: Vec<(&'static str, DeviceIdentity)> = sensor_data::CHANNELS
+        .iter()
+        .copied()
+        .zip(sensors.iter().flatten().map(Sensor::get_device_identity))
+        .collect();
+
+    let mut mqtt_tx = or_exit(MqttSettings::from_config(&config)).map(|settings| {
+        let discovery_messages = home_assistant::discovery_messages(&settings, &devices);
+        mqtt::create_mqtt_thread(settings, discovery_messages, derived.clone()).1
+    });
+
+    let line_format = or_exit(LineFormat::from_config(&config)).with_devices(&devices);
+    let mut influxdb_tx = or_exit(InfluxExporter::from_config(&config, line_format.clone()))
+        .map(|exporter| influxdb::create_influxdb_thread(exporter).1);
+
+    let mut subscribers =
+        or_exit(Subscribers::from_config(&config)).with_derived_channels(derived.clone());
 
     let sampling_duration_ms = Duration::from_millis(SAMPLING_TIME_MS);
     // array of historic queues of MINUTE, HOUR, DAYS
+    // the MINUTE historic can be read by the server thread while it is updated
+    let (minute_size, minute_limit) = historic::TIER_SIZES[QueuesIndex::MINUTE as usize];
+    let (hour_size, hour_limit) = historic::TIER_SIZES[QueuesIndex::HOUR as usize];
+    let (days_size, days_limit) = historic::TIER_SIZES[QueuesIndex::DAYS as usize];
+    let (minute_historic, minute_reader) =
+        Historic::<SensorData>::new_shared(minute_size, minute_limit);
     let mut historic_queues = [
-        Historic::<SensorData>::new(32, 24),
-        Historic::<SensorData>::new(128, 120),
-        Historic::<SensorData>::new(9192, 9192),
+        minute_historic,
+        Historic::<SensorData>::new(hour_size, hour_limit),
+        match days_file_name {
+            Some(days_file_name) => {
+                println!("DAYS historic file : {}", days_file_name);
+                or_exit(
+                    Historic::<SensorData>::new_mapped(
+                        &days_file_name,
+                        DAYS_FILE_NB_ITEMS,
+                        DAYS_FILE_NB_ITEMS,
+                    )
+                    .map_err(|err| format!("couldn't open {} : {}", days_file_name, err)),
+                )
+            }
+            None => Historic::<SensorData>::new(days_size, days_limit),
+        },
     ];
-    let (_, rx) = Server::create_server_thread(socket_name.as_str());
+    if let Some(detector) = &mut anomaly_detector {
+        detector.update_reference(&historic_queues[QueuesIndex::DAYS as usize].to_vec());
+    }
+    let (tx, rx) = channel::<ClientRequest>();
+    for settings in or_exit(server::listeners_from_config(&config)) {
+        let activated = activated_sockets.iter().position(|socket| {
+            matches!(socket, ActivatedSocket::Tcp(Some(name), _) if name == settings.get_name())
+        });
+        match activated.map(|index| activated_sockets.remove(index)) {
+            Some(ActivatedSocket::Tcp(_, listener)) => {
+                Server::create_activated_tcp_server_thread(
+                    listener,
+                    settings,
+                    minute_reader.clone(),
+                    derived.clone(),
+                    tx.clone(),
+                );
+            }
+            _ => {
+                or_exit(Server::create_tcp_server_thread(
+                    settings,
+                    minute_reader.clone(),
+                    derived.clone(),
+                    tx.clone(),
+                ));
+            }
+        }
+    }
+    let activated = activated_sockets
+        .iter()
+        .position(|socket| matches!(socket, ActivatedSocket::Unix(_)));
+    match activated.map(|index| activated_sockets.remove(index)) {
+        Some(ActivatedSocket::Unix(listener)) => {
+            println!("Socket passed by systemd");
+            Server::create_activated_server_thread(listener, minute_reader, derived.clone(), tx);
+        }
+        _ => {
+            println!("Socket name : {}", socket_name);
+            or_exit(Server::create_server_thread(
+                &socket_name,
+                &socket_settings,
+                minute_reader,
+                derived.clone(),
+                tx,
+            ));
+        }
+    }
+    for socket in activated_sockets {
+        if let ActivatedSocket::Tcp(name, _) = socket {
+            println!(
+                "Socket {} passed by systemd is ignored, no listener has this name",
+                name.unwrap_or_default()
+            );
+        }
+    }
     //println!("Enter loop");
     loop {
-        historic_queues[QueuesIndex::MINUTE as usize].add(SensorData::create(
-            &sensor_bmp280_pressure,
-            &sensor_bmp280_temperature,
-            &sensor_htu21_temperature,
-            &sensor_htu21_humidity,
-        ));
-        //println!("nbElements (MINUTE) = {}\tnbElements (HOUR) = {}\tnbElements (DAYS) = {}\n",
-        //historic_queues[QueuesIndex::MINUTE as usize].get_nb_items(),
-        //historic_queues[QueuesIndex::HOUR as usize].get_nb_items(),
-        //historic_queues[QueuesIndex::DAYS as usize].get_nb_items()
-        //);
-        Historic::<SensorData>::reduce(&mut historic_queues);
+        let filtered = sensors
+            .as_ref()
+            .and_then(|sensors| oversampler.start_period(sensors))
+            .map(|reads| sample_filter.filter(&reads, oversampler.combination()));
+        let sample = match filtered {
+            Some(Ok(raw)) => Some(calibration.apply(raw)),
+            Some(Err(why)) => {
+                println!("Sample rejected : {}", why);
+                None
+            }
+            None => None,
+        };
+        if let Some(sensor_data) = sample {
+            for event in alert_engine.update(&sensor_data) {
+                println!(
+                    "Alert {} is {} ({} = {:.3})",
+                    event.name,
+                    event.state.name(),
+                    event.channel,
+                    event.value
+                );
+                forward(&mut notification_tx, "notification", event);
+            }
+            for event in anomaly_detector
+                .iter_mut()
+                .flat_map(|detector| detector.update(&sensor_data))
+            {
+                println!(
+                    "Anomaly {} of {} {} ({:.3}, score {:.2})",
+                    event.kind.name(),
+                    event.channel,
+                    if event.active { "started" } else { "ended" },
+                    event.value,
+                    event.score
+                );
+                subscribers.publish(Event::Anomaly(event));
+            }
+            forward(&mut mqtt_tx, "MQTT", Publication::Sample(sensor_data));
+            forward(&mut influxdb_tx, "InfluxDB", sensor_data);
+            subscribers.publish(Event::Sample(sensor_data));
+            historic_queues[QueuesIndex::MINUTE as usize].add(sensor_data);
+            //println!("nbElements (MINUTE) = {}\tnbElements (HOUR) = {}\tnbElements (DAYS) = {}\n",
+            //historic_queues[QueuesIndex::MINUTE as usize].get_nb_items(),
+            //historic_queues[QueuesIndex::HOUR as usize].get_nb_items(),
+            //historic_queues[QueuesIndex::DAYS as usize].get_nb_items()
+            //);
+            for (index, reduced_data) in Historic::<SensorData>::reduce(&mut historic_queues) {
+                if index == QueuesIndex::HOUR as usize {
+                    summaries.add(&reduced_data);
+                }
+                if let Some(detector) = &mut anomaly_detector {
+                    if index == QueuesIndex::DAYS as usize {
+                        detector.update_reference(
+                            &historic_queues[QueuesIndex::DAYS as usize].to_vec(),
+                        );
+                    }
+                }
+                forward(
+                    &mut mqtt_tx,
+                    "MQTT",
+                    Publication::Reduced(historic::TIER_NAMES[index], reduced_data),
+                );
+                subscribers.publish(Event::Reduced(historic::TIER_NAMES[index], reduced_data));
+            }
+            forward(&mut push_tx, "push", sensor_data);
+        }
 
         // treatSocket(sockfd, historicQueues, QUEUE_NBELEMENTS);
         let now = Instant::now();
         while now.elapsed() <= sampling_duration_ms {
-            match rx.recv_timeout(sampling_duration_ms) {
+            // the spread reads are made while waiting for the requests
+            let timeout = match (&sensors, oversampler.next_read()) {
+                (Some(_), Some(next_read)) => next_read.min(sampling_duration_ms),
+                _ => sampling_duration_ms,
+            };
+            let request = rx.recv_timeout(timeout);
+            if let Some(sensors) = &sensors {
+                oversampler.read_due(sensors);
+            }
+            match request {
                 Err(_ /*err*/) => (), /*println!("no request {}", err) */
-                Ok(mut stream) => {
-                    Historic::<SensorData>::write_json_historics(&historic_queues, &mut stream);
+                Ok((Request::Filter, mut stream)) => {
+                    if let Err(err) = sample_filter.write_json(&mut stream) {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((Request::Trend, mut stream)) => {
+                    let answer = match trend_settings.compute(
+                        &trend_settings
+                            .window_samples(&historic_queues[QueuesIndex::HOUR as usize..]),
+                        &derived,
+                    ) {
+                        Some(trend) => trend.write_json(&mut stream),
+                        None => server::write_json_error(stream, "not enough samples for a trend"),
+                    };
+                    if let Err(err) = answer {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((Request::Metrics, mut stream)) => {
+                    let latest = historic_queues[QueuesIndex::MINUTE as usize].to_vec().pop();
+                    let trend = trend_settings.compute(
+                        &trend_settings
+                            .window_samples(&historic_queues[QueuesIndex::HOUR as usize..]),
+                        &derived,
+                    );
+                    let answer = latest
+                        .map_or(Ok(()), |latest| {
+                            metrics::write_sample_metrics(&latest, &mut stream)
+                        })
+                        .and_then(|_| {
+                            trend.map_or(Ok(()), |trend| trend.write_metrics(&mut stream))
+                        })
+                        .and_then(|_| sample_filter.write_metrics(&mut stream))
+                        .and_then(|_| {
+                            anomaly_detector
+                                .as_ref()
+                                .map_or(Ok(()), |detector| detector.write_metrics(&mut stream))
+                        });
+                    if let Err(err) = answer {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((Request::Anomalies, mut stream)) => {
+                    let answer = match &anomaly_detector {
+                        Some(detector) => detector.write_json(&mut stream),
+                        None => server::write_json_error(stream, "no anomaly detection"),
+                    };
+                    if let Err(err) = answer {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((Request::Summary(period), mut stream)) => {
+                    if let Err(err) = summaries.write_json(period, &mut stream) {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((Request::Alerts, mut stream)) => {
+                    if let Err(err) = alert_engine.write_json(&mut stream) {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((Request::Nodes, mut stream)) => {
+                    let answer = match &collector_nodes {
+                        Some(nodes) => nodes.write_json(&derived, &mut stream),
+                        None => server::write_json_error(stream, "no node pushes its samples here"),
+                    };
+                    if let Err(err) = answer {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((Request::Subscribe { format, reduced }, stream)) => {
+                    if let Err(err) = subscribers.subscribe(stream, format, reduced) {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((Request::Tier(tier), mut stream)) => {
+                    let answer = stream
+                        .write_all(b"[")
+                        .and_then(|_| historic_queues[tier].write_json_chunk(&derived, &mut stream))
+                        .and_then(|_| stream.write_all(b"]\n"));
+                    if let Err(err) = answer {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((Request::Smooth { tier, smoothing }, mut stream)) => {
+                    let samples = smoothing.apply(&historic_queues[tier].to_vec());
+                    let answer = stream
+                        .write_all(b"[")
+                        .and_then(|_| {
+                            json_display::write_json_chunk(&samples, &derived, &mut stream)
+                        })
+                        .and_then(|_| stream.write_all(b"]\n"));
+                    if let Err(err) = answer {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((Request::Range { from, to }, mut stream)) => {
+                    let samples = Historic::select_range(
+                        &historic_queues,
+                        Duration::from_millis(from),
+                        Duration::from_millis(to),
+                        SensorData::get_timestamp,
+                    );
+                    let answer = stream
+                        .write_all(b"[")
+                        .and_then(|_| {
+                            json_display::write_json_chunk(&samples, &derived, &mut stream)
+                        })
+                        .and_then(|_| stream.write_all(b"]\n"));
+                    if let Err(err) = answer {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((
+                    Request::Chart {
+                        tier,
+                        channels,
+                        range,
+                    },
+                    mut stream,
+                )) => {
+                    let range = range
+                        .map(|(from, to)| (Duration::from_millis(from), Duration::from_millis(to)));
+                    let samples = historic_queues[tier].to_vec();
+                    if let Err(err) = chart::write_svg(&samples, &channels, range, &mut stream) {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((Request::LineProtocol(tier), mut stream)) => {
+                    let samples = historic_queues[tier].to_vec();
+                    if let Err(err) = line_format.write_lines(&samples, &mut stream) {
+                        println!("Failed to answer : {}", err);
+                    }
+                }
+                Ok((_, mut stream)) => {
+                    if let Err(err) = Historic::<SensorData>::write_json_historics(
+                        &historic_queues,
+                        &derived,
+                        &mut stream,
+                    ) {
+                        println!("Failed to answer : {}", err);
+                    }
                 }
             }
         }
     }
 }
 
+/// Send an item to a worker thread. The outputs which may block, on the network or on slow
+/// clients, each have their own thread fed through a channel so that they never delay the
+/// sampling, and a thread which stopped is forgotten so that the sampling goes on without it.
+fn forward<T>(tx: &mut Option<Sender<T>>, thread_name: &str, item: T) {
+    if let Some(sender) = tx {
+        if sender.send(item).is_err() {
+            println!(
+                "The {} thread stopped, nothing is sent to it anymore",
+                thread_name
+            );
+            *tx = None;
+        }
+    }
+}
+
+/// Value of a step of the start, or else its error on stderr and the end of the process, a
+/// start which can't complete is a setup mistake rather than a bug
+fn or_exit<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|why| {
+        eprintln!("{}", why);
+        process::exit(1);
+    })
+}
+
+/// Sensors of the board, in the order of `sensor_data::CHANNELS`
+fn probe_sensors() -> Result<Vec<Sensor>, String> {
+    [
+        "/sys/bus/i2c/devices/i2c-1/1-0076/iio:device{}/in_pressure_input",
+        "/sys/bus/i2c/devices/i2c-1/1-0076/iio:device{}/in_temp_input",
+        "/sys/bus/i2c/devices/i2c-1/1-0040/iio:device{}/in_temp_input",
+        "/sys/bus/i2c/devices/i2c-1/1-0040/iio:device{}/in_humidityrelative_input",
+    ]
+    .iter()
+    .map(|path| Sensor::probe(path))
+    .collect()
+}
+
 /* // The expected way I want to write my for loop iteration (no more need to call an explicit constructor)
  * // but currently, IntoIterator didn't compile
 fn print<T: Display>(cb : &CircularBuffer<T>) {
@@ -99,7 +543,7 @@ fn print<T: Display>(cb : &CircularBuffer<T>) {
 */
 
 #[cfg(test)]
-fn print<T: Display + JsonDisplay>(cb: &CircularBuffer<T>) {
+fn print<T: Display>(cb: &CircularBuffer<T>) {
     println!("=================================================");
     for data in cb {
         println!("{}", data);
@@ -107,10 +551,10 @@ fn print<T: Display + JsonDisplay>(cb: &CircularBuffer<T>) {
 }
 
 #[cfg(test)]
-fn write_json<T: Display + JsonDisplay>(cb: &CircularBuffer<T>, w: &mut io::Write) {
-    let _ = w.write(b"[");
-    let _ = cb.write_json_chunk(w);
-    let _ = w.write(b"]\n");
+fn write_json<T: JsonDisplay>(cb: &CircularBuffer<T>, context: &T::Context, w: &mut dyn io::Write) {
+    let _ = w.write_all(b"[");
+    let _ = cb.write_json_chunk(context, w);
+    let _ = w.write_all(b"]\n");
 }
 
 #[test]
@@ -139,7 +583,7 @@ fn test_circ_buff() {
 
     print(&circ_buf);
 
-    match circ_buf.get_item() {
+    match circ_buf.pop_front() {
         Some(data0bis_unwrap) => {
             println!("data0bis = {}", data0bis_unwrap);
             assert_eq!(data0bis_unwrap.get_bmp280_pressure(), 1.0);
@@ -154,5 +598,44 @@ fn test_circ_buff() {
     // circ_buf.put_item(SensorData::create());
     // print(&circ_buf);
 
-    write_json(&circ_buf, &mut io::stdout());
+    write_json(&circ_buf, &DerivedChannels::default(), &mut io::stdout());
+}
+
+#[test]
+fn test_circ_buff_collection_api() {
+    let mut circ_buf: CircularBuffer<u32> = (1..=4).collect();
+    assert_eq!(circ_buf.len(), 4);
+    assert_eq!(circ_buf.capacity(), 4);
+    assert_eq!(circ_buf.push_back(5), Err(5));
+
+    assert_eq!(circ_buf.pop_front(), Some(1));
+    assert_eq!(circ_buf.push_back(5), Ok(()));
+    assert_eq!(circ_buf[0], 2);
+    assert_eq!(circ_buf[3], 5);
+    assert_eq!(
+        circ_buf.iter().rev().copied().collect::<Vec<_>>(),
+        [5, 4, 3, 2]
+    );
+    assert_eq!(circ_buf.iter().len(), 4);
+
+    for item in circ_buf.iter_mut() {
+        *item *= 10;
+    }
+    circ_buf[0] += 1;
+    assert_eq!(
+        circ_buf.iter().copied().collect::<Vec<_>>(),
+        [21, 30, 40, 50]
+    );
+
+    assert_eq!(circ_buf.drain(1..3).collect::<Vec<_>>(), [30, 40]);
+    assert_eq!(circ_buf.iter().copied().collect::<Vec<_>>(), [21, 50]);
+
+    circ_buf.extend(vec![60, 70, 80]);
+    assert_eq!(circ_buf.into_iter().collect::<Vec<_>>(), [21, 50, 60, 70]);
+
+    let mut circ_buf = CircularBuffer::<u32>::new(3);
+    circ_buf.extend(0..3);
+    circ_buf.clear();
+    assert!(circ_buf.is_empty());
+    assert_eq!(circ_buf.pop_front(), None);
 }
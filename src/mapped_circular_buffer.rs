@@ -0,0 +1,498 @@
+use memmap2::{Mmap, MmapMut, MmapOptions};
+use std::{
+    convert::TryInto,
+    fs::{File, OpenOptions},
+    io::{self, Error, ErrorKind},
+    path::Path,
+};
+
+/// Items which can be stored in a `MappedCircularBuffer`: they are serialized in a fixed number
+/// of bytes, in little endian so the file can be read on any machine.
+pub trait FixedSizeRecord: Sized {
+    const RECORD_SIZE: usize;
+    fn write_record(&self, record: &mut [u8]);
+    fn read_record(record: &[u8]) -> Self;
+}
+
+const MAGIC: &[u8; 8] = b"RDMCBUF\0";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 128;
+// The state (position of the items) is stored twice and the copies are updated alternately,
+// so a torn write of the state only loses the last modification.
+const STATE_OFFSETS: [usize; 2] = [24, 56];
+const STATE_SIZE: usize = 32;
+// Every record is followed by a checksum in order to detect torn writes
+const CHECKSUM_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct State {
+    generation: u64,
+    first: u64,
+    valid_items: u64,
+}
+
+enum Mapping {
+    ReadWrite(MmapMut),
+    ReadOnly(Mmap),
+}
+
+/// Bounded FIFO queue stored in a memory-mapped file.
+///
+/// The content survives restarts and the file can be opened read-only by other processes.
+pub struct MappedCircularBuffer<T> {
+    mapping: Mapping,
+    capacity: usize,
+    record_size: usize,
+    state: State,
+    // Kept as function pointers so the buffer can be used without the `FixedSizeRecord` bound
+    write_record: fn(&T, &mut [u8]),
+    read_record: fn(&[u8]) -> T,
+}
+
+impl<T: FixedSizeRecord> MappedCircularBuffer<T> {
+    /// Open the file for reading and writing, it is created if it doesn't exist or is empty
+    pub fn open<P: AsRef<Path>>(path: P, capacity: usize) -> io::Result<MappedCircularBuffer<T>> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let file_size = HEADER_SIZE + capacity * (T::RECORD_SIZE + CHECKSUM_SIZE);
+        let created = file.metadata()?.len() == 0;
+        if created {
+            file.set_len(file_size as u64)?;
+        }
+        let mut map = unsafe { MmapOptions::new().map_mut(&file)? };
+        if created {
+            write_prefix(&mut map, T::RECORD_SIZE, capacity);
+            let state = State {
+                generation: 0,
+                first: 0,
+                valid_items: 0,
+            };
+            write_state(&mut map, &state);
+            map.flush()?;
+        }
+        let mut circular_buffer = MappedCircularBuffer::from_mapping(Mapping::ReadWrite(map))?;
+        if circular_buffer.capacity != capacity {
+            return Err(invalid_data(format!(
+                "the file has a capacity of {} items instead of {}",
+                circular_buffer.capacity, capacity
+            )));
+        }
+        circular_buffer.reload()?;
+        Ok(circular_buffer)
+    }
+
+    /// Open the file of a buffer possibly updated by another process, see `reload`
+    pub fn open_read_only<P: AsRef<Path>>(path: P) -> io::Result<MappedCircularBuffer<T>> {
+        let file = File::open(path)?;
+        let map = unsafe { MmapOptions::new().map(&file)? };
+        MappedCircularBuffer::from_mapping(Mapping::ReadOnly(map))
+    }
+
+    fn from_mapping(mapping: Mapping) -> io::Result<MappedCircularBuffer<T>> {
+        let bytes = match &mapping {
+            Mapping::ReadWrite(map) => &map[..],
+            Mapping::ReadOnly(map) => &map[..],
+        };
+        if bytes.len() < HEADER_SIZE {
+            return Err(invalid_data(format!(
+                "truncated header ({} bytes)",
+                bytes.len()
+            )));
+        }
+        if &bytes[0..8] != MAGIC {
+            return Err(invalid_data("not a circular buffer file".to_string()));
+        }
+        let version = read_u32(bytes, 8);
+        if version != VERSION {
+            return Err(invalid_data(format!("unsupported version {}", version)));
+        }
+        let record_size = read_u32(bytes, 12) as usize;
+        if record_size != T::RECORD_SIZE {
+            return Err(invalid_data(format!(
+                "the file stores records of {} bytes instead of {}",
+                record_size,
+                T::RECORD_SIZE
+            )));
+        }
+        let capacity = read_u64(bytes, 16) as usize;
+        let expected_size = HEADER_SIZE + capacity * (record_size + CHECKSUM_SIZE);
+        if bytes.len() < expected_size {
+            return Err(invalid_data(format!(
+                "truncated file ({} bytes instead of {})",
+                bytes.len(),
+                expected_size
+            )));
+        }
+        let state = read_state(bytes, capacity)?;
+        Ok(MappedCircularBuffer {
+            mapping,
+            capacity,
+            record_size,
+            state,
+            write_record: T::write_record,
+            read_record: T::read_record,
+        })
+    }
+}
+
+impl<T> MappedCircularBuffer<T> {
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.valid_items as usize
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Read again the state written by the process which updates the file
+    pub fn reload(&mut self) -> io::Result<()> {
+        self.state = read_state(self.bytes(), self.capacity)?;
+        Ok(())
+    }
+
+    /// Append an item at the back of the buffer.
+    ///
+    /// The item is handed back when the buffer is full or opened read-only.
+    pub fn push_back(&mut self, item_value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item_value);
+        }
+        let offset = self.record_offset(self.len());
+        let record_size = self.record_size;
+        let write_record = self.write_record;
+        let map = match &mut self.mapping {
+            Mapping::ReadWrite(map) => map,
+            Mapping::ReadOnly(_) => return Err(item_value),
+        };
+        // The record is complete before the state refers to it
+        let record = &mut map[offset..offset + record_size];
+        write_record(&item_value, record);
+        let checksum = checksum(record) as u32;
+        map[offset + record_size..offset + record_size + CHECKSUM_SIZE]
+            .copy_from_slice(&checksum.to_le_bytes());
+        self.commit(State {
+            generation: self.state.generation + 1,
+            first: self.state.first,
+            valid_items: self.state.valid_items + 1,
+        });
+        Ok(())
+    }
+
+    /// Overwrite the newest item, for an item which is updated until it is complete.
+    ///
+    /// A torn write only corrupts this item. The item is handed back when the buffer is empty
+    /// or opened read-only.
+    pub fn replace_back(&mut self, item_value: T) -> Result<(), T> {
+        if self.is_empty() {
+            return Err(item_value);
+        }
+        let offset = self.record_offset(self.len() - 1);
+        let record_size = self.record_size;
+        let write_record = self.write_record;
+        let map = match &mut self.mapping {
+            Mapping::ReadWrite(map) => map,
+            Mapping::ReadOnly(_) => return Err(item_value),
+        };
+        let record = &mut map[offset..offset + record_size];
+        write_record(&item_value, record);
+        let checksum = checksum(record) as u32;
+        map[offset + record_size..offset + record_size + CHECKSUM_SIZE]
+            .copy_from_slice(&checksum.to_le_bytes());
+        if let Err(err) = map.flush() {
+            println!("Failed to flush the mapped circular buffer : {}", err);
+        }
+        Ok(())
+    }
+
+    /// Remove the `nb_items` oldest items, the corrupted ones are skipped
+    pub fn drain_front(&mut self, nb_items: usize) -> Vec<T> {
+        let nb_items = nb_items.min(self.len());
+        if nb_items == 0 || self.is_read_only() {
+            return Vec::new();
+        }
+        let drained_items = (0..nb_items).filter_map(|index| self.get(index)).collect();
+        self.commit(State {
+            generation: self.state.generation + 1,
+            first: (self.state.first + nb_items as u64) % self.capacity as u64,
+            valid_items: self.state.valid_items - nb_items as u64,
+        });
+        drained_items
+    }
+
+    /// Item at the logical position `index` (0 is the oldest), `None` if it is corrupted
+    pub fn get(&self, index: usize) -> Option<T> {
+        if index >= self.len() {
+            return None;
+        }
+        let offset = self.record_offset(index);
+        let bytes = self.bytes();
+        let record = &bytes[offset..offset + self.record_size];
+        let stored_checksum = read_u32(bytes, offset + self.record_size);
+        if checksum(record) as u32 == stored_checksum {
+            Some((self.read_record)(record))
+        } else {
+            None
+        }
+    }
+
+    /// Iterate over the valid items, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = T> + '_ {
+        (0..self.len()).filter_map(move |index| self.get(index))
+    }
+
+    /// Number of items of the buffer whose record is corrupted
+    pub fn nb_corrupted_items(&self) -> usize {
+        self.len() - self.iter().count()
+    }
+
+    fn is_read_only(&self) -> bool {
+        match self.mapping {
+            Mapping::ReadWrite(_) => false,
+            Mapping::ReadOnly(_) => true,
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        match &self.mapping {
+            Mapping::ReadWrite(map) => &map[..],
+            Mapping::ReadOnly(map) => &map[..],
+        }
+    }
+
+    fn record_offset(&self, index: usize) -> usize {
+        let internal_index = (self.state.first as usize + index) % self.capacity;
+        HEADER_SIZE + internal_index * (self.record_size + CHECKSUM_SIZE)
+    }
+
+    fn commit(&mut self, state: State) {
+        if let Mapping::ReadWrite(map) = &mut self.mapping {
+            write_state(map, &state);
+            if let Err(err) = map.flush() {
+                println!("Failed to flush the mapped circular buffer : {}", err);
+            }
+            self.state = state;
+        }
+    }
+}
+
+fn invalid_data(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// FNV-1a hash, enough to detect torn writes
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn write_prefix(map: &mut [u8], record_size: usize, capacity: usize) {
+    map[0..8].copy_from_slice(MAGIC);
+    map[8..12].copy_from_slice(&VERSION.to_le_bytes());
+    map[12..16].copy_from_slice(&(record_size as u32).to_le_bytes());
+    map[16..24].copy_from_slice(&(capacity as u64).to_le_bytes());
+}
+
+fn state_bytes(state: &State) -> [u8; STATE_SIZE] {
+    let mut bytes = [0u8; STATE_SIZE];
+    bytes[0..8].copy_from_slice(&state.generation.to_le_bytes());
+    bytes[8..16].copy_from_slice(&state.first.to_le_bytes());
+    bytes[16..24].copy_from_slice(&state.valid_items.to_le_bytes());
+    let checksum = checksum(&bytes[0..24]);
+    bytes[24..32].copy_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+fn write_state(map: &mut [u8], state: &State) {
+    let offset = STATE_OFFSETS[(state.generation % 2) as usize];
+    map[offset..offset + STATE_SIZE].copy_from_slice(&state_bytes(state));
+}
+
+/// The most recent copy of the state which is not corrupted
+fn read_state(bytes: &[u8], capacity: usize) -> io::Result<State> {
+    STATE_OFFSETS
+        .iter()
+        .filter_map(|&offset| {
+            let state = State {
+                generation: read_u64(bytes, offset),
+                first: read_u64(bytes, offset + 8),
+                valid_items: read_u64(bytes, offset + 16),
+            };
+            let consistent = state_bytes(&state)[..] == bytes[offset..offset + STATE_SIZE]
+                && state.valid_items <= capacity as u64
+                && (state.first < capacity as u64 || capacity == 0);
+            if consistent {
+                Some(state)
+            } else {
+                None
+            }
+        })
+        .max_by_key(|state| state.generation)
+        .ok_or_else(|| invalid_data("no valid state in the header".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FixedSizeRecord, MappedCircularBuffer, HEADER_SIZE, STATE_OFFSETS};
+    use crate::test_utils::TempPath;
+    use std::{
+        convert::TryInto,
+        fs::{self, OpenOptions},
+        io::ErrorKind,
+    };
+
+    impl FixedSizeRecord for u64 {
+        const RECORD_SIZE: usize = 8;
+
+        fn write_record(&self, record: &mut [u8]) {
+            record.copy_from_slice(&self.to_le_bytes());
+        }
+
+        fn read_record(record: &[u8]) -> u64 {
+            u64::from_le_bytes(record.try_into().unwrap())
+        }
+    }
+
+    fn content(circ_buf: &MappedCircularBuffer<u64>) -> Vec<u64> {
+        circ_buf.iter().collect()
+    }
+
+    #[test]
+    fn content_survives_reopening() {
+        let file = TempPath::new("mapped_circular_buffer");
+        {
+            let mut circ_buf = MappedCircularBuffer::<u64>::open(file.path(), 4).unwrap();
+            assert!(circ_buf.is_empty());
+            for value in 0..4 {
+                assert_eq!(circ_buf.push_back(value), Ok(()));
+            }
+            assert_eq!(circ_buf.push_back(4), Err(4));
+            assert_eq!(circ_buf.drain_front(3), [0, 1, 2]);
+            // wrap-around
+            circ_buf.push_back(4).unwrap();
+            circ_buf.push_back(5).unwrap();
+        }
+        let mut circ_buf = MappedCircularBuffer::<u64>::open(file.path(), 4).unwrap();
+        assert_eq!(content(&circ_buf), [3, 4, 5]);
+        circ_buf.push_back(6).unwrap();
+        assert_eq!(content(&circ_buf), [3, 4, 5, 6]);
+        circ_buf.replace_back(7).unwrap();
+        assert_eq!(
+            content(&MappedCircularBuffer::<u64>::open_read_only(file.path()).unwrap()),
+            [3, 4, 5, 7]
+        );
+
+        let error = MappedCircularBuffer::<u64>::open(file.path(), 8)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn read_only_reader_follows_the_writer() {
+        let file = TempPath::new("mapped_circular_buffer");
+        let mut writer = MappedCircularBuffer::<u64>::open(file.path(), 3).unwrap();
+        writer.push_back(1).unwrap();
+        let mut reader = MappedCircularBuffer::<u64>::open_read_only(file.path()).unwrap();
+        assert_eq!(content(&reader), [1]);
+        assert_eq!(reader.push_back(2), Err(2));
+        assert!(reader.drain_front(1).is_empty());
+
+        writer.push_back(2).unwrap();
+        writer.drain_front(1);
+        assert_eq!(content(&reader), [1]);
+        reader.reload().unwrap();
+        assert_eq!(content(&reader), [2]);
+    }
+
+    #[test]
+    fn truncated_files_are_refused() {
+        let file = TempPath::new("mapped_circular_buffer");
+        {
+            let mut circ_buf = MappedCircularBuffer::<u64>::open(file.path(), 4).unwrap();
+            circ_buf.push_back(1).unwrap();
+        }
+        let file_handle = OpenOptions::new().write(true).open(file.path()).unwrap();
+        file_handle.set_len(HEADER_SIZE as u64 + 10).unwrap();
+        let error = MappedCircularBuffer::<u64>::open(file.path(), 4)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+
+        file_handle.set_len(HEADER_SIZE as u64 / 2).unwrap();
+        let error = MappedCircularBuffer::<u64>::open_read_only(file.path())
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+        assert!(error.to_string().contains("truncated header"));
+    }
+
+    #[test]
+    fn torn_state_falls_back_to_previous_state() {
+        let file = TempPath::new("mapped_circular_buffer");
+        let last_state_offset = {
+            let mut circ_buf = MappedCircularBuffer::<u64>::open(file.path(), 4).unwrap();
+            circ_buf.push_back(1).unwrap();
+            circ_buf.push_back(2).unwrap();
+            STATE_OFFSETS[(circ_buf.state.generation % 2) as usize]
+        };
+        let mut bytes = fs::read(file.path()).unwrap();
+        // Only the beginning of the last state update reached the disk
+        bytes[last_state_offset + 16] ^= 0xff;
+        fs::write(file.path(), &bytes).unwrap();
+
+        let circ_buf = MappedCircularBuffer::<u64>::open(file.path(), 4).unwrap();
+        assert_eq!(content(&circ_buf), [1]);
+
+        // Both copies of the state are corrupted
+        let other_state_offset = STATE_OFFSETS[0] + STATE_OFFSETS[1] - last_state_offset;
+        bytes[other_state_offset] ^= 0xff;
+        fs::write(file.path(), &bytes).unwrap();
+        let error = MappedCircularBuffer::<u64>::open(file.path(), 4)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn torn_records_are_skipped() {
+        let file = TempPath::new("mapped_circular_buffer");
+        {
+            let mut circ_buf = MappedCircularBuffer::<u64>::open(file.path(), 4).unwrap();
+            for value in 10..13 {
+                circ_buf.push_back(value).unwrap();
+            }
+        }
+        let mut bytes = fs::read(file.path()).unwrap();
+        // Second record (8 bytes and its checksum) only partially written
+        bytes[HEADER_SIZE + 12 + 3] ^= 0x55;
+        fs::write(file.path(), &bytes).unwrap();
+
+        let mut circ_buf = MappedCircularBuffer::<u64>::open(file.path(), 4).unwrap();
+        assert_eq!(circ_buf.len(), 3);
+        assert_eq!(circ_buf.nb_corrupted_items(), 1);
+        assert_eq!(content(&circ_buf), [10, 12]);
+        assert_eq!(circ_buf.drain_front(2), [10]);
+        assert_eq!(content(&circ_buf), [12]);
+    }
+}
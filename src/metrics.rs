@@ -0,0 +1,91 @@
+use crate::sensor_data::{SensorData, CHANNELS, UNITS};
+use std::io::{self, Write};
+
+/// Prefix of the names of the metrics
+pub const PREFIX: &str = "weather";
+
+/// Content type of the metrics, the text format of Prometheus
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Write a metric with its help and type lines, once per `(labels, value)`
+pub fn write_metric(
+    w: &mut dyn Write,
+    name: &str,
+    kind: &str,
+    help: &str,
+    values: &[(&str, f64)],
+) -> io::Result<()> {
+    writeln!(w, "# HELP {}_{} {}", PREFIX, name, help)?;
+    writeln!(w, "# TYPE {}_{} {}", PREFIX, name, kind)?;
+    for (labels, value) in values {
+        if labels.is_empty() {
+            writeln!(w, "{}_{} {}", PREFIX, name, value)?;
+        } else {
+            writeln!(w, "{}_{}{{{}}} {}", PREFIX, name, labels, value)?;
+        }
+    }
+    Ok(())
+}
+
+/// Gauges of the channels of the latest sample
+pub fn write_sample_metrics(sample: &SensorData, w: &mut dyn Write) -> io::Result<()> {
+    let labels: Vec<String> = CHANNELS
+        .iter()
+        .zip(UNITS.iter())
+        .map(|(channel, unit)| format!("channel=\"{}\",unit=\"{}\"", channel, unit))
+        .collect();
+    let values: Vec<(&str, f64)> = CHANNELS
+        .iter()
+        .zip(labels.iter())
+        .filter_map(|(channel, labels)| {
+            sample
+                .get_channel(channel)
+                .map(|value| (labels.as_str(), value))
+        })
+        .collect();
+    write_metric(w, "channel", "gauge", "Latest value of a channel", &values)?;
+    write_metric(
+        w,
+        "sample_timestamp_seconds",
+        "gauge",
+        "Time of the latest sample",
+        &[("", sample.get_timestamp().as_secs_f64())],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_metric, write_sample_metrics};
+    use crate::sensor_data::SensorData;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn text_format() {
+        let mut text = Vec::new();
+        write_metric(
+            &mut text,
+            "samples_total",
+            "counter",
+            "Samples",
+            &[("result=\"accepted\"", 12.0), ("result=\"rejected\"", 1.0)],
+        )
+        .unwrap();
+        let sample = SensorData::new(
+            UNIX_EPOCH + Duration::from_secs(1000),
+            101.3,
+            20_000,
+            21_000,
+            50_000,
+        );
+        write_sample_metrics(&sample, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.starts_with(
+            "# HELP weather_samples_total Samples\n\
+             # TYPE weather_samples_total counter\n\
+             weather_samples_total{result=\"accepted\"} 12\n\
+             weather_samples_total{result=\"rejected\"} 1\n"
+        ));
+        assert!(text.contains("weather_channel{channel=\"humidity\",unit=\"%\"} 50\n"));
+        assert!(text.ends_with("weather_sample_timestamp_seconds 1000\n"));
+    }
+}
@@ -0,0 +1,786 @@
+use crate::{
+    circular_buffer::CircularBuffer,
+    config::{Config, Section},
+    derived::DerivedChannels,
+    json_display::JsonDisplay,
+    sensor_data::SensorData,
+};
+use std::{
+    io::{self, Error, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread::{spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+const DEFAULT_TOPIC_PREFIX: &str = "rustdatamonitoring";
+const MAX_RECONNECT_DELAY_S: u64 = 300;
+const CONNECT_TIMEOUT_S: u64 = 10;
+
+/// MQTT publication settings, read from the `[mqtt]` section of the configuration:
+/// ```text
+/// [mqtt]
+/// host = 192.168.1.2
+/// port = 1883
+/// client_id = weather-station
+/// username = station           # optional, with password
+/// topic_prefix = home/weather  # default topics : <prefix>/sample, <prefix>/latest (retained),
+///                              # <prefix>/status (retained online/offline, last will)
+///                              # and <prefix>/{tier} for the reduced samples
+/// qos = 1                      # 0 or 1
+/// reduced = true               # also publish the samples produced by the reduction
+/// buffer = 1000                # samples kept while the broker is unreachable
+/// keep_alive = 60s            # 0 disables the keep-alive
+/// discovery = true             # Home Assistant discovery, published under discovery_prefix
+///                              # (homeassistant by default)
+/// ```
+/// Each topic can also be set with `sample_topic`, `latest_topic`, `status_topic` and
+/// `reduced_topic`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MqttSettings {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub sample_topic: String,
+    pub latest_topic: String,
+    pub status_topic: String,
+    pub reduced_topic: Option<String>,
+    pub qos: u8,
+    pub buffer_size: usize,
+    pub keep_alive: Duration,
+    pub discovery_prefix: Option<String>,
+}
+
+/// Message waiting to be published
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+/// What the sampling thread hands to the MQTT thread
+pub enum Publication {
+    Sample(SensorData),
+    /// Sample produced by the reduction of a historic, with the name of the historic receiving it
+    Reduced(&'static str, SensorData),
+}
+
+/// Connection to the broker, only publishing is supported
+struct MqttConnection {
+    stream: TcpStream,
+    next_packet_id: u16,
+    last_packet: Instant,
+}
+
+/// Publishes the messages as long as the broker is reachable, and keeps them in a bounded
+/// buffer (the oldest are dropped) until the next successful connection otherwise.
+pub struct MqttPublisher {
+    settings: MqttSettings,
+    connection: Option<MqttConnection>,
+    pending: CircularBuffer<Message>,
+    connect_messages: Vec<Message>,
+    derived: DerivedChannels,
+    reconnect_delay: Duration,
+    next_connection_attempt: Instant,
+}
+
+impl MqttSettings {
+    pub fn new(host: &str, port: u16, topic_prefix: &str) -> MqttSettings {
+        MqttSettings {
+            host: host.to_string(),
+            port,
+            client_id: DEFAULT_TOPIC_PREFIX.to_string(),
+            username: None,
+            password: None,
+            sample_topic: format!("{}/sample", topic_prefix),
+            latest_topic: format!("{}/latest", topic_prefix),
+            status_topic: format!("{}/status", topic_prefix),
+            reduced_topic: None,
+            qos: 0,
+            buffer_size: 1000,
+            keep_alive: Duration::from_secs(60),
+            discovery_prefix: None,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Result<Option<MqttSettings>, String> {
+        match config.section("mqtt")? {
+            Some(section) => MqttSettings::from_section(section).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn from_section(section: &Section) -> Result<MqttSettings, String> {
+        section.check_keys(&[
+            "host",
+            "port",
+            "client_id",
+            "username",
+            "password",
+            "topic_prefix",
+            "sample_topic",
+            "latest_topic",
+            "status_topic",
+            "reduced_topic",
+            "reduced",
+            "qos",
+            "buffer",
+            "keep_alive",
+            "discovery",
+            "discovery_prefix",
+        ])?;
+        let topic_prefix: String =
+            section.get_or("topic_prefix", DEFAULT_TOPIC_PREFIX.to_string())?;
+        let mut settings = MqttSettings::new(
+            &section.require::<String>("host")?,
+            section.get_or("port", 1883)?,
+            &topic_prefix,
+        );
+        settings.client_id = section.get_or("client_id", settings.client_id)?;
+        settings.username = section.get("username")?;
+        settings.password = section.get("password")?;
+        if settings.password.is_some() && settings.username.is_none() {
+            return Err(section.error("password needs a username"));
+        }
+        settings.sample_topic = section.get_or("sample_topic", settings.sample_topic)?;
+        settings.latest_topic = section.get_or("latest_topic", settings.latest_topic)?;
+        settings.status_topic = section.get_or("status_topic", settings.status_topic)?;
+        settings.reduced_topic = match section.get::<String>("reduced_topic")? {
+            Some(topic) => Some(topic),
+            None if section.get_or("reduced", false)? => Some(format!("{}/{{tier}}", topic_prefix)),
+            None => None,
+        };
+        settings.qos = section.get_or("qos", 0)?;
+        if settings.qos > 1 {
+            return Err(section.error("only qos 0 and 1 are supported"));
+        }
+        settings.buffer_size = section.get_or("buffer", settings.buffer_size)?;
+        if settings.buffer_size == 0 {
+            return Err(section.error("the buffer needs at least 1 sample"));
+        }
+        settings.keep_alive = section
+            .get_duration("keep_alive")?
+            .unwrap_or(settings.keep_alive);
+        if settings.keep_alive.as_secs() > u64::from(u16::MAX) {
+            return Err(section.error("keep_alive is limited to 65535 seconds"));
+        }
+        settings.discovery_prefix = match section.get::<String>("discovery_prefix")? {
+            Some(prefix) => Some(prefix),
+            None if section.get_or("discovery", false)? => Some("homeassistant".to_string()),
+            None => None,
+        };
+        Ok(settings)
+    }
+}
+
+impl MqttConnection {
+    fn connect(settings: &MqttSettings) -> io::Result<MqttConnection> {
+        let mut addresses = (settings.host.as_str(), settings.port).to_socket_addrs()?;
+        let mut stream = loop {
+            let address = addresses.next().ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("no address for {}", settings.host),
+                )
+            })?;
+            match TcpStream::connect_timeout(&address, Duration::from_secs(CONNECT_TIMEOUT_S)) {
+                Ok(stream) => break stream,
+                Err(err) if addresses.len() == 0 => return Err(err),
+                Err(_) => {}
+            }
+        };
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+        stream.write_all(&connect_packet(settings))?;
+        let (packet_type, body) = read_packet(&mut stream)?;
+        if packet_type != CONNACK || body.len() != 2 {
+            return Err(Error::new(ErrorKind::InvalidData, "CONNACK expected"));
+        }
+        if body[1] != 0 {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("connection refused by the broker (code {})", body[1]),
+            ));
+        }
+        Ok(MqttConnection {
+            stream,
+            next_packet_id: 1,
+            last_packet: Instant::now(),
+        })
+    }
+
+    fn publish(&mut self, message: &Message, qos: u8) -> io::Result<()> {
+        let packet_id = self.next_packet_id;
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        self.stream
+            .write_all(&publish_packet(message, qos, packet_id))?;
+        self.last_packet = Instant::now();
+        if qos == 1 {
+            self.wait_for(PUBACK, Some(packet_id))?;
+        }
+        Ok(())
+    }
+
+    fn ping(&mut self) -> io::Result<()> {
+        self.stream.write_all(&[PINGREQ, 0])?;
+        self.last_packet = Instant::now();
+        self.wait_for(PINGRESP, None)
+    }
+
+    fn disconnect(mut self) {
+        let _ = self.stream.write_all(&[DISCONNECT, 0]);
+    }
+
+    /// Read the packets until the expected one, the other packets are ignored
+    fn wait_for(&mut self, expected_type: u8, packet_id: Option<u16>) -> io::Result<()> {
+        loop {
+            let (packet_type, body) = read_packet(&mut self.stream)?;
+            if packet_type == expected_type
+                && (packet_id.is_none()
+                    || body.len() >= 2 && Some(u16::from_be_bytes([body[0], body[1]])) == packet_id)
+            {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl MqttPublisher {
+    pub fn new(settings: MqttSettings) -> MqttPublisher {
+        let pending = CircularBuffer::new(settings.buffer_size);
+        MqttPublisher {
+            settings,
+            connection: None,
+            pending,
+            connect_messages: Vec::new(),
+            derived: DerivedChannels::default(),
+            reconnect_delay: Duration::from_secs(1),
+            next_connection_attempt: Instant::now(),
+        }
+    }
+
+    /// Messages published after the online status at each connection, like the discovery
+    /// configurations
+    pub fn with_connect_messages(mut self, connect_messages: Vec<Message>) -> MqttPublisher {
+        self.connect_messages = connect_messages;
+        self
+    }
+
+    /// Derived channels published with the samples
+    pub fn with_derived_channels(mut self, derived: DerivedChannels) -> MqttPublisher {
+        self.derived = derived;
+        self
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connection.is_some()
+    }
+
+    pub fn nb_pending_messages(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Messages produced by a publication
+    pub fn messages(&self, publication: &Publication) -> Vec<Message> {
+        let json = |data: &SensorData| {
+            let mut payload = Vec::new();
+            let _ = data.json_item(&self.derived, &mut payload);
+            payload
+        };
+        match publication {
+            Publication::Sample(data) => vec![
+                Message {
+                    topic: self.settings.sample_topic.clone(),
+                    payload: json(data),
+                    retain: false,
+                },
+                Message {
+                    topic: self.settings.latest_topic.clone(),
+                    payload: json(data),
+                    retain: true,
+                },
+            ],
+            Publication::Reduced(tier, data) => match &self.settings.reduced_topic {
+                Some(reduced_topic) => vec![Message {
+                    topic: reduced_topic.replace("{tier}", tier),
+                    payload: json(data),
+                    retain: false,
+                }],
+                None => Vec::new(),
+            },
+        }
+    }
+
+    /// Queue the message and publish all the pending messages if the broker is reachable
+    pub fn publish(&mut self, message: Message) {
+        if self.pending.is_full() {
+            self.pending.pop_front();
+        }
+        let _ = self.pending.push_back(message);
+        self.flush();
+    }
+
+    /// Publish the pending messages, (re)connecting if needed
+    pub fn flush(&mut self) {
+        if !self.ensure_connected() {
+            return;
+        }
+        while let Some(message) = self.pending.get(0) {
+            let result = match &mut self.connection {
+                Some(connection) => connection.publish(message, self.settings.qos),
+                None => return,
+            };
+            match result {
+                Ok(()) => {
+                    self.pending.pop_front();
+                }
+                Err(err) => {
+                    println!("MQTT publication failed : {}", err);
+                    self.connection = None;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Keep the connection alive when nothing is published
+    pub fn keep_alive(&mut self) {
+        let ping_needed = match &self.connection {
+            Some(connection) => connection.last_packet.elapsed() >= self.settings.keep_alive / 2,
+            None => false,
+        };
+        if ping_needed {
+            if let Some(Err(err)) = self.connection.as_mut().map(MqttConnection::ping) {
+                println!("MQTT connection lost : {}", err);
+                self.connection = None;
+            }
+        }
+        self.flush();
+    }
+
+    /// Clean disconnection, the broker doesn't publish the last will
+    pub fn disconnect(&mut self) {
+        if let Some(mut connection) = self.connection.take() {
+            let offline = Message {
+                topic: self.settings.status_topic.clone(),
+                payload: b"offline".to_vec(),
+                retain: true,
+            };
+            let _ = connection.publish(&offline, 0);
+            connection.disconnect();
+        }
+    }
+
+    fn ensure_connected(&mut self) -> bool {
+        if self.connection.is_some() {
+            return true;
+        }
+        if Instant::now() < self.next_connection_attempt {
+            return false;
+        }
+        let online = Message {
+            topic: self.settings.status_topic.clone(),
+            payload: b"online".to_vec(),
+            retain: true,
+        };
+        let connect_messages = &self.connect_messages;
+        match MqttConnection::connect(&self.settings).and_then(|mut connection| {
+            connection.publish(&online, 0)?;
+            for message in connect_messages {
+                connection.publish(message, 0)?;
+            }
+            Ok(connection)
+        }) {
+            Ok(connection) => {
+                println!(
+                    "MQTT connected to {}:{}",
+                    self.settings.host, self.settings.port
+                );
+                self.connection = Some(connection);
+                self.reconnect_delay = Duration::from_secs(1);
+                true
+            }
+            Err(err) => {
+                println!(
+                    "MQTT connection failed, next attempt in {:?} : {}",
+                    self.reconnect_delay, err
+                );
+                self.next_connection_attempt = Instant::now() + self.reconnect_delay;
+                self.reconnect_delay =
+                    (self.reconnect_delay * 2).min(Duration::from_secs(MAX_RECONNECT_DELAY_S));
+                false
+            }
+        }
+    }
+}
+
+/// Thread which publishes the received publications and keeps the connection to the broker
+/// alive
+pub fn create_mqtt_thread(
+    settings: MqttSettings,
+    connect_messages: Vec<Message>,
+    derived: DerivedChannels,
+) -> (JoinHandle<()>, Sender<Publication>) {
+    let (tx, rx) = channel::<Publication>();
+    (
+        spawn(move || {
+            let keep_alive = settings.keep_alive;
+            let mut publisher = MqttPublisher::new(settings)
+                .with_connect_messages(connect_messages)
+                .with_derived_channels(derived);
+            publisher.flush();
+            loop {
+                let received = if keep_alive.is_zero() {
+                    rx.recv().map_err(|_| RecvTimeoutError::Disconnected)
+                } else {
+                    rx.recv_timeout(keep_alive / 4)
+                };
+                match received {
+                    Ok(publication) => {
+                        for message in publisher.messages(&publication) {
+                            publisher.publish(message);
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => publisher.keep_alive(),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            publisher.disconnect();
+        }),
+        tx,
+    )
+}
+
+fn push_string(packet: &mut Vec<u8>, bytes: &[u8]) {
+    packet.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    packet.extend_from_slice(bytes);
+}
+
+/// Fixed header followed by the body, with its variable length encoding
+fn packet(first_byte: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![first_byte];
+    let mut remaining_length = body.len();
+    loop {
+        let mut byte = (remaining_length % 128) as u8;
+        remaining_length /= 128;
+        if remaining_length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if remaining_length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+fn connect_packet(settings: &MqttSettings) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_string(&mut body, b"MQTT");
+    // protocol level 3.1.1
+    body.push(4);
+    // clean session, retained last will with qos 0
+    let mut flags = 0x02 | 0x04 | 0x20;
+    if settings.username.is_some() {
+        flags |= 0x80;
+    }
+    if settings.password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&(settings.keep_alive.as_secs() as u16).to_be_bytes());
+    push_string(&mut body, settings.client_id.as_bytes());
+    push_string(&mut body, settings.status_topic.as_bytes());
+    push_string(&mut body, b"offline");
+    if let Some(username) = &settings.username {
+        push_string(&mut body, username.as_bytes());
+    }
+    if let Some(password) = &settings.password {
+        push_string(&mut body, password.as_bytes());
+    }
+    packet(CONNECT, &body)
+}
+
+fn publish_packet(message: &Message, qos: u8, packet_id: u16) -> Vec<u8> {
+    let mut body = Vec::new();
+    push_string(&mut body, message.topic.as_bytes());
+    if qos > 0 {
+        body.extend_from_slice(&packet_id.to_be_bytes());
+    }
+    body.extend_from_slice(&message.payload);
+    packet(PUBLISH | qos << 1 | message.retain as u8, &body)
+}
+
+/// Read a packet, return its first byte and its body
+fn read_packet(stream: &mut dyn Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8];
+    stream.read_exact(&mut byte)?;
+    let first_byte = byte[0];
+    let mut remaining_length = 0usize;
+    let mut multiplier = 1usize;
+    loop {
+        stream.read_exact(&mut byte)?;
+        remaining_length += (byte[0] & 0x7f) as usize * multiplier;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        multiplier *= 128;
+        if multiplier > 128 * 128 * 128 {
+            return Err(Error::new(ErrorKind::InvalidData, "invalid packet length"));
+        }
+    }
+    let mut body = vec![0u8; remaining_length];
+    stream.read_exact(&mut body)?;
+    Ok((first_byte, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        read_packet, Message, MqttPublisher, MqttSettings, Publication, CONNACK, CONNECT,
+        DISCONNECT, PINGREQ, PINGRESP, PUBACK, PUBLISH,
+    };
+    use crate::{config::Config, test_utils::sample};
+    use std::{
+        io::Write,
+        net::{Shutdown, TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+        thread,
+        time::Duration,
+    };
+
+    /// What the fake broker received
+    #[derive(Debug, Default)]
+    struct BrokerLog {
+        client_ids: Vec<String>,
+        wills: Vec<(String, String)>,
+        messages: Vec<(String, String, bool)>,
+        pings: usize,
+        disconnections: usize,
+    }
+
+    fn read_string(body: &[u8], offset: &mut usize) -> String {
+        let length = u16::from_be_bytes([body[*offset], body[*offset + 1]]) as usize;
+        let text = String::from_utf8_lossy(&body[*offset + 2..*offset + 2 + length]).to_string();
+        *offset += 2 + length;
+        text
+    }
+
+    /// In-process broker accepting the connections one after the other, the first connection is
+    /// closed by the broker after `messages_on_first_connection` publications.
+    fn fake_broker(messages_on_first_connection: usize) -> (u16, Arc<Mutex<BrokerLog>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let log = Arc::new(Mutex::new(BrokerLog::default()));
+        let broker_log = Arc::clone(&log);
+        thread::spawn(move || {
+            let mut messages_per_connection = messages_on_first_connection;
+            for stream in listener.incoming() {
+                serve(stream.unwrap(), &broker_log, messages_per_connection);
+                messages_per_connection = usize::MAX;
+            }
+        });
+        (port, log)
+    }
+
+    fn serve(mut stream: TcpStream, log: &Mutex<BrokerLog>, messages_per_connection: usize) {
+        let mut nb_messages = 0;
+        while let Ok((first_byte, body)) = read_packet(&mut stream) {
+            let mut log = log.lock().unwrap();
+            match first_byte & 0xf0 {
+                CONNECT => {
+                    let mut offset = 10;
+                    log.client_ids.push(read_string(&body, &mut offset));
+                    let will_topic = read_string(&body, &mut offset);
+                    let will_message = read_string(&body, &mut offset);
+                    log.wills.push((will_topic, will_message));
+                    stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+                }
+                PUBLISH => {
+                    let qos = (first_byte >> 1) & 3;
+                    let mut offset = 0;
+                    let topic = read_string(&body, &mut offset);
+                    let packet_id = if qos == 1 {
+                        offset += 2;
+                        Some([body[offset - 2], body[offset - 1]])
+                    } else {
+                        None
+                    };
+                    let payload = String::from_utf8_lossy(&body[offset..]).to_string();
+                    log.messages.push((topic, payload, first_byte & 1 == 1));
+                    nb_messages += 1;
+                    // the last message of the connection is never acknowledged
+                    if nb_messages == messages_per_connection {
+                        let _ = stream.shutdown(Shutdown::Both);
+                        return;
+                    }
+                    if let Some([high, low]) = packet_id {
+                        stream.write_all(&[PUBACK, 2, high, low]).unwrap();
+                    }
+                }
+                PINGREQ => {
+                    log.pings += 1;
+                    stream.write_all(&[PINGRESP, 0]).unwrap();
+                }
+                DISCONNECT => {
+                    log.disconnections += 1;
+                    return;
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn message(topic: &str, payload: &str) -> Message {
+        Message {
+            topic: topic.to_string(),
+            payload: payload.as_bytes().to_vec(),
+            retain: false,
+        }
+    }
+
+    #[test]
+    fn publish_samples_with_retained_latest() {
+        let (port, log) = fake_broker(usize::MAX);
+        let mut settings = MqttSettings::new("127.0.0.1", port, "home");
+        settings.qos = 1;
+        settings.reduced_topic = Some("home/{tier}".to_string());
+        let mut publisher = MqttPublisher::new(settings)
+            .with_connect_messages(vec![message("homeassistant/sensor/x/config", "{}")]);
+        for publication in &[
+            Publication::Sample(sample(5)),
+            Publication::Reduced("hour", sample(60)),
+        ] {
+            for message in publisher.messages(publication) {
+                publisher.publish(message);
+            }
+        }
+        assert!(publisher.is_connected());
+        publisher.disconnect();
+        thread::sleep(Duration::from_millis(100));
+
+        let log = log.lock().unwrap();
+        assert_eq!(log.client_ids, ["rustdatamonitoring"]);
+        assert_eq!(
+            log.wills,
+            [("home/status".to_string(), "offline".to_string())]
+        );
+        let topics: Vec<_> = log
+            .messages
+            .iter()
+            .map(|(topic, _, retain)| (topic.as_str(), *retain))
+            .collect();
+        assert_eq!(
+            topics,
+            [
+                ("home/status", true),
+                ("homeassistant/sensor/x/config", false),
+                ("home/sample", false),
+                ("home/latest", true),
+                ("home/hour", false),
+                ("home/status", true)
+            ]
+        );
+        assert!(log.messages[2].1.starts_with("{\"timestamp\": 5000,"));
+        assert_eq!(log.messages[5].1, "offline");
+        assert_eq!(log.disconnections, 1);
+    }
+
+    #[test]
+    fn unacknowledged_messages_are_published_again() {
+        // the broker drops the first connection on the third publication (status included)
+        let (port, log) = fake_broker(3);
+        let mut settings = MqttSettings::new("127.0.0.1", port, "home");
+        settings.qos = 1;
+        let mut publisher = MqttPublisher::new(settings);
+        publisher.publish(message("home/sample", "1"));
+        publisher.publish(message("home/sample", "2"));
+        assert!(!publisher.is_connected());
+        assert_eq!(publisher.nb_pending_messages(), 1);
+        publisher.publish(message("home/sample", "3"));
+        assert!(publisher.is_connected());
+        assert_eq!(publisher.nb_pending_messages(), 0);
+        let log = log.lock().unwrap();
+        let payloads: Vec<_> = log
+            .messages
+            .iter()
+            .map(|(_, payload, _)| payload.as_str())
+            .collect();
+        assert_eq!(payloads, ["online", "1", "2", "online", "2", "3"]);
+    }
+
+    #[test]
+    fn messages_are_buffered_while_the_broker_is_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let mut settings = MqttSettings::new("127.0.0.1", port, "home");
+        settings.buffer_size = 2;
+        let mut publisher = MqttPublisher::new(settings);
+        for payload in ["1", "2", "3"] {
+            publisher.publish(message("home/sample", payload));
+        }
+        assert!(!publisher.is_connected());
+        // only the most recent messages are kept, the reconnection is delayed
+        let payloads: Vec<_> = publisher
+            .pending
+            .iter()
+            .map(|message| message.payload.as_slice())
+            .collect();
+        assert_eq!(payloads, [b"2", b"3"]);
+        assert_eq!(publisher.reconnect_delay, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn keep_alive_pings() {
+        let (port, log) = fake_broker(usize::MAX);
+        let mut settings = MqttSettings::new("127.0.0.1", port, "home");
+        settings.keep_alive = Duration::from_millis(20);
+        let mut publisher = MqttPublisher::new(settings);
+        publisher.flush();
+        thread::sleep(Duration::from_millis(30));
+        publisher.keep_alive();
+        assert!(publisher.is_connected());
+        assert_eq!(log.lock().unwrap().pings, 1);
+    }
+
+    #[test]
+    fn settings_from_config() {
+        let config = Config::parse(
+            "[mqtt]\nhost = broker\ntopic_prefix = home/weather\nqos = 1\nreduced = true\n",
+        )
+        .unwrap();
+        let settings = MqttSettings::from_config(&config).unwrap().unwrap();
+        assert_eq!(settings.port, 1883);
+        assert_eq!(settings.latest_topic, "home/weather/latest");
+        assert_eq!(
+            settings.reduced_topic,
+            Some("home/weather/{tier}".to_string())
+        );
+        assert_eq!(settings.qos, 1);
+
+        let config = Config::parse("[mqtt]\nhost = broker\nqos = 2\n").unwrap();
+        assert_eq!(
+            MqttSettings::from_config(&config).unwrap_err(),
+            "line 1: section [mqtt]: only qos 0 and 1 are supported"
+        );
+        let config = Config::parse("[mqtt]\nhost = broker\nbuffer = 0\n").unwrap();
+        assert_eq!(
+            MqttSettings::from_config(&config).unwrap_err(),
+            "line 1: section [mqtt]: the buffer needs at least 1 sample"
+        );
+        assert_eq!(MqttSettings::from_config(&Config::default()), Ok(None));
+    }
+}
@@ -0,0 +1,560 @@
+use crate::{
+    alert::{AlertEvent, AlertState},
+    config::{Config, Section},
+    http::{self, HttpUrl},
+    json_display::JsonDisplay,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::Write,
+    os::unix::net::UnixDatagram,
+    process::{self, Command, Stdio},
+    sync::mpsc::{channel, Sender},
+    thread::{sleep, spawn, JoinHandle},
+    time::{Duration, Instant},
+};
+
+const DEFAULT_TIMEOUT_S: u64 = 30;
+const DEFAULT_SYSLOG_SOCKET: &str = "/dev/log";
+// Registered enterprise number used in the structured data of the syslog messages
+const SYSLOG_SD_ID: &str = "alert@32473";
+
+/// Where the alerts are delivered
+#[derive(Debug, PartialEq)]
+pub enum Backend {
+    /// Local command receiving the alert as JSON on its standard input
+    Command {
+        program: String,
+        args: Vec<String>,
+        timeout: Duration,
+    },
+    /// The alert is POSTed as JSON
+    Webhook { url: HttpUrl, timeout: Duration },
+    /// RFC 5424 message with structured data, understood by syslog daemons and journald
+    Syslog { socket_path: String },
+}
+
+/// Result of a notification which didn't fail
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Delivered,
+    /// The state of the alert is not delivered by this notifier
+    Filtered,
+    /// The same alert in the same state was already delivered recently
+    Duplicate,
+    RateLimited,
+}
+
+/// Notifier read from a `[notifier <name>]` section of the configuration:
+/// ```text
+/// [notifier ops]
+/// type = webhook                  # or command (with command = ...), or syslog (socket = ...)
+/// url = http://192.168.1.10:8080/alerts
+/// states = firing resolved        # states which are delivered
+/// retries = 3                     # attempts after a failure, retry_delay apart
+/// retry_delay = 10s
+/// dedup = 30m                     # an alert in the same state is delivered once per 30 minutes
+/// rate_limit = 10                 # at most 10 notifications per rate_period
+/// rate_period = 1h
+/// ```
+pub struct Notifier {
+    name: String,
+    backend: Backend,
+    states: Vec<String>,
+    retries: u32,
+    retry_delay: Duration,
+    dedup: Duration,
+    rate_limit: Option<(usize, Duration)>,
+    // timestamp of the last delivery of each (alert, state)
+    last_deliveries: HashMap<(String, &'static str), Duration>,
+    // timestamps of the deliveries inside the rate limit period
+    recent_deliveries: VecDeque<Duration>,
+}
+
+impl Notifier {
+    pub fn new(name: &str, backend: Backend) -> Notifier {
+        Notifier {
+            name: name.to_string(),
+            backend,
+            states: vec!["firing".to_string(), "resolved".to_string()],
+            retries: 0,
+            retry_delay: Duration::from_secs(0),
+            dedup: Duration::from_secs(0),
+            rate_limit: None,
+            last_deliveries: HashMap::new(),
+            recent_deliveries: VecDeque::new(),
+        }
+    }
+
+    pub fn from_section(section: &Section) -> Result<Notifier, String> {
+        section.check_keys(&[
+            "type",
+            "command",
+            "url",
+            "socket",
+            "timeout",
+            "states",
+            "retries",
+            "retry_delay",
+            "dedup",
+            "rate_limit",
+            "rate_period",
+        ])?;
+        let timeout = section
+            .get_duration("timeout")?
+            .unwrap_or_else(|| Duration::from_secs(DEFAULT_TIMEOUT_S));
+        let kind: String = section.require("type")?;
+        let backend = match kind.as_str() {
+            "command" => {
+                let command: String = section.require("command")?;
+                let mut words = command.split_whitespace().map(str::to_string);
+                match words.next() {
+                    Some(program) => Backend::Command {
+                        program,
+                        args: words.collect(),
+                        timeout,
+                    },
+                    None => return Err(section.error("empty command")),
+                }
+            }
+            "webhook" => Backend::Webhook {
+                url: HttpUrl::parse(&section.require::<String>("url")?)
+                    .map_err(|why| section.error(&why))?,
+                timeout,
+            },
+            "syslog" => Backend::Syslog {
+                socket_path: section.get_or("socket", DEFAULT_SYSLOG_SOCKET.to_string())?,
+            },
+            _ => {
+                return Err(section.error(&format!(
+                    "unknown type {}, expected command, webhook or syslog",
+                    kind
+                )))
+            }
+        };
+        let mut notifier = Notifier::new(section.name()?, backend);
+        if let Some(states) = section.get_str("states") {
+            notifier.states = states.split_whitespace().map(str::to_string).collect();
+            let known_states = ["pending", "firing", "resolved", "inactive"];
+            if let Some(state) = notifier
+                .states
+                .iter()
+                .find(|state| !known_states.contains(&state.as_str()))
+            {
+                return Err(section.error(&format!("unknown alert state {}", state)));
+            }
+        }
+        notifier.retries = section.get_or("retries", 0)?;
+        notifier.retry_delay = section.get_duration("retry_delay")?.unwrap_or_default();
+        notifier.dedup = section.get_duration("dedup")?.unwrap_or_default();
+        if let Some(rate_limit) = section.get::<usize>("rate_limit")? {
+            let rate_period = section
+                .get_duration("rate_period")?
+                .unwrap_or_else(|| Duration::from_secs(3600));
+            notifier.rate_limit = Some((rate_limit, rate_period));
+        }
+        Ok(notifier)
+    }
+
+    /// Deliver the event unless it is filtered, deduplicated or rate limited.
+    ///
+    /// A failed delivery is retried, the error of the last attempt is returned.
+    pub fn notify(&mut self, event: &AlertEvent) -> Result<Outcome, String> {
+        let state = event.state.name();
+        if !self.states.iter().any(|delivered| delivered == state) {
+            return Ok(Outcome::Filtered);
+        }
+        let key = (event.name.clone(), state);
+        if let Some(&last_delivery) = self.last_deliveries.get(&key) {
+            if event.timestamp < last_delivery + self.dedup {
+                return Ok(Outcome::Duplicate);
+            }
+        }
+        if let Some((rate_limit, rate_period)) = self.rate_limit {
+            while let Some(&oldest) = self.recent_deliveries.front() {
+                if oldest + rate_period <= event.timestamp {
+                    self.recent_deliveries.pop_front();
+                } else {
+                    break;
+                }
+            }
+            if self.recent_deliveries.len() >= rate_limit {
+                return Ok(Outcome::RateLimited);
+            }
+        }
+
+        let mut attempt = 0;
+        loop {
+            match self.deliver(event) {
+                Ok(()) => break,
+                Err(why) if attempt >= self.retries => {
+                    return Err(format!("{} (after {} attempts)", why, attempt + 1))
+                }
+                Err(why) => {
+                    println!("Notifier {} failed, retrying : {}", self.name, why);
+                    attempt += 1;
+                    sleep(self.retry_delay);
+                }
+            }
+        }
+        self.last_deliveries.insert(key, event.timestamp);
+        if self.rate_limit.is_some() {
+            self.recent_deliveries.push_back(event.timestamp);
+        }
+        Ok(Outcome::Delivered)
+    }
+
+    fn deliver(&self, event: &AlertEvent) -> Result<(), String> {
+        let mut json = Vec::new();
+        event
+            .json_item(&(), &mut json)
+            .map_err(|why| why.to_string())?;
+        match &self.backend {
+            Backend::Command {
+                program,
+                args,
+                timeout,
+            } => run_command(program, args, &json, *timeout),
+            Backend::Webhook { url, timeout } => {
+                match http::post(url, "application/json", &json, *timeout) {
+                    Ok(response) if (200..300).contains(&response.status) => Ok(()),
+                    Ok(response) => Err(format!("HTTP status {}", response.status)),
+                    Err(why) => Err(why.to_string()),
+                }
+            }
+            Backend::Syslog { socket_path } => {
+                let socket = UnixDatagram::unbound().map_err(|why| why.to_string())?;
+                socket
+                    .send_to(syslog_message(event).as_bytes(), socket_path)
+                    .map(|_| ())
+                    .map_err(|why| format!("{}: {}", socket_path, why))
+            }
+        }
+    }
+}
+
+pub fn notifiers_from_config(config: &Config) -> Result<Vec<Notifier>, String> {
+    let mut notifiers: Vec<Notifier> = Vec::new();
+    for section in config.sections("notifier") {
+        let notifier = Notifier::from_section(section)?;
+        if notifiers.iter().any(|other| other.name == notifier.name) {
+            return Err(section.error("a notifier with this name is already defined"));
+        }
+        notifiers.push(notifier);
+    }
+    Ok(notifiers)
+}
+
+/// Thread which delivers the events sent through the returned channel to every notifier,
+/// with their retries
+pub fn create_notification_thread(
+    mut notifiers: Vec<Notifier>,
+) -> (JoinHandle<()>, Sender<AlertEvent>) {
+    let (tx, rx) = channel::<AlertEvent>();
+    (
+        spawn(move || {
+            for event in rx {
+                for notifier in &mut notifiers {
+                    match notifier.notify(&event) {
+                        Ok(Outcome::Delivered) | Ok(Outcome::Filtered) => (),
+                        Ok(outcome) => println!(
+                            "Notifier {} skipped alert {} : {:?}",
+                            notifier.name, event.name, outcome
+                        ),
+                        Err(why) => println!("Notifier {} failed : {}", notifier.name, why),
+                    }
+                }
+            }
+        }),
+        tx,
+    )
+}
+
+fn run_command(
+    program: &str,
+    args: &[String],
+    input: &[u8],
+    timeout: Duration,
+) -> Result<(), String> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|why| format!("couldn't run {}: {}", program, why))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // the command may not read its input, it is not an error
+        let _ = stdin.write_all(input);
+    }
+    let start = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return Ok(()),
+            Ok(Some(status)) => return Err(format!("{} failed: {}", program, status)),
+            Ok(None) if start.elapsed() > timeout => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("{} timed out", program));
+            }
+            Ok(None) => sleep(Duration::from_millis(10)),
+            Err(why) => return Err(why.to_string()),
+        }
+    }
+}
+
+fn syslog_message(event: &AlertEvent) -> String {
+    // facility daemon (3)
+    let severity = match event.state {
+        AlertState::Firing { .. } => 4,
+        AlertState::Resolved { .. } => 5,
+        AlertState::Pending { .. } | AlertState::Inactive => 6,
+    };
+    let escape = |text: &str| {
+        text.replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace(']', "\\]")
+    };
+    format!(
+        "<{}>1 - - rustdatamonitoring {} alert [{} name=\"{}\" channel=\"{}\" state=\"{}\" value=\"{:.3}\" timestamp=\"{}\"] Alert {} is {} ({} = {:.3})",
+        3 * 8 + severity,
+        process::id(),
+        SYSLOG_SD_ID,
+        escape(&event.name),
+        event.channel,
+        event.state.name(),
+        event.value,
+        event.timestamp.as_millis(),
+        event.name,
+        event.state.name(),
+        event.channel,
+        event.value
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{notifiers_from_config, Backend, Notifier, Outcome};
+    use crate::{
+        alert::{AlertEvent, AlertState},
+        config::Config,
+        http::HttpUrl,
+        test_utils::{serve_http, TempPath},
+    };
+    use std::{
+        fs,
+        net::TcpListener,
+        os::unix::{fs::PermissionsExt, net::UnixDatagram},
+        time::Duration,
+    };
+
+    fn event(name: &str, state: AlertState, seconds: u64) -> AlertEvent {
+        AlertEvent {
+            name: name.to_string(),
+            channel: "humidity".to_string(),
+            state,
+            value: 85.0,
+            timestamp: Duration::from_secs(seconds),
+        }
+    }
+
+    fn firing(seconds: u64) -> AlertState {
+        AlertState::Firing {
+            since: Duration::from_secs(seconds),
+        }
+    }
+
+    /// Shell script which appends its input to `output` and exits with `exit_code`
+    fn script(output: &TempPath, exit_code: i32) -> TempPath {
+        let script = TempPath::new("notifier_script");
+        fs::write(
+            script.path(),
+            format!(
+                "#!/bin/sh\ncat >> {}\nexit {}\n",
+                output.path().display(),
+                exit_code
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(script.path(), fs::Permissions::from_mode(0o755)).unwrap();
+        script
+    }
+
+    fn command_notifier(script: &TempPath) -> Notifier {
+        Notifier::new(
+            "script",
+            Backend::Command {
+                program: script.path().display().to_string(),
+                args: Vec::new(),
+                timeout: Duration::from_secs(10),
+            },
+        )
+    }
+
+    #[test]
+    fn command_receives_json_on_stdin() {
+        let output = TempPath::new("notifier_output");
+        let script = script(&output, 0);
+        let mut notifier = command_notifier(&script);
+        assert_eq!(
+            notifier.notify(&event("humid", firing(60), 60)),
+            Ok(Outcome::Delivered)
+        );
+        assert_eq!(
+            fs::read_to_string(output.path()).unwrap(),
+            "{\"name\": \"humid\", \"channel\": \"humidity\", \"state\": \"firing\", \"value\": 85.000, \"timestamp\": 60000}\n"
+        );
+    }
+
+    #[test]
+    fn failed_command_is_retried() {
+        let output = TempPath::new("notifier_output");
+        let script = script(&output, 1);
+        let mut notifier = command_notifier(&script);
+        notifier.retries = 2;
+        let error = notifier.notify(&event("humid", firing(0), 0)).unwrap_err();
+        assert!(error.ends_with("(after 3 attempts)"), "{}", error);
+        assert_eq!(
+            fs::read_to_string(output.path()).unwrap().lines().count(),
+            3
+        );
+    }
+
+    #[test]
+    fn duplicates_and_rate_limit() {
+        let output = TempPath::new("notifier_output");
+        let script = script(&output, 0);
+        let mut notifier = command_notifier(&script);
+        notifier.dedup = Duration::from_secs(600);
+        notifier.rate_limit = Some((2, Duration::from_secs(3600)));
+
+        let pending = AlertState::Pending {
+            since: Duration::from_secs(0),
+        };
+        assert_eq!(
+            notifier.notify(&event("a", pending, 0)),
+            Ok(Outcome::Filtered)
+        );
+        assert_eq!(
+            notifier.notify(&event("a", firing(0), 0)),
+            Ok(Outcome::Delivered)
+        );
+        assert_eq!(
+            notifier.notify(&event("a", firing(300), 300)),
+            Ok(Outcome::Duplicate)
+        );
+        assert_eq!(
+            notifier.notify(&event("b", firing(300), 300)),
+            Ok(Outcome::Delivered)
+        );
+        assert_eq!(
+            notifier.notify(&event("a", firing(900), 900)),
+            Ok(Outcome::RateLimited)
+        );
+        assert_eq!(
+            notifier.notify(&event("a", firing(3600), 3600)),
+            Ok(Outcome::Delivered)
+        );
+        assert_eq!(
+            fs::read_to_string(output.path()).unwrap().lines().count(),
+            3
+        );
+    }
+
+    #[test]
+    fn webhook_is_retried_until_accepted() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = serve_http(
+            listener,
+            vec![
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+                "HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n",
+            ],
+        );
+
+        let mut notifier = Notifier::new(
+            "hook",
+            Backend::Webhook {
+                url: HttpUrl::parse(&format!("http://127.0.0.1:{}/alerts", port)).unwrap(),
+                timeout: Duration::from_secs(5),
+            },
+        );
+        notifier.retries = 1;
+        assert_eq!(
+            notifier.notify(&event("humid", firing(5), 5)),
+            Ok(Outcome::Delivered)
+        );
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].body.starts_with(b"{\"name\": \"humid\""));
+    }
+
+    #[test]
+    fn syslog_structured_message() {
+        let socket_path = TempPath::new("notifier_syslog");
+        let socket = UnixDatagram::bind(socket_path.path()).unwrap();
+        let mut notifier = Notifier::new(
+            "log",
+            Backend::Syslog {
+                socket_path: socket_path.path().display().to_string(),
+            },
+        );
+        let resolved = AlertState::Resolved {
+            at: Duration::from_secs(7),
+        };
+        assert_eq!(
+            notifier.notify(&event("hum\"id", resolved, 7)),
+            Ok(Outcome::Delivered)
+        );
+        let mut message = [0; 1024];
+        let length = socket.recv(&mut message).unwrap();
+        let message = String::from_utf8_lossy(&message[..length]);
+        assert!(
+            message.starts_with("<29>1 - - rustdatamonitoring "),
+            "{}",
+            message
+        );
+        assert!(message.contains(
+            "[alert@32473 name=\"hum\\\"id\" channel=\"humidity\" state=\"resolved\" value=\"85.000\" timestamp=\"7000\"]"
+        ), "{}", message);
+    }
+
+    #[test]
+    fn notifiers_from_config_file() {
+        let config = Config::parse(
+            "[notifier hook]\ntype = webhook\nurl = http://localhost:8080/a\nretries = 2\n\
+             [notifier log]\ntype = syslog\nstates = firing\nrate_limit = 5\n",
+        )
+        .unwrap();
+        let notifiers = notifiers_from_config(&config).unwrap();
+        assert_eq!(notifiers.len(), 2);
+        assert_eq!(notifiers[0].retries, 2);
+        assert_eq!(
+            notifiers[1].backend,
+            Backend::Syslog {
+                socket_path: "/dev/log".to_string()
+            }
+        );
+        assert_eq!(
+            notifiers[1].rate_limit,
+            Some((5, Duration::from_secs(3600)))
+        );
+
+        let error = |content: &str| {
+            notifiers_from_config(&Config::parse(content).unwrap())
+                .err()
+                .unwrap()
+        };
+        assert_eq!(
+            error("[notifier a]\ntype = mail"),
+            "line 1: section [notifier a]: unknown type mail, expected command, webhook or syslog"
+        );
+        assert_eq!(
+            error("[notifier a]\ntype = webhook\nurl = https://example.com"),
+            "line 1: section [notifier a]: only http:// URLs are supported : https://example.com"
+        );
+        assert_eq!(
+            error("[notifier a]\ntype = syslog\nstates = fired"),
+            "line 1: section [notifier a]: unknown alert state fired"
+        );
+    }
+}
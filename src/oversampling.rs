@@ -0,0 +1,305 @@
+use crate::{
+    average::Average,
+    config::Config,
+    sensor_data::{SensorData, CHANNELS},
+    sensors::Sensor,
+};
+use std::time::{Duration, Instant};
+
+/// How the reads of a channel are combined into the value of the sample
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Combination {
+    Mean,
+    Median,
+}
+
+/// Reads of each channel during a sampling period, in the order of `CHANNELS`. A read only
+/// holds the value of its channel.
+pub type Reads = [Vec<SensorData>; 4];
+
+/// Reads of the sensors in each sampling period, from the `[oversampling]` section of the
+/// configuration:
+/// ```text
+/// [oversampling]
+/// reads = 4           # reads of each sensor per sample
+/// humidity = 8        # reads of the sensor of a channel, instead of reads
+/// spread = true       # reads spread over the sampling period, instead of a burst at its start
+/// combine = median    # mean (default) or median of the reads
+/// ```
+/// Spread reads give the sample of a period at the start of the next one, so that the samples
+/// keep the timestamps of the sampling schedule.
+pub struct Oversampler {
+    /// Number of reads of each channel per period
+    reads: [usize; 4],
+    spread: bool,
+    combination: Combination,
+    period: Duration,
+    start: Instant,
+    /// Number of reads of each channel made since the start of the period
+    made: [usize; 4],
+    pending: Reads,
+}
+
+impl Combination {
+    /// Value of a channel (index in `CHANNELS`) combining its reads, `Average` gives the mean
+    pub fn combine(&self, reads: &[SensorData], channel: usize) -> f64 {
+        let name = CHANNELS[channel];
+        match self {
+            Combination::Mean if reads.is_empty() => f64::NAN,
+            Combination::Mean => {
+                let mut cumulated = SensorData::empty_cumulator();
+                for read in reads {
+                    read.cumulate(&mut cumulated);
+                }
+                SensorData::divide(&cumulated, reads.len())
+                    .get_channel(name)
+                    .unwrap_or(f64::NAN)
+            }
+            Combination::Median => {
+                let mut values: Vec<f64> = reads
+                    .iter()
+                    .filter_map(|read| read.get_channel(name))
+                    .collect();
+                median(&mut values)
+            }
+        }
+    }
+}
+
+/// Median of values, NaN when there is none
+pub fn median(values: &mut [f64]) -> f64 {
+    if values.is_empty() {
+        return f64::NAN;
+    }
+    values.sort_by(f64::total_cmp);
+    let middle = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    }
+}
+
+impl Oversampler {
+    /// One read of each sensor at the start of each period
+    pub fn new(period: Duration) -> Oversampler {
+        Oversampler {
+            reads: [1; 4],
+            spread: false,
+            combination: Combination::Mean,
+            period,
+            start: Instant::now(),
+            made: [0; 4],
+            pending: Default::default(),
+        }
+    }
+
+    pub fn from_config(config: &Config, period: Duration) -> Result<Oversampler, String> {
+        let mut oversampler = Oversampler::new(period);
+        // `median = <reads>` of the [filter] section, from before the [oversampling] section
+        if let Some(section) = config.section("filter")? {
+            if let Some(reads) = section.get::<usize>("median")? {
+                if reads == 0 {
+                    return Err(section.error("median needs at least 1 read"));
+                }
+                println!(
+                    "{}",
+                    section.error(&format!(
+                        "median is deprecated, use reads = {} and combine = median in the [oversampling] section",
+                        reads
+                    ))
+                );
+                oversampler.reads = [reads; 4];
+                oversampler.combination = Combination::Median;
+            }
+        }
+        let section = match config.section("oversampling")? {
+            Some(section) => section,
+            None => return Ok(oversampler),
+        };
+        let mut keys = CHANNELS.to_vec();
+        keys.extend(["reads", "spread", "combine"]);
+        section.check_keys(&keys)?;
+        let reads = section.get_or("reads", oversampler.reads[0])?;
+        for (channel, channel_reads) in CHANNELS.iter().zip(oversampler.reads.iter_mut()) {
+            *channel_reads = section.get_or(channel, reads)?;
+            if *channel_reads == 0 {
+                return Err(section.error(&format!("{} needs at least 1 read", channel)));
+            }
+        }
+        oversampler.spread = section.get_or("spread", false)?;
+        oversampler.combination = match section.get_str("combine") {
+            None => oversampler.combination,
+            Some("mean") => Combination::Mean,
+            Some("median") => Combination::Median,
+            Some(other) => {
+                return Err(section.error(&format!(
+                    "unknown combination {}, expected mean or median",
+                    other
+                )))
+            }
+        };
+        Ok(oversampler)
+    }
+
+    pub fn combination(&self) -> Combination {
+        self.combination
+    }
+
+    /// Start a sampling period, give the reads of the sample to store if any: the reads just
+    /// made for a burst, the reads of the previous period when they are spread
+    pub fn start_period(&mut self, sensors: &[Sensor]) -> Option<Reads> {
+        let previous = std::mem::take(&mut self.pending);
+        self.start = Instant::now();
+        self.made = [0; 4];
+        self.read_due(sensors);
+        if self.spread {
+            Some(previous).filter(|reads| reads.iter().all(|reads| !reads.is_empty()))
+        } else {
+            Some(std::mem::take(&mut self.pending))
+        }
+    }
+
+    /// Make the reads whose time has come, the failed reads are dropped
+    pub fn read_due(&mut self, sensors: &[Sensor]) {
+        let elapsed = self.start.elapsed();
+        for (channel, sensor) in sensors.iter().enumerate().take(CHANNELS.len()) {
+            while self
+                .read_offset(channel, self.made[channel])
+                .is_some_and(|offset| offset <= elapsed)
+            {
+                match SensorData::read(sensor, channel) {
+                    Ok(read) => self.pending[channel].push(read),
+                    Err(why) => println!("Read of {} failed : {}", CHANNELS[channel], why),
+                }
+                self.made[channel] += 1;
+            }
+        }
+    }
+
+    /// Time until the next read of the period
+    pub fn next_read(&self) -> Option<Duration> {
+        (0..CHANNELS.len())
+            .filter_map(|channel| self.read_offset(channel, self.made[channel]))
+            .min()
+            .map(|offset| offset.saturating_sub(self.start.elapsed()))
+    }
+
+    /// Offset in the period of a read of a channel, none after its last read
+    fn read_offset(&self, channel: usize, read: usize) -> Option<Duration> {
+        let reads = self.reads[channel];
+        if read >= reads {
+            None
+        } else if self.spread {
+            Some(self.period * read as u32 / reads as u32)
+        } else {
+            Some(Duration::ZERO)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{median, Combination, Oversampler};
+    use crate::{config::Config, sensor_data::SensorData, sensors::Sensor, test_utils::TempPath};
+    use std::{
+        fs,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    fn read(humidity: i32) -> SensorData {
+        SensorData::new(UNIX_EPOCH, 0.0, 0, 0, humidity)
+    }
+
+    #[test]
+    fn combinations() {
+        let reads = [read(50_000), read(51_000), read(56_000)];
+        assert_eq!(Combination::Mean.combine(&reads, 3), 52.333);
+        assert_eq!(Combination::Median.combine(&reads, 3), 51.0);
+        assert!(Combination::Mean.combine(&[], 3).is_nan());
+        assert_eq!(median(&mut [4.0, 1.0, 3.0, 2.0]), 2.5);
+    }
+
+    #[test]
+    fn read_schedule() {
+        let period = Duration::from_secs(5);
+        let config = Config::parse(
+            "[oversampling]\nreads = 2\nhumidity = 5\nspread = true\ncombine = median\n",
+        )
+        .unwrap();
+        let oversampler = Oversampler::from_config(&config, period).unwrap();
+        assert_eq!(oversampler.reads, [2, 2, 2, 5]);
+        assert_eq!(oversampler.combination(), Combination::Median);
+        assert_eq!(
+            oversampler.read_offset(0, 1),
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(oversampler.read_offset(3, 4), Some(Duration::from_secs(4)));
+        assert_eq!(oversampler.read_offset(3, 5), None);
+        let oversampler = Oversampler::new(period);
+        assert_eq!(oversampler.read_offset(0, 0), Some(Duration::ZERO));
+        assert_eq!(oversampler.read_offset(0, 1), None);
+        assert_eq!(oversampler.next_read(), Some(Duration::ZERO));
+
+        let error =
+            |text: &str| Oversampler::from_config(&Config::parse(text).unwrap(), period).err();
+        assert_eq!(
+            error("[oversampling]\ncombine = max\n"),
+            Some(
+                "line 1: section [oversampling]: unknown combination max, expected mean or median"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error("[oversampling]\npressure = 0\n"),
+            Some("line 1: section [oversampling]: pressure needs at least 1 read".to_string())
+        );
+    }
+
+    #[test]
+    fn deprecated_filter_median() {
+        let period = Duration::from_secs(5);
+        let oversampler =
+            |text: &str| Oversampler::from_config(&Config::parse(text).unwrap(), period).unwrap();
+        let legacy = oversampler("[filter]\nmedian = 3\n");
+        assert_eq!(legacy.reads, [3; 4]);
+        assert_eq!(legacy.combination(), Combination::Median);
+        // the [oversampling] section wins
+        let both =
+            oversampler("[filter]\nmedian = 3\n[oversampling]\nhumidity = 1\ncombine = mean\n");
+        assert_eq!(both.reads, [3, 3, 3, 1]);
+        assert_eq!(both.combination(), Combination::Mean);
+        assert_eq!(
+            Oversampler::from_config(&Config::parse("[filter]\nmedian = 0\n").unwrap(), period)
+                .err(),
+            Some("line 1: section [filter]: median needs at least 1 read".to_string())
+        );
+    }
+
+    #[test]
+    fn burst_reads() {
+        let sysfs = TempPath::new("oversampling");
+        fs::create_dir_all(sysfs.path().join("iio:device0")).unwrap();
+        let sensors: Vec<Sensor> = ["101.325", "21370", "20500", "48200"]
+            .iter()
+            .enumerate()
+            .map(|(index, value)| {
+                let path = sysfs.path().join(format!("iio:device0/in_{}", index));
+                fs::write(&path, value).unwrap();
+                Sensor::probe(path.to_str().unwrap()).unwrap()
+            })
+            .collect();
+        let config = Config::parse("[oversampling]\nreads = 3\nhumidity = 1\n").unwrap();
+        let mut oversampler = Oversampler::from_config(&config, Duration::from_secs(5)).unwrap();
+        let reads = oversampler.start_period(&sensors).unwrap();
+        assert_eq!(reads.iter().map(Vec::len).collect::<Vec<_>>(), [3, 3, 3, 1]);
+        assert_eq!(reads[1][2].get_channel("bmp280Temp"), Some(21.37));
+        assert_eq!(reads[3][0].get_channel("humidity"), Some(48.2));
+        assert_eq!(oversampler.next_read(), None);
+
+        // a failed read is dropped, the filter rejects the sample without a read of a channel
+        fs::write(sysfs.path().join("iio:device0/in_3"), "").unwrap();
+        let reads = oversampler.start_period(&sensors).unwrap();
+        assert_eq!(reads.iter().map(Vec::len).collect::<Vec<_>>(), [3, 3, 3, 0]);
+    }
+}
@@ -0,0 +1,409 @@
+use crate::{
+    dashboard, historic::TIER_NAMES, sensor_data::CHANNELS, smoothing::Smoothing,
+    subscription::StreamFormat, summary::Period,
+};
+use std::fmt::{self, Display};
+
+/// Requests a client can send on the first line after connecting to the socket.
+///
+/// A client which sends nothing gets all the historics, like before the requests existed.
+/// The `Display` implementation writes the line the client sends.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Request {
+    Historics,
+    Minute,
+    Alerts,
+    /// `lineprotocol <tier>` : content of a historic tier in InfluxDB line protocol, for backfilling
+    LineProtocol(usize),
+    /// Historics of the nodes pushing their samples to this instance, keyed by node name
+    Nodes,
+    /// Most recent sample, in an array which is empty before the first sample
+    Latest,
+    /// `tier <tier>` : content of a historic tier
+    Tier(usize),
+    /// `smooth <tier> <smoothing>` : content of a historic tier whose channels are smoothed,
+    /// like `smooth minute ema 60s`
+    Smooth {
+        tier: usize,
+        smoothing: Smoothing,
+    },
+    /// `range <from> <to>` : samples between two timestamps in milliseconds, taken from the
+    /// most detailed tier which goes back to `from`
+    Range {
+        from: u64,
+        to: u64,
+    },
+    /// Names and units of the channels of the samples
+    Channels,
+    /// Counters of the samples accepted and rejected by the `SampleFilter`
+    Filter,
+    /// Barometric tendency and forecast computed from the HOUR tier
+    Trend,
+    /// Latest sample, trend and counters in the text format of Prometheus
+    Metrics,
+    /// `summary day|month` : statistics of each local day or month
+    Summary(Period),
+    /// Active anomalies of each channel and their number since the start
+    Anomalies,
+    /// `chart <tier> [<channel>,<channel>...] [<from> <to>]` : SVG chart of channels (indexes
+    /// in `CHANNELS`, all of them by default) of a historic tier, between two timestamps in
+    /// milliseconds
+    Chart {
+        tier: usize,
+        channels: Vec<usize>,
+        range: Option<(u64, u64)>,
+    },
+    /// File of the web dashboard, the other requests are also accepted as HTTP requests whose
+    /// path gives the words of the request : `GET /tier/hour HTTP/1.1`
+    Dashboard(String),
+    /// `subscribe [reduced]` : the connection stays open and each new sample is streamed, the
+    /// reduced samples too when asked. Browsers use `GET /subscribe[?reduced] HTTP/1.1` to
+    /// get them as Server-Sent Events.
+    Subscribe {
+        format: StreamFormat,
+        reduced: bool,
+    },
+}
+
+impl Request {
+    pub fn parse(line: &str) -> Result<Request, String> {
+        let mut words = line.split_whitespace();
+        let request = match words.next() {
+            None | Some("historics") => Request::Historics,
+            Some("minute") => Request::Minute,
+            Some("alerts") => Request::Alerts,
+            Some("nodes") => Request::Nodes,
+            Some("subscribe") => match words.next() {
+                Some("reduced") => Request::Subscribe {
+                    format: StreamFormat::Ndjson,
+                    reduced: true,
+                },
+                Some(argument) => return Err(format!("unexpected argument {}", argument)),
+                None => Request::Subscribe {
+                    format: StreamFormat::Ndjson,
+                    reduced: false,
+                },
+            },
+            Some("GET") => {
+                let target = words.next().unwrap_or_default();
+                let (path, _) = target.split_once('?').unwrap_or((target, ""));
+                // the HTTP version ends the line
+                words.next();
+                if path == "/subscribe" {
+                    Request::Subscribe {
+                        format: StreamFormat::ServerSentEvents,
+                        reduced: query_parameter(line, "reduced").is_some(),
+                    }
+                } else if dashboard::asset(path).is_some() {
+                    Request::Dashboard(path.to_string())
+                } else if path.starts_with('/') && path.len() > 1 {
+                    // `/tier/hour` is the `tier hour` request
+                    Request::parse(&path[1..].replace('/', " "))?
+                } else {
+                    return Err(format!("unknown path {}", path));
+                }
+            }
+            Some("latest") => Request::Latest,
+            Some("channels") => Request::Channels,
+            Some("filter") => Request::Filter,
+            Some("trend") => Request::Trend,
+            Some("metrics") => Request::Metrics,
+            Some("summary") => Request::Summary(Period::parse(words.next())?),
+            Some("anomalies") => Request::Anomalies,
+            Some("lineprotocol") => Request::LineProtocol(parse_tier(words.next())?),
+            Some("tier") => Request::Tier(parse_tier(words.next())?),
+            Some("smooth") => Request::Smooth {
+                tier: parse_tier(words.next())?,
+                smoothing: Smoothing::parse(words.next(), words.next())?,
+            },
+            Some("range") => {
+                let (from, to) = parse_range(words.next(), words.next())?;
+                Request::Range { from, to }
+            }
+            Some("chart") => {
+                let tier = parse_tier(words.next())?;
+                let mut words = words.by_ref().peekable();
+                let channels = match words.peek() {
+                    Some(channels) if !channels.starts_with(|c: char| c.is_ascii_digit()) => {
+                        let channels = channels
+                            .split(',')
+                            .map(|channel| {
+                                CHANNELS
+                                    .iter()
+                                    .position(|name| *name == channel)
+                                    .ok_or_else(|| format!("unknown channel {}", channel))
+                            })
+                            .collect::<Result<_, _>>()?;
+                        words.next();
+                        channels
+                    }
+                    _ => (0..CHANNELS.len()).collect(),
+                };
+                let range = match words.next() {
+                    Some(from) => Some(parse_range(Some(from), words.next())?),
+                    None => None,
+                };
+                Request::Chart {
+                    tier,
+                    channels,
+                    range,
+                }
+            }
+            Some(unknown) => return Err(format!("unknown request {}", unknown)),
+        };
+        match words.next() {
+            Some(argument) => Err(format!("unexpected argument {}", argument)),
+            None => Ok(request),
+        }
+    }
+}
+
+impl Display for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Request::Historics => write!(f, "historics"),
+            Request::Minute => write!(f, "minute"),
+            Request::Alerts => write!(f, "alerts"),
+            Request::LineProtocol(tier) => write!(f, "lineprotocol {}", TIER_NAMES[*tier]),
+            Request::Nodes => write!(f, "nodes"),
+            Request::Latest => write!(f, "latest"),
+            Request::Tier(tier) => write!(f, "tier {}", TIER_NAMES[*tier]),
+            Request::Smooth { tier, smoothing } => {
+                write!(f, "smooth {} {}", TIER_NAMES[*tier], smoothing)
+            }
+            Request::Range { from, to } => write!(f, "range {} {}", from, to),
+            Request::Channels => write!(f, "channels"),
+            Request::Filter => write!(f, "filter"),
+            Request::Trend => write!(f, "trend"),
+            Request::Metrics => write!(f, "metrics"),
+            Request::Summary(period) => write!(f, "summary {}", period.name()),
+            Request::Anomalies => write!(f, "anomalies"),
+            Request::Chart {
+                tier,
+                channels,
+                range,
+            } => {
+                let channels: Vec<&str> =
+                    channels.iter().map(|channel| CHANNELS[*channel]).collect();
+                write!(f, "chart {} {}", TIER_NAMES[*tier], channels.join(","))?;
+                match range {
+                    Some((from, to)) => write!(f, " {} {}", from, to),
+                    None => Ok(()),
+                }
+            }
+            Request::Dashboard(path) => write!(f, "GET {} HTTP/1.1", path),
+            Request::Subscribe {
+                format: StreamFormat::Ndjson,
+                reduced,
+            } => write!(f, "subscribe{}", if *reduced { " reduced" } else { "" }),
+            Request::Subscribe {
+                format: StreamFormat::ServerSentEvents,
+                reduced,
+            } => write!(
+                f,
+                "GET /subscribe{} HTTP/1.1",
+                if *reduced { "?reduced" } else { "" }
+            ),
+        }
+    }
+}
+
+fn parse_range(from: Option<&str>, to: Option<&str>) -> Result<(u64, u64), String> {
+    let timestamp = |timestamp: Option<&str>| match timestamp {
+        Some(timestamp) => timestamp
+            .parse::<u64>()
+            .map_err(|_| format!("invalid timestamp {}", timestamp)),
+        None => Err("missing timestamp".to_string()),
+    };
+    let (from, to) = (timestamp(from)?, timestamp(to)?);
+    if from > to {
+        return Err("the range ends before it starts".to_string());
+    }
+    Ok((from, to))
+}
+
+fn parse_tier(tier: Option<&str>) -> Result<usize, String> {
+    match tier {
+        Some(tier) => TIER_NAMES
+            .iter()
+            .position(|name| *name == tier)
+            .ok_or_else(|| format!("unknown tier {}", tier)),
+        None => Err("missing tier".to_string()),
+    }
+}
+
+/// Value of a parameter of the query of an HTTP request line, empty for a parameter without
+/// value
+pub fn query_parameter<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let target = line.split_whitespace().nth(1)?;
+    let (_, query) = target.split_once('?')?;
+    query.split('&').find_map(|parameter| {
+        let (key, value) = parameter.split_once('=').unwrap_or((parameter, ""));
+        if key == name {
+            Some(value)
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{query_parameter, Request};
+    use crate::{smoothing::Smoothing, subscription::StreamFormat, summary::Period};
+    use std::time::Duration;
+
+    #[test]
+    fn parse_requests() {
+        assert_eq!(Request::parse(""), Ok(Request::Historics));
+        assert_eq!(Request::parse(" minute "), Ok(Request::Minute));
+        assert_eq!(Request::parse("alerts"), Ok(Request::Alerts));
+        assert_eq!(Request::parse("nodes"), Ok(Request::Nodes));
+        assert_eq!(
+            Request::parse("alert"),
+            Err("unknown request alert".to_string())
+        );
+        assert_eq!(
+            Request::parse("lineprotocol days"),
+            Ok(Request::LineProtocol(2))
+        );
+        assert_eq!(
+            Request::parse("lineprotocol week"),
+            Err("unknown tier week".to_string())
+        );
+        assert_eq!(
+            Request::parse("subscribe reduced"),
+            Ok(Request::Subscribe {
+                format: StreamFormat::Ndjson,
+                reduced: true
+            })
+        );
+        assert_eq!(
+            Request::parse("GET /subscribe HTTP/1.1"),
+            Ok(Request::Subscribe {
+                format: StreamFormat::ServerSentEvents,
+                reduced: false
+            })
+        );
+        assert_eq!(
+            Request::parse("GET /subscribe?token=secret&reduced HTTP/1.1"),
+            Ok(Request::Subscribe {
+                format: StreamFormat::ServerSentEvents,
+                reduced: true
+            })
+        );
+        assert_eq!(
+            Request::parse("GET /index.html HTTP/1.1"),
+            Ok(Request::Dashboard("/index.html".to_string()))
+        );
+        assert_eq!(
+            Request::parse("GET /tier/hour?token=secret HTTP/1.1"),
+            Ok(Request::Tier(1))
+        );
+        assert_eq!(
+            Request::parse("GET /favicon.ico HTTP/1.1"),
+            Err("unknown request favicon.ico".to_string())
+        );
+        assert_eq!(
+            Request::parse("GET /subscribe/now HTTP/1.1"),
+            Err("unexpected argument now".to_string())
+        );
+        assert_eq!(
+            Request::parse("GET /smooth/minute/sma/12 HTTP/1.1"),
+            Ok(Request::Smooth {
+                tier: 0,
+                smoothing: Smoothing::Sma(12)
+            })
+        );
+        assert_eq!(
+            Request::parse("smooth hour"),
+            Err("missing smoothing".to_string())
+        );
+        assert_eq!(
+            Request::parse("alerts all"),
+            Err("unexpected argument all".to_string())
+        );
+    }
+
+    #[test]
+    fn requests_are_written_as_parsed() {
+        let requests = [
+            Request::Historics,
+            Request::Minute,
+            Request::Alerts,
+            Request::LineProtocol(1),
+            Request::Nodes,
+            Request::Latest,
+            Request::Tier(2),
+            Request::Smooth {
+                tier: 0,
+                smoothing: Smoothing::Ema(Duration::from_secs(90)),
+            },
+            Request::Smooth {
+                tier: 1,
+                smoothing: Smoothing::SavitzkyGolay(7),
+            },
+            Request::Range {
+                from: 1000,
+                to: 5000,
+            },
+            Request::Channels,
+            Request::Filter,
+            Request::Trend,
+            Request::Metrics,
+            Request::Summary(Period::Month),
+            Request::Anomalies,
+            Request::Dashboard("/dashboard.js".to_string()),
+            Request::Chart {
+                tier: 1,
+                channels: vec![0, 3],
+                range: None,
+            },
+            Request::Chart {
+                tier: 2,
+                channels: vec![1],
+                range: Some((1000, 2000)),
+            },
+            Request::Subscribe {
+                format: StreamFormat::Ndjson,
+                reduced: true,
+            },
+            Request::Subscribe {
+                format: StreamFormat::ServerSentEvents,
+                reduced: false,
+            },
+        ];
+        for request in requests {
+            assert_eq!(Request::parse(&request.to_string()), Ok(request));
+        }
+        assert_eq!(
+            Request::parse("chart minute 0 60000"),
+            Ok(Request::Chart {
+                tier: 0,
+                channels: vec![0, 1, 2, 3],
+                range: Some((0, 60000))
+            })
+        );
+        assert_eq!(
+            Request::parse("chart minute wind"),
+            Err("unknown channel wind".to_string())
+        );
+        assert_eq!(
+            Request::parse("range 5000 1000"),
+            Err("the range ends before it starts".to_string())
+        );
+        assert_eq!(
+            Request::parse("range 1000"),
+            Err("missing timestamp".to_string())
+        );
+    }
+
+    #[test]
+    fn http_query_parameters() {
+        let line = "GET /subscribe?token=a=b&reduced HTTP/1.1";
+        assert_eq!(query_parameter(line, "token"), Some("a=b"));
+        assert_eq!(query_parameter(line, "reduced"), Some(""));
+        assert_eq!(query_parameter(line, "format"), None);
+        assert_eq!(query_parameter("GET /subscribe HTTP/1.1", "token"), None);
+    }
+}
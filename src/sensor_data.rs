@@ -1,10 +1,14 @@
 use std::{
+    convert::TryInto,
     fmt::{self, Display},
     io,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{average::Average, json_display::JsonDisplay, sensors::Sensor};
+use crate::{
+    average::Average, derived::DerivedChannels, json_display::JsonDisplay, json_value::JsonValue,
+    mapped_circular_buffer::FixedSizeRecord, sensors::Sensor,
+};
 
 macro_rules! convTimeEpochDuration {
     ($systemtime:expr) => {
@@ -20,6 +24,11 @@ macro_rules! convDurationMs {
     };
 }
 
+/// Names of the channels of a `SensorData`, as written in the JSON output
+pub const CHANNELS: [&str; 4] = ["pressure", "bmp280Temp", "htu21Temp", "humidity"];
+/// Units of the channels, in the order of `CHANNELS`
+pub const UNITS: [&str; 4] = ["hPa", "°C", "°C", "%"];
+
 #[derive(Copy, Clone)]
 pub struct SensorData {
     timestamp: Duration,
@@ -27,6 +36,9 @@ pub struct SensorData {
     bmp280_temperature: i32,
     htu21_temperature: i32,
     htu21_humidity: i32,
+    /// Whether the sample went through a `Calibration`, for the whole sample even when only
+    /// some of its channels are corrected
+    calibrated: bool,
 }
 
 pub struct SensorCumulatedData {
@@ -35,8 +47,13 @@ pub struct SensorCumulatedData {
     bmp280_temperature: i64,
     htu21_temperature: i64,
     htu21_humidity: i64,
+    nb_calibrated: usize,
 }
 
+// The nanoseconds of the timestamp are below 2^30, the highest bit of their field holds the
+// calibrated flag in the records
+const CALIBRATED_FLAG: u32 = 1 << 31;
+
 impl SensorData {
     pub fn new(
         timestamp: SystemTime,
@@ -51,22 +68,74 @@ impl SensorData {
             bmp280_temperature,
             htu21_temperature,
             htu21_humidity,
+            calibrated: false,
         }
     }
 
-    pub fn create(
-        sensor_bmp280_pressure: &Sensor,
-        sensor_bmp280_temperature: &Sensor,
-        sensor_htu21_temperature: &Sensor,
-        sensor_htu21_humidity: &Sensor,
-    ) -> SensorData {
-        SensorData::new(
-            SystemTime::now(),
-            sensor_bmp280_pressure.get::<f32>(),
-            sensor_bmp280_temperature.get::<i32>(),
-            sensor_htu21_temperature.get::<i32>(),
-            sensor_htu21_humidity.get::<i32>(),
-        )
+    /// Raw read of the sensor of a channel (index in `CHANNELS`), the other channels are 0.
+    /// The reads are combined, filtered and calibrated before they make a sample.
+    pub fn read(sensor: &Sensor, channel: usize) -> Result<SensorData, String> {
+        let mut data = SensorData::new(SystemTime::now(), 0.0, 0, 0, 0);
+        match channel {
+            0 => data.bmp280_pressure = sensor.get::<f32>()?,
+            1 => data.bmp280_temperature = sensor.get::<i32>()?,
+            2 => data.htu21_temperature = sensor.get::<i32>()?,
+            _ => data.htu21_humidity = sensor.get::<i32>()?,
+        }
+        Ok(data)
+    }
+
+    /// Sample written by `json_item`
+    pub fn from_json(value: &JsonValue) -> Result<SensorData, String> {
+        let field = |name: &str| {
+            value
+                .get(name)
+                .and_then(JsonValue::as_f64)
+                .ok_or_else(|| format!("missing {} in the sample", name))
+        };
+        Ok(SensorData {
+            timestamp: Duration::from_millis(field("timestamp")? as u64),
+            bmp280_pressure: (field("pressure")? / 10.0) as f32,
+            bmp280_temperature: (field("bmp280Temp")? * 1000.0).round() as i32,
+            htu21_temperature: (field("htu21Temp")? * 1000.0).round() as i32,
+            htu21_humidity: (field("humidity")? * 1000.0).round() as i32,
+            calibrated: value.get("calibrated") == Some(&JsonValue::Bool(true)),
+        })
+    }
+
+    pub fn get_timestamp(&self) -> Duration {
+        self.timestamp
+    }
+
+    /// Value of a measured channel in the unit of the JSON output (hPa, °C, %), see
+    /// `DerivedChannels::values` for the derived channels
+    pub fn get_channel(&self, name: &str) -> Option<f64> {
+        match name {
+            "pressure" => Some(f64::from(self.bmp280_pressure) * 10.0),
+            "bmp280Temp" => Some(f64::from(self.bmp280_temperature) / 1000.0),
+            "htu21Temp" => Some(f64::from(self.htu21_temperature) / 1000.0),
+            "humidity" => Some(f64::from(self.htu21_humidity) / 1000.0),
+            _ => None,
+        }
+    }
+
+    /// Set a channel from a value in the unit of the JSON output, unknown channels are ignored
+    pub fn set_channel(&mut self, name: &str, value: f64) {
+        match name {
+            "pressure" => self.bmp280_pressure = (value / 10.0) as f32,
+            "bmp280Temp" => self.bmp280_temperature = (value * 1000.0).round() as i32,
+            "htu21Temp" => self.htu21_temperature = (value * 1000.0).round() as i32,
+            "humidity" => self.htu21_humidity = (value * 1000.0).round() as i32,
+            _ => (),
+        }
+    }
+
+    pub fn is_calibrated(&self) -> bool {
+        self.calibrated
+    }
+
+    pub fn set_calibrated(&mut self, calibrated: bool) {
+        self.calibrated = calibrated;
     }
 
     pub fn get_bmp280_pressure(&self) -> f32 {
@@ -100,16 +169,34 @@ impl Display for SensorData {
     }
 }
 
-impl JsonDisplay for SensorData {
-    fn json_item(&self, w: &mut io::Write) -> io::Result<()> {
+impl SensorData {
+    /// JSON object of the sample, followed by the given derived channels
+    pub fn write_json_with(
+        &self,
+        derived: &[(&str, f64)],
+        w: &mut dyn io::Write,
+    ) -> io::Result<()> {
         w.write_fmt(format_args!(
-            "{{\"timestamp\": {},\n\"pressure\"  : {:.2},\n\"bmp280Temp\": {:.3},\n\"htu21Temp\" : {:.3},\n\"humidity\"  : {:.2}}}\n",
+            "{{\"timestamp\": {},\n\"pressure\"  : {:.2},\n\"bmp280Temp\": {:.3},\n\"htu21Temp\" : {:.3},\n\"humidity\"  : {:.2}",
             convDurationMs!(self.timestamp),
             self.bmp280_pressure * 10.0,
             self.bmp280_temperature as f32 / 1000.0,
             self.htu21_temperature as f32 / 1000.0,
             self.htu21_humidity  as f32 / 1000.0
-        ))
+        ))?;
+        w.write_fmt(format_args!(",\n\"calibrated\": {}", self.calibrated))?;
+        for (name, value) in derived {
+            w.write_fmt(format_args!(",\n\"{}\": {:.2}", name, value))?;
+        }
+        w.write_all(b"}\n")
+    }
+}
+
+impl JsonDisplay for SensorData {
+    type Context = DerivedChannels;
+
+    fn json_item(&self, derived: &DerivedChannels, w: &mut dyn io::Write) -> io::Result<()> {
+        self.write_json_with(&derived.values(self), w)
     }
 }
 
@@ -123,15 +210,17 @@ impl Average<SensorData> for SensorData {
             bmp280_temperature: 0,
             htu21_temperature: 0,
             htu21_humidity: 0,
+            nb_calibrated: 0,
         }
     }
 
-    fn cumulate<'a, 'b>(&'a self, cumulated_data: &'b mut Self::Acc) -> &'b Self::Acc {
+    fn cumulate<'b>(&self, cumulated_data: &'b mut Self::Acc) -> &'b Self::Acc {
         cumulated_data.timestamp += self.timestamp;
         cumulated_data.bmp280_pressure += self.bmp280_pressure as f64;
         cumulated_data.bmp280_temperature += self.bmp280_temperature as i64;
         cumulated_data.htu21_temperature += self.htu21_temperature as i64;
         cumulated_data.htu21_humidity += self.htu21_humidity as i64;
+        cumulated_data.nb_calibrated += usize::from(self.calibrated);
         cumulated_data
     }
 
@@ -142,6 +231,38 @@ impl Average<SensorData> for SensorData {
             bmp280_temperature: (cumulated_data.bmp280_temperature / nb_elements as i64) as i32,
             htu21_temperature: (cumulated_data.htu21_temperature / nb_elements as i64) as i32,
             htu21_humidity: (cumulated_data.htu21_humidity / nb_elements as i64) as i32,
+            // an average of raw and calibrated values is not calibrated
+            calibrated: cumulated_data.nb_calibrated == nb_elements,
+        }
+    }
+}
+
+impl FixedSizeRecord for SensorData {
+    const RECORD_SIZE: usize = 28;
+
+    fn write_record(&self, record: &mut [u8]) {
+        record[0..8].copy_from_slice(&self.timestamp.as_secs().to_le_bytes());
+        let flag = if self.calibrated { CALIBRATED_FLAG } else { 0 };
+        record[8..12].copy_from_slice(&(self.timestamp.subsec_nanos() | flag).to_le_bytes());
+        record[12..16].copy_from_slice(&self.bmp280_pressure.to_le_bytes());
+        record[16..20].copy_from_slice(&self.bmp280_temperature.to_le_bytes());
+        record[20..24].copy_from_slice(&self.htu21_temperature.to_le_bytes());
+        record[24..28].copy_from_slice(&self.htu21_humidity.to_le_bytes());
+    }
+
+    fn read_record(record: &[u8]) -> SensorData {
+        let field = |offset: usize| -> [u8; 4] { record[offset..offset + 4].try_into().unwrap() };
+        let nanos = u32::from_le_bytes(field(8));
+        SensorData {
+            timestamp: Duration::new(
+                u64::from_le_bytes(record[0..8].try_into().unwrap()),
+                nanos & !CALIBRATED_FLAG,
+            ),
+            bmp280_pressure: f32::from_le_bytes(field(12)),
+            bmp280_temperature: i32::from_le_bytes(field(16)),
+            htu21_temperature: i32::from_le_bytes(field(20)),
+            htu21_humidity: i32::from_le_bytes(field(24)),
+            calibrated: nanos & CALIBRATED_FLAG != 0,
         }
     }
 }
@@ -1,9 +1,22 @@
-use std::{fs::File, io::prelude::*, path::Path};
+use std::{
+    fs::{self, File},
+    io::prelude::*,
+    path::Path,
+};
 
 pub struct Sensor {
     filename: String,
 }
 
+/// Identity of the device providing a value, found in the path of the sysfs file
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceIdentity {
+    /// `<bus>-<address>`, like 1-0076
+    pub i2c_address: Option<String>,
+    /// Name of the IIO driver, like bmp280
+    pub iio_name: Option<String>,
+}
+
 impl Sensor {
     /// Probe permits to find if index of the sensor for iio:device in the sys fs
     ///
@@ -14,43 +27,94 @@ impl Sensor {
             let path_to_test = path.replace("{}", i.to_string().as_str());
             if Path::new(&path_to_test).exists() {
                 return Ok(Sensor {
-                    filename: String::from(path_to_test),
+                    filename: path_to_test,
                 });
             }
         }
         Err("Can't find the driver in the sysfs : ".to_string() + path)
     }
 
-    pub fn get<T>(&self) -> T
+    /// Value read from the sysfs file, the I2C transfers can fail
+    pub fn get<T>(&self) -> Result<T, String>
     where
         T: std::str::FromStr,
         <T>::Err: std::fmt::Display,
     {
         get::<T>(self.filename.as_str())
     }
+
+    pub fn get_device_identity(&self) -> DeviceIdentity {
+        let path = Path::new(&self.filename);
+        // .../i2c-1/1-0076/iio:device0/in_temp_input
+        let iio_device = path.parent();
+        let i2c_address = iio_device
+            .and_then(Path::parent)
+            .and_then(Path::file_name)
+            .and_then(|name| name.to_str())
+            .filter(|name| is_i2c_address(name))
+            .map(str::to_string);
+        let iio_name = iio_device
+            .and_then(|iio_device| fs::read_to_string(iio_device.join("name")).ok())
+            .map(|name| name.trim().to_string())
+            .filter(|name| !name.is_empty());
+        DeviceIdentity {
+            i2c_address,
+            iio_name,
+        }
+    }
+}
+
+impl DeviceIdentity {
+    /// Identifier made of `[a-z0-9_]` characters, like bmp280_1_0076
+    pub fn id(&self) -> String {
+        let id = match (&self.iio_name, &self.i2c_address) {
+            (Some(name), Some(address)) => format!("{}_{}", name, address),
+            (Some(name), None) => name.clone(),
+            (None, Some(address)) => format!("i2c_{}", address),
+            (None, None) => "unknown".to_string(),
+        };
+        id.chars()
+            .map(|c| match c.to_ascii_lowercase() {
+                c @ ('a'..='z' | '0'..='9') => c,
+                _ => '_',
+            })
+            .collect()
+    }
+}
+
+/// I2C device directories are named after the bus number and the 4 digits address
+fn is_i2c_address(name: &str) -> bool {
+    match name.split_once('-') {
+        Some((bus, address)) => {
+            !bus.is_empty()
+                && bus.chars().all(|c| c.is_ascii_digit())
+                && address.len() == 4
+                && address.chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => false,
+    }
 }
 
-fn get<T>(filename: &str) -> T
+fn get<T>(filename: &str) -> Result<T, String>
 where
     T: std::str::FromStr,
     <T>::Err: std::fmt::Display,
 {
     let mut file = match File::open(filename) {
-        Err(why) => panic!("couldn't open {}: {}", filename, why),
+        Err(why) => return Err(format!("couldn't open {} : {}", filename, why)),
         Ok(file) => file,
     };
 
     let mut s = String::new();
     match file.read_to_string(&mut s) {
-        Err(why) => panic!("couldn't read {}: {}", filename, why),
+        Err(why) => Err(format!("couldn't read {} : {}", filename, why)),
         Ok(_) => {
             let mut lines = s.lines();
             match lines.next() {
-                None => panic!("couldn't extract line {}", s),
-                Some(l) => match l.parse::<T>() {
-                    Err(why) => panic!("couldn't parse {}: {}", l, why),
-                    Ok(val) => val,
-                },
+                None => Err(format!("{} is empty", filename)),
+                Some(l) => l
+                    .parse::<T>()
+                    .map_err(|why| format!("couldn't parse {} of {} : {}", l, filename, why)),
             }
         }
     }
@@ -74,3 +138,49 @@ pub fn get_htu21_humidity() -> i32{
     get::<i32>("/sys/bus/i2c/devices/i2c-1/1-0040/iio:device0/in_humidityrelative_input")
 }
 */
+
+#[cfg(test)]
+mod tests {
+    use super::{DeviceIdentity, Sensor};
+    use crate::test_utils::TempPath;
+    use std::fs;
+
+    #[test]
+    fn probe_and_identify_device() {
+        let sysfs = TempPath::new("sysfs");
+        let iio_device = sysfs.path().join("i2c-1/1-0076/iio:device2");
+        fs::create_dir_all(&iio_device).unwrap();
+        fs::write(iio_device.join("name"), "bmp280\n").unwrap();
+        fs::write(iio_device.join("in_temp_input"), "21370\n").unwrap();
+
+        let sensor = Sensor::probe(
+            sysfs
+                .path()
+                .join("i2c-1/1-0076/iio:device{}/in_temp_input")
+                .to_str()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(sensor.get::<i32>(), Ok(21370));
+        fs::write(iio_device.join("in_temp_input"), "\n").unwrap();
+        assert!(sensor
+            .get::<i32>()
+            .unwrap_err()
+            .starts_with("couldn't parse"));
+        fs::remove_file(iio_device.join("in_temp_input")).unwrap();
+        assert!(sensor
+            .get::<i32>()
+            .unwrap_err()
+            .starts_with("couldn't open"));
+        let identity = sensor.get_device_identity();
+        assert_eq!(
+            identity,
+            DeviceIdentity {
+                i2c_address: Some("1-0076".to_string()),
+                iio_name: Some("bmp280".to_string()),
+            }
+        );
+        assert_eq!(identity.id(), "bmp280_1_0076");
+        assert!(Sensor::probe(sysfs.path().join("iio:device{}/x").to_str().unwrap()).is_err());
+    }
+}
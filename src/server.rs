@@ -1,65 +1,1128 @@
+use crate::{
+    config::{Config, Section},
+    connection_limit::ConnectionLimit,
+    dashboard,
+    derived::DerivedChannels,
+    json_display::{self, JsonDisplay},
+    metrics,
+    request::{self, Request},
+    sensor_data::{SensorData, CHANNELS, UNITS},
+    shared_circular_buffer::SharedCircularBufferReader,
+    subscription::StreamFormat,
+    tls,
+};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
 use std::{
-    fs::remove_file,
-    os::unix::net::{UnixListener, UnixStream},
+    ffi::CString,
+    fs::{remove_file, set_permissions, symlink_metadata, Permissions},
+    io::{self, ErrorKind, Read, Write},
+    mem,
+    net::{Shutdown, TcpListener, TcpStream},
+    os::unix::{
+        fs::{chown, FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
     path::Path,
-    sync::mpsc::{channel, Receiver, Sender},
+    ptr,
+    sync::{mpsc::Sender, Arc},
     thread::{sleep, spawn, JoinHandle},
     time::Duration,
 };
 
+// Time let to a client to send its request, a silent client gets all the historics
+const REQUEST_TIMEOUT_MS: u64 = 200;
+const MAX_REQUEST_LENGTH: usize = 256;
+const MAX_HTTP_HEADERS: usize = 64;
+// Time let to a remote client to establish TLS and send its token
+const HANDSHAKE_TIMEOUT_S: u64 = 5;
+const WRITE_TIMEOUT_S: u64 = 30;
+const DEFAULT_MAX_CONNECTIONS: usize = 32;
+// Largest buffer given to getpwnam_r and getgrnam_r for the strings of an entry
+const MAX_ENTRY_BUFFER_SIZE: usize = 1 << 20;
+
+/// Connection of a client, on the Unix socket or on a TCP listener, with or without TLS
+pub trait ClientStream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> ClientStream for T {}
+
+/// Requests handed to the sampling thread, with the connection to answer on
+pub type ClientRequest = (Request, Box<dyn ClientStream>);
+
+/// TCP listener read from a `[listener <name>]` section of the configuration:
+/// ```text
+/// [listener remote]
+/// address = [::]:7879                  # IPv4 or IPv6 address and port
+/// certificate = /etc/rustdatamonitoring/server.pem  # TLS is enabled with a certificate
+/// key = /etc/rustdatamonitoring/server.key
+/// client_ca = /etc/rustdatamonitoring/clients.pem   # optional, the clients need a certificate
+/// token = secret                       # optional, sent by the client as "token <secret>"
+/// dashboard = true                     # optional, serves the web dashboard on /
+/// max_connections = 32                 # clients served at the same time, the others are closed
+/// ```
+/// The requests are the same as on the Unix socket. When a token is needed, the client sends
+/// it on the first line, before its request, or in the `token` parameter of the query of an
+/// HTTP request. The files of the dashboard are served without it, the page is opened as
+/// `/?token=<secret>` and gives it to its data requests.
+#[derive(Clone)]
+pub struct TcpSettings {
+    name: String,
+    address: String,
+    tls: Option<Arc<ServerConfig>>,
+    token: Option<String>,
+    dashboard: bool,
+    max_connections: usize,
+}
+
+/// Unix socket settings, read from the `[socket]` section of the configuration:
+/// ```text
+/// [socket]
+/// path = /run/rustdatamonitoring/socket   # the socket name given on the command line wins
+/// mode = 660                              # octal
+/// owner = pi                              # name or id
+/// group = weather
+/// ```
+#[derive(Debug, Default, PartialEq)]
+pub struct SocketSettings {
+    pub path: Option<String>,
+    pub mode: Option<u32>,
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+}
+
 pub struct Server {
-    listener: UnixListener,
+    listener: Listener,
+    minute_reader: SharedCircularBufferReader<SensorData>,
+    derived: Arc<DerivedChannels>,
 }
 
-impl Server {
-    pub fn create_server_thread(socket_path: &str) -> (JoinHandle<()>, Receiver<UnixStream>) {
-        let socket_path_copy = socket_path.to_string();
-        let (tx, rx) = channel::<UnixStream>();
-        (
-            spawn(move || {
-                let mut serv = Server::new(socket_path_copy.as_str());
-                serv.receive(tx);
-            }),
-            rx,
-        )
-    }
+enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener, TcpSettings, ConnectionLimit),
+}
 
-    fn new(socket_path: &str) -> Server {
-        let socket = Path::new(socket_path);
+/// TLS connection which is closed cleanly when the answer is complete
+struct TlsStream(StreamOwned<ServerConnection, TcpStream>);
 
-        // Delete old socket if necessary
-        if socket.exists() {
-            let _ = remove_file(socket);
+impl TcpSettings {
+    pub fn new(name: &str, address: &str) -> TcpSettings {
+        TcpSettings {
+            name: name.to_string(),
+            address: address.to_string(),
+            tls: None,
+            token: None,
+            dashboard: false,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
         }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn with_tls(mut self, tls: Arc<ServerConfig>) -> TcpSettings {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn with_token(mut self, token: &str) -> TcpSettings {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    pub fn with_dashboard(mut self) -> TcpSettings {
+        self.dashboard = true;
+        self
+    }
 
-        // Bind to socket
-        match UnixListener::bind(&socket) {
-            Err(_) => panic!("failed to bind socket"),
-            Ok(listener) => {
-                //listener.set_nonblocking(true).expect("Couldn't set non blocking");
-                Server { listener }
+    pub fn with_max_connections(mut self, max: usize) -> TcpSettings {
+        self.max_connections = max;
+        self
+    }
+
+    fn from_section(section: &Section) -> Result<TcpSettings, String> {
+        section.check_keys(&[
+            "address",
+            "certificate",
+            "key",
+            "client_ca",
+            "token",
+            "dashboard",
+            "max_connections",
+        ])?;
+        let mut settings =
+            TcpSettings::new(section.name()?, &section.require::<String>("address")?);
+        match (section.get_str("certificate"), section.get_str("key")) {
+            (Some(certificate), Some(key)) => {
+                let tls = tls::server_config(certificate, key, section.get_str("client_ca"))
+                    .map_err(|why| section.error(&why))?;
+                settings = settings.with_tls(tls);
+            }
+            (None, None) if section.get_str("client_ca").is_some() => {
+                return Err(section.error("client_ca needs a certificate and a key"))
             }
+            (None, None) => (),
+            _ => return Err(section.error("certificate and key go together")),
+        }
+        if let Some(token) = section.get_str("token") {
+            settings = settings.with_token(token);
+        }
+        if section.get_or("dashboard", false)? {
+            settings = settings.with_dashboard();
+        }
+        if let Some(max) = section.get("max_connections")? {
+            settings = settings.with_max_connections(max);
+        }
+        Ok(settings)
+    }
+}
+
+impl SocketSettings {
+    pub fn from_config(config: &Config) -> Result<SocketSettings, String> {
+        let section = match config.section("socket")? {
+            Some(section) => section,
+            None => return Ok(SocketSettings::default()),
+        };
+        section.check_keys(&["path", "mode", "owner", "group"])?;
+        let mode = match section.get_str("mode") {
+            Some(mode) => match u32::from_str_radix(mode, 8) {
+                Ok(mode) if mode <= 0o7777 => Some(mode),
+                _ => return Err(section.error(&format!("invalid octal mode {}", mode))),
+            },
+            None => None,
+        };
+        let owner = match section.get_str("owner") {
+            Some(owner) => Some(user_id(owner).map_err(|why| section.error(&why))?),
+            None => None,
+        };
+        let group = match section.get_str("group") {
+            Some(group) => Some(group_id(group).map_err(|why| section.error(&why))?),
+            None => None,
+        };
+        Ok(SocketSettings {
+            path: section.get("path")?,
+            mode,
+            owner,
+            group,
+        })
+    }
+}
+
+pub fn listeners_from_config(config: &Config) -> Result<Vec<TcpSettings>, String> {
+    config
+        .sections("listener")
+        .map(TcpSettings::from_section)
+        .collect()
+}
+
+impl Server {
+    /// The requests which need the state of the sampling thread are handed to it through
+    /// `tx_channel`, the MINUTE historic is directly served from `minute_reader`, with the
+    /// `derived` channels.
+    ///
+    /// The socket is bound before returning.
+    pub fn create_server_thread(
+        socket_path: &str,
+        settings: &SocketSettings,
+        minute_reader: SharedCircularBufferReader<SensorData>,
+        derived: DerivedChannels,
+        tx_channel: Sender<ClientRequest>,
+    ) -> Result<JoinHandle<()>, String> {
+        let listener = bind_unix_socket(Path::new(socket_path), settings)?;
+        Ok(Server::create_activated_server_thread(
+            listener,
+            minute_reader,
+            derived,
+            tx_channel,
+        ))
+    }
+
+    /// Same as `create_server_thread` for a socket which is already listening, like the
+    /// ones given by systemd
+    pub fn create_activated_server_thread(
+        listener: UnixListener,
+        minute_reader: SharedCircularBufferReader<SensorData>,
+        derived: DerivedChannels,
+        tx_channel: Sender<ClientRequest>,
+    ) -> JoinHandle<()> {
+        let mut serv = Server {
+            listener: Listener::Unix(listener),
+            minute_reader,
+            derived: Arc::new(derived),
+        };
+        spawn(move || serv.receive(tx_channel))
+    }
+
+    /// Same as `create_server_thread` for a TCP listener
+    pub fn create_tcp_server_thread(
+        settings: TcpSettings,
+        minute_reader: SharedCircularBufferReader<SensorData>,
+        derived: DerivedChannels,
+        tx_channel: Sender<ClientRequest>,
+    ) -> Result<JoinHandle<()>, String> {
+        let listener = TcpListener::bind(&settings.address).map_err(|err| {
+            format!(
+                "listener {} can't listen on {} : {}",
+                settings.name, settings.address, err
+            )
+        })?;
+        Ok(Server::create_activated_tcp_server_thread(
+            listener,
+            settings,
+            minute_reader,
+            derived,
+            tx_channel,
+        ))
+    }
+
+    pub fn create_activated_tcp_server_thread(
+        listener: TcpListener,
+        settings: TcpSettings,
+        minute_reader: SharedCircularBufferReader<SensorData>,
+        derived: DerivedChannels,
+        tx_channel: Sender<ClientRequest>,
+    ) -> JoinHandle<()> {
+        if let Ok(address) = listener.local_addr() {
+            println!(
+                "Listener {} on {}{}",
+                settings.name,
+                address,
+                if settings.tls.is_some() { " (TLS)" } else { "" }
+            );
         }
+        let connections = ConnectionLimit::new(settings.max_connections);
+        let mut serv = Server {
+            listener: Listener::Tcp(listener, settings, connections),
+            minute_reader,
+            derived: Arc::new(derived),
+        };
+        spawn(move || serv.receive(tx_channel))
     }
 
-    fn receive(&mut self, tx_channel: Sender<UnixStream>) {
+    fn receive(&mut self, tx_channel: Sender<ClientRequest>) {
         println!("Server started, waiting for clients");
 
         // accept connections and process them
-        for stream in self.listener.incoming() {
-            match stream {
-                Ok(stream) => {
+        loop {
+            let accepted = match &self.listener {
+                Listener::Unix(listener) => listener.accept().map(|(mut stream, _)| {
                     /* connection succeeded */
                     println!("Connection succeeded {:?}", stream);
-                    tx_channel.send(stream).unwrap();
-                    //stream.write_all(b"hello world\n").unwrap();
+                    let _ =
+                        stream.set_read_timeout(Some(Duration::from_millis(REQUEST_TIMEOUT_MS)));
+                    let _ = stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_S)));
+                    let request = read_request(&mut stream);
+                    skip_http_headers(&mut stream, &request);
+                    let _ = stream.set_read_timeout(None);
+                    self.answer(Box::new(stream), &request, &tx_channel);
+                }),
+                Listener::Tcp(listener, settings, connections) => {
+                    listener.accept().map(|(stream, peer)| {
+                        // the connection is closed before the TLS handshake, nothing can be answered
+                        let slot = match connections.acquire() {
+                            Some(slot) => slot,
+                            None => {
+                                println!(
+                                    "Client {} refused : {} connections already served",
+                                    peer,
+                                    connections.max()
+                                );
+                                return;
+                            }
+                        };
+                        // a slow remote client must not delay the other ones
+                        let settings = settings.clone();
+                        let minute_reader = self.minute_reader.clone();
+                        let derived = self.derived.clone();
+                        let tx_channel = tx_channel.clone();
+                        spawn(move || {
+                            match accept_tcp(stream, &settings) {
+                                Ok((stream, request)) => Server::answer_with(
+                                    &minute_reader,
+                                    &derived,
+                                    stream,
+                                    &request,
+                                    settings.dashboard,
+                                    &tx_channel,
+                                ),
+                                Err(why) => println!("Client {} refused : {}", peer, why),
+                            }
+                            drop(slot);
+                        });
+                    })
                 }
-                Err(err) => {
-                    /* connection failed */
-                    println!("Connection failed : {}", err);
-                    sleep(Duration::from_secs(5));
+            };
+            if let Err(err) = accepted {
+                /* connection failed */
+                println!("Connection failed : {}", err);
+                sleep(Duration::from_secs(5));
+            }
+        }
+    }
+
+    fn answer(
+        &self,
+        stream: Box<dyn ClientStream>,
+        request: &str,
+        tx_channel: &Sender<ClientRequest>,
+    ) {
+        // the dashboard is only for the browsers, on the TCP listeners
+        Server::answer_with(
+            &self.minute_reader,
+            &self.derived,
+            stream,
+            request,
+            false,
+            tx_channel,
+        )
+    }
+
+    /// Request handling shared by all the listeners, the web dashboard is only served when
+    /// `dashboard` is set
+    fn answer_with(
+        minute_reader: &SharedCircularBufferReader<SensorData>,
+        derived: &DerivedChannels,
+        mut stream: Box<dyn ClientStream>,
+        request: &str,
+        dashboard: bool,
+        tx_channel: &Sender<ClientRequest>,
+    ) {
+        let http = request.starts_with("GET ");
+        let answer = match Request::parse(request) {
+            Ok(Request::Dashboard(path)) => match dashboard::asset(&path) {
+                Some((content_type, content)) if dashboard => {
+                    write_http_asset(stream, content_type, content)
                 }
+                _ => write_http_error(stream, "404 Not Found", &format!("unknown path {}", path)),
+            },
+            // the Server-Sent Events come with their own header
+            Ok(
+                request @ Request::Subscribe {
+                    format: StreamFormat::ServerSentEvents,
+                    ..
+                },
+            ) => Server::dispatch(minute_reader, derived, stream, request, tx_channel),
+            Ok(request) if http => {
+                write_http_header(&mut stream, content_type(&request)).and_then(|_| {
+                    Server::dispatch(minute_reader, derived, stream, request, tx_channel)
+                })
+            }
+            Ok(request) => Server::dispatch(minute_reader, derived, stream, request, tx_channel),
+            Err(why) if http => write_http_error(stream, "400 Bad Request", &why),
+            Err(why) => write_json_error(stream, &why),
+        };
+        if let Err(err) = answer {
+            println!("Failed to answer : {}", err);
+        }
+    }
+
+    fn dispatch(
+        minute_reader: &SharedCircularBufferReader<SensorData>,
+        derived: &DerivedChannels,
+        stream: Box<dyn ClientStream>,
+        request: Request,
+        tx_channel: &Sender<ClientRequest>,
+    ) -> io::Result<()> {
+        match request {
+            Request::Minute => write_json_minute(minute_reader, derived, stream),
+            Request::Latest => write_json_latest(minute_reader, derived, stream),
+            Request::Channels => write_json_channels(derived, stream),
+            request => {
+                tx_channel.send((request, stream)).unwrap();
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Bind the socket, after deleting the socket left by a previous instance, and give it the
+/// configured permissions
+fn bind_unix_socket(socket: &Path, settings: &SocketSettings) -> Result<UnixListener, String> {
+    match symlink_metadata(socket) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if UnixStream::connect(socket).is_ok() {
+                return Err(format!(
+                    "socket {} is already used by another process",
+                    socket.display()
+                ));
             }
+            remove_file(socket)
+                .map_err(|err| format!("couldn't delete {} : {}", socket.display(), err))?;
         }
+        Ok(_) => {
+            return Err(format!(
+                "{} exists and is not a socket, it is not deleted",
+                socket.display()
+            ))
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => (),
+        Err(err) => return Err(format!("couldn't check {} : {}", socket.display(), err)),
+    }
+    let listener = UnixListener::bind(socket)
+        .map_err(|err| format!("failed to bind socket {} : {}", socket.display(), err))?;
+    if let Some(mode) = settings.mode {
+        set_permissions(socket, Permissions::from_mode(mode))
+            .map_err(|err| format!("couldn't set the mode of {} : {}", socket.display(), err))?;
+    }
+    if settings.owner.is_some() || settings.group.is_some() {
+        chown(socket, settings.owner, settings.group)
+            .map_err(|err| format!("couldn't set the owner of {} : {}", socket.display(), err))?;
+    }
+    Ok(listener)
+}
+
+/// Id of a user given by name or id
+fn user_id(user: &str) -> Result<u32, String> {
+    if let Ok(id) = user.parse::<u32>() {
+        return Ok(id);
+    }
+    let name = CString::new(user).map_err(|_| format!("invalid user {}", user))?;
+    let mut buffer_size = 16384;
+    loop {
+        let mut buffer = vec![0 as libc::c_char; buffer_size];
+        // SAFETY: the entry and its strings live in `passwd` and `buffer`, which outlive the call
+        let (code, found, uid) = unsafe {
+            let mut passwd: libc::passwd = mem::zeroed();
+            let mut result = ptr::null_mut();
+            let code = libc::getpwnam_r(
+                name.as_ptr(),
+                &mut passwd,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            );
+            (code, !result.is_null(), passwd.pw_uid)
+        };
+        match code {
+            0 if found => return Ok(uid),
+            0 => return Err(format!("unknown user {}", user)),
+            libc::ERANGE if buffer_size < MAX_ENTRY_BUFFER_SIZE => buffer_size *= 2,
+            code => {
+                return Err(format!(
+                    "can't look up the user {} : {}",
+                    user,
+                    io::Error::from_raw_os_error(code)
+                ))
+            }
+        }
+    }
+}
+
+/// Id of a group given by name or id
+fn group_id(group: &str) -> Result<u32, String> {
+    if let Ok(id) = group.parse::<u32>() {
+        return Ok(id);
+    }
+    let name = CString::new(group).map_err(|_| format!("invalid group {}", group))?;
+    let mut buffer_size = 16384;
+    loop {
+        let mut buffer = vec![0 as libc::c_char; buffer_size];
+        // SAFETY: the entry and its strings live in `entry` and `buffer`, which outlive the call
+        let (code, found, gid) = unsafe {
+            let mut entry: libc::group = mem::zeroed();
+            let mut result = ptr::null_mut();
+            let code = libc::getgrnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buffer.as_mut_ptr(),
+                buffer.len(),
+                &mut result,
+            );
+            (code, !result.is_null(), entry.gr_gid)
+        };
+        // a group with many members needs a large buffer
+        match code {
+            0 if found => return Ok(gid),
+            0 => return Err(format!("unknown group {}", group)),
+            libc::ERANGE if buffer_size < MAX_ENTRY_BUFFER_SIZE => buffer_size *= 2,
+            code => {
+                return Err(format!(
+                    "can't look up the group {} : {}",
+                    group,
+                    io::Error::from_raw_os_error(code)
+                ))
+            }
+        }
+    }
+}
+
+/// Establish TLS and check the token, then read the request
+fn accept_tcp(
+    stream: TcpStream,
+    settings: &TcpSettings,
+) -> Result<(Box<dyn ClientStream>, String), String> {
+    let socket = stream.try_clone().map_err(|err| err.to_string())?;
+    socket
+        .set_read_timeout(Some(Duration::from_secs(HANDSHAKE_TIMEOUT_S)))
+        .and_then(|_| socket.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_S))))
+        .map_err(|err| err.to_string())?;
+    let mut stream: Box<dyn ClientStream> = match &settings.tls {
+        Some(tls) => {
+            let mut connection =
+                ServerConnection::new(Arc::clone(tls)).map_err(|err| err.to_string())?;
+            let mut stream = stream;
+            while connection.is_handshaking() {
+                connection
+                    .complete_io(&mut stream)
+                    .map_err(|err| format!("TLS handshake failed : {}", err))?;
+            }
+            Box::new(TlsStream(StreamOwned::new(connection, stream)))
+        }
+        None => Box::new(stream),
+    };
+    let mut http_request = None;
+    if let Some(token) = &settings.token {
+        let line = read_request(&mut stream);
+        // the files of the dashboard hold no data, the page gives its own token to the data
+        // requests, but the browser loads its script and style sheet without it
+        let path = line
+            .strip_prefix("GET ")
+            .and_then(|line| line.split(['?', ' ']).next())
+            .unwrap_or_default();
+        let public = settings.dashboard && dashboard::asset(path).is_some();
+        // a browser can only give its token in the query of its request
+        let client_token = if line.starts_with("GET ") {
+            let client_token = request::query_parameter(&line, "token").map(str::to_string);
+            http_request = Some(line);
+            client_token
+        } else {
+            line.strip_prefix("token ")
+                .map(|token| token.trim().to_string())
+        };
+        let valid = match client_token {
+            Some(client_token) => constant_time_eq(&client_token, token),
+            None => public,
+        };
+        if !valid {
+            let _ = if http_request.is_some() {
+                write_http_error(stream, "401 Unauthorized", "authentication failed")
+            } else {
+                write_json_error(stream, "authentication failed")
+            };
+            // the unread request would reset the connection before the client reads the error
+            let _ = socket.shutdown(Shutdown::Write);
+            let _ = socket.set_read_timeout(Some(Duration::from_millis(REQUEST_TIMEOUT_MS)));
+            let _ = io::copy(
+                &mut (&socket).take(MAX_REQUEST_LENGTH as u64),
+                &mut io::sink(),
+            );
+            return Err("invalid token".to_string());
+        }
+    }
+    socket
+        .set_read_timeout(Some(Duration::from_millis(REQUEST_TIMEOUT_MS)))
+        .map_err(|err| err.to_string())?;
+    let request = match http_request {
+        Some(request) => request,
+        None => read_request(&mut stream),
+    };
+    skip_http_headers(&mut stream, &request);
+    Ok((stream, request))
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl Drop for TlsStream {
+    fn drop(&mut self) {
+        self.0.conn.send_close_notify();
+        let _ = self.0.conn.complete_io(&mut self.0.sock);
+    }
+}
+
+fn write_json_minute(
+    minute_reader: &SharedCircularBufferReader<SensorData>,
+    derived: &DerivedChannels,
+    mut stream: Box<dyn ClientStream>,
+) -> io::Result<()> {
+    stream.write_all(b"[")?;
+    minute_reader
+        .snapshot()
+        .write_json_chunk(derived, &mut stream)?;
+    stream.write_all(b"]\n")
+}
+
+fn write_json_latest(
+    minute_reader: &SharedCircularBufferReader<SensorData>,
+    derived: &DerivedChannels,
+    mut stream: Box<dyn ClientStream>,
+) -> io::Result<()> {
+    stream.write_all(b"[")?;
+    if let Some(latest) = minute_reader.latest() {
+        latest.json_item(derived, &mut stream)?;
+    }
+    stream.write_all(b"]\n")
+}
+
+fn write_json_channels(
+    derived: &DerivedChannels,
+    mut stream: Box<dyn ClientStream>,
+) -> io::Result<()> {
+    let derived = derived.names_and_units();
+    let channels: Vec<String> = CHANNELS
+        .iter()
+        .copied()
+        .zip(UNITS.iter().copied())
+        .chain(derived)
+        .map(|(name, unit)| format!("{{\"name\": \"{}\", \"unit\": \"{}\"}}", name, unit))
+        .collect();
+    stream.write_fmt(format_args!("[{}]\n", channels.join(",\n")))
+}
+
+pub fn write_json_error(mut stream: Box<dyn ClientStream>, message: &str) -> io::Result<()> {
+    stream.write_fmt(format_args!(
+        "{{\"error\": \"{}\"}}\n",
+        json_display::escape(message)
+    ))
+}
+
+fn content_type(request: &Request) -> &'static str {
+    match request {
+        Request::LineProtocol(_) => "text/plain; charset=utf-8",
+        Request::Chart { .. } => "image/svg+xml",
+        Request::Subscribe { .. } => "application/x-ndjson",
+        Request::Metrics => metrics::CONTENT_TYPE,
+        _ => "application/json",
+    }
+}
+
+/// Header of an answer whose length is not known, it ends when the connection is closed
+fn write_http_header(stream: &mut Box<dyn ClientStream>, content_type: &str) -> io::Result<()> {
+    stream.write_fmt(format_args!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        content_type
+    ))
+}
+
+fn write_http_asset(
+    mut stream: Box<dyn ClientStream>,
+    content_type: &str,
+    content: &str,
+) -> io::Result<()> {
+    stream.write_fmt(format_args!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        content_type,
+        content.len()
+    ))?;
+    stream.write_all(content.as_bytes())
+}
+
+/// `status` is the code followed by its reason phrase, like "404 Not Found"
+fn write_http_error(
+    mut stream: Box<dyn ClientStream>,
+    status: &str,
+    message: &str,
+) -> io::Result<()> {
+    stream.write_fmt(format_args!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}\n",
+        status,
+        message.len() + 1,
+        message
+    ))
+}
+
+/// Read the first line sent by the client, empty if the client sent nothing before the
+/// read timeout of the socket
+fn read_request(stream: &mut dyn Read) -> String {
+    let mut request = Vec::new();
+    let mut byte = [0u8];
+    while request.len() < MAX_REQUEST_LENGTH {
+        match stream.read(&mut byte) {
+            Ok(1) if byte[0] != b'\n' => request.push(byte[0]),
+            _ => break,
+        }
+    }
+    String::from_utf8_lossy(&request).trim().to_string()
+}
+
+/// The headers of an HTTP request are not used, they are read up to the empty line which ends
+/// them, so the connection is not reset when it is closed
+fn skip_http_headers(stream: &mut dyn Read, request: &str) {
+    if request.starts_with("GET ") {
+        for _ in 0..MAX_HTTP_HEADERS {
+            if read_request(stream).is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+/// The duration doesn't tell how many characters of the token are right
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0u8, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        bind_unix_socket, listeners_from_config, ClientRequest, Server, SocketSettings, TcpSettings,
+    };
+    use crate::{
+        config::Config, derived::DerivedChannels, sensor_data::SensorData,
+        shared_circular_buffer::SharedCircularBuffer, test_utils::TempPath, tls,
+    };
+    use rustls::{pki_types::ServerName, ClientConnection, StreamOwned};
+    use std::{
+        convert::TryFrom,
+        fs,
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        os::unix::{
+            fs::{FileTypeExt, PermissionsExt},
+            net::{UnixListener, UnixStream},
+        },
+        sync::mpsc::{channel, Receiver},
+        thread,
+        time::{Duration, UNIX_EPOCH},
+    };
+
+    const CERTIFICATES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls/");
+
+    fn certificate(name: &str) -> String {
+        format!("{}{}", CERTIFICATES, name)
+    }
+
+    /// Start a listener on a free port, the requests handed to the sampling thread are
+    /// answered by "historics of <request>"
+    fn start(settings: TcpSettings) -> u16 {
+        let listener = TcpListener::bind(&settings.address).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut minute = SharedCircularBuffer::<SensorData>::new(4);
+        let _ = minute.push_back(SensorData::new(
+            UNIX_EPOCH + Duration::from_secs(1),
+            101.3,
+            20_000,
+            21_000,
+            50_000,
+        ));
+        let (tx, rx) = channel::<ClientRequest>();
+        Server::create_activated_tcp_server_thread(
+            listener,
+            settings,
+            minute.reader(),
+            DerivedChannels::default(),
+            tx,
+        );
+        thread::spawn(move || answer_requests(rx));
+        port
+    }
+
+    fn answer_requests(rx: Receiver<ClientRequest>) {
+        for (request, mut stream) in rx {
+            let _ = writeln!(stream, "historics of {:?}", request);
+        }
+    }
+
+    fn exchange(stream: &mut dyn ReadWrite, lines: &str) -> String {
+        stream.write_all(lines.as_bytes()).unwrap();
+        let mut answer = String::new();
+        stream.read_to_string(&mut answer).unwrap();
+        answer
+    }
+
+    trait ReadWrite: Read + Write {}
+    impl<T: Read + Write> ReadWrite for T {}
+
+    fn tls_client(port: u16, client_certificate: bool) -> StreamOwned<ClientConnection, TcpStream> {
+        let client_key = (certificate("client.pem"), certificate("client.key"));
+        let config = tls::client_config(
+            &certificate("ca.pem"),
+            if client_certificate {
+                Some((client_key.0.as_str(), client_key.1.as_str()))
+            } else {
+                None
+            },
+        )
+        .unwrap();
+        let connection =
+            ClientConnection::new(config, ServerName::try_from("localhost").unwrap()).unwrap();
+        StreamOwned::new(connection, TcpStream::connect(("127.0.0.1", port)).unwrap())
+    }
+
+    #[test]
+    fn plain_tcp_with_token() {
+        let port = start(TcpSettings::new("test", "127.0.0.1:0").with_token("secret"));
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert_eq!(
+            exchange(&mut stream, "token secret\nalerts\n"),
+            "historics of Alerts\n"
+        );
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert_eq!(
+            exchange(&mut stream, "token secret\nminute\n"),
+            "[{\"timestamp\": 1000,\n\"pressure\"  : 1013.00,\n\"bmp280Temp\": 20.000,\n\"htu21Temp\" : 21.000,\n\"humidity\"  : 50.00,\n\"calibrated\": false}\n]\n"
+        );
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert_eq!(
+            exchange(&mut stream, "token guess\nalerts\n"),
+            "{\"error\": \"authentication failed\"}\n"
+        );
+    }
+
+    #[test]
+    fn http_request_with_token() {
+        let port = start(TcpSettings::new("test", "127.0.0.1:0").with_token("secret"));
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert_eq!(
+            exchange(
+                &mut stream,
+                "GET /subscribe?token=secret&reduced HTTP/1.1\r\nHost: localhost\r\n\r\n"
+            ),
+            "historics of Subscribe { format: ServerSentEvents, reduced: true }\n"
+        );
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert_eq!(
+            exchange(&mut stream, "GET /index.html?token=secret HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 25\r\nConnection: close\r\n\r\nunknown path /index.html\n"
+        );
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert_eq!(
+            exchange(&mut stream, "GET /subscribe HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 401 Unauthorized\r\nContent-Type: text/plain\r\nContent-Length: 22\r\nConnection: close\r\n\r\nauthentication failed\n"
+        );
+    }
+
+    #[test]
+    fn connections_are_limited() {
+        let port = start(
+            TcpSettings::new("test", "127.0.0.1:0")
+                .with_token("secret")
+                .with_max_connections(1),
+        );
+        // the first client is waited for until it sends its token
+        let first = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        let mut second = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut answer = String::new();
+        second.read_to_string(&mut answer).unwrap();
+        assert_eq!(answer, "");
+
+        drop(first);
+        for _ in 0..50 {
+            // a refused connection may be reset
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            answer.clear();
+            let _ = stream
+                .write_all(b"token secret\nalerts\n")
+                .and_then(|_| stream.read_to_string(&mut answer));
+            if !answer.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(answer, "historics of Alerts\n");
+    }
+
+    #[test]
+    fn dashboard() {
+        let port = start(TcpSettings::new("test", "127.0.0.1:0").with_dashboard());
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            exchange(
+                &mut stream,
+                &format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path),
+            )
+        };
+        let page = get("/");
+        assert!(page.starts_with(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: "
+        ));
+        assert!(page.contains("<script src=\"dashboard.js\"></script>"));
+        assert!(get("/dashboard.js")
+            .starts_with("HTTP/1.1 200 OK\r\nContent-Type: application/javascript"));
+        assert!(get("/dashboard.css").starts_with("HTTP/1.1 200 OK\r\nContent-Type: text/css"));
+        assert_eq!(
+            get("/tier/hour"),
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\nhistorics of Tier(1)\n"
+        );
+        assert!(get("/latest").ends_with("\r\n\r\n[{\"timestamp\": 1000,\n\"pressure\"  : 1013.00,\n\"bmp280Temp\": 20.000,\n\"htu21Temp\" : 21.000,\n\"humidity\"  : 50.00,\n\"calibrated\": false}\n]\n"));
+        assert!(get("/tier/week").starts_with("HTTP/1.1 400 Bad Request\r\n"));
+        assert_eq!(
+            get("/chart/days/pressure,humidity"),
+            "HTTP/1.1 200 OK\r\nContent-Type: image/svg+xml\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\nhistorics of Chart { tier: 2, channels: [0, 3], range: None }\n"
+        );
+
+        // with a token, the page and its files are served without it, not the data
+        let port = start(
+            TcpSettings::new("test", "127.0.0.1:0")
+                .with_dashboard()
+                .with_token("secret"),
+        );
+        let get = |path: &str| {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            exchange(&mut stream, &format!("GET {} HTTP/1.1\r\n\r\n", path))
+        };
+        assert!(get("/?token=secret").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("/dashboard.js").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("/dashboard.css").starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(get("/tier/hour").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(get("/tier/hour?token=wrong").starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+        assert!(get("/tier/hour?token=secret").ends_with("historics of Tier(1)\n"));
+
+        // the other listeners don't serve it
+        let port = start(TcpSettings::new("test", "127.0.0.1:0"));
+        let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        assert!(exchange(&mut stream, "GET / HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+    }
+
+    #[test]
+    fn ipv6_listener() {
+        if TcpListener::bind("[::1]:0").is_err() {
+            println!("IPv6 is not available");
+            return;
+        }
+        let port = start(TcpSettings::new("test", "[::1]:0"));
+        let mut stream = TcpStream::connect(("::1", port)).unwrap();
+        assert_eq!(exchange(&mut stream, "nodes\n"), "historics of Nodes\n");
+    }
+
+    #[test]
+    fn tls_with_client_certificate() {
+        let tls = tls::server_config(
+            &certificate("server.pem"),
+            &certificate("server.key"),
+            Some(&certificate("ca.pem")),
+        )
+        .unwrap();
+        let port = start(TcpSettings::new("test", "127.0.0.1:0").with_tls(tls));
+        // a silent client gets all the historics
+        assert_eq!(
+            exchange(&mut tls_client(port, true), ""),
+            "historics of Historics\n"
+        );
+        assert_eq!(
+            exchange(&mut tls_client(port, true), "lineprotocol hour\n"),
+            "historics of LineProtocol(1)\n"
+        );
+        let mut anonymous = tls_client(port, false);
+        let _ = anonymous.write_all(b"alerts\n");
+        let mut answer = String::new();
+        assert!(anonymous.read_to_string(&mut answer).is_err());
+        assert_eq!(answer, "");
+    }
+
+    #[test]
+    fn listeners_configuration() {
+        let config = Config::parse(&format!(
+            "[listener local]\naddress = 127.0.0.1:7879\ndashboard = true\n[listener remote]\naddress = [::]:7880\ncertificate = {}\nkey = {}\nclient_ca = {}\ntoken = secret\nmax_connections = 4\n",
+            certificate("server.pem"),
+            certificate("server.key"),
+            certificate("ca.pem")
+        ))
+        .unwrap();
+        let listeners = listeners_from_config(&config).unwrap();
+        assert_eq!(listeners.len(), 2);
+        assert!(listeners[0].tls.is_none() && listeners[0].token.is_none());
+        assert!(listeners[0].dashboard && !listeners[1].dashboard);
+        assert!(listeners[1].tls.is_some());
+        assert_eq!(listeners[1].token.as_deref(), Some("secret"));
+        assert_eq!(listeners[0].max_connections, super::DEFAULT_MAX_CONNECTIONS);
+        assert_eq!(listeners[1].max_connections, 4);
+
+        let config =
+            Config::parse("[listener remote]\naddress = [::]:7880\ncertificate = server.pem\n")
+                .unwrap();
+        assert_eq!(
+            listeners_from_config(&config).err(),
+            Some("line 1: section [listener remote]: certificate and key go together".to_string())
+        );
+    }
+
+    #[test]
+    fn stale_socket_is_replaced() {
+        let socket = TempPath::new("stale_socket");
+        drop(UnixListener::bind(socket.path()).unwrap());
+        let settings = SocketSettings {
+            mode: Some(0o660),
+            ..SocketSettings::default()
+        };
+        let _listener = bind_unix_socket(socket.path(), &settings).unwrap();
+        let metadata = fs::symlink_metadata(socket.path()).unwrap();
+        assert!(metadata.file_type().is_socket());
+        assert_eq!(metadata.permissions().mode() & 0o7777, 0o660);
+        UnixStream::connect(socket.path()).unwrap();
+    }
+
+    #[test]
+    fn used_socket_is_not_replaced() {
+        let socket = TempPath::new("used_socket");
+        let _listener = UnixListener::bind(socket.path()).unwrap();
+        assert_eq!(
+            bind_unix_socket(socket.path(), &SocketSettings::default()).err(),
+            Some(format!(
+                "socket {} is already used by another process",
+                socket.path().display()
+            ))
+        );
+    }
+
+    #[test]
+    fn other_files_are_not_deleted() {
+        let file = TempPath::new("not_a_socket");
+        fs::write(file.path(), "data").unwrap();
+        assert_eq!(
+            bind_unix_socket(file.path(), &SocketSettings::default()).err(),
+            Some(format!(
+                "{} exists and is not a socket, it is not deleted",
+                file.path().display()
+            ))
+        );
+        assert_eq!(fs::read_to_string(file.path()).unwrap(), "data");
+
+        let missing_directory = TempPath::new("missing_directory");
+        let error = bind_unix_socket(
+            &missing_directory.path().join("socket"),
+            &SocketSettings::default(),
+        )
+        .err()
+        .unwrap();
+        assert!(error.starts_with("failed to bind socket"), "{}", error);
+    }
+
+    #[test]
+    fn socket_configuration() {
+        let config = Config::parse(
+            "[socket]\npath = /run/weather/socket\nmode = 0660\nowner = 1000\ngroup = root\n",
+        )
+        .unwrap();
+        assert_eq!(
+            SocketSettings::from_config(&config).unwrap(),
+            SocketSettings {
+                path: Some("/run/weather/socket".to_string()),
+                mode: Some(0o660),
+                owner: Some(1000),
+                group: Some(0),
+            }
+        );
+        assert_eq!(
+            SocketSettings::from_config(&Config::default()).unwrap(),
+            SocketSettings::default()
+        );
+        let config = Config::parse("[socket]\nmode = 0668\n").unwrap();
+        assert_eq!(
+            SocketSettings::from_config(&config).err(),
+            Some("line 1: section [socket]: invalid octal mode 0668".to_string())
+        );
+        let config = Config::parse("[socket]\nowner = no_such_user_here\n").unwrap();
+        assert_eq!(
+            SocketSettings::from_config(&config).err(),
+            Some("line 1: section [socket]: unknown user no_such_user_here".to_string())
+        );
     }
 }
@@ -0,0 +1,336 @@
+use crate::circular_buffer::CircularBuffer;
+use std::{
+    cell::UnsafeCell,
+    hint,
+    mem::MaybeUninit,
+    ptr,
+    sync::{
+        atomic::{fence, AtomicUsize, Ordering},
+        Arc,
+    },
+    thread,
+};
+
+/// Bounded FIFO queue with one writer and many readers living in other threads.
+///
+/// The writer never waits for the readers: every modification is surrounded by a sequence
+/// counter (seqlock), and a reader copies the whole content and starts again if the counter
+/// moved during the copy. The readers only get copies, so they need `T: Copy`.
+pub struct SharedCircularBuffer<T> {
+    inner: Arc<Inner<T>>,
+}
+
+/// Read-only handle on a `SharedCircularBuffer`, it can be cloned and sent to other threads
+pub struct SharedCircularBufferReader<T> {
+    inner: Arc<Inner<T>>,
+}
+
+struct Inner<T> {
+    // Odd while the writer is modifying the buffer
+    sequence: AtomicUsize,
+    first: AtomicUsize,
+    valid_items: AtomicUsize,
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+}
+
+// The slots are only modified by the unique writer, the readers validate their copies with
+// the sequence counter before using them.
+unsafe impl<T: Send> Send for Inner<T> {}
+unsafe impl<T: Send> Sync for Inner<T> {}
+
+impl<T> Inner<T> {
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        let first = self.first.load(Ordering::Relaxed);
+        self.slots[(first + index) % self.capacity()].get()
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        for index in 0..*self.valid_items.get_mut() {
+            unsafe { ptr::drop_in_place((*self.slot(index)).as_mut_ptr()) };
+        }
+    }
+}
+
+impl<T> SharedCircularBuffer<T> {
+    pub fn new(size: usize) -> SharedCircularBuffer<T> {
+        SharedCircularBuffer {
+            inner: Arc::new(Inner {
+                sequence: AtomicUsize::new(0),
+                first: AtomicUsize::new(0),
+                valid_items: AtomicUsize::new(0),
+                slots: (0..size)
+                    .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+                    .collect(),
+            }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len() >= self.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.valid_items.load(Ordering::Relaxed)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.inner.capacity()
+    }
+
+    /// Append an item at the back of the buffer, a full buffer hands the item back
+    pub fn push_back(&mut self, item_value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item_value);
+        }
+        self.write_section(|inner| {
+            let valid_items = inner.valid_items.load(Ordering::Relaxed);
+            unsafe { ptr::write_volatile(inner.slot(valid_items), MaybeUninit::new(item_value)) };
+            inner.valid_items.store(valid_items + 1, Ordering::Relaxed);
+        });
+        Ok(())
+    }
+
+    /// Remove the oldest item of the buffer and return it
+    pub fn pop_front(&mut self) -> Option<T> {
+        self.drain_front(1).pop()
+    }
+
+    /// Remove the `nb_items` oldest items at once, the readers never see a partial removal
+    pub fn drain_front(&mut self, nb_items: usize) -> Vec<T> {
+        let nb_items = nb_items.min(self.len());
+        let mut drained_items = Vec::with_capacity(nb_items);
+        if nb_items == 0 {
+            return drained_items;
+        }
+        self.write_section(|inner| {
+            for index in 0..nb_items {
+                drained_items.push(unsafe { (*inner.slot(index)).as_ptr().read() });
+            }
+            let first = inner.first.load(Ordering::Relaxed);
+            let valid_items = inner.valid_items.load(Ordering::Relaxed);
+            inner
+                .first
+                .store((first + nb_items) % inner.capacity(), Ordering::Relaxed);
+            inner
+                .valid_items
+                .store(valid_items - nb_items, Ordering::Relaxed);
+        });
+        drained_items
+    }
+
+    /// Iterate over the items from the writer side, oldest first
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> + ExactSizeIterator {
+        // Nobody else can modify the slots while the writer is borrowed
+        (0..self.len()).map(move |index| unsafe { &*(*self.inner.slot(index)).as_ptr() })
+    }
+
+    fn write_section<F: FnOnce(&Inner<T>)>(&mut self, modification: F) {
+        let sequence = self.inner.sequence.load(Ordering::Relaxed);
+        self.inner.sequence.store(sequence + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        modification(&self.inner);
+        self.inner.sequence.store(sequence + 2, Ordering::Release);
+    }
+}
+
+impl<T: Copy> SharedCircularBuffer<T> {
+    pub fn reader(&self) -> SharedCircularBufferReader<T> {
+        SharedCircularBufferReader {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T: Copy> SharedCircularBufferReader<T> {
+    /// Consistent copy of the whole content of the buffer
+    pub fn snapshot(&self) -> CircularBuffer<T> {
+        let mut items = Vec::with_capacity(self.inner.capacity());
+        self.read_section(|inner| {
+            items.clear();
+            let valid_items = inner.valid_items.load(Ordering::Relaxed);
+            for index in 0..valid_items.min(inner.capacity()) {
+                items.push(unsafe { ptr::read_volatile(inner.slot(index)) });
+            }
+        });
+        // The copies are only used once the sequence counter validated them
+        items
+            .into_iter()
+            .map(|item| unsafe { item.assume_init() })
+            .collect()
+    }
+
+    /// Copy of the most recent item
+    pub fn latest(&self) -> Option<T> {
+        let mut latest = None;
+        self.read_section(|inner| {
+            let valid_items = inner.valid_items.load(Ordering::Relaxed);
+            latest = match valid_items {
+                0 => None,
+                _ if valid_items > inner.capacity() => None,
+                _ => Some(unsafe { ptr::read_volatile(inner.slot(valid_items - 1)) }),
+            };
+        });
+        latest.map(|item| unsafe { item.assume_init() })
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.valid_items.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Run `copy` until it has been executed without any concurrent modification
+    fn read_section<F: FnMut(&Inner<T>)>(&self, mut copy: F) {
+        let mut attempts: u32 = 0;
+        loop {
+            let sequence = self.inner.sequence.load(Ordering::Acquire);
+            if sequence.is_multiple_of(2) {
+                copy(&self.inner);
+                fence(Ordering::Acquire);
+                if self.inner.sequence.load(Ordering::Relaxed) == sequence {
+                    return;
+                }
+            }
+            attempts = attempts.wrapping_add(1);
+            if attempts.is_multiple_of(64) {
+                thread::yield_now();
+            } else {
+                hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl<T> Clone for SharedCircularBufferReader<T> {
+    fn clone(&self) -> Self {
+        SharedCircularBufferReader {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedCircularBuffer;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    /// Items with redundant fields, a torn copy would break the relation between them
+    #[derive(Copy, Clone, Debug, PartialEq)]
+    struct Sample {
+        index: u64,
+        double: u64,
+        inverted: u64,
+    }
+
+    impl Sample {
+        fn new(index: u64) -> Sample {
+            Sample {
+                index,
+                double: index.wrapping_mul(2),
+                inverted: !index,
+            }
+        }
+
+        fn is_consistent(&self) -> bool {
+            self.double == self.index.wrapping_mul(2) && self.inverted == !self.index
+        }
+    }
+
+    #[test]
+    fn writer_side_operations() {
+        let mut circ_buf = SharedCircularBuffer::<u32>::new(3);
+        let reader = circ_buf.reader();
+        assert_eq!(reader.latest(), None);
+        for value in 0..3 {
+            assert_eq!(circ_buf.push_back(value), Ok(()));
+        }
+        assert_eq!(circ_buf.push_back(3), Err(3));
+        assert_eq!(circ_buf.pop_front(), Some(0));
+        assert_eq!(circ_buf.push_back(3), Ok(()));
+        assert_eq!(circ_buf.iter().copied().collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(reader.snapshot().into_iter().collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(reader.latest(), Some(3));
+        assert_eq!(circ_buf.drain_front(5), [1, 2, 3]);
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn items_not_popped_are_dropped() {
+        use std::rc::Rc;
+
+        let item = Rc::new(1);
+        let mut circ_buf = SharedCircularBuffer::new(4);
+        circ_buf.push_back(Rc::clone(&item)).unwrap();
+        circ_buf.push_back(Rc::clone(&item)).unwrap();
+        assert_eq!(Rc::strong_count(&item), 3);
+        drop(circ_buf);
+        assert_eq!(Rc::strong_count(&item), 1);
+    }
+
+    /// One thread pushes consecutive samples and pops them by batches like `Historic::reduce`,
+    /// while several readers check that every snapshot they get is consistent.
+    #[test]
+    fn readers_never_see_torn_snapshots() {
+        const CAPACITY: usize = 32;
+        let mut circ_buf = SharedCircularBuffer::<Sample>::new(CAPACITY);
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                let reader = circ_buf.reader();
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    let mut nb_snapshots = 0;
+                    while !stop.load(Ordering::Relaxed) {
+                        let snapshot = reader.snapshot();
+                        assert!(snapshot.len() <= CAPACITY);
+                        let mut previous: Option<u64> = None;
+                        for sample in &snapshot {
+                            assert!(sample.is_consistent(), "torn sample {:?}", sample);
+                            if let Some(previous) = previous {
+                                assert_eq!(sample.index, previous + 1);
+                            }
+                            previous = Some(sample.index);
+                        }
+                        if let Some(latest) = reader.latest() {
+                            assert!(latest.is_consistent(), "torn sample {:?}", latest);
+                        }
+                        nb_snapshots += 1;
+                    }
+                    nb_snapshots
+                })
+            })
+            .collect();
+
+        for index in 0..200_000 {
+            if circ_buf.is_full() {
+                let drained = circ_buf.drain_front(CAPACITY / 2);
+                assert_eq!(drained.len(), CAPACITY / 2);
+            }
+            circ_buf.push_back(Sample::new(index)).unwrap();
+        }
+        stop.store(true, Ordering::Relaxed);
+
+        for reader in readers {
+            assert!(reader.join().unwrap() > 0);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use crate::{
+    config::parse_duration,
+    sensor_data::{SensorData, CHANNELS},
+};
+use std::{
+    fmt::{self, Display},
+    time::Duration,
+};
+
+/// Smoothing of the channels of a series of samples, the samples keep their timestamps
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Smoothing {
+    /// `ema <time constant>` : exponential moving average, weighted by the time between the
+    /// samples so that the gaps of the series are handled
+    Ema(Duration),
+    /// `sma <samples>` : average of the last samples
+    Sma(usize),
+    /// `sg <window>` : Savitzky–Golay filter of degree 2 over an odd window of samples
+    /// centered on each sample, narrowed at the ends of the series
+    SavitzkyGolay(usize),
+}
+
+impl Smoothing {
+    /// Words of a request after the tier: `ema 60s`, `sma 12` or `sg 7`
+    pub fn parse(method: Option<&str>, parameter: Option<&str>) -> Result<Smoothing, String> {
+        let method = method.ok_or("missing smoothing")?;
+        let parameter = parameter.ok_or("missing smoothing parameter")?;
+        let count = || match parameter.parse::<usize>() {
+            Ok(count) if count > 0 => Ok(count),
+            _ => Err(format!("invalid number of samples {}", parameter)),
+        };
+        match method {
+            "ema" => match parse_duration(parameter) {
+                Some(time_constant) if !time_constant.is_zero() => {
+                    Ok(Smoothing::Ema(time_constant))
+                }
+                _ => Err(format!("invalid time constant {}", parameter)),
+            },
+            "sma" => count().map(Smoothing::Sma),
+            "sg" => match count()? {
+                window if window % 2 == 1 && window >= 5 => Ok(Smoothing::SavitzkyGolay(window)),
+                _ => Err(format!(
+                    "the window of sg must be odd and at least 5, not {}",
+                    parameter
+                )),
+            },
+            method => Err(format!(
+                "unknown smoothing {}, expected ema, sma or sg",
+                method
+            )),
+        }
+    }
+
+    /// Smoothed copy of the samples
+    pub fn apply(&self, samples: &[SensorData]) -> Vec<SensorData> {
+        let timestamps: Vec<f64> = samples
+            .iter()
+            .map(|sample| sample.get_timestamp().as_secs_f64())
+            .collect();
+        let mut smoothed = samples.to_vec();
+        for channel in CHANNELS.iter() {
+            let values: Vec<f64> = samples
+                .iter()
+                .map(|sample| sample.get_channel(channel).unwrap_or(f64::NAN))
+                .collect();
+            let values = match self {
+                Smoothing::Ema(time_constant) => {
+                    ema(&timestamps, &values, time_constant.as_secs_f64())
+                }
+                Smoothing::Sma(count) => sma(&values, *count),
+                Smoothing::SavitzkyGolay(window) => savitzky_golay(&values, *window),
+            };
+            for (sample, value) in smoothed.iter_mut().zip(values) {
+                sample.set_channel(channel, value);
+            }
+        }
+        smoothed
+    }
+}
+
+impl Display for Smoothing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Smoothing::Ema(time_constant) => write!(f, "ema {}s", time_constant.as_secs_f64()),
+            Smoothing::Sma(count) => write!(f, "sma {}", count),
+            Smoothing::SavitzkyGolay(window) => write!(f, "sg {}", window),
+        }
+    }
+}
+
+/// Exponential moving average of values at the given times, in seconds
+pub fn ema(timestamps: &[f64], values: &[f64], time_constant: f64) -> Vec<f64> {
+    let mut average: Option<(f64, f64)> = None;
+    timestamps
+        .iter()
+        .zip(values)
+        .map(|(timestamp, value)| {
+            let smoothed = match average {
+                None => *value,
+                Some((previous_timestamp, previous)) => {
+                    let alpha = 1.0 - (-(timestamp - previous_timestamp) / time_constant).exp();
+                    previous + alpha * (value - previous)
+                }
+            };
+            average = Some((*timestamp, smoothed));
+            smoothed
+        })
+        .collect()
+}
+
+/// Average of each value and of the `count - 1` values before it, fewer at the start
+pub fn sma(values: &[f64], count: usize) -> Vec<f64> {
+    let mut sum = 0.0;
+    values
+        .iter()
+        .enumerate()
+        .map(|(index, value)| {
+            sum += value;
+            if index >= count {
+                sum -= values[index - count];
+            }
+            sum / (index + 1).min(count) as f64
+        })
+        .collect()
+}
+
+/// Savitzky–Golay smoothing of degree 2 (and 3), whose convolution coefficients over the
+/// window `-m..=m` are `3 (3m² + 3m - 1 - 5i²) / ((4m² - 1)(2m + 3))`
+pub fn savitzky_golay(values: &[f64], window: usize) -> Vec<f64> {
+    let half = window / 2;
+    (0..values.len())
+        .map(|index| {
+            // the window is centered, so it is narrowed near the ends
+            let m = half.min(index).min(values.len() - 1 - index) as f64;
+            let denominator = (4.0 * m * m - 1.0) * (2.0 * m + 3.0);
+            (-(m as isize)..=m as isize)
+                .map(|offset| {
+                    let i = offset as f64;
+                    let coefficient =
+                        3.0 * (3.0 * m * m + 3.0 * m - 1.0 - 5.0 * i * i) / denominator;
+                    coefficient * values[(index as isize + offset) as usize]
+                })
+                .sum()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ema, savitzky_golay, sma, Smoothing};
+    use crate::sensor_data::SensorData;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn assert_close(values: &[f64], expected: &[f64]) {
+        assert_eq!(values.len(), expected.len());
+        for (value, expected) in values.iter().zip(expected) {
+            assert!(
+                (value - expected).abs() < 1e-9,
+                "{:?} instead of {:?}",
+                values,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn moving_averages() {
+        assert_close(
+            &sma(&[1.0, 2.0, 3.0, 4.0, 5.0], 3),
+            &[1.0, 1.5, 2.0, 3.0, 4.0],
+        );
+        assert_close(&sma(&[1.0, 5.0], 1), &[1.0, 5.0]);
+        // a step, one sample per time constant
+        let half = 1.0 - (-1f64).exp();
+        assert_close(
+            &ema(&[0.0, 10.0, 20.0], &[0.0, 1.0, 1.0], 10.0),
+            &[0.0, half, half + (1.0 - half) * half],
+        );
+        // a gap of 2 time constants weighs as 2 samples
+        assert_close(
+            &ema(&[0.0, 20.0], &[0.0, 1.0], 10.0),
+            &[0.0, 1.0 - (-2f64).exp()],
+        );
+    }
+
+    #[test]
+    fn savitzky_golay_filter() {
+        // a parabola is kept by a filter of degree 2
+        let parabola: Vec<f64> = (0..9).map(|x| (x * x) as f64 - 3.0 * x as f64).collect();
+        assert_close(&savitzky_golay(&parabola, 5), &parabola);
+        // the coefficients of the window of 5 samples are (-3, 12, 17, 12, -3) / 35
+        let impulse = [0.0, 0.0, 0.0, 0.0, 35.0, 0.0, 0.0, 0.0, 0.0];
+        assert_close(
+            &savitzky_golay(&impulse, 5),
+            &[0.0, 0.0, -3.0, 12.0, 17.0, 12.0, -3.0, 0.0, 0.0],
+        );
+        // the window of 3 samples keeps the values, so the requests need 5 of them
+        assert_close(&savitzky_golay(&[1.0, 4.0, 2.0], 3), &[1.0, 4.0, 2.0]);
+    }
+
+    #[test]
+    fn smoothed_samples() {
+        let samples: Vec<SensorData> = [50_000, 52_000, 60_000]
+            .iter()
+            .enumerate()
+            .map(|(index, humidity)| {
+                SensorData::new(
+                    UNIX_EPOCH + Duration::from_secs(index as u64 * 5),
+                    101.3,
+                    20_000,
+                    20_000,
+                    *humidity,
+                )
+            })
+            .collect();
+        let smoothed = Smoothing::Sma(2).apply(&samples);
+        let humidity: Vec<f64> = smoothed
+            .iter()
+            .map(|sample| sample.get_channel("humidity").unwrap())
+            .collect();
+        assert_close(&humidity, &[50.0, 51.0, 56.0]);
+        assert_eq!(smoothed[2].get_timestamp(), Duration::from_secs(10));
+        assert_eq!(smoothed[2].get_channel("bmp280Temp"), Some(20.0));
+    }
+
+    #[test]
+    fn parse_smoothings() {
+        assert_eq!(
+            Smoothing::parse(Some("ema"), Some("2m")),
+            Ok(Smoothing::Ema(Duration::from_secs(120)))
+        );
+        assert_eq!(
+            Smoothing::parse(Some("sma"), Some("12")),
+            Ok(Smoothing::Sma(12))
+        );
+        assert_eq!(
+            Smoothing::parse(Some("sg"), Some("4")),
+            Err("the window of sg must be odd and at least 5, not 4".to_string())
+        );
+        assert_eq!(
+            Smoothing::parse(Some("median"), Some("4")),
+            Err("unknown smoothing median, expected ema, sma or sg".to_string())
+        );
+        assert_eq!(
+            Smoothing::parse(Some("ema"), Some("0s")),
+            Err("invalid time constant 0s".to_string())
+        );
+        assert_eq!(
+            Smoothing::parse(Some("sma"), None),
+            Err("missing smoothing parameter".to_string())
+        );
+    }
+}
@@ -0,0 +1,368 @@
+use crate::{
+    anomaly::AnomalyEvent, config::Config, derived::DerivedChannels, json_display::JsonDisplay,
+    sensor_data::SensorData, server::ClientStream,
+};
+use std::{
+    io::{self, Write},
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc,
+    },
+    thread::spawn,
+};
+
+const DEFAULT_QUEUE_SIZE: usize = 32;
+const DEFAULT_MAX_SUBSCRIBERS: usize = 16;
+
+/// How the events are written to a subscriber
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamFormat {
+    /// One JSON object per line : `{"event": "sample", "data": {...}}`
+    Ndjson,
+    /// Server-Sent Events, after an HTTP response header, for the browsers
+    ServerSentEvents,
+}
+
+/// Samples as they are produced by the sampling thread
+#[derive(Clone, Copy)]
+pub enum Event {
+    Sample(SensorData),
+    /// Average added by `Historic::reduce` to the tier of that name
+    Reduced(&'static str, SensorData),
+    /// Start or end of an anomaly, sent to all the subscribers
+    Anomaly(AnomalyEvent),
+}
+
+struct Subscriber {
+    reduced: bool,
+    events: SyncSender<Event>,
+}
+
+/// Clients subscribed to the new samples, read from the `[subscriptions]` section of the
+/// configuration:
+/// ```text
+/// [subscriptions]
+/// queue = 32      # events kept for a client which doesn't read them fast enough
+/// max = 16        # number of clients subscribed at the same time
+/// ```
+/// Each subscriber is written to by its own thread, and is dropped once its queue is full.
+pub struct Subscribers {
+    queue_size: usize,
+    max_subscribers: usize,
+    subscribers: Vec<Subscriber>,
+    derived: Arc<DerivedChannels>,
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Sample(_) => "sample",
+            Event::Reduced(tier, _) => tier,
+            Event::Anomaly(_) => "anomaly",
+        }
+    }
+
+    /// The sample, with its `derived` channels, or the anomaly as JSON on a single line
+    fn json_data(&self, derived: &DerivedChannels) -> String {
+        let mut json = Vec::new();
+        let _ = match self {
+            Event::Sample(data) | Event::Reduced(_, data) => data.json_item(derived, &mut json),
+            Event::Anomaly(anomaly) => anomaly.write_json(&mut json),
+        };
+        String::from_utf8_lossy(&json).replace('\n', "")
+    }
+
+    pub fn write(
+        &self,
+        format: StreamFormat,
+        derived: &DerivedChannels,
+        w: &mut dyn Write,
+    ) -> io::Result<()> {
+        match format {
+            StreamFormat::Ndjson => w.write_fmt(format_args!(
+                "{{\"event\": \"{}\", \"data\": {}}}\n",
+                self.name(),
+                self.json_data(derived)
+            ))?,
+            StreamFormat::ServerSentEvents => w.write_fmt(format_args!(
+                "event: {}\ndata: {}\n\n",
+                self.name(),
+                self.json_data(derived)
+            ))?,
+        }
+        w.flush()
+    }
+}
+
+impl Subscribers {
+    pub fn new(queue_size: usize, max_subscribers: usize) -> Subscribers {
+        Subscribers {
+            queue_size,
+            max_subscribers,
+            subscribers: Vec::new(),
+            derived: Arc::default(),
+        }
+    }
+
+    /// Derived channels written with the samples
+    pub fn with_derived_channels(mut self, derived: DerivedChannels) -> Subscribers {
+        self.derived = Arc::new(derived);
+        self
+    }
+
+    pub fn from_config(config: &Config) -> Result<Subscribers, String> {
+        let section = match config.section("subscriptions")? {
+            Some(section) => section,
+            None => {
+                return Ok(Subscribers::new(
+                    DEFAULT_QUEUE_SIZE,
+                    DEFAULT_MAX_SUBSCRIBERS,
+                ))
+            }
+        };
+        section.check_keys(&["queue", "max"])?;
+        let queue_size = section.get_or("queue", DEFAULT_QUEUE_SIZE)?;
+        if queue_size == 0 {
+            return Err(section.error("the queue can't be empty"));
+        }
+        Ok(Subscribers::new(
+            queue_size,
+            section.get_or("max", DEFAULT_MAX_SUBSCRIBERS)?,
+        ))
+    }
+
+    pub fn len(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    /// Stream the next events to the client, the reduced samples too when `reduced` is set
+    pub fn subscribe(
+        &mut self,
+        mut stream: Box<dyn ClientStream>,
+        format: StreamFormat,
+        reduced: bool,
+    ) -> io::Result<()> {
+        if self.subscribers.len() >= self.max_subscribers {
+            return match format {
+                StreamFormat::Ndjson => crate::server::write_json_error(stream, "too many subscribers"),
+                StreamFormat::ServerSentEvents => {
+                    stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+                }
+            };
+        }
+        let (events, receiver) = sync_channel(self.queue_size);
+        let derived = self.derived.clone();
+        spawn(move || {
+            if let Err(err) = stream_events(stream, format, &derived, receiver) {
+                println!("Subscriber left : {}", err);
+            }
+        });
+        self.subscribers.push(Subscriber { reduced, events });
+        Ok(())
+    }
+
+    /// Queue the event for every subscriber, the subscribers which left or are too slow are
+    /// dropped
+    pub fn publish(&mut self, event: Event) {
+        self.subscribers.retain(|subscriber| {
+            if let Event::Reduced(..) = event {
+                if !subscriber.reduced {
+                    return true;
+                }
+            }
+            match subscriber.events.try_send(event) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    println!("Subscriber dropped, it doesn't read the events fast enough");
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
+
+/// Write the events until the subscriber is dropped or the client leaves
+fn stream_events(
+    mut stream: Box<dyn ClientStream>,
+    format: StreamFormat,
+    derived: &DerivedChannels,
+    events: Receiver<Event>,
+) -> io::Result<()> {
+    if format == StreamFormat::ServerSentEvents {
+        stream.write_all(
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: keep-alive\r\n\r\n",
+        )?;
+        stream.flush()?;
+    }
+    for event in events {
+        event.write(format, derived, &mut stream)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, StreamFormat, Subscribers};
+    use crate::{
+        anomaly::{AnomalyEvent, AnomalyKind},
+        config::Config,
+        derived::DerivedChannels,
+        test_utils::sample,
+    };
+    use std::{
+        io::{BufRead, BufReader, Read},
+        os::unix::net::UnixStream,
+        time::Duration,
+    };
+
+    #[test]
+    fn event_formats() {
+        let mut ndjson = Vec::new();
+        Event::Reduced("hour", sample(1))
+            .write(
+                StreamFormat::Ndjson,
+                &DerivedChannels::default(),
+                &mut ndjson,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(ndjson).unwrap(),
+            "{\"event\": \"hour\", \"data\": {\"timestamp\": 1000,\"pressure\"  : 1013.00,\"bmp280Temp\": 20.000,\"htu21Temp\" : 21.000,\"humidity\"  : 50.00,\"calibrated\": false}}\n"
+        );
+        let mut sse = Vec::new();
+        Event::Sample(sample(2))
+            .write(
+                StreamFormat::ServerSentEvents,
+                &DerivedChannels::default(),
+                &mut sse,
+            )
+            .unwrap();
+        let sse = String::from_utf8(sse).unwrap();
+        assert!(sse.starts_with("event: sample\ndata: {\"timestamp\": 2000,"));
+        assert!(sse.ends_with("}\n\n"));
+        let mut ndjson = Vec::new();
+        Event::Anomaly(AnomalyEvent {
+            kind: AnomalyKind::Stuck,
+            channel: "humidity",
+            active: true,
+            value: 48.2,
+            score: 7200.0,
+            timestamp: Duration::from_secs(3),
+        })
+        .write(
+            StreamFormat::Ndjson,
+            &DerivedChannels::default(),
+            &mut ndjson,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(ndjson).unwrap(),
+            "{\"event\": \"anomaly\", \"data\": {\"kind\": \"stuck\", \"channel\": \"humidity\", \"state\": \"started\", \"value\": 48.200, \"score\": 7200.00, \"timestamp\": 3000}}\n"
+        );
+    }
+
+    #[test]
+    fn events_are_streamed_to_the_subscribers() {
+        let mut subscribers = Subscribers::new(8, 4);
+        let (server, client) = UnixStream::pair().unwrap();
+        subscribers
+            .subscribe(Box::new(server), StreamFormat::Ndjson, false)
+            .unwrap();
+        let (server, reduced_client) = UnixStream::pair().unwrap();
+        subscribers
+            .subscribe(Box::new(server), StreamFormat::Ndjson, true)
+            .unwrap();
+        subscribers.publish(Event::Sample(sample(1)));
+        subscribers.publish(Event::Reduced("hour", sample(2)));
+        subscribers.publish(Event::Sample(sample(3)));
+
+        let events = |client: UnixStream, nb_events: usize| -> Vec<String> {
+            BufReader::new(client)
+                .lines()
+                .take(nb_events)
+                .map(|line| line.unwrap()[..27].to_string())
+                .collect()
+        };
+        assert_eq!(
+            events(client, 2),
+            [
+                "{\"event\": \"sample\", \"data\":",
+                "{\"event\": \"sample\", \"data\":"
+            ]
+        );
+        assert_eq!(
+            events(reduced_client, 3),
+            [
+                "{\"event\": \"sample\", \"data\":",
+                "{\"event\": \"hour\", \"data\": {",
+                "{\"event\": \"sample\", \"data\":"
+            ]
+        );
+    }
+
+    #[test]
+    fn slow_and_gone_subscribers_are_dropped() {
+        let mut subscribers = Subscribers::new(2, 4);
+        let (server, gone_client) = UnixStream::pair().unwrap();
+        subscribers
+            .subscribe(Box::new(server), StreamFormat::Ndjson, false)
+            .unwrap();
+        drop(gone_client);
+        // a client which never reads, once the socket buffer is full
+        let (server, slow_client) = UnixStream::pair().unwrap();
+        subscribers
+            .subscribe(Box::new(server), StreamFormat::Ndjson, false)
+            .unwrap();
+        for seconds in 0..100_000 {
+            subscribers.publish(Event::Sample(sample(seconds)));
+            if subscribers.is_empty() {
+                break;
+            }
+        }
+        assert!(subscribers.is_empty());
+        // the events queued before it was dropped are still written
+        let mut received = String::new();
+        BufReader::new(slow_client)
+            .take(1000)
+            .read_to_string(&mut received)
+            .unwrap();
+        assert!(received.starts_with("{\"event\": \"sample\""));
+    }
+
+    #[test]
+    fn too_many_subscribers() {
+        let mut subscribers = Subscribers::new(2, 1);
+        let (first, _first_client) = UnixStream::pair().unwrap();
+        subscribers
+            .subscribe(Box::new(first), StreamFormat::Ndjson, false)
+            .unwrap();
+        let (second, mut second_client) = UnixStream::pair().unwrap();
+        subscribers
+            .subscribe(Box::new(second), StreamFormat::Ndjson, false)
+            .unwrap();
+        let mut answer = String::new();
+        second_client.read_to_string(&mut answer).unwrap();
+        assert_eq!(answer, "{\"error\": \"too many subscribers\"}\n");
+        assert_eq!(subscribers.len(), 1);
+    }
+
+    #[test]
+    fn subscriptions_configuration() {
+        let config = Config::parse("[subscriptions]\nqueue = 4\nmax = 2\n").unwrap();
+        let subscribers = Subscribers::from_config(&config).unwrap();
+        assert_eq!(
+            (subscribers.queue_size, subscribers.max_subscribers),
+            (4, 2)
+        );
+        let config = Config::parse("[subscriptions]\nqueue = 0\n").unwrap();
+        assert_eq!(
+            Subscribers::from_config(&config).err(),
+            Some("line 1: section [subscriptions]: the queue can't be empty".to_string())
+        );
+    }
+}
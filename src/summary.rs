@@ -0,0 +1,687 @@
+use crate::{
+    config::{civil_date, Config},
+    json_value::JsonValue,
+    mapped_circular_buffer::{FixedSizeRecord, MappedCircularBuffer},
+    sensor_data::{SensorData, CHANNELS, UNITS},
+};
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    io::{self, Write},
+    mem,
+};
+
+// Days kept by default : 10 years
+const DEFAULT_DAYS: usize = 3660;
+const DAY_SECONDS: i64 = 86400;
+
+/// Time zone in which the days of the summaries start
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimeZone {
+    Utc,
+    /// Fixed offset from UTC, in seconds
+    Offset(i64),
+    /// Time zone of the system, from the `TZ` environment variable or `/etc/localtime`, with
+    /// its daylight saving time
+    Local,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    Day,
+    Month,
+}
+
+/// Statistics of a channel over a period, the times are timestamps in milliseconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelStats {
+    pub min: f64,
+    pub min_time: u64,
+    pub max: f64,
+    pub max_time: u64,
+    pub mean: f64,
+}
+
+/// Summary of a day or of a month
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    /// `YYYY-MM-DD` or `YYYY-MM`
+    pub period: String,
+    /// Days which have samples
+    pub days: u32,
+    pub samples: u32,
+    pub heating_degree_days: f64,
+    pub cooling_degree_days: f64,
+    /// In the order of `CHANNELS`
+    pub channels: [ChannelStats; 4],
+}
+
+/// Accumulated values of a channel during a day
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct ChannelDay {
+    min: f64,
+    min_time: u64,
+    max: f64,
+    max_time: u64,
+    sum: f64,
+}
+
+/// Accumulated samples of a local day
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DaySummary {
+    /// Days since the epoch, in the time zone of the summaries
+    day: i64,
+    samples: u32,
+    channels: [ChannelDay; 4],
+}
+
+/// Calendar summaries of the samples, read from the `[summary]` section of the configuration:
+/// ```text
+/// [summary]
+/// timezone = local             # utc (default), local or an offset like +01:00
+/// file = /var/lib/weather/days # file keeping the days across restarts
+/// days = 3660                  # days kept
+/// temperature = htu21Temp      # channel of the degree-days
+/// heating_base = 18            # the degree-days compare the mean temperature of a day to
+/// cooling_base = 21            # these bases, in °C
+/// ```
+/// The days are summarized from the samples added to the HOUR tier, the months are rolled up
+/// from the days.
+pub struct Summaries {
+    zone: TimeZone,
+    temperature: usize,
+    heating_base: f64,
+    cooling_base: f64,
+    capacity: usize,
+    days: VecDeque<DaySummary>,
+    file: Option<MappedCircularBuffer<DaySummary>>,
+}
+
+impl TimeZone {
+    /// `utc`, `local` or an offset like `+01:00`, `-03:30` or `+2`
+    pub fn parse(text: &str) -> Option<TimeZone> {
+        match text {
+            "utc" | "UTC" => return Some(TimeZone::Utc),
+            "local" => return Some(TimeZone::Local),
+            _ => (),
+        }
+        let sign = match text.chars().next()? {
+            '+' => 1,
+            '-' => -1,
+            _ => return None,
+        };
+        let (hours, minutes) = text[1..].split_once(':').unwrap_or((&text[1..], "0"));
+        let hours: i64 = hours.parse().ok()?;
+        let minutes: i64 = minutes.parse().ok()?;
+        if hours > 14 || minutes >= 60 {
+            return None;
+        }
+        Some(TimeZone::Offset(sign * (hours * 3600 + minutes * 60)))
+    }
+
+    /// Offset from UTC at a time, in seconds
+    pub fn offset(&self, seconds: i64) -> i64 {
+        match self {
+            TimeZone::Utc => 0,
+            TimeZone::Offset(offset) => *offset,
+            TimeZone::Local => {
+                let time = seconds as libc::time_t;
+                // SAFETY: an all-zero tm is valid, it only holds integers and a nullable pointer
+                let mut tm: libc::tm = unsafe { mem::zeroed() };
+                // SAFETY: both pointers come from references to locals which outlive the call
+                if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+                    0
+                } else {
+                    tm.tm_gmtoff as i64
+                }
+            }
+        }
+    }
+
+    /// Local day of a timestamp in milliseconds, in days since the epoch
+    pub fn day(&self, timestamp: u64) -> i64 {
+        let seconds = (timestamp / 1000) as i64;
+        (seconds + self.offset(seconds)).div_euclid(DAY_SECONDS)
+    }
+}
+
+impl Period {
+    pub fn parse(word: Option<&str>) -> Result<Period, String> {
+        match word {
+            Some("day") => Ok(Period::Day),
+            Some("month") => Ok(Period::Month),
+            Some(word) => Err(format!("unknown period {}, expected day or month", word)),
+            None => Err("missing period".to_string()),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Month => "month",
+        }
+    }
+}
+
+impl ChannelDay {
+    fn new(value: f64, time: u64) -> ChannelDay {
+        ChannelDay {
+            min: value,
+            min_time: time,
+            max: value,
+            max_time: time,
+            sum: value,
+        }
+    }
+
+    fn add(&mut self, value: f64, time: u64) {
+        if value < self.min {
+            self.min = value;
+            self.min_time = time;
+        }
+        if value > self.max {
+            self.max = value;
+            self.max_time = time;
+        }
+        self.sum += value;
+    }
+}
+
+impl DaySummary {
+    fn new(day: i64, sample: &SensorData) -> DaySummary {
+        let time = sample.get_timestamp().as_millis() as u64;
+        let mut channels = [ChannelDay::new(0.0, time); 4];
+        for (channel, name) in channels.iter_mut().zip(CHANNELS.iter()) {
+            *channel = ChannelDay::new(sample.get_channel(name).unwrap_or(f64::NAN), time);
+        }
+        DaySummary {
+            day,
+            samples: 1,
+            channels,
+        }
+    }
+
+    fn add(&mut self, sample: &SensorData) {
+        let time = sample.get_timestamp().as_millis() as u64;
+        for (channel, name) in self.channels.iter_mut().zip(CHANNELS.iter()) {
+            channel.add(sample.get_channel(name).unwrap_or(f64::NAN), time);
+        }
+        self.samples += 1;
+    }
+
+    fn mean(&self, channel: usize) -> f64 {
+        self.channels[channel].sum / self.samples as f64
+    }
+}
+
+impl FixedSizeRecord for DaySummary {
+    const RECORD_SIZE: usize = 12 + 4 * 40;
+
+    fn write_record(&self, record: &mut [u8]) {
+        record[0..8].copy_from_slice(&self.day.to_le_bytes());
+        record[8..12].copy_from_slice(&self.samples.to_le_bytes());
+        for (index, channel) in self.channels.iter().enumerate() {
+            let offset = 12 + index * 40;
+            record[offset..offset + 8].copy_from_slice(&channel.min.to_le_bytes());
+            record[offset + 8..offset + 16].copy_from_slice(&channel.min_time.to_le_bytes());
+            record[offset + 16..offset + 24].copy_from_slice(&channel.max.to_le_bytes());
+            record[offset + 24..offset + 32].copy_from_slice(&channel.max_time.to_le_bytes());
+            record[offset + 32..offset + 40].copy_from_slice(&channel.sum.to_le_bytes());
+        }
+    }
+
+    fn read_record(record: &[u8]) -> DaySummary {
+        let field = |offset: usize| -> [u8; 8] { record[offset..offset + 8].try_into().unwrap() };
+        let mut channels = [ChannelDay::new(0.0, 0); 4];
+        for (index, channel) in channels.iter_mut().enumerate() {
+            let offset = 12 + index * 40;
+            *channel = ChannelDay {
+                min: f64::from_le_bytes(field(offset)),
+                min_time: u64::from_le_bytes(field(offset + 8)),
+                max: f64::from_le_bytes(field(offset + 16)),
+                max_time: u64::from_le_bytes(field(offset + 24)),
+                sum: f64::from_le_bytes(field(offset + 32)),
+            };
+        }
+        DaySummary {
+            day: i64::from_le_bytes(field(0)),
+            samples: u32::from_le_bytes(record[8..12].try_into().unwrap()),
+            channels,
+        }
+    }
+}
+
+impl Summaries {
+    pub fn new(zone: TimeZone) -> Summaries {
+        Summaries {
+            zone,
+            temperature: 2,
+            heating_base: 18.0,
+            cooling_base: 21.0,
+            capacity: DEFAULT_DAYS,
+            days: VecDeque::new(),
+            file: None,
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Result<Summaries, String> {
+        let mut summaries = Summaries::new(TimeZone::Utc);
+        let section = match config.section("summary")? {
+            Some(section) => section,
+            None => return Ok(summaries),
+        };
+        section.check_keys(&[
+            "timezone",
+            "file",
+            "days",
+            "temperature",
+            "heating_base",
+            "cooling_base",
+        ])?;
+        if let Some(zone) = section.get_str("timezone") {
+            summaries.zone = TimeZone::parse(zone).ok_or_else(|| {
+                section.error(&format!(
+                    "invalid time zone {}, expected utc, local or an offset like +01:00",
+                    zone
+                ))
+            })?;
+        }
+        if let Some(channel) = section.get_str("temperature") {
+            summaries.temperature = CHANNELS
+                .iter()
+                .position(|name| *name == channel)
+                .ok_or_else(|| section.error(&format!("unknown channel {}", channel)))?;
+        }
+        summaries.heating_base = section.get_or("heating_base", summaries.heating_base)?;
+        summaries.cooling_base = section.get_or("cooling_base", summaries.cooling_base)?;
+        summaries.capacity = section.get_or("days", summaries.capacity)?;
+        if summaries.capacity == 0 {
+            return Err(section.error("at least 1 day must be kept"));
+        }
+        if let Some(path) = section.get_str("file") {
+            let file = MappedCircularBuffer::<DaySummary>::open(path, summaries.capacity)
+                .map_err(|err| section.error(&format!("couldn't open {} : {}", path, err)))?;
+            summaries.days = file.iter().collect();
+            summaries.file = Some(file);
+        }
+        Ok(summaries)
+    }
+
+    /// Add a sample to the summary of its day, the samples older than the last day are ignored
+    pub fn add(&mut self, sample: &SensorData) {
+        let day = self.zone.day(sample.get_timestamp().as_millis() as u64);
+        match self.days.back_mut() {
+            Some(last) if last.day == day => {
+                last.add(sample);
+                if let Some(file) = &mut self.file {
+                    if file.replace_back(*last).is_err() {
+                        println!("Failed to update the summary of the day : the file is empty");
+                    }
+                }
+            }
+            Some(last) if last.day > day => (),
+            _ => {
+                let summary = DaySummary::new(day, sample);
+                if self.days.len() == self.capacity {
+                    self.days.pop_front();
+                }
+                self.days.push_back(summary);
+                if let Some(file) = &mut self.file {
+                    if file.is_full() {
+                        file.drain_front(1);
+                    }
+                    if file.push_back(summary).is_err() {
+                        println!("Failed to store the summary of the day : the file is full");
+                    }
+                }
+            }
+        }
+    }
+
+    /// Summaries of the days or of the months, from the oldest
+    pub fn summaries(&self, period: Period) -> Vec<Summary> {
+        let mut summaries: Vec<Summary> = Vec::new();
+        let mut days: Vec<&DaySummary> = Vec::new();
+        let mut current = String::new();
+        for day in &self.days {
+            let (year, month, day_of_month) = civil_date(day.day);
+            let period = match period {
+                Period::Day => format!("{:04}-{:02}-{:02}", year, month, day_of_month),
+                Period::Month => format!("{:04}-{:02}", year, month),
+            };
+            if period != current && !days.is_empty() {
+                summaries.push(self.summary(mem::take(&mut current), &days));
+                days.clear();
+            }
+            current = period;
+            days.push(day);
+        }
+        if !days.is_empty() {
+            summaries.push(self.summary(current, &days));
+        }
+        summaries
+    }
+
+    fn summary(&self, period: String, days: &[&DaySummary]) -> Summary {
+        let samples: u32 = days.iter().map(|day| day.samples).sum();
+        let mut channels = [ChannelStats {
+            min: f64::INFINITY,
+            min_time: 0,
+            max: f64::NEG_INFINITY,
+            max_time: 0,
+            mean: 0.0,
+        }; 4];
+        for (index, stats) in channels.iter_mut().enumerate() {
+            for day in days {
+                let channel = &day.channels[index];
+                if channel.min < stats.min {
+                    stats.min = channel.min;
+                    stats.min_time = channel.min_time;
+                }
+                if channel.max > stats.max {
+                    stats.max = channel.max;
+                    stats.max_time = channel.max_time;
+                }
+                stats.mean += channel.sum;
+            }
+            stats.mean /= samples as f64;
+        }
+        let means: Vec<f64> = days.iter().map(|day| day.mean(self.temperature)).collect();
+        Summary {
+            period,
+            days: days.len() as u32,
+            samples,
+            heating_degree_days: means
+                .iter()
+                .map(|mean| (self.heating_base - mean).max(0.0))
+                .sum(),
+            cooling_degree_days: means
+                .iter()
+                .map(|mean| (mean - self.cooling_base).max(0.0))
+                .sum(),
+            channels,
+        }
+    }
+
+    pub fn write_json(&self, period: Period, w: &mut dyn Write) -> io::Result<()> {
+        write_json(&self.summaries(period), w)
+    }
+}
+
+impl Summary {
+    pub fn from_json(json: &JsonValue) -> Result<Summary, String> {
+        let number = |value: Option<&JsonValue>, name: &str| {
+            value
+                .and_then(JsonValue::as_f64)
+                .ok_or_else(|| format!("missing {} in the summary", name))
+        };
+        let mut channels = [ChannelStats {
+            min: 0.0,
+            min_time: 0,
+            max: 0.0,
+            max_time: 0,
+            mean: 0.0,
+        }; 4];
+        for (stats, name) in channels.iter_mut().zip(CHANNELS.iter()) {
+            let channel = json
+                .get(name)
+                .ok_or_else(|| format!("missing {} in the summary", name))?;
+            *stats = ChannelStats {
+                min: number(channel.get("min"), "min")?,
+                min_time: number(channel.get("minTime"), "minTime")? as u64,
+                max: number(channel.get("max"), "max")?,
+                max_time: number(channel.get("maxTime"), "maxTime")? as u64,
+                mean: number(channel.get("mean"), "mean")?,
+            };
+        }
+        Ok(Summary {
+            period: json
+                .get("period")
+                .and_then(JsonValue::as_str)
+                .ok_or("missing period in the summary")?
+                .to_string(),
+            days: number(json.get("days"), "days")? as u32,
+            samples: number(json.get("samples"), "samples")? as u32,
+            heating_degree_days: number(json.get("heatingDegreeDays"), "heatingDegreeDays")?,
+            cooling_degree_days: number(json.get("coolingDegreeDays"), "coolingDegreeDays")?,
+            channels,
+        })
+    }
+}
+
+pub fn write_json(summaries: &[Summary], w: &mut dyn Write) -> io::Result<()> {
+    w.write_all(b"[")?;
+    for (index, summary) in summaries.iter().enumerate() {
+        if index > 0 {
+            w.write_all(b",\n")?;
+        }
+        w.write_fmt(format_args!(
+            "{{\"period\": \"{}\", \"days\": {}, \"samples\": {}, \"heatingDegreeDays\": {:.2}, \"coolingDegreeDays\": {:.2}",
+            summary.period,
+            summary.days,
+            summary.samples,
+            summary.heating_degree_days,
+            summary.cooling_degree_days
+        ))?;
+        for (name, stats) in CHANNELS.iter().zip(summary.channels.iter()) {
+            w.write_fmt(format_args!(
+                ",\n\"{}\": {{\"min\": {:.3}, \"minTime\": {}, \"max\": {:.3}, \"maxTime\": {}, \"mean\": {:.3}}}",
+                name, stats.min, stats.min_time, stats.max, stats.max_time, stats.mean
+            ))?;
+        }
+        w.write_all(b"}")?;
+    }
+    w.write_all(b"]\n")
+}
+
+/// One line per summary, the times are timestamps in milliseconds
+pub fn write_csv(summaries: &[Summary], w: &mut dyn Write) -> io::Result<()> {
+    write!(
+        w,
+        "period,days,samples,heating_degree_days,cooling_degree_days"
+    )?;
+    for name in CHANNELS.iter() {
+        write!(
+            w,
+            ",{0}_min,{0}_min_time,{0}_max,{0}_max_time,{0}_mean",
+            name
+        )?;
+    }
+    writeln!(w)?;
+    for summary in summaries {
+        write!(
+            w,
+            "{},{},{},{:.2},{:.2}",
+            summary.period,
+            summary.days,
+            summary.samples,
+            summary.heating_degree_days,
+            summary.cooling_degree_days
+        )?;
+        for stats in summary.channels.iter() {
+            write!(
+                w,
+                ",{:.3},{},{:.3},{},{:.3}",
+                stats.min, stats.min_time, stats.max, stats.max_time, stats.mean
+            )?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+/// Minimum, mean and maximum of each channel
+pub fn write_table(summaries: &[Summary], w: &mut dyn Write) -> io::Result<()> {
+    write!(
+        w,
+        "{:<10}  {:>7}  {:>6}  {:>6}",
+        "period", "samples", "HDD", "CDD"
+    )?;
+    let headers: Vec<String> = CHANNELS
+        .iter()
+        .zip(UNITS.iter())
+        .map(|(name, unit)| format!("{} ({}) min/mean/max", name, unit))
+        .collect();
+    for header in &headers {
+        write!(w, "  {}", header)?;
+    }
+    writeln!(w)?;
+    for summary in summaries {
+        write!(
+            w,
+            "{:<10}  {:>7}  {:>6.1}  {:>6.1}",
+            summary.period,
+            summary.samples,
+            summary.heating_degree_days,
+            summary.cooling_degree_days
+        )?;
+        for (stats, header) in summary.channels.iter().zip(headers.iter()) {
+            let values = format!("{:.1}/{:.1}/{:.1}", stats.min, stats.mean, stats.max);
+            write!(w, "  {:>width$}", values, width = header.chars().count())?;
+        }
+        writeln!(w)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_csv, Period, Summaries, Summary, TimeZone};
+    use crate::{
+        config::Config, json_value::JsonValue, sensor_data::SensorData, test_utils::TempPath,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    // 2023-11-14 00:00:00 UTC
+    const MIDNIGHT: u64 = 1_699_920_000;
+
+    fn sample(seconds: u64, temperature: i32, humidity: i32) -> SensorData {
+        SensorData::new(
+            UNIX_EPOCH + Duration::from_secs(seconds),
+            101.3,
+            temperature,
+            temperature,
+            humidity,
+        )
+    }
+
+    #[test]
+    fn time_zones() {
+        assert_eq!(TimeZone::parse("+01:00"), Some(TimeZone::Offset(3600)));
+        assert_eq!(TimeZone::parse("-03:30"), Some(TimeZone::Offset(-12600)));
+        assert_eq!(TimeZone::parse("+2"), Some(TimeZone::Offset(7200)));
+        assert_eq!(TimeZone::parse("UTC"), Some(TimeZone::Utc));
+        assert_eq!(TimeZone::parse("Paris"), None);
+        assert_eq!(TimeZone::parse("+01:75"), None);
+        // 23:30 UTC is already the next day 1 hour east
+        let timestamp = (MIDNIGHT - 1800) * 1000;
+        assert_eq!(TimeZone::Utc.day(timestamp), 19674);
+        assert_eq!(TimeZone::Offset(3600).day(timestamp), 19675);
+        assert_eq!(TimeZone::Offset(-3600).day(MIDNIGHT * 1000), 19674);
+    }
+
+    #[test]
+    fn days_and_months() {
+        let mut summaries = Summaries::new(TimeZone::Offset(3600));
+        // the last sample of 2023-11-14 in UTC+1 is at 22:30 UTC
+        summaries.add(&sample(MIDNIGHT + 3600, 10_000, 60_000));
+        summaries.add(&sample(MIDNIGHT + 12 * 3600, 16_000, 40_000));
+        summaries.add(&sample(MIDNIGHT + 22 * 3600 + 1800, 7_000, 80_000));
+        summaries.add(&sample(MIDNIGHT + 23 * 3600 + 1800, 25_000, 30_000));
+        // older than the last day
+        summaries.add(&sample(MIDNIGHT, 0, 0));
+        summaries.add(&sample(MIDNIGHT + 17 * 86400, 23_000, 30_000));
+        let days = summaries.summaries(Period::Day);
+        assert_eq!(days.len(), 3);
+        assert_eq!(days[0].period, "2023-11-14");
+        assert_eq!(days[0].samples, 3);
+        let temperature = days[0].channels[2];
+        assert_eq!(temperature.min, 7.0);
+        assert_eq!(temperature.min_time, (MIDNIGHT + 22 * 3600 + 1800) * 1000);
+        assert_eq!(temperature.max, 16.0);
+        assert_eq!(temperature.mean, 11.0);
+        // the mean of 11 °C is 7 degrees below the heating base
+        assert_eq!(days[0].heating_degree_days, 7.0);
+        assert_eq!(days[0].cooling_degree_days, 0.0);
+        assert_eq!(days[1].period, "2023-11-15");
+        assert_eq!(days[1].cooling_degree_days, 4.0);
+        assert_eq!(days[2].period, "2023-12-01");
+
+        let months = summaries.summaries(Period::Month);
+        assert_eq!(months.len(), 2);
+        assert_eq!(months[0].period, "2023-11");
+        assert_eq!(months[0].days, 2);
+        assert_eq!(months[0].samples, 4);
+        assert_eq!(months[0].heating_degree_days, 7.0);
+        assert_eq!(months[0].cooling_degree_days, 4.0);
+        assert_eq!(months[0].channels[3].max, 80.0);
+        assert_eq!(months[0].channels[2].max, 25.0);
+        assert_eq!(months[0].channels[2].mean, 14.5);
+
+        let mut json = Vec::new();
+        summaries.write_json(Period::Month, &mut json).unwrap();
+        let json = JsonValue::parse(&String::from_utf8(json).unwrap()).unwrap();
+        let read_back: Vec<Summary> = json
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|summary| Summary::from_json(summary).unwrap())
+            .collect();
+        // the JSON has 3 decimals
+        assert_eq!(read_back[1].channels[1], months[1].channels[1]);
+        assert_eq!(read_back[1].cooling_degree_days, 2.0);
+        assert_eq!(read_back[0].period, "2023-11");
+        let mut csv = Vec::new();
+        write_csv(&read_back[1..], &mut csv).unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert!(lines[0].starts_with(
+            "period,days,samples,heating_degree_days,cooling_degree_days,pressure_min,pressure_min_time,"
+        ));
+        assert!(lines[1].starts_with("2023-12,1,1,0.00,2.00,1013.000,1701388800000,"));
+    }
+
+    #[test]
+    fn days_survive_restarts() {
+        let file = TempPath::new("summary");
+        let config = format!(
+            "[summary]\nfile = {}\ndays = 2\ntemperature = bmp280Temp\n",
+            file.path().display()
+        );
+        let config = Config::parse(&config).unwrap();
+        {
+            let mut summaries = Summaries::from_config(&config).unwrap();
+            for day in 0..3 {
+                summaries.add(&sample(MIDNIGHT + day * 86400, 10_000, 50_000));
+            }
+            summaries.add(&sample(MIDNIGHT + 2 * 86400 + 60, 20_000, 50_000));
+        }
+        let summaries = Summaries::from_config(&config).unwrap();
+        let days = summaries.summaries(Period::Day);
+        assert_eq!(days.len(), 2);
+        assert_eq!(days[0].period, "2023-11-15");
+        assert_eq!(days[1].samples, 2);
+        assert_eq!(days[1].channels[1].mean, 15.0);
+        assert_eq!(days[1].heating_degree_days, 3.0);
+    }
+
+    #[test]
+    fn invalid_summaries() {
+        let error = |text: &str| Summaries::from_config(&Config::parse(text).unwrap()).err();
+        assert_eq!(
+            error("[summary]\ntimezone = Europe/Paris\n"),
+            Some(
+                "line 1: section [summary]: invalid time zone Europe/Paris, expected utc, local or an offset like +01:00"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            error("[summary]\ntemperature = humidity2\n"),
+            Some("line 1: section [summary]: unknown channel humidity2".to_string())
+        );
+        assert_eq!(
+            Period::parse(Some("week")),
+            Err("unknown period week, expected day or month".to_string())
+        );
+    }
+}
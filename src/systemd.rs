@@ -0,0 +1,88 @@
+use std::{
+    env, io, mem,
+    net::TcpListener,
+    os::unix::{
+        io::{FromRawFd, RawFd},
+        net::UnixListener,
+    },
+    process,
+};
+
+// First file descriptor passed by the init system
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Listening socket handed over by systemd
+pub enum ActivatedSocket {
+    Unix(UnixListener),
+    /// With its name (FileDescriptorName= of the socket unit), which selects the
+    /// `[listener <name>]` section it is used for
+    Tcp(Option<String>, TcpListener),
+}
+
+/// Sockets passed by systemd socket activation (`LISTEN_PID`, `LISTEN_FDS` and
+/// `LISTEN_FDNAMES`), empty when the process was not started this way.
+///
+/// The environment variables are removed, so the sockets are not used twice nor inherited by
+/// the child processes.
+pub fn listen_fds() -> Result<Vec<ActivatedSocket>, String> {
+    let listen_pid = env::var("LISTEN_PID").ok();
+    let listen_fds = env::var("LISTEN_FDS").ok();
+    let listen_fdnames = env::var("LISTEN_FDNAMES").ok();
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    match listen_pid.map(|pid| pid.parse::<u32>()) {
+        Some(Ok(pid)) if pid == process::id() => (),
+        // the variables were meant for another process
+        _ => return Ok(Vec::new()),
+    }
+    let nb_fds = match listen_fds.map(|nb_fds| nb_fds.parse::<RawFd>()) {
+        Some(Ok(nb_fds)) if nb_fds >= 0 => nb_fds,
+        _ => return Err("invalid LISTEN_FDS".to_string()),
+    };
+    let names: Vec<String> = match listen_fdnames {
+        Some(names) => names.split(':').map(str::to_string).collect(),
+        None => Vec::new(),
+    };
+    (0..nb_fds)
+        .map(|index| {
+            let fd = SD_LISTEN_FDS_START + index;
+            let name = names.get(index as usize).cloned();
+            activated_socket(fd, name)
+                .map_err(|err| format!("invalid socket {} passed by systemd : {}", fd, err))
+        })
+        .collect()
+}
+
+fn activated_socket(fd: RawFd, name: Option<String>) -> io::Result<ActivatedSocket> {
+    // SAFETY: plain system calls on a file descriptor, the address is large enough for any family
+    let family = unsafe {
+        if libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut address: libc::sockaddr_storage = mem::zeroed();
+        let mut length = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        if libc::getsockname(
+            fd,
+            &mut address as *mut libc::sockaddr_storage as *mut libc::sockaddr,
+            &mut length,
+        ) != 0
+        {
+            return Err(io::Error::last_os_error());
+        }
+        address.ss_family as libc::c_int
+    };
+    // SAFETY: the descriptor was given to this process, which is now its only owner
+    match family {
+        libc::AF_UNIX => Ok(ActivatedSocket::Unix(unsafe {
+            UnixListener::from_raw_fd(fd)
+        })),
+        libc::AF_INET | libc::AF_INET6 => Ok(ActivatedSocket::Tcp(name, unsafe {
+            TcpListener::from_raw_fd(fd)
+        })),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported address family {}", family),
+        )),
+    }
+}
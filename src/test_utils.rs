@@ -0,0 +1,109 @@
+use crate::sensor_data::SensorData;
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpListener,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    thread::{self, JoinHandle},
+    time::{Duration, UNIX_EPOCH},
+};
+
+/// Sample `seconds` after the epoch: 1013 hPa, 20 and 21 °C, 50 %
+pub fn sample(seconds: u64) -> SensorData {
+    SensorData::new(
+        UNIX_EPOCH + Duration::from_secs(seconds),
+        101.3,
+        20_000,
+        21_000,
+        50_000,
+    )
+}
+
+/// Unique path in the temporary directory, the file (or directory) is removed at the end of the test
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    pub fn new(prefix: &str) -> TempPath {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let mut path = env::temp_dir();
+        path.push(format!(
+            "{}_{}_{}",
+            prefix,
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        TempPath(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.0.is_dir() {
+            let _ = fs::remove_dir_all(&self.0);
+        } else {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
+
+/// Request received by `serve_http`
+pub struct HttpRequest {
+    pub request_line: String,
+    pub headers: Vec<String>,
+    pub body: Vec<u8>,
+}
+
+impl HttpRequest {
+    /// Value of the first header of that name
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find_map(|header| {
+            let (header_name, value) = header.split_once(':')?;
+            Some(value.trim()).filter(|_| header_name.eq_ignore_ascii_case(name))
+        })
+    }
+}
+
+/// Stub HTTP server which answers each connection with the next of `responses` (status line,
+/// headers and body), then gives back the requests it received
+pub fn serve_http(
+    listener: TcpListener,
+    responses: Vec<&'static str>,
+) -> JoinHandle<Vec<HttpRequest>> {
+    thread::spawn(move || {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut headers = Vec::new();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header.trim().is_empty() {
+                    break;
+                }
+                headers.push(header.trim_end().to_string());
+            }
+            let mut request = HttpRequest {
+                request_line,
+                headers,
+                body: Vec::new(),
+            };
+            let content_length = request
+                .header("Content-Length")
+                .map_or(0, |length| length.parse().unwrap());
+            request.body = vec![0; content_length];
+            reader.read_exact(&mut request.body).unwrap();
+            reader.get_mut().write_all(response.as_bytes()).unwrap();
+            requests.push(request);
+        }
+        requests
+    })
+}
@@ -0,0 +1,78 @@
+use rustls::{
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    ClientConfig, RootCertStore, ServerConfig,
+};
+use std::sync::Arc;
+
+/// Certificates of a PEM file, the first one is the certificate of the owner of the key
+pub fn load_certificates(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|why| format!("couldn't read the certificates of {} : {}", path, why))?;
+    if certificates.is_empty() {
+        return Err(format!("no certificate in {}", path));
+    }
+    Ok(certificates)
+}
+
+/// First private key of a PEM file (PKCS #8, PKCS #1 or SEC1)
+pub fn load_private_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    PrivateKeyDer::from_pem_file(path)
+        .map_err(|why| format!("couldn't read the private key of {} : {}", path, why))
+}
+
+fn root_store(ca_path: &str) -> Result<RootCertStore, String> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_certificates(ca_path)? {
+        roots
+            .add(certificate)
+            .map_err(|why| format!("invalid CA certificate in {} : {}", ca_path, why))?;
+    }
+    Ok(roots)
+}
+
+/// TLS configuration of a listener, the clients need a certificate signed by one of the CA of
+/// `client_ca_path` when it is given
+pub fn server_config(
+    certificate_path: &str,
+    key_path: &str,
+    client_ca_path: Option<&str>,
+) -> Result<Arc<ServerConfig>, String> {
+    let builder = ServerConfig::builder();
+    let builder = match client_ca_path {
+        Some(client_ca_path) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(root_store(client_ca_path)?))
+                .build()
+                .map_err(|why| format!("invalid client CA {} : {}", client_ca_path, why))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+    builder
+        .with_single_cert(
+            load_certificates(certificate_path)?,
+            load_private_key(key_path)?,
+        )
+        .map(Arc::new)
+        .map_err(|why| format!("invalid certificate {} : {}", certificate_path, why))
+}
+
+/// TLS configuration of a client trusting the CA of `ca_path`, with an optional client
+/// certificate and its key
+pub fn client_config(
+    ca_path: &str,
+    client_certificate: Option<(&str, &str)>,
+) -> Result<Arc<ClientConfig>, String> {
+    let builder = ClientConfig::builder().with_root_certificates(root_store(ca_path)?);
+    match client_certificate {
+        Some((certificate_path, key_path)) => builder
+            .with_client_auth_cert(
+                load_certificates(certificate_path)?,
+                load_private_key(key_path)?,
+            )
+            .map_err(|why| format!("invalid certificate {} : {}", certificate_path, why)),
+        None => Ok(builder.with_no_client_auth()),
+    }
+    .map(Arc::new)
+}
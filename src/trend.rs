@@ -0,0 +1,419 @@
+use crate::{
+    config::Config,
+    derived::{sea_level_pressure, DerivedChannels},
+    historic::Historic,
+    metrics,
+    sensor_data::SensorData,
+};
+use std::{
+    io::{self, Write},
+    time::Duration,
+};
+
+/// Class of the change of the pressure over the window
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tendency {
+    Rising,
+    Steady,
+    Falling,
+}
+
+/// Barometric tendency computed from the HOUR tier, completed by the DAYS tier since the HOUR
+/// one holds less than the window, read from the `[trend]` section of the configuration:
+/// ```text
+/// [trend]
+/// window = 3h          # samples used by the linear regression of the pressure
+/// steady = 1.6         # largest change of the pressure over the window, in hPa, which is steady
+/// ```
+/// There is no trend until the samples cover at least half of the window. The forecast uses
+/// the pressure at the sea level, which needs the altitude of the `[derived]` section.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TrendSettings {
+    window: Duration,
+    steady: f64,
+}
+
+/// Tendency of the pressure and its forecast
+#[derive(Clone, Debug, PartialEq)]
+pub struct Trend {
+    /// Time covered by the samples of the regression, at least half of the window
+    pub span: Duration,
+    /// hPa per hour
+    pub slope: f64,
+    /// Change over the window in hPa, according to the slope
+    pub change: f64,
+    pub tendency: Tendency,
+    /// Sea level pressure at the end of the window according to the regression, in hPa
+    pub pressure: f64,
+    pub forecast: &'static str,
+}
+
+// Forecasts of the Zambretti forecaster, from A to Z
+const FORECASTS: [&str; 26] = [
+    "Settled fine",
+    "Fine weather",
+    "Becoming fine",
+    "Fine, becoming less settled",
+    "Fine, possible showers",
+    "Fairly fine, improving",
+    "Fairly fine, possible showers early",
+    "Fairly fine, showery later",
+    "Showery early, improving",
+    "Changeable, mending",
+    "Fairly fine, showers likely",
+    "Rather unsettled clearing later",
+    "Unsettled, probably improving",
+    "Showery, bright intervals",
+    "Showery, becoming less settled",
+    "Changeable, some rain",
+    "Unsettled, short fine intervals",
+    "Unsettled, rain later",
+    "Unsettled, some rain",
+    "Mostly very unsettled",
+    "Occasional rain, worsening",
+    "Rain at times, very unsettled",
+    "Rain at frequent intervals",
+    "Rain, very unsettled",
+    "Stormy, may improve",
+    "Stormy, much rain",
+];
+
+impl Tendency {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tendency::Rising => "rising",
+            Tendency::Steady => "steady",
+            Tendency::Falling => "falling",
+        }
+    }
+}
+
+impl Default for TrendSettings {
+    fn default() -> TrendSettings {
+        TrendSettings {
+            window: Duration::from_secs(3 * 3600),
+            steady: 1.6,
+        }
+    }
+}
+
+/// Slope and intercept of the least squares line through the points, none without at least
+/// 2 distinct abscissas
+pub fn linear_regression(points: &[(f64, f64)]) -> Option<(f64, f64)> {
+    let count = points.len() as f64;
+    let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / count;
+    let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / count;
+    let covariance: f64 = points
+        .iter()
+        .map(|(x, y)| (x - mean_x) * (y - mean_y))
+        .sum();
+    let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+    if points.len() < 2 || variance == 0.0 {
+        return None;
+    }
+    let slope = covariance / variance;
+    Some((slope, mean_y - slope * mean_x))
+}
+
+/// Forecast of the Zambretti forecaster for a sea level pressure in hPa
+pub fn zambretti(pressure: f64, tendency: Tendency) -> &'static str {
+    // letters of the Z numbers of each tendency, A being 0
+    const FALLING: [usize; 9] = [0, 1, 3, 7, 14, 17, 20, 23, 25];
+    const STEADY: [usize; 10] = [0, 1, 4, 10, 13, 15, 18, 22, 23, 25];
+    const RISING: [usize; 13] = [0, 1, 2, 5, 6, 8, 9, 11, 12, 16, 19, 24, 25];
+    let (z, first, letters): (f64, f64, &[usize]) = match tendency {
+        Tendency::Falling => (127.0 - 0.12 * pressure, 1.0, &FALLING),
+        Tendency::Steady => (144.0 - 0.13 * pressure, 10.0, &STEADY),
+        Tendency::Rising => (185.0 - 0.16 * pressure, 20.0, &RISING),
+    };
+    let index = (z.round() - first).clamp(0.0, (letters.len() - 1) as f64) as usize;
+    FORECASTS[letters[index]]
+}
+
+impl TrendSettings {
+    pub fn from_config(config: &Config) -> Result<TrendSettings, String> {
+        let mut settings = TrendSettings::default();
+        if let Some(section) = config.section("trend")? {
+            section.check_keys(&["window", "steady"])?;
+            if let Some(window) = section.get_duration("window")? {
+                settings.window = window;
+            }
+            settings.steady = section.get_or("steady", settings.steady)?;
+        }
+        Ok(settings)
+    }
+
+    /// Samples of the window which ends with the last sample of the historics, the most
+    /// detailed historic first
+    pub fn window_samples(&self, historics: &[Historic<SensorData>]) -> Vec<SensorData> {
+        let end = historics
+            .iter()
+            .filter_map(|historic| historic.to_vec().last().map(SensorData::get_timestamp))
+            .max();
+        match end {
+            Some(end) => Historic::merge_range(
+                historics,
+                end.saturating_sub(self.window),
+                end,
+                SensorData::get_timestamp,
+            ),
+            None => Vec::new(),
+        }
+    }
+
+    /// Trend of the samples of the window which ends with the last sample, none when they
+    /// cover less than half of the window. The sea level pressure uses the altitude of the
+    /// `derived` channels.
+    pub fn compute(&self, samples: &[SensorData], derived: &DerivedChannels) -> Option<Trend> {
+        let last = samples.last()?;
+        let end = last.get_timestamp();
+        let start = end.saturating_sub(self.window);
+        let points: Vec<(f64, f64)> = samples
+            .iter()
+            .filter(|sample| sample.get_timestamp() >= start)
+            .filter_map(|sample| {
+                let hours = (sample.get_timestamp() - start).as_secs_f64() / 3600.0;
+                sample
+                    .get_channel("pressure")
+                    .map(|pressure| (hours, pressure))
+            })
+            .collect();
+        let span = end - samples[samples.len() - points.len()].get_timestamp();
+        if span < self.window / 2 {
+            return None;
+        }
+        let (slope, intercept) = linear_regression(&points)?;
+        let window = self.window.as_secs_f64() / 3600.0;
+        let change = slope * window;
+        let tendency = if change >= self.steady {
+            Tendency::Rising
+        } else if change <= -self.steady {
+            Tendency::Falling
+        } else {
+            Tendency::Steady
+        };
+        let pressure = sea_level_pressure(
+            intercept + slope * points[points.len() - 1].0,
+            last.get_channel("bmp280Temp").unwrap_or(15.0),
+            derived.altitude,
+        );
+        Some(Trend {
+            span,
+            slope,
+            change,
+            tendency,
+            pressure,
+            forecast: zambretti(pressure, tendency),
+        })
+    }
+}
+
+impl Trend {
+    pub fn write_json(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_fmt(format_args!(
+            "{{\"span\": {}, \"slope\": {:.3}, \"change\": {:.2}, \"tendency\": \"{}\", \"pressure\": {:.2}, \"forecast\": \"{}\"}}\n",
+            self.span.as_secs(),
+            self.slope,
+            self.change,
+            self.tendency.name(),
+            self.pressure,
+            self.forecast
+        ))
+    }
+
+    pub fn write_metrics(&self, w: &mut dyn Write) -> io::Result<()> {
+        metrics::write_metric(
+            w,
+            "pressure_slope_hpa_per_hour",
+            "gauge",
+            "Slope of the regression of the pressure over the trend window",
+            &[("", self.slope)],
+        )?;
+        let tendencies: Vec<(String, f64)> =
+            [Tendency::Rising, Tendency::Steady, Tendency::Falling]
+                .iter()
+                .map(|tendency| {
+                    (
+                        format!("tendency=\"{}\"", tendency.name()),
+                        if *tendency == self.tendency { 1.0 } else { 0.0 },
+                    )
+                })
+                .collect();
+        let tendencies: Vec<(&str, f64)> = tendencies
+            .iter()
+            .map(|(labels, value)| (labels.as_str(), *value))
+            .collect();
+        metrics::write_metric(
+            w,
+            "pressure_tendency",
+            "gauge",
+            "Tendency of the pressure, 1 for the current one",
+            &tendencies,
+        )?;
+        let forecast = format!("forecast=\"{}\"", self.forecast);
+        metrics::write_metric(
+            w,
+            "forecast",
+            "gauge",
+            "Zambretti forecast, always 1",
+            &[(&forecast, 1.0)],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{linear_regression, zambretti, Tendency, TrendSettings};
+    use crate::{
+        config::Config,
+        derived::DerivedChannels,
+        historic::{Historic, TIER_SIZES},
+        sensor_data::SensorData,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// One sample every 2 minutes during `hours`, with a pressure in hPa given by `pressure`
+    /// of the time in hours
+    fn curve(hours: f64, pressure: impl Fn(f64) -> f64) -> Vec<SensorData> {
+        (0..=(hours * 30.0) as u64)
+            .map(|index| {
+                let time = index as f64 / 30.0;
+                SensorData::new(
+                    UNIX_EPOCH + Duration::from_secs(1_700_000_000 + index * 120),
+                    (pressure(time) / 10.0) as f32,
+                    15_000,
+                    15_000,
+                    50_000,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn regression() {
+        let (slope, intercept) =
+            linear_regression(&[(0.0, 1.0), (1.0, 3.1), (2.0, 4.9), (3.0, 7.0)]).unwrap();
+        assert!((slope - 1.98).abs() < 1e-9);
+        assert!((intercept - 1.03).abs() < 1e-9);
+        assert_eq!(linear_regression(&[(1.0, 1.0)]), None);
+        assert_eq!(linear_regression(&[(1.0, 1.0), (1.0, 2.0)]), None);
+    }
+
+    #[test]
+    fn tendencies_of_synthetic_curves() {
+        let settings = TrendSettings::default();
+        let derived = DerivedChannels::default();
+        // falling by 1 hPa an hour, with a ripple which the regression ignores
+        let trend = settings
+            .compute(
+                &curve(6.0, |t| 1010.0 - t + 0.3 * (t * 20.0).sin()),
+                &derived,
+            )
+            .unwrap();
+        assert!((trend.slope + 1.0).abs() < 0.05, "{}", trend.slope);
+        assert_eq!(trend.tendency, Tendency::Falling);
+        assert_eq!(trend.span, Duration::from_secs(3 * 3600));
+        assert!((trend.pressure - 1004.0).abs() < 0.1, "{}", trend.pressure);
+        assert_eq!(trend.forecast, "Occasional rain, worsening");
+
+        let trend = settings
+            .compute(&curve(4.0, |t| 1025.0 + 0.2 * t), &derived)
+            .unwrap();
+        assert_eq!(trend.tendency, Tendency::Steady);
+        assert_eq!(trend.forecast, "Fine weather");
+
+        let trend = settings
+            .compute(&curve(4.0, |t| 1000.0 + 0.8 * t), &derived)
+            .unwrap();
+        assert_eq!(trend.tendency, Tendency::Rising);
+        assert!((trend.change - 2.4).abs() < 1e-3);
+        assert_eq!(trend.forecast, "Fairly fine, possible showers early");
+
+        // a short historic gives a shorter span, down to half of the window
+        let trend = settings
+            .compute(&curve(1.5, |t| 1000.0 + t), &derived)
+            .unwrap();
+        assert_eq!(trend.span, Duration::from_secs(5400));
+        assert!(settings
+            .compute(&curve(1.0, |t| 1000.0 + t), &derived)
+            .is_none());
+        assert!(settings
+            .compute(&curve(0.0, |_| 1000.0), &derived)
+            .is_none());
+
+        let mut json = Vec::new();
+        trend.write_json(&mut json).unwrap();
+        assert_eq!(
+            String::from_utf8(json).unwrap(),
+            "{\"span\": 5400, \"slope\": 1.000, \"change\": 3.00, \"tendency\": \"rising\", \"pressure\": 1001.50, \"forecast\": \"Showery early, improving\"}\n"
+        );
+    }
+
+    #[test]
+    fn window_of_the_historic_tiers() {
+        let settings = TrendSettings::default();
+        let derived = DerivedChannels::default();
+        let mut historics: Vec<Historic<SensorData>> = TIER_SIZES
+            .iter()
+            .map(|(size, limit)| Historic::new(*size, *limit))
+            .collect();
+        // one sample every 5 s, falling by 1 hPa an hour
+        let mut time = 0;
+        let mut feed = |historics: &mut Vec<Historic<SensorData>>, hours: u64| {
+            for _ in 0..hours * 720 {
+                let pressure = 1010.0 - time as f64 / 3600.0;
+                historics[0].add(SensorData::new(
+                    UNIX_EPOCH + Duration::from_secs(1_700_000_000 + time),
+                    (pressure / 10.0) as f32,
+                    15_000,
+                    15_000,
+                    50_000,
+                ));
+                Historic::reduce(historics);
+                time += 5;
+            }
+        };
+        feed(&mut historics, 8);
+        // the HOUR tier alone is too short for the window
+        let hour = historics[1].to_vec();
+        let hour_span = hour[hour.len() - 1].get_timestamp() - hour[0].get_timestamp();
+        assert!(hour_span < settings.window, "{:?}", hour_span);
+        let samples = settings.window_samples(&historics[1..]);
+        let trend = settings.compute(&samples, &derived).unwrap();
+        assert!(
+            trend.span >= Duration::from_secs(2 * 3600),
+            "{:?}",
+            trend.span
+        );
+        assert!((trend.slope + 1.0).abs() < 0.05, "{}", trend.slope);
+        assert!((trend.change + 3.0).abs() < 0.15, "{}", trend.change);
+        assert_eq!(trend.tendency, Tendency::Falling);
+        assert!(settings.window_samples(&[]).is_empty());
+    }
+
+    #[test]
+    fn zambretti_forecasts() {
+        assert_eq!(zambretti(1050.0, Tendency::Rising), "Settled fine");
+        assert_eq!(
+            zambretti(1013.0, Tendency::Steady),
+            "Fine, possible showers"
+        );
+        assert_eq!(
+            zambretti(1013.0, Tendency::Falling),
+            "Showery, becoming less settled"
+        );
+        assert_eq!(zambretti(950.0, Tendency::Falling), "Stormy, much rain");
+    }
+
+    #[test]
+    fn settings() {
+        let config = Config::parse("[trend]\nwindow = 6h\nsteady = 1\n").unwrap();
+        let settings = TrendSettings::from_config(&config).unwrap();
+        assert_eq!(settings.window, Duration::from_secs(6 * 3600));
+        assert_eq!(settings.steady, 1.0);
+        assert_eq!(
+            TrendSettings::from_config(&Config::default()),
+            Ok(TrendSettings::default())
+        );
+    }
+}
@@ -0,0 +1,77 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::{self, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The `query` subcommand against a daemon running as a collector, without sensors
+#[test]
+fn query_subcommand() {
+    let directory: PathBuf = env::temp_dir().join(format!("query_client_{}", process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let socket = directory.join("socket");
+    let config = directory.join("config.ini");
+    fs::write(
+        &config,
+        "[collector]\nlisten = 127.0.0.1:0\nsecret = secret shared by the nodes\n",
+    )
+    .unwrap();
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_circular_buffer"))
+        .arg("--config")
+        .arg(&config)
+        .arg(&socket)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let query = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_circular_buffer"))
+            .arg("query")
+            .arg("--socket")
+            .arg(&socket)
+            .args(args)
+            .output()
+            .unwrap();
+        (
+            output.status.success(),
+            String::from_utf8_lossy(&output.stdout).to_string(),
+            String::from_utf8_lossy(&output.stderr).to_string(),
+        )
+    };
+    let start = Instant::now();
+    let channels = loop {
+        let (success, output, _) = query(&["--format", "csv", "channels"]);
+        if success || start.elapsed() > Duration::from_secs(10) {
+            break output;
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    let latest = query(&["--format", "json", "latest"]);
+    let range = query(&["range", "2h"]);
+    let unknown_tier = query(&["tier", "week"]);
+    let _ = daemon.kill();
+    let _ = daemon.wait();
+    let _ = fs::remove_dir_all(&directory);
+
+    assert_eq!(
+        channels,
+        "channel,unit\npressure,hPa\nbmp280Temp,°C\nhtu21Temp,°C\nhumidity,%\n"
+    );
+    assert_eq!(latest, (true, "[]\n".to_string(), String::new()));
+    assert_eq!(
+        range,
+        (
+            true,
+            "time (UTC)           pressure (hPa)  bmp280Temp (°C)  htu21Temp (°C)  humidity (%)\n"
+                .to_string(),
+            String::new()
+        )
+    );
+    // the errors go to stderr, only the answers are written on stdout
+    assert_eq!(
+        unknown_tier,
+        (false, String::new(), "unknown tier week\n".to_string())
+    );
+}
@@ -0,0 +1,72 @@
+use std::{
+    env, fs,
+    io::{Read, Write},
+    os::unix::{io::AsRawFd, net::UnixListener, net::UnixStream, process::CommandExt},
+    path::PathBuf,
+    process::{self, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// The socket passed as descriptor 3, like systemd does, is served instead of the socket given
+/// on the command line
+#[test]
+fn socket_passed_by_systemd_is_served() {
+    let directory: PathBuf = env::temp_dir().join(format!("socket_activation_{}", process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let activated_socket = directory.join("activated");
+    let other_socket = directory.join("other");
+    let config = directory.join("config.ini");
+    // a collector runs without sensors
+    fs::write(
+        &config,
+        "[collector]\nlisten = 127.0.0.1:0\nsecret = secret shared by the nodes\n",
+    )
+    .unwrap();
+
+    let listener = UnixListener::bind(&activated_socket).unwrap();
+    let fd = listener.as_raw_fd();
+    let mut command = Command::new("sh");
+    command
+        .arg("-c")
+        .arg("LISTEN_PID=$$ LISTEN_FDS=1 exec \"$0\" --config \"$1\" \"$2\"")
+        .arg(env!("CARGO_BIN_EXE_circular_buffer"))
+        .arg(&config)
+        .arg(&other_socket)
+        .stdout(Stdio::null());
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            let result = if fd == 3 {
+                libc::fcntl(fd, libc::F_SETFD, 0)
+            } else {
+                libc::dup2(fd, 3)
+            };
+            if result < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let mut child = command.spawn().unwrap();
+    drop(listener);
+
+    let start = Instant::now();
+    let answer = loop {
+        let mut stream = UnixStream::connect(&activated_socket).unwrap();
+        stream.write_all(b"nodes\n").unwrap();
+        let mut answer = String::new();
+        let _ = stream.read_to_string(&mut answer);
+        if !answer.is_empty() || start.elapsed() > Duration::from_secs(10) {
+            break answer;
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+    let other_socket_exists = other_socket.exists();
+    let _ = child.kill();
+    let _ = child.wait();
+    let _ = fs::remove_dir_all(&directory);
+
+    assert_eq!(answer, "{}\n");
+    assert!(!other_socket_exists);
+}